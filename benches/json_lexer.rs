@@ -4,6 +4,7 @@ extern crate libfern;
 use libfern::{
     fern::FernLexer,
     grammar::{
+        compiled::CompiledLexTable,
         lg::{LexicalGrammar, StateGraph},
         opg::OpGrammar,
     },
@@ -26,14 +27,14 @@ fn bench_parallel_lexing(path: &str, threads: usize) {
     let g = LexicalGrammar::from(buf.clone());
     let nfa = StateGraph::from(g.clone());
     let dfa = nfa.convert_to_dfa();
-    let table = dfa.build_table();
+    let table = CompiledLexTable::new(dfa.build_table());
 
     let file = File::open(path).unwrap();
     let mut memmap: memmap::Mmap = unsafe { MmapOptions::new().map(&file).unwrap() };
     let chunks = split_file_into_chunks(&mut memmap, 6000).unwrap();
 
     let _ = thread::scope(|s| {
-        let mut lexer: ParallelLexer<JsonLexer> = ParallelLexer::new(table.clone(), s, threads);
+        let mut lexer: ParallelLexer<JsonLexer> = ParallelLexer::new(&table, s, threads);
         let batch = lexer.new_batch();
         for task in chunks.iter().enumerate() {
             lexer.add_to_batch(&batch, task.1, task.0);
@@ -0,0 +1,62 @@
+extern crate core;
+extern crate libfern;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use libfern::fern;
+use libfern::grammar::compiled::CompiledGrammar;
+use libfern::grammar::opg::{OpGrammar, Token};
+use libfern::parser::Parser;
+
+/// `let x = 1 + 2 * y;` repeated `count` times.
+fn let_statements(g: &OpGrammar, count: usize) -> Vec<Token> {
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    let statement = [
+        t("LET"),
+        t("NAME"),
+        t("EQ"),
+        t("NUMBER"),
+        t("PLUS"),
+        t("NUMBER"),
+        t("ASTERISK"),
+        t("NAME"),
+        t("SEMI"),
+    ];
+    statement.iter().cycle().take(statement.len() * count).copied().collect()
+}
+
+fn parse(g: &CompiledGrammar, tokens: Vec<Token>) {
+    let mut parser = Parser::new(g);
+    parser.parse(tokens, Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    black_box(parser.collect_parse_tree().unwrap());
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let g = fern::grammar(&fern::lexing_table().unwrap()).unwrap();
+    let tokens = let_statements(&g, 200);
+    c.bench_function("parser_fern_let_statements_200", |b| b.iter(|| parse(&g, tokens.clone())));
+    let long = let_statements(&g, 2000);
+    c.bench_function("parser_fern_let_statements_2000", |b| b.iter(|| parse(&g, long.clone())));
+    c.bench_function("op_table_lookup", |b| {
+        b.iter(|| {
+            let mut n = 0;
+            for w in tokens.windows(2) {
+                n += g.get_precedence(black_box(w[0]), black_box(w[1])) as usize;
+            }
+            n
+        })
+    });
+    let handles: Vec<Vec<Token>> = g.rules.iter().map(|r| r.right.clone()).collect();
+    c.bench_function("reduction_tree_match_rule", |b| {
+        b.iter(|| {
+            let mut n = 0;
+            for h in &handles {
+                n += g.new_reduction_tree.match_rule(black_box(h).iter().copied()).is_some() as usize;
+            }
+            n
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
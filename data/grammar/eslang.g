@@ -75,6 +75,7 @@
 %terminal NUMBER
 %terminal STRING
 %terminal NAME
+%terminal TEXT
 %terminal PLUS
 %terminal MINUS
 %terminal ASTERISK
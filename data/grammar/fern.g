@@ -31,8 +31,6 @@
 %nonterminal fieldList
 %nonterminal fieldListBody
 %nonterminal field
-%nonterminal typeExpr
-%nonterminal ptrTypeStart
 
 %axiom chunk
 
@@ -95,10 +93,13 @@
 %terminal QUESTIONMARK
 %terminal STRUCT
 %terminal COMMENT
+%terminal ARROW
+
+%sync SEMI RBRACE
 
 %%
 
-chunk : statList
+chunk : statList.Module
 	;
 
 statList : stat
@@ -108,107 +109,89 @@ statList : stat
 	| statList SEMI
 	;
 
-stat : baseExp EQ expr
+stat : baseExp EQ expr %ast Assign
 	| functionCall
 	| retStat
-	| LBRACE statList RBRACE
-	| LBRACE RBRACE
-	| WHILE expr LBRACE statList RBRACE
-	| WHILE expr LBRACE RBRACE
-	| IF expr LBRACE statList RBRACE
-	| IF expr LBRACE RBRACE 
-	| IF expr LBRACE statList RBRACE elseIfBlock
-	| IF expr LBRACE RBRACE elseIfBlock
-	| STRUCT baseExp LBRACE RBRACE
-	| STRUCT baseExp LBRACE fieldList RBRACE
-	| FUNCTION baseExp LBRACK RBRACK LBRACE statList RBRACE
-	| FUNCTION baseExp LBRACK fieldList RBRACK LBRACE statList RBRACE
-	| FUNCTION baseExp LBRACK fieldList RBRACK LBRACE RBRACE
-	| FUNCTION baseExp LBRACK baseExp RBRACK LBRACE statList RBRACE
-	| FUNCTION baseExp LBRACK baseExp RBRACK LBRACE RBRACE
-	| FUNCTION baseExp LBRACK RBRACK LBRACE RBRACE
-	| FOR nameList IN exprList LBRACE statList RBRACE
-	| FOR nameList IN exprList LBRACE RBRACE
-	| LET baseExp EQ expr
-	| LET baseExp
-	;
-
-functionCall : baseExp LPAREN exprList RPAREN
-	| baseExp LPAREN expr RPAREN
-	| baseExp LPAREN RPAREN
-	;
-
-retStat : RETURN SEMI
-	| RETURN exprList SEMI
-	| RETURN expr SEMI
-	| RETURN
-	| RETURN exprList
-	| RETURN expr
-	;
-
-elseIfBlock : ELSEIF expr LBRACE statList RBRACE
-	| ELSEIF expr LBRACE RBRACE
-	| ELSEIF expr LBRACE RBRACE elseIfBlock
-	| ELSEIF expr LBRACE statList RBRACE elseIfBlock
-	| ELSE LBRACE RBRACE
-	| ELSE LBRACE statList RBRACE
-	;
-
-exprList : expr COMMA expr
-	| exprList COMMA expr
+	| LBRACE statList.StatList? RBRACE
+	| WHILE expr LBRACE statList.StatList? RBRACE %ast While
+	| IF expr LBRACE statList.StatList? RBRACE elseIfBlock? %ast If
+	| STRUCT baseExp LBRACE fieldList.FieldList? RBRACE %ast Struct
+	| FUNCTION baseExp LBRACK (fieldList.FieldList | baseExp.Field.FieldList)? RBRACK LBRACE statList.StatList? RBRACE %ast Function
+	| FUNCTION baseExp LBRACK (fieldList.FieldList | baseExp.Field.FieldList)? RBRACK ARROW baseExp LBRACE statList.StatList? RBRACE %ast Function
+	| FOR (baseExp | nameList) IN (expr | exprList) LBRACE statList.StatList? RBRACE %ast For
+	| LET (baseExp | field) EQ expr %ast LetAssign
+	| LET (baseExp | field) %ast Let
+	;
+
+functionCall : baseExp LPAREN (exprList.ExprList | expr.ExprList)? RPAREN %ast FunctionCall
+	;
+
+retStat : RETURN (exprList | expr)? SEMI? %ast Return
+	;
+
+elseIfBlock : ELSEIF expr LBRACE statList.StatList? RBRACE elseIfBlock? %ast ElseIf
+	| ELSE LBRACE statList.StatList? RBRACE %ast Else
+	;
+
+nameList : baseExp COMMA baseExp %ast ExprList
+	| nameList.flatten COMMA baseExp %ast ExprList
+	;
+
+exprList : expr COMMA expr %ast ExprList
+	| exprList.flatten COMMA expr %ast ExprList
 	;
 
 expr : logicalOrExp
 	;
 
 logicalOrExp : logicalAndExp
-	| logicalOrExp OR logicalAndExp
+	| logicalOrExp OR logicalAndExp %ast Or
 	;
 
 logicalAndExp : relationalExp
-	| logicalAndExp AND relationalExp
+	| logicalAndExp AND relationalExp %ast And
 	;
 
 relationalExp : concatExp
-	| relationalExp LT concatExp
-	| relationalExp GT concatExp
-	| relationalExp LTEQ concatExp
-	| relationalExp GTEQ concatExp
-	| relationalExp NEQ concatExp
-	| relationalExp EQDOUBLE concatExp
+	| relationalExp LT concatExp %ast LessThan
+	| relationalExp GT concatExp %ast GreaterThan
+	| relationalExp LTEQ concatExp %ast LessThanOrEqual
+	| relationalExp GTEQ concatExp %ast GreaterThanOrEqual
+	| relationalExp NEQ concatExp %ast NotEqual
+	| relationalExp EQDOUBLE concatExp %ast Equal
 	;
 
 concatExp : additiveExp
-	| additiveExp DOT2 concatExp
+	| additiveExp DOT2 concatExp %ast Concat
 	;
 
 additiveExp : multiplicativeExp
-	| additiveExp PLUS multiplicativeExp
-	| additiveExp MINUS multiplicativeExp
+	| additiveExp PLUS multiplicativeExp %ast Add
+	| additiveExp MINUS multiplicativeExp %ast Subtract
 	;
 
 multiplicativeExp : unaryExp
-	| multiplicativeExp ASTERISK unaryExp
-	| multiplicativeExp DIVIDE unaryExp
-	| multiplicativeExp PERCENT unaryExp
+	| multiplicativeExp ASTERISK unaryExp %ast Multiply
+	| multiplicativeExp DIVIDE unaryExp %ast Divide
+	| multiplicativeExp PERCENT unaryExp %ast Modulo
 	;
 
 unaryExp : caretExp
-	| NOT unaryExp
-	| SHARP unaryExp
-	| UMINUS unaryExp
+	| NOT unaryExp %ast Not
+	| SHARP unaryExp %ast Length
+	| UMINUS unaryExp %ast Negate
 	;
 
 caretExp : baseExp
-	| baseExp CARET caretExp
+	| baseExp CARET caretExp %ast Power
 	;
 
-baseExp : NIL
-	| FALSE
-	| TRUE
-	| NUMBER
-	| STRING
-	| NAME
+baseExp : NIL.Nil
+	| FALSE.Bool
+	| TRUE.Bool
+	| NUMBER.Number
+	| STRING.String
+	| NAME.Name
 	| prefixExp
 	;
 
@@ -218,28 +201,28 @@ prefixExp : var
 	;
 
 fieldList : fieldListBody
-	| fieldListBody COMMA
+	| fieldListBody.flatten COMMA %ast FieldList
 	;
 
 fieldListBody : field
-	| fieldListBody COMMA field
+	| fieldListBody.flatten COMMA field %ast FieldList
 	;
 
-field : baseExp COLON baseExp
+field : baseExp COLON baseExp %ast Field
 	;
 
-var : baseExp DOT baseExp
-	| prefixExp DOT baseExp
+var : baseExp DOT baseExp %ast Member
+	| prefixExp DOT baseExp %ast Member
 	;
 
-varList : var COMMA var
-	| varList COMMA var
+varList : var COMMA var %ast VarList
+	| varList.flatten COMMA var %ast VarList
 	;
 
 funcName : nameDotList
-	| nameDotList COLON baseExp
+	| nameDotList COLON baseExp %ast Method
 	;
 
-nameDotList : baseExp DOT baseExp
-	| nameDotList DOT baseExp
+nameDotList : baseExp DOT baseExp %ast Member
+	| nameDotList DOT baseExp %ast Member
 	;
@@ -23,34 +23,34 @@
 
 %%
 
-OBJECT : LBRACE RBRACE
-       | LBRACE MEMBERS RBRACE
+OBJECT : LBRACE RBRACE %ast Object
+       | LBRACE MEMBERS RBRACE %ast Object
        ;
 
 MEMBERS : PAIR
         | PAIR COMMA MEMBERS
         ;
 
-PAIR : STRING COLON VALUE
+PAIR : STRING COLON VALUE %ast Pair
      ;
 
 VALUE : STRING
-      | NUMBER
+      | NUMBER.Number
       | OBJECT
       | ARRAY
-      | BOOL
+      | BOOL.Bool
       ;
 
-STRING : QUOTES QUOTES
-       | QUOTES CHARS QUOTES
+STRING : QUOTES QUOTES %ast String
+       | QUOTES CHARS QUOTES %ast String
        ;
 
-CHARS : CHAR
-      | CHAR CHARS
+CHARS : CHAR.Char
+      | CHAR.Char CHARS
       ;
 
-ARRAY : LSQUARE RSQUARE
-      | LSQUARE ELEMENTS RSQUARE
+ARRAY : LSQUARE RSQUARE %ast Array
+      | LSQUARE ELEMENTS RSQUARE %ast Array
       ;
 
 ELEMENTS : VALUE
@@ -101,16 +101,16 @@
 %%
 
 
-chunk : block
-	| ENDFILE
+chunk : block.Module
+	| ENDFILE %ast Module
 	;
 
 block : statList
 	| retStat
-	| statList RETURN SEMI
-	| statList RETURN exprList SEMI
-	| statList RETURN
-	| statList RETURN exprList
+	| statList RETURN .Return SEMI
+	| statList RETURN exprList.ExprList.Return SEMI
+	| statList RETURN .Return
+	| statList RETURN exprList.ExprList.Return
 	;
 
 statList : stat
@@ -121,57 +121,57 @@ statList : stat
 	;
 
 
-stat :  varList XEQ exprList
+stat :  varList.VarList XEQ exprList.ExprList %ast Assign
 	| functionCall
 	| label
-	| BREAK
-	| GOTO NAME
-	| DO block END
-	| DO END
-	| WHILE expr DO block END
-	| WHILE expr DO END
-	| REPEAT block UNTIL expr
-	| REPEAT UNTIL expr
-	| IF exprThen END
-	| IF exprThen ELSE block END
-	| IF exprThen ELSE END
-	| IF exprThenElseIfB END
-	| IF exprThenElseIfB ELSE block END
-	| IF exprThenElseIfB ELSE END
-	| FOR name XEQ eCe DO block END
-	| FOR name XEQ eCeCe DO block END
-	| FOR nameList IN exprList DO block END
-	| FUNCTION funcName LPARENFUNC parList RPARENFUNC block END
-	| FUNCTION funcName LPARENFUNC RPARENFUNC block END
-	| FOR name XEQ eCe DO END
-	| FOR name XEQ eCeCe DO END
-	| FOR nameList IN exprList DO END
-	| FUNCTION funcName LPARENFUNC parList RPARENFUNC END
-	| FUNCTION funcName LPARENFUNC RPARENFUNC END
-	| LOCAL FUNCTION name LPARENFUNC parList RPARENFUNC block END
-	| LOCAL FUNCTION name LPARENFUNC RPARENFUNC block END
-	| LOCAL FUNCTION name LPARENFUNC parList RPARENFUNC END
-	| LOCAL FUNCTION name LPARENFUNC RPARENFUNC END
-	| LOCAL nameList
-	| LOCAL nameList XEQ exprList
-	;
-
-elseIfBlock : block ELSEIF expr THEN block
-	| block ELSEIF expr THEN elseIfBlock
-	| ELSEIF expr THEN block
-	| block ELSEIF expr THEN
-	| ELSEIF expr THEN
-	| ELSEIF expr THEN elseIfBlock
+	| BREAK %ast Break
+	| GOTO NAME.Name %ast Goto
+	| DO block.StatList END
+	| DO .StatList END
+	| WHILE expr DO block.StatList END %ast While
+	| WHILE expr DO .StatList END %ast While
+	| REPEAT block.StatList UNTIL expr %ast Repeat
+	| REPEAT .StatList UNTIL expr %ast Repeat
+	| IF exprThen END %ast If
+	| IF exprThen ELSE block.StatList.Else END %ast If
+	| IF exprThen ELSE .StatList.Else END %ast If
+	| IF exprThenElseIfB END %ast If
+	| IF exprThenElseIfB ELSE block.StatList.Else END %ast If
+	| IF exprThenElseIfB ELSE .StatList.Else END %ast If
+	| FOR name XEQ eCe DO block.StatList END %ast NumericFor
+	| FOR name XEQ eCeCe DO block.StatList END %ast NumericFor
+	| FOR nameList.NameList IN exprList.ExprList DO block.StatList END %ast For
+	| FUNCTION funcName LPARENFUNC parList.NameList RPARENFUNC block.StatList END %ast Function
+	| FUNCTION funcName LPARENFUNC .NameList RPARENFUNC block.StatList END %ast Function
+	| FOR name XEQ eCe DO .StatList END %ast NumericFor
+	| FOR name XEQ eCeCe DO .StatList END %ast NumericFor
+	| FOR nameList.NameList IN exprList.ExprList DO .StatList END %ast For
+	| FUNCTION funcName LPARENFUNC parList.NameList RPARENFUNC .StatList END %ast Function
+	| FUNCTION funcName LPARENFUNC .NameList RPARENFUNC .StatList END %ast Function
+	| LOCAL FUNCTION name LPARENFUNC parList.NameList RPARENFUNC block.StatList END %ast LocalFunction
+	| LOCAL FUNCTION name LPARENFUNC .NameList RPARENFUNC block.StatList END %ast LocalFunction
+	| LOCAL FUNCTION name LPARENFUNC parList.NameList RPARENFUNC .StatList END %ast LocalFunction
+	| LOCAL FUNCTION name LPARENFUNC .NameList RPARENFUNC .StatList END %ast LocalFunction
+	| LOCAL nameList.NameList %ast Local
+	| LOCAL nameList.NameList XEQ exprList.ExprList %ast LocalAssign
+	;
+
+elseIfBlock : block.StatList ELSEIF expr THEN block.StatList %ast ElseIf
+	| block.StatList ELSEIF expr THEN elseIfBlock %ast ElseIf
+	| .StatList ELSEIF expr THEN block.StatList %ast ElseIf
+	| block.StatList ELSEIF expr THEN .StatList %ast ElseIf
+	| .StatList ELSEIF expr THEN .StatList %ast ElseIf
+	| .StatList ELSEIF expr THEN elseIfBlock %ast ElseIf
 	;
 
 exprThenElseIfB : expr THEN elseIfBlock
  	;
 
-exprThen : expr THEN block
-	| expr THEN
+exprThen : expr THEN block.StatList
+	| expr THEN .StatList
 	;
 
-name : NAME
+name : NAME.Name
 	;
 
 eCe : expr COMMA expr
@@ -180,94 +180,94 @@ eCe : expr COMMA expr
 eCeCe  : eCe COMMA expr
 	;
 
-dot3 : DOT3
+dot3 : DOT3.Vararg
 	;
 
-retStat : RETURN SEMI
-	| RETURN exprList SEMI
-	| RETURN
-	| RETURN exprList
+retStat : RETURN SEMI %ast Return
+	| RETURN exprList.ExprList SEMI %ast Return
+	| RETURN %ast Return
+	| RETURN exprList.ExprList %ast Return
 	;
 
-label : COLON2 NAME COLON2
+label : COLON2 NAME.Name COLON2 %ast Label
 	;
 
 funcName : nameDotList
-	| nameDotList COLON name
+	| nameDotList COLON name %ast Method
 	;
 
-nameDotList : NAME
-	| nameDotList DOT NAME
+nameDotList : NAME.Name
+	| nameDotList DOT NAME.Name %ast Member
 	;
 
 varList : var
-	| varList COMMA var
+	| varList.flatten COMMA var %ast VarList
 	;
 
-var : NAME
-	| prefixExp LBRACK expr RBRACK
-	| prefixExp DOT NAME
+var : NAME.Name
+	| prefixExp LBRACK expr RBRACK %ast Index
+	| prefixExp DOT NAME.Name %ast Member
 	;
 
-nameList : NAME
-	| nameList COMMA name
+nameList : NAME.Name
+	| nameList.flatten COMMA name %ast NameList
 	;
 
 exprList	: expr
-	| exprList COMMA expr
+	| exprList.flatten COMMA expr %ast ExprList
 	;
 
 expr : logicalOrExp
 	;
 
 logicalOrExp : logicalAndExp
-	| logicalOrExp OR logicalAndExp
+	| logicalOrExp OR logicalAndExp %ast Or
 	;
 
 logicalAndExp : relationalExp
-	| logicalAndExp AND relationalExp
+	| logicalAndExp AND relationalExp %ast And
 	;
 
 relationalExp : concatExp
-	| relationalExp LT concatExp
-	| relationalExp GT concatExp
-	| relationalExp LTEQ concatExp
-	| relationalExp GTEQ concatExp
-	| relationalExp NEQ concatExp
-	| relationalExp EQ2 concatExp
+	| relationalExp LT concatExp %ast LessThan
+	| relationalExp GT concatExp %ast GreaterThan
+	| relationalExp LTEQ concatExp %ast LessThanOrEqual
+	| relationalExp GTEQ concatExp %ast GreaterThanOrEqual
+	| relationalExp NEQ concatExp %ast NotEqual
+	| relationalExp EQ2 concatExp %ast Equal
 	;
 
 concatExp : additiveExp
-	| additiveExp DOT2 concatExp
+	| additiveExp DOT2 concatExp %ast Concat
 	;
 
 additiveExp : multiplicativeExp
-	| additiveExp PLUS multiplicativeExp
-	| additiveExp MINUS multiplicativeExp
+	| additiveExp PLUS multiplicativeExp %ast Add
+	| additiveExp MINUS multiplicativeExp %ast Subtract
 	;
 
 multiplicativeExp : unaryExp
-	| multiplicativeExp ASTERISK unaryExp
-	| multiplicativeExp DIVIDE unaryExp
-	| multiplicativeExp PERCENT unaryExp
+	| multiplicativeExp ASTERISK unaryExp %ast Multiply
+	| multiplicativeExp DIVIDE unaryExp %ast Divide
+	| multiplicativeExp PERCENT unaryExp %ast Modulo
 	;
 
 unaryExp : caretExp
-	| NOT unaryExp
-	| SHARP unaryExp
-	| UMINUS unaryExp
+	| NOT unaryExp %ast Not
+	| SHARP unaryExp %ast Length
+	| UMINUS unaryExp %ast Negate
 	;
 
 caretExp : baseExp
-	| baseExp CARET caretExp
+	| baseExp CARET caretExp %ast Power
 	;
 
-baseExp : NIL
-	| FALSE
-	| TRUE
-	| NUMBER
-	| STRING
-	| DOT3
+baseExp : NIL.Nil
+	| FALSE.Bool
+	| TRUE.Bool
+	| NUMBER.Number
+	| STRING.String
+	| DOT3.Vararg
 	| functionDef
 	| prefixExp
 	| tableConstructor
@@ -278,32 +278,32 @@ prefixExp : var
 	| LPAREN expr RPAREN
 	;
 
-functionCall : prefixExp LPAREN exprList RPAREN
-	| prefixExp LPAREN RPAREN
-	| prefixExp LBRACE fieldList RBRACE
-	| prefixExp LBRACE RBRACE
-	| prefixExp STRING
-	| prefixExp COLON name LPAREN exprList RPAREN
-	| prefixExp COLON name LPAREN RPAREN
-	| prefixExp COLON name LBRACE fieldList RBRACE
-	| prefixExp COLON name LBRACE RBRACE
-	| prefixExp COLON name STRING
+functionCall : prefixExp LPAREN exprList.ExprList RPAREN %ast FunctionCall
+	| prefixExp LPAREN .ExprList RPAREN %ast FunctionCall
+	| prefixExp LBRACE fieldList.Table.ExprList RBRACE %ast FunctionCall
+	| prefixExp LBRACE .Table.ExprList RBRACE %ast FunctionCall
+	| prefixExp STRING.String %ast FunctionCall
+	| prefixExp COLON name LPAREN exprList.ExprList RPAREN %ast MethodCall
+	| prefixExp COLON name LPAREN .ExprList RPAREN %ast MethodCall
+	| prefixExp COLON name LBRACE fieldList.Table.ExprList RBRACE %ast MethodCall
+	| prefixExp COLON name LBRACE .Table.ExprList RBRACE %ast MethodCall
+	| prefixExp COLON name STRING.String %ast MethodCall
 	;
 
 
-functionDef : FUNCTION LPARENFUNC parList RPARENFUNC block END
-	| FUNCTION LPARENFUNC RPARENFUNC block END
-	| FUNCTION LPARENFUNC parList RPARENFUNC END
-	| FUNCTION LPARENFUNC RPARENFUNC END
+functionDef : FUNCTION LPARENFUNC parList.NameList RPARENFUNC block.StatList END %ast FunctionDef
+	| FUNCTION LPARENFUNC .NameList RPARENFUNC block.StatList END %ast FunctionDef
+	| FUNCTION LPARENFUNC parList.NameList RPARENFUNC .StatList END %ast FunctionDef
+	| FUNCTION LPARENFUNC .NameList RPARENFUNC .StatList END %ast FunctionDef
 	;
 
 parList : nameList
-	| nameList COMMA dot3
-	| DOT3
+	| nameList.flatten COMMA dot3 %ast NameList
+	| DOT3.Vararg %ast NameList
 	;
 
-tableConstructor : LBRACE fieldList RBRACE
-	| LBRACE RBRACE
+tableConstructor : LBRACE fieldList RBRACE %ast Table
+	| LBRACE RBRACE %ast Table
 	;
 
 fieldList : fieldListBody
@@ -316,8 +316,8 @@ fieldListBody : field
 	| fieldListBody SEMIFIELD field
 	;
 
-field : bracketedExp EQ expr
-	| name EQ expr
+field : bracketedExp EQ expr %ast IndexField
+	| name EQ expr %ast Field
 	| expr
 	;
 
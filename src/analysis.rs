@@ -1,134 +1,267 @@
-use std::collections::{HashMap, VecDeque};
+//! Name resolution. Every `Name` that is used is tied to the `Name` that declared it, looking
+//! outwards through a tree of scopes from the point of use.
+//!
+//! The module, each function and each block has a scope. Parameters are in the scope of their
+//! function, along with the statements of its body. Functions and structs can be used anywhere
+//! in the scope they are declared in, lets only after their statement. A declaration in an
+//! inner scope shadows any of the same name further out, while declaring a name twice in one
+//! scope is an error. Fields and types are not resolved.
 
-use log::{debug, error, info, warn};
+use crate::ast::{walk, AstView, Block, ForStmt, FunctionDecl, LetStmt, MemberExpr, NameExpr, Node, NodeId, StructDecl, Visitor};
+use crate::fern::{AstNodeKind, FernAst};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
 
-use crate::{ir::SymbolData, parser::fern_ast::AstNode};
+/// Position of a scope in [`Resolution::scopes`]. The module is scope 0.
+pub type ScopeId = usize;
+
+#[derive(Debug)]
+pub struct ResolveError {
+    /// The name that could not be declared or resolved.
+    pub node: NodeId,
+    /// Input tokens of the name, if it came from source.
+    pub span: Option<Range<usize>>,
+    message: String,
+}
+
+impl Error for ResolveError {}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Resolve Error: {}", self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeKind {
+    Module,
+    Function,
+    Block,
+}
 
 #[derive(Clone, Debug)]
-struct ScopeTreeNode {
-    pub tbl: HashMap<String, ()>,
-    pub nodes: Vec<ScopeTreeNode>,
+pub struct ScopeTreeNode {
+    pub kind: ScopeKind,
+    /// The module, function, block or `for` the scope belongs to.
+    pub node: NodeId,
+    pub parent: Option<ScopeId>,
+    /// Names declared in the scope and the last declaration of each.
+    pub tbl: BTreeMap<String, NodeId>,
+    /// Scopes directly inside this one.
+    pub nodes: Vec<ScopeId>,
 }
 
-impl ScopeTreeNode {
-    pub fn new() -> Self {
-        Self {
-            nodes: Vec::new(),
-            tbl: HashMap::new(),
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeclarationKind {
+    Function,
+    Struct,
+    Parameter,
+    Local,
+}
+
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: DeclarationKind,
+    pub scope: ScopeId,
+}
+
+/// The scope tree of an AST and what each name in it refers to. Declarations are identified
+/// by the id of the `Name` node that declares them.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    scopes: Vec<ScopeTreeNode>,
+    declarations: BTreeMap<NodeId, Declaration>,
+    uses: BTreeMap<NodeId, NodeId>,
+    errors: Vec<ResolveError>,
+}
+
+impl Resolution {
+    pub fn scopes(&self) -> &[ScopeTreeNode] {
+        &self.scopes
+    }
+
+    /// The declaration made by the `Name` node `id`, if it is one.
+    pub fn declaration(&self, id: NodeId) -> Option<&Declaration> {
+        self.declarations.get(&id)
+    }
+
+    pub fn declarations(&self) -> impl Iterator<Item = (NodeId, &Declaration)> {
+        self.declarations.iter().map(|(id, d)| (*id, d))
+    }
+
+    /// The declaration the `Name` node `id` refers to, if it is a use of a declared name.
+    pub fn resolve(&self, id: NodeId) -> Option<NodeId> {
+        self.uses.get(&id).copied()
+    }
+
+    /// Uses of names with the declarations they refer to, by node id.
+    pub fn uses(&self) -> impl Iterator<Item = (NodeId, NodeId)> + '_ {
+        self.uses.iter().map(|(u, d)| (*u, *d))
+    }
+
+    pub fn errors(&self) -> &[ResolveError] {
+        &self.errors
+    }
+}
+
+impl FernAst {
+    /// Resolve every name in the AST, see the [module documentation](crate::analysis).
+    pub fn resolve(&self) -> Resolution {
+        let mut resolver = Resolver {
+            resolution: Resolution::default(),
+            current: 0,
+        };
+        if let Some(root) = self.root() {
+            resolver.open(ScopeKind::Module, root);
+            match Block::cast(root) {
+                Some(module) => resolver.statements(module.node()),
+                None => resolver.visit_node(root),
+            }
         }
+        resolver.resolution
     }
 }
 
-pub fn check_used_before_declared(root: Box<AstNode>) -> Vec<Box<AstNode>> {
-    let root = if let AstNode::Module(list) = *root {
-        list
-    } else {
-        panic!("Only works with modules")
-    };
-    let mut scopes: Vec<(HashMap<String, ()>, VecDeque<AstNode>)> = Vec::from(&[(HashMap::new(), [*root].into_iter().collect())]);
-    let mut final_scopes = Vec::new();
-
-    let new_scopes = |scopes: &mut Vec<(HashMap<String, ()>, VecDeque<AstNode>)>,
-                      old_tbl: HashMap<String, ()>,
-                      old_nodes: VecDeque<AstNode>,
-                      nodes: VecDeque<AstNode>,
-                      final_scopes: &mut Vec<ScopeTreeNode>| {
-        info!("Creating new scope.");
-        scopes.push((old_tbl, old_nodes));
-        scopes.push((HashMap::new(), nodes.into()));
-        final_scopes.push(ScopeTreeNode::new());
-    };
-
-    pub fn check_exists(scopes: &Vec<(HashMap<String, ()>, VecDeque<AstNode>)>, local_tbl: &HashMap<String, ()>, name: &String) -> bool {
-        if local_tbl.contains_key(name) {
-            return true;
+struct Resolver {
+    resolution: Resolution,
+    current: ScopeId,
+}
+
+impl Resolver {
+    fn open(&mut self, kind: ScopeKind, node: Node) {
+        let id = self.resolution.scopes.len();
+        let parent = (id > 0).then_some(self.current);
+        self.resolution.scopes.push(ScopeTreeNode {
+            kind,
+            node: node.id(),
+            parent,
+            tbl: BTreeMap::new(),
+            nodes: Vec::new(),
+        });
+        if let Some(parent) = parent {
+            self.resolution.scopes[parent].nodes.push(id);
         }
-        for (tbl, _) in scopes.iter().rev() {
-            if tbl.contains_key(name) {
-                return true;
-            }
+        self.current = id;
+    }
+
+    fn close(&mut self) {
+        if let Some(parent) = self.resolution.scopes[self.current].parent {
+            self.current = parent;
         }
+    }
 
-        false
-    }
-
-    'outer: while let Some((mut local_tbl, mut s)) = scopes.pop() {
-        while let Some(n) = s.pop_front() {
-            match n {
-                AstNode::Binary(_, _, _) => {}
-                AstNode::Unary(_, _) => {}
-                AstNode::Number(_) => {}
-                AstNode::String(_) => {}
-                AstNode::Name(_) => {}
-                AstNode::ExprList(_) => {}
-                AstNode::Assign(name, _expr) => {
-                    if let AstNode::Name(name) = *name {
-                        if !check_exists(&scopes, &local_tbl, &name) {
-                            warn!("Identifier {} is used but not previously declared.", name);
-                        }
-                    }
-                }
-                AstNode::Let(left, _type, _right) => match *left {
-                    AstNode::Name(name) => {
-                        info!("Inserting {} into symbol table.", name);
-                        if check_exists(&scopes, &local_tbl, &name) {
-                            warn!("Identifier {} already exists in super scope.", name);
-                        }
-                        local_tbl.insert(name, ());
-                    }
-                    _ => error!("Wrong ast node type on left side of let statement."),
-                },
-                AstNode::Return(_) => {}
-                AstNode::Module(list) => {
-                    if let AstNode::StatList(_) = *list {
-                        s.push_back(*list);
-                    }
-                }
-                AstNode::StatList(nodes) => {
-                    info!("Inside statlist");
-                    new_scopes(&mut scopes, local_tbl, s, nodes, &mut final_scopes);
-                    continue 'outer;
-                }
-                AstNode::FunctionCall(_, _) => {}
-                AstNode::Function(_, _, body) => {
-                    info!("Inside function");
-                    if let Some(list) = body {
-                        if let AstNode::StatList(nodes) = *list {
-                            new_scopes(&mut scopes, local_tbl, s, nodes, &mut final_scopes);
-                            continue 'outer;
-                        } else {
-                            error!("big bad.");
-                        }
+    fn error(&mut self, node: Node, message: String) {
+        self.resolution.errors.push(ResolveError {
+            node: node.id(),
+            span: node.span(),
+            message,
+        });
+    }
+
+    fn declare(&mut self, name: NameExpr, kind: DeclarationKind) {
+        let node = name.node();
+        let tbl = &mut self.resolution.scopes[self.current].tbl;
+        if tbl.insert(name.name().to_string(), node.id()).is_some() {
+            self.error(node, format!("Identifier {} already exists.", name.name()));
+        }
+        let declaration = Declaration {
+            name: name.name().to_string(),
+            kind,
+            scope: self.current,
+        };
+        self.resolution.declarations.insert(node.id(), declaration);
+    }
+
+    /// Declare the functions and structs among the children of `block`, then visit them.
+    fn statements(&mut self, block: Node) {
+        for stmt in block.children() {
+            match stmt.kind() {
+                AstNodeKind::Function => {
+                    if let Some(name) = FunctionDecl::cast(stmt).and_then(|f| f.name()) {
+                        self.declare(name, DeclarationKind::Function);
                     }
                 }
-                AstNode::If(_expr, body, _next) => {
-                    if let Some(stat_list) = body {
-                        if let AstNode::StatList(nodes) = *stat_list {
-                            new_scopes(&mut scopes, local_tbl, s, nodes, &mut final_scopes);
-                            continue 'outer;
-                        }
+                AstNodeKind::Struct => {
+                    if let Some(name) = StructDecl::cast(stmt).and_then(|s| s.name()) {
+                        self.declare(name, DeclarationKind::Struct);
                     }
                 }
-                AstNode::ExprThen(_, _) => {}
-                AstNode::ElseIf(_, _, _) => {}
-                AstNode::Else(_) => {}
-                AstNode::For(_, _, _) => {}
-                AstNode::While(_, _) => {}
+                _ => (),
             }
         }
+        walk(self, block);
+    }
 
-        if final_scopes.len() > 1 {
-            let mut last = final_scopes.pop().unwrap();
-            let second_last = final_scopes.last_mut().unwrap();
-            last.tbl = local_tbl;
-            second_last.nodes.push(last);
-        } else if scopes.len() > 0 {
-            let last = final_scopes.last_mut().unwrap();
-            last.tbl = local_tbl;
+    /// Visit the body of a function or `for`, whose scope is already open.
+    fn body(&mut self, body: Option<Node>) {
+        match body {
+            Some(body) if matches!(body.kind(), AstNodeKind::StatList) => self.statements(body),
+            Some(body) => self.visit_node(body),
+            None => (),
         }
     }
+}
 
-    println!("{:?}", final_scopes);
+impl<'a> Visitor<'a> for Resolver {
+    fn visit_block(&mut self, block: Block<'a>) {
+        self.open(ScopeKind::Block, block.node());
+        self.statements(block.node());
+        self.close();
+    }
 
-    return Vec::new();
+    fn visit_function(&mut self, function: FunctionDecl<'a>) {
+        self.open(ScopeKind::Function, function.node());
+        for param in function.params() {
+            if let Some(name) = param.name() {
+                self.declare(name, DeclarationKind::Parameter);
+            }
+        }
+        self.body(function.body());
+        self.close();
+    }
+
+    fn visit_struct(&mut self, _declaration: StructDecl<'a>) {}
+
+    fn visit_let(&mut self, statement: LetStmt<'a>) {
+        // `let x = x;` refers to an `x` from before.
+        if let Some(value) = statement.value() {
+            self.visit_node(value.node());
+        }
+        if let Some(name) = statement.name() {
+            self.declare(name, DeclarationKind::Local);
+        }
+    }
+
+    fn visit_for(&mut self, statement: ForStmt<'a>) {
+        for iterable in statement.iterables() {
+            self.visit_node(iterable.node());
+        }
+        self.open(ScopeKind::Block, statement.node());
+        for binding in statement.bindings() {
+            self.declare(binding, DeclarationKind::Local);
+        }
+        self.body(statement.body());
+        self.close();
+    }
+
+    fn visit_member(&mut self, expr: MemberExpr<'a>) {
+        if let Some(object) = expr.object() {
+            self.visit_node(object.node());
+        }
+    }
+
+    fn visit_name(&mut self, name: NameExpr<'a>) {
+        let mut scope = Some(self.current);
+        while let Some(id) = scope {
+            if let Some(declaration) = self.resolution.scopes[id].tbl.get(name.name()) {
+                self.resolution.uses.insert(name.node().id(), *declaration);
+                return;
+            }
+            scope = self.resolution.scopes[id].parent;
+        }
+        self.error(name.node(), format!("Identifier {} used but not declared.", name.name()));
+    }
 }
@@ -0,0 +1,1041 @@
+use crate::fern::{AstNode, AstNodeKind, FernAst, OperatorKind};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Position of a node in pre-order, with the root at 0. These are the ids
+/// [`crate::query::Queryable`] uses for a [`FernAst`].
+pub type NodeId = usize;
+
+impl FernAst {
+    /// Takes nodes in reverse pre-order and works out the size of every subtree.
+    pub(crate) fn new(nodes: Vec<AstNode>, token_map: Arc<BTreeMap<usize, String>>) -> FernAst {
+        // Every node comes right after its subtrees, so their sizes are on top of the stack.
+        let mut sizes = Vec::with_capacity(nodes.len());
+        let mut subtrees: Vec<usize> = Vec::new();
+        for n in &nodes {
+            let first = subtrees.len().saturating_sub(n.child_count);
+            let size = 1 + subtrees.drain(first..).sum::<usize>();
+            sizes.push(size);
+            subtrees.push(size);
+        }
+        FernAst { nodes, token_map, sizes }
+    }
+
+    fn index(&self, id: NodeId) -> usize {
+        self.nodes.len() - 1 - id
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn root(&self) -> Option<Node<'_>> {
+        (!self.nodes.is_empty()).then_some(Node { ast: self, id: 0 })
+    }
+
+    pub fn node(&self, id: NodeId) -> Node<'_> {
+        assert!(id < self.nodes.len(), "No AST node {}", id);
+        Node { ast: self, id }
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> NodeMut<'_> {
+        assert!(id < self.nodes.len(), "No AST node {}", id);
+        NodeMut { ast: self, id }
+    }
+
+    pub fn visit<'a, V: Visitor<'a> + ?Sized>(&'a self, visitor: &mut V) {
+        if let Some(root) = self.root() {
+            visitor.visit_node(root);
+        }
+    }
+
+    pub fn visit_mut<V: VisitorMut + ?Sized>(&mut self, visitor: &mut V) {
+        if !self.nodes.is_empty() {
+            visitor.visit_node_mut(self.node_mut(0));
+        }
+    }
+
+    /// A new AST with every node replaced by what `folder` makes of it.
+    pub fn fold<F: Fold + ?Sized>(&self, folder: &mut F) -> FernAst {
+        let mut out = AstBuilder::new();
+        if let Some(root) = self.root() {
+            folder.fold_node(root, &mut out);
+        }
+        let mut ast = out.finish();
+        ast.token_map = self.token_map.clone();
+        ast
+    }
+}
+
+/// A node of a [`FernAst`]. The typed views below wrap one of these.
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    ast: &'a FernAst,
+    id: NodeId,
+}
+
+impl<'a> Node<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn ast(&self) -> &'a FernAst {
+        self.ast
+    }
+
+    pub fn kind(&self) -> &'a AstNodeKind {
+        &self.ast.nodes[self.ast.index(self.id)].kind
+    }
+
+    /// Input tokens the node was lowered from. None for nodes that were made up, such as by
+    /// an [`AstBuilder`].
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.ast.nodes[self.ast.index(self.id)].span.clone()
+    }
+
+    /// Number of nodes in the subtree rooted here, including this one.
+    pub fn size(&self) -> usize {
+        self.ast.sizes[self.ast.index(self.id)]
+    }
+
+    pub fn children(&self) -> Children<'a> {
+        Children {
+            ast: self.ast,
+            next: self.id + 1,
+            end: self.id + self.size(),
+        }
+    }
+
+    pub fn child(&self, n: usize) -> Option<Node<'a>> {
+        self.children().nth(n)
+    }
+
+    /// This node and everything under it, in pre-order.
+    pub fn descendants(&self) -> impl Iterator<Item = Node<'a>> {
+        let ast = self.ast;
+        (self.id..self.id + self.size()).map(move |id| Node { ast, id })
+    }
+
+    pub fn cast<T: AstView<'a>>(self) -> Option<T> {
+        T::cast(self)
+    }
+}
+
+impl Debug for Node<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:?}", self.id, self.kind())
+    }
+}
+
+/// Children of a node, found by skipping over the subtree of each one.
+pub struct Children<'a> {
+    ast: &'a FernAst,
+    next: NodeId,
+    end: NodeId,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        if self.next >= self.end {
+            return None;
+        }
+        let node = Node { ast: self.ast, id: self.next };
+        self.next += node.size();
+        Some(node)
+    }
+}
+
+/// A node whose kind and contents can be changed, though not its children.
+pub struct NodeMut<'a> {
+    ast: &'a mut FernAst,
+    id: NodeId,
+}
+
+impl NodeMut<'_> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn kind(&self) -> &AstNodeKind {
+        &self.ast.nodes[self.ast.index(self.id)].kind
+    }
+
+    pub fn kind_mut(&mut self) -> &mut AstNodeKind {
+        let i = self.ast.index(self.id);
+        &mut self.ast.nodes[i].kind
+    }
+
+    pub fn as_node(&self) -> Node<'_> {
+        Node { ast: self.ast, id: self.id }
+    }
+}
+
+/// A typed view of a node of particular kinds, with accessors for its parts.
+pub trait AstView<'a>: Sized {
+    /// The view of `node`, if it is of the right kind.
+    fn cast(node: Node<'a>) -> Option<Self>;
+    fn node(&self) -> Node<'a>;
+}
+
+macro_rules! view {
+    ($(#[$doc:meta])* $view:ident: $($kind:pat_param)|+ $(if $guard:expr)?) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug)]
+        pub struct $view<'a>(Node<'a>);
+
+        impl<'a> AstView<'a> for $view<'a> {
+            fn cast(node: Node<'a>) -> Option<Self> {
+                matches!(node.kind(), $($kind)|+ $(if $guard)?).then_some($view(node))
+            }
+
+            fn node(&self) -> Node<'a> {
+                self.0
+            }
+        }
+    };
+}
+
+view!(
+    /// `{ ... }`, the body of a function or of control flow, or the whole module.
+    Block: AstNodeKind::StatList | AstNodeKind::Module
+);
+view!(
+    /// `fn name[params] -> type { body }`, where the return type is optional.
+    FunctionDecl: AstNodeKind::Function
+);
+view!(
+    /// `struct Name { fields }`
+    StructDecl: AstNodeKind::Struct
+);
+view!(
+    /// `name: type`, in a struct, a parameter list or a `let`.
+    FieldDecl: AstNodeKind::Field
+);
+view!(
+    /// `let name` or `let name = value`, where the name can have a type as in `let name: type`.
+    LetStmt: AstNodeKind::Let | AstNodeKind::LetAssign
+);
+view!(
+    /// `target = value`
+    AssignStmt: AstNodeKind::Assign
+);
+view!(
+    /// `return value`
+    ReturnStmt: AstNodeKind::Return
+);
+view!(
+    /// `if condition { body } else ...`
+    IfStmt: AstNodeKind::If
+);
+view!(
+    /// `elseif condition { body } else ...`
+    ElseIfStmt: AstNodeKind::ElseIf
+);
+view!(
+    /// `else { body }`
+    ElseStmt: AstNodeKind::Else
+);
+view!(
+    /// `while condition { body }`
+    WhileStmt: AstNodeKind::While
+);
+view!(
+    /// `for binding in iterable { body }`
+    ForStmt: AstNodeKind::For
+);
+view!(
+    /// `callee(arguments)`
+    CallExpr: AstNodeKind::FunctionCall
+);
+view!(
+    /// `lhs op rhs`
+    BinaryExpr: AstNodeKind::Operator(op) if !op.is_unary()
+);
+view!(
+    /// `op operand`
+    UnaryExpr: AstNodeKind::Operator(op) if op.is_unary()
+);
+view!(
+    /// `object.field`
+    MemberExpr: AstNodeKind::Member
+);
+view!(NameExpr: AstNodeKind::Name(_));
+view!(NumberLit: AstNodeKind::Number(_));
+view!(StringLit: AstNodeKind::String(_));
+view!(BoolLit: AstNodeKind::Bool(_));
+view!(NilLit: AstNodeKind::Nil);
+
+/// Statements under `node`: its children if it is a block, otherwise itself. Nodes that are
+/// not statements are left out.
+fn statements(node: Option<Node<'_>>) -> Vec<Stmt<'_>> {
+    match node {
+        Some(n) if matches!(n.kind(), AstNodeKind::StatList | AstNodeKind::Module) => n.children().filter_map(Stmt::cast).collect(),
+        Some(n) => Stmt::cast(n).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Children of `node` with any nested nodes of kind `list` flattened into them.
+fn flatten<'a>(node: Node<'a>, list: &AstNodeKind, out: &mut Vec<Node<'a>>) {
+    if node.kind() == list {
+        for c in node.children() {
+            flatten(c, list, out);
+        }
+    } else {
+        out.push(node);
+    }
+}
+
+/// The items of an `ExprList`, or `node` alone if it is something else.
+fn list<'a, T: AstView<'a>>(node: Option<Node<'a>>) -> Vec<T> {
+    let mut items = Vec::new();
+    if let Some(node) = node {
+        flatten(node, &AstNodeKind::ExprList, &mut items);
+    }
+    items.into_iter().filter_map(Node::cast).collect()
+}
+
+fn is_else(node: &Node) -> bool {
+    matches!(node.kind(), AstNodeKind::ElseIf | AstNodeKind::Else)
+}
+
+impl<'a> Block<'a> {
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(Some(self.0))
+    }
+}
+
+impl<'a> FunctionDecl<'a> {
+    pub fn name(&self) -> Option<NameExpr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn params(&self) -> Vec<FieldDecl<'a>> {
+        let mut params = Vec::new();
+        if let Some(list) = self
+            .0
+            .children()
+            .skip(1)
+            .find(|c| matches!(c.kind(), AstNodeKind::FieldList | AstNodeKind::Field))
+        {
+            flatten(list, &AstNodeKind::FieldList, &mut params);
+        }
+        params.into_iter().filter_map(Node::cast).collect()
+    }
+
+    /// The type after `->`, if there is one.
+    pub fn return_type(&self) -> Option<NameExpr<'a>> {
+        match self.rest().as_slice() {
+            [ty, _] => ty.cast(),
+            _ => None,
+        }
+    }
+
+    /// The block or single statement after the name, parameters and return type.
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.rest().last().copied()
+    }
+
+    /// Children after the name and parameters.
+    fn rest(&self) -> Vec<Node<'a>> {
+        self.0
+            .children()
+            .skip(1)
+            .filter(|c| !matches!(c.kind(), AstNodeKind::FieldList | AstNodeKind::Field))
+            .collect()
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+}
+
+impl<'a> StructDecl<'a> {
+    pub fn name(&self) -> Option<NameExpr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn fields(&self) -> Vec<FieldDecl<'a>> {
+        let mut fields = Vec::new();
+        if let Some(list) = self.0.child(1) {
+            flatten(list, &AstNodeKind::FieldList, &mut fields);
+        }
+        fields.into_iter().filter_map(Node::cast).collect()
+    }
+}
+
+impl<'a> FieldDecl<'a> {
+    pub fn name(&self) -> Option<NameExpr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn ty(&self) -> Option<NameExpr<'a>> {
+        self.0.child(1)?.cast()
+    }
+}
+
+impl<'a> LetStmt<'a> {
+    pub fn name(&self) -> Option<NameExpr<'a>> {
+        match self.0.child(0)?.cast::<FieldDecl>() {
+            Some(field) => field.name(),
+            None => self.0.child(0)?.cast(),
+        }
+    }
+
+    pub fn ty(&self) -> Option<NameExpr<'a>> {
+        self.0.child(0)?.cast::<FieldDecl>()?.ty()
+    }
+
+    /// What the name is initialised to, if anything.
+    pub fn value(&self) -> Option<Expr<'a>> {
+        self.0.child(1)?.cast()
+    }
+}
+
+impl<'a> AssignStmt<'a> {
+    pub fn target(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn value(&self) -> Option<Expr<'a>> {
+        self.0.child(1)?.cast()
+    }
+}
+
+impl<'a> ReturnStmt<'a> {
+    pub fn value(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+}
+
+impl<'a> IfStmt<'a> {
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.0.child(1).filter(|c| !is_else(c))
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+
+    /// The `elseif` or `else` that follows, if any.
+    pub fn else_branch(&self) -> Option<Node<'a>> {
+        self.0.children().skip(1).find(is_else)
+    }
+}
+
+impl<'a> ElseIfStmt<'a> {
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.0.child(1).filter(|c| !is_else(c))
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+
+    pub fn else_branch(&self) -> Option<Node<'a>> {
+        self.0.children().skip(1).find(is_else)
+    }
+}
+
+impl<'a> ElseStmt<'a> {
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.0.child(0)
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+}
+
+impl<'a> WhileStmt<'a> {
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.0.child(1)
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+}
+
+impl<'a> ForStmt<'a> {
+    /// The names of `for k, v in ...`, or the one of `for x in ...`.
+    pub fn bindings(&self) -> Vec<NameExpr<'a>> {
+        list(self.0.child(0))
+    }
+
+    pub fn iterables(&self) -> Vec<Expr<'a>> {
+        list(self.0.child(1))
+    }
+
+    pub fn body(&self) -> Option<Node<'a>> {
+        self.0.child(2)
+    }
+
+    pub fn statements(&self) -> Vec<Stmt<'a>> {
+        statements(self.body())
+    }
+}
+
+impl<'a> CallExpr<'a> {
+    pub fn callee(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn arguments(&self) -> Vec<Expr<'a>> {
+        list(self.0.child(1))
+    }
+}
+
+impl<'a> BinaryExpr<'a> {
+    pub fn op(&self) -> OperatorKind {
+        match self.0.kind() {
+            AstNodeKind::Operator(op) => *op,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn lhs(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn rhs(&self) -> Option<Expr<'a>> {
+        self.0.child(1)?.cast()
+    }
+}
+
+impl<'a> UnaryExpr<'a> {
+    pub fn op(&self) -> OperatorKind {
+        match self.0.kind() {
+            AstNodeKind::Operator(op) => *op,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn operand(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+}
+
+impl<'a> MemberExpr<'a> {
+    pub fn object(&self) -> Option<Expr<'a>> {
+        self.0.child(0)?.cast()
+    }
+
+    pub fn field(&self) -> Option<NameExpr<'a>> {
+        self.0.child(1)?.cast()
+    }
+}
+
+impl<'a> NameExpr<'a> {
+    pub fn name(&self) -> &'a str {
+        match self.0.kind() {
+            AstNodeKind::Name(s) => s,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a> NumberLit<'a> {
+    pub fn value(&self) -> &'a str {
+        match self.0.kind() {
+            AstNodeKind::Number(s) => s,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a> StringLit<'a> {
+    /// The literal as written, with its quotes.
+    pub fn value(&self) -> &'a str {
+        match self.0.kind() {
+            AstNodeKind::String(s) => s,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl BoolLit<'_> {
+    pub fn value(&self) -> bool {
+        *self.0.kind() == AstNodeKind::Bool(true)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Expr<'a> {
+    Binary(BinaryExpr<'a>),
+    Unary(UnaryExpr<'a>),
+    Member(MemberExpr<'a>),
+    Call(CallExpr<'a>),
+    Name(NameExpr<'a>),
+    Number(NumberLit<'a>),
+    String(StringLit<'a>),
+    Bool(BoolLit<'a>),
+    Nil(NilLit<'a>),
+}
+
+impl<'a> AstView<'a> for Expr<'a> {
+    fn cast(node: Node<'a>) -> Option<Self> {
+        Some(match node.kind() {
+            AstNodeKind::Operator(op) if op.is_unary() => Expr::Unary(UnaryExpr(node)),
+            AstNodeKind::Operator(_) => Expr::Binary(BinaryExpr(node)),
+            AstNodeKind::Member => Expr::Member(MemberExpr(node)),
+            AstNodeKind::FunctionCall => Expr::Call(CallExpr(node)),
+            AstNodeKind::Name(_) => Expr::Name(NameExpr(node)),
+            AstNodeKind::Number(_) => Expr::Number(NumberLit(node)),
+            AstNodeKind::String(_) => Expr::String(StringLit(node)),
+            AstNodeKind::Bool(_) => Expr::Bool(BoolLit(node)),
+            AstNodeKind::Nil => Expr::Nil(NilLit(node)),
+            _ => return None,
+        })
+    }
+
+    fn node(&self) -> Node<'a> {
+        match self {
+            Expr::Binary(e) => e.0,
+            Expr::Unary(e) => e.0,
+            Expr::Member(e) => e.0,
+            Expr::Call(e) => e.0,
+            Expr::Name(e) => e.0,
+            Expr::Number(e) => e.0,
+            Expr::String(e) => e.0,
+            Expr::Bool(e) => e.0,
+            Expr::Nil(e) => e.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stmt<'a> {
+    Block(Block<'a>),
+    Function(FunctionDecl<'a>),
+    Struct(StructDecl<'a>),
+    Let(LetStmt<'a>),
+    Assign(AssignStmt<'a>),
+    Return(ReturnStmt<'a>),
+    If(IfStmt<'a>),
+    While(WhileStmt<'a>),
+    For(ForStmt<'a>),
+    Call(CallExpr<'a>),
+}
+
+impl<'a> AstView<'a> for Stmt<'a> {
+    fn cast(node: Node<'a>) -> Option<Self> {
+        Some(match node.kind() {
+            AstNodeKind::StatList | AstNodeKind::Module => Stmt::Block(Block(node)),
+            AstNodeKind::Function => Stmt::Function(FunctionDecl(node)),
+            AstNodeKind::Struct => Stmt::Struct(StructDecl(node)),
+            AstNodeKind::Let | AstNodeKind::LetAssign => Stmt::Let(LetStmt(node)),
+            AstNodeKind::Assign => Stmt::Assign(AssignStmt(node)),
+            AstNodeKind::Return => Stmt::Return(ReturnStmt(node)),
+            AstNodeKind::If => Stmt::If(IfStmt(node)),
+            AstNodeKind::While => Stmt::While(WhileStmt(node)),
+            AstNodeKind::For => Stmt::For(ForStmt(node)),
+            AstNodeKind::FunctionCall => Stmt::Call(CallExpr(node)),
+            _ => return None,
+        })
+    }
+
+    fn node(&self) -> Node<'a> {
+        match self {
+            Stmt::Block(s) => s.0,
+            Stmt::Function(s) => s.0,
+            Stmt::Struct(s) => s.0,
+            Stmt::Let(s) => s.0,
+            Stmt::Assign(s) => s.0,
+            Stmt::Return(s) => s.0,
+            Stmt::If(s) => s.0,
+            Stmt::While(s) => s.0,
+            Stmt::For(s) => s.0,
+            Stmt::Call(s) => s.0,
+        }
+    }
+}
+
+/// Read only traversal of an AST. `visit_node` hands each node to the method for its kind,
+/// and those walk into the children unless overridden, so an implementation only needs the
+/// methods for the nodes it is interested in. Call [`walk`] from an override to keep going.
+pub trait Visitor<'a> {
+    fn visit_node(&mut self, node: Node<'a>) {
+        match node.kind() {
+            AstNodeKind::StatList | AstNodeKind::Module => self.visit_block(Block(node)),
+            AstNodeKind::Function => self.visit_function(FunctionDecl(node)),
+            AstNodeKind::Struct => self.visit_struct(StructDecl(node)),
+            AstNodeKind::Field => self.visit_field(FieldDecl(node)),
+            AstNodeKind::Let | AstNodeKind::LetAssign => self.visit_let(LetStmt(node)),
+            AstNodeKind::Assign => self.visit_assign(AssignStmt(node)),
+            AstNodeKind::Return => self.visit_return(ReturnStmt(node)),
+            AstNodeKind::If => self.visit_if(IfStmt(node)),
+            AstNodeKind::ElseIf => self.visit_else_if(ElseIfStmt(node)),
+            AstNodeKind::Else => self.visit_else(ElseStmt(node)),
+            AstNodeKind::While => self.visit_while(WhileStmt(node)),
+            AstNodeKind::For => self.visit_for(ForStmt(node)),
+            AstNodeKind::FunctionCall => self.visit_call(CallExpr(node)),
+            AstNodeKind::Operator(op) if op.is_unary() => self.visit_unary(UnaryExpr(node)),
+            AstNodeKind::Operator(_) => self.visit_binary(BinaryExpr(node)),
+            AstNodeKind::Member => self.visit_member(MemberExpr(node)),
+            AstNodeKind::Name(_) => self.visit_name(NameExpr(node)),
+            AstNodeKind::Number(_) => self.visit_number(NumberLit(node)),
+            AstNodeKind::String(_) => self.visit_string(StringLit(node)),
+            AstNodeKind::Bool(_) => self.visit_bool(BoolLit(node)),
+            AstNodeKind::Nil => self.visit_nil(NilLit(node)),
+            AstNodeKind::ExprList | AstNodeKind::FieldList => walk(self, node),
+        }
+    }
+
+    fn visit_block(&mut self, block: Block<'a>) {
+        walk(self, block.0);
+    }
+
+    fn visit_function(&mut self, function: FunctionDecl<'a>) {
+        walk(self, function.0);
+    }
+
+    fn visit_struct(&mut self, declaration: StructDecl<'a>) {
+        walk(self, declaration.0);
+    }
+
+    fn visit_field(&mut self, field: FieldDecl<'a>) {
+        walk(self, field.0);
+    }
+
+    fn visit_let(&mut self, statement: LetStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_assign(&mut self, statement: AssignStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_return(&mut self, statement: ReturnStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_if(&mut self, statement: IfStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_else_if(&mut self, statement: ElseIfStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_else(&mut self, statement: ElseStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_while(&mut self, statement: WhileStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_for(&mut self, statement: ForStmt<'a>) {
+        walk(self, statement.0);
+    }
+
+    fn visit_call(&mut self, call: CallExpr<'a>) {
+        walk(self, call.0);
+    }
+
+    fn visit_binary(&mut self, expr: BinaryExpr<'a>) {
+        walk(self, expr.0);
+    }
+
+    fn visit_unary(&mut self, expr: UnaryExpr<'a>) {
+        walk(self, expr.0);
+    }
+
+    fn visit_member(&mut self, expr: MemberExpr<'a>) {
+        walk(self, expr.0);
+    }
+
+    fn visit_name(&mut self, _name: NameExpr<'a>) {}
+
+    fn visit_number(&mut self, _number: NumberLit<'a>) {}
+
+    fn visit_string(&mut self, _string: StringLit<'a>) {}
+
+    fn visit_bool(&mut self, _value: BoolLit<'a>) {}
+
+    fn visit_nil(&mut self, _nil: NilLit<'a>) {}
+}
+
+/// Visit the children of `node` in order.
+pub fn walk<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: Node<'a>) {
+    for c in node.children() {
+        visitor.visit_node(c);
+    }
+}
+
+/// Traversal that can change the contents of nodes in place, but not the shape of the tree.
+/// Use [`Fold`] to add or remove nodes.
+pub trait VisitorMut {
+    fn visit_node_mut(&mut self, node: NodeMut<'_>) {
+        walk_mut(self, node);
+    }
+
+    fn visit_name_mut(&mut self, _name: &mut String) {}
+
+    fn visit_number_mut(&mut self, _value: &mut String) {}
+
+    fn visit_string_mut(&mut self, _value: &mut String) {}
+
+    fn visit_operator_mut(&mut self, _op: &mut OperatorKind) {}
+}
+
+/// Hand the contents of `node` to the matching method of `visitor`, then visit its children.
+pub fn walk_mut<V: VisitorMut + ?Sized>(visitor: &mut V, mut node: NodeMut<'_>) {
+    match node.kind_mut() {
+        AstNodeKind::Name(s) => visitor.visit_name_mut(s),
+        AstNodeKind::Number(s) => visitor.visit_number_mut(s),
+        AstNodeKind::String(s) => visitor.visit_string_mut(s),
+        AstNodeKind::Operator(op) => visitor.visit_operator_mut(op),
+        _ => (),
+    }
+    let children: Vec<NodeId> = node.as_node().children().map(|c| c.id).collect();
+    for id in children {
+        visitor.visit_node_mut(NodeMut { ast: &mut *node.ast, id });
+    }
+}
+
+/// AST to AST transform. Each method writes what its node becomes to `out`, which can be
+/// any number of subtrees, including none. By default a node is kept and its children folded.
+pub trait Fold {
+    fn fold_node(&mut self, node: Node<'_>, out: &mut AstBuilder) {
+        match node.kind() {
+            AstNodeKind::StatList | AstNodeKind::Module => self.fold_block(Block(node), out),
+            AstNodeKind::Function => self.fold_function(FunctionDecl(node), out),
+            AstNodeKind::Struct => self.fold_struct(StructDecl(node), out),
+            AstNodeKind::Field => self.fold_field(FieldDecl(node), out),
+            AstNodeKind::Let | AstNodeKind::LetAssign => self.fold_let(LetStmt(node), out),
+            AstNodeKind::Assign => self.fold_assign(AssignStmt(node), out),
+            AstNodeKind::Return => self.fold_return(ReturnStmt(node), out),
+            AstNodeKind::If => self.fold_if(IfStmt(node), out),
+            AstNodeKind::ElseIf => self.fold_else_if(ElseIfStmt(node), out),
+            AstNodeKind::Else => self.fold_else(ElseStmt(node), out),
+            AstNodeKind::While => self.fold_while(WhileStmt(node), out),
+            AstNodeKind::For => self.fold_for(ForStmt(node), out),
+            AstNodeKind::FunctionCall => self.fold_call(CallExpr(node), out),
+            AstNodeKind::Operator(op) if op.is_unary() => self.fold_unary(UnaryExpr(node), out),
+            AstNodeKind::Operator(_) => self.fold_binary(BinaryExpr(node), out),
+            AstNodeKind::Member => self.fold_member(MemberExpr(node), out),
+            AstNodeKind::Name(_) => self.fold_name(NameExpr(node), out),
+            AstNodeKind::Number(_) => self.fold_number(NumberLit(node), out),
+            AstNodeKind::String(_) => self.fold_string(StringLit(node), out),
+            AstNodeKind::Bool(_) => self.fold_bool(BoolLit(node), out),
+            AstNodeKind::Nil => self.fold_nil(NilLit(node), out),
+            AstNodeKind::ExprList | AstNodeKind::FieldList => fold_children(self, node, out),
+        }
+    }
+
+    fn fold_block(&mut self, block: Block<'_>, out: &mut AstBuilder) {
+        fold_children(self, block.0, out);
+    }
+
+    fn fold_function(&mut self, function: FunctionDecl<'_>, out: &mut AstBuilder) {
+        fold_children(self, function.0, out);
+    }
+
+    fn fold_struct(&mut self, declaration: StructDecl<'_>, out: &mut AstBuilder) {
+        fold_children(self, declaration.0, out);
+    }
+
+    fn fold_field(&mut self, field: FieldDecl<'_>, out: &mut AstBuilder) {
+        fold_children(self, field.0, out);
+    }
+
+    fn fold_let(&mut self, statement: LetStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_assign(&mut self, statement: AssignStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_return(&mut self, statement: ReturnStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_if(&mut self, statement: IfStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_else_if(&mut self, statement: ElseIfStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_else(&mut self, statement: ElseStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_while(&mut self, statement: WhileStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_for(&mut self, statement: ForStmt<'_>, out: &mut AstBuilder) {
+        fold_children(self, statement.0, out);
+    }
+
+    fn fold_call(&mut self, call: CallExpr<'_>, out: &mut AstBuilder) {
+        fold_children(self, call.0, out);
+    }
+
+    fn fold_binary(&mut self, expr: BinaryExpr<'_>, out: &mut AstBuilder) {
+        fold_children(self, expr.0, out);
+    }
+
+    fn fold_unary(&mut self, expr: UnaryExpr<'_>, out: &mut AstBuilder) {
+        fold_children(self, expr.0, out);
+    }
+
+    fn fold_member(&mut self, expr: MemberExpr<'_>, out: &mut AstBuilder) {
+        fold_children(self, expr.0, out);
+    }
+
+    fn fold_name(&mut self, name: NameExpr<'_>, out: &mut AstBuilder) {
+        fold_children(self, name.0, out);
+    }
+
+    fn fold_number(&mut self, number: NumberLit<'_>, out: &mut AstBuilder) {
+        fold_children(self, number.0, out);
+    }
+
+    fn fold_string(&mut self, string: StringLit<'_>, out: &mut AstBuilder) {
+        fold_children(self, string.0, out);
+    }
+
+    fn fold_bool(&mut self, value: BoolLit<'_>, out: &mut AstBuilder) {
+        fold_children(self, value.0, out);
+    }
+
+    fn fold_nil(&mut self, nil: NilLit<'_>, out: &mut AstBuilder) {
+        fold_children(self, nil.0, out);
+    }
+}
+
+/// Write a copy of `node` to `out`, with its children folded by `folder`.
+pub fn fold_children<F: Fold + ?Sized>(folder: &mut F, node: Node<'_>, out: &mut AstBuilder) {
+    out.open(node.kind().clone());
+    if let Some(span) = node.span() {
+        out.span(span);
+    }
+    for c in node.children() {
+        folder.fold_node(c, out);
+    }
+    out.close();
+}
+
+pub(crate) struct Checkpoint {
+    len: usize,
+    open: usize,
+    /// Child count of the innermost open node, or the number of roots.
+    siblings: usize,
+}
+
+/// Builds a [`FernAst`] in pre-order. Everything added between `open` and the matching
+/// `close` becomes the children of the opened node.
+#[derive(Default)]
+pub struct AstBuilder {
+    /// In pre-order, unlike in a finished AST.
+    nodes: Vec<AstNode>,
+    open: Vec<usize>,
+    roots: usize,
+}
+
+impl AstBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, kind: AstNodeKind) {
+        match self.open.last() {
+            Some(&parent) => self.nodes[parent].child_count += 1,
+            None => self.roots += 1,
+        }
+        self.open.push(self.nodes.len());
+        self.nodes.push(AstNode {
+            kind,
+            child_count: 0,
+            span: None,
+        });
+    }
+
+    /// Set the span of the node added last.
+    pub fn span(&mut self, span: Range<usize>) {
+        if let Some(n) = self.nodes.last_mut() {
+            n.span = Some(span);
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.open.pop();
+    }
+
+    /// Where the builder is, to undo everything added since with [`AstBuilder::rewind`].
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            len: self.nodes.len(),
+            open: self.open.len(),
+            siblings: self.open.last().map_or(self.roots, |p| self.nodes[*p].child_count),
+        }
+    }
+
+    pub(crate) fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.nodes.truncate(checkpoint.len);
+        self.open.truncate(checkpoint.open);
+        match self.open.last() {
+            Some(&parent) => self.nodes[parent].child_count = checkpoint.siblings,
+            None => self.roots = checkpoint.siblings,
+        }
+    }
+
+    pub fn leaf(&mut self, kind: AstNodeKind) {
+        self.open(kind);
+        self.close();
+    }
+
+    /// Add a copy of a subtree of another AST.
+    pub fn copy(&mut self, node: Node<'_>) {
+        self.open(node.kind().clone());
+        if let Some(span) = node.span() {
+            self.span(span);
+        }
+        for c in node.children() {
+            self.copy(c);
+        }
+        self.close();
+    }
+
+    /// Nodes left open are closed. More than one top level subtree are put in a `StatList`.
+    pub fn finish(mut self) -> FernAst {
+        if self.roots > 1 {
+            self.nodes.insert(
+                0,
+                AstNode {
+                    kind: AstNodeKind::StatList,
+                    child_count: self.roots,
+                    span: None,
+                },
+            );
+        }
+        self.nodes.reverse();
+        FernAst::new(self.nodes, Arc::new(BTreeMap::new()))
+    }
+}
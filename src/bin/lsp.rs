@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::io::{self, BufReader};
+use std::thread;
+
+use flexi_logger::Logger;
+use libfern::fern;
+use libfern::lsp::{self, LanguageServer};
+use log::warn;
+
+/// fern-lsp
+///
+/// Language server for Fern over stdio. Logs go to stderr.
+fn main() -> Result<(), Box<dyn Error>> {
+    Logger::try_with_str("warn")?.start()?;
+    let table = fern::lexing_table()?;
+    let grammar = fern::grammar(&table)?;
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        let mut stdin = BufReader::new(io::stdin());
+        loop {
+            match lsp::read_message(&mut stdin) {
+                Ok(Some(message)) => {
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => warn!("{}", e),
+            }
+        }
+    });
+
+    let mut server = LanguageServer::new(&table, &grammar);
+    let mut stdout = io::stdout();
+    server.serve(&receiver, |message| {
+        if let Err(e) = lsp::write_message(&mut stdout, &message) {
+            warn!("Could not write to the client: {}", e);
+        }
+    });
+    if !server.shut_down() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -2,15 +2,205 @@
 #![allow(ambiguous_glob_reexports)]
 
 use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
 
 use flexi_logger::Logger;
+use libfern::fern;
+use libfern::fern::FernAst;
+use libfern::fmt::{self as formatter, FormatOptions};
+use libfern::grammar::analysis::GrammarAnalysis;
+use libfern::grammar::lg::LexicalGrammar;
+use libfern::grammar::opg::RawGrammar;
+use libfern::parser::Parser;
+use libfern::query::{self, Queryable};
+use libfern::trace::ParseTrace;
 extern crate core;
 
 fn main() -> Result<(), Box<dyn Error>> {
     Logger::try_with_str("trace, core::grammar = info")?
         .format(flexi_logger::colored_default_format)
         .start_with_specfile("log.toml")?;
-    libfern::fern::compile()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        Some("grammar") => grammar_report(&args[1..])?,
+        Some("trace") => trace_report(&args[1..])?,
+        Some("query") => query(&args[1..])?,
+        Some("fmt") => format(&args[1..])?,
+        Some("dump") => dump(&args[1..])?,
+        _ => libfern::fern::compile(args.iter().any(|a| a == "--pipeline"))?,
+    }
     // json::compile()?;
     Ok(())
 }
+
+/// fern grammar [--json] [GRAMMAR] [LEXICAL_GRAMMAR...]
+///
+/// Print the analysis of a .g grammar, checked against the terminals of the given .lg files.
+/// Defaults to the fern grammar and its lexical and keyword grammars.
+fn grammar_report(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let json = args.iter().any(|a| a == "--json");
+    let mut paths: Vec<&str> = args.iter().filter(|a| *a != "--json").map(|a| a.as_str()).collect();
+    if paths.is_empty() {
+        paths = vec!["data/grammar/fern.g", "data/grammar/fern.lg", "data/grammar/keywords.lg"];
+    }
+
+    let mut lexical_sync = Vec::new();
+    for path in &paths[1..] {
+        lexical_sync.extend(LexicalGrammar::from(&fs::read_to_string(path)?).token_names());
+    }
+    let raw = RawGrammar::from(paths[0], lexical_sync.clone())?;
+    let analysis = GrammarAnalysis::new(&raw, &lexical_sync);
+    if json {
+        println!("{}", analysis.to_json().pretty(2));
+    } else {
+        print!("{}", analysis);
+    }
+    Ok(())
+}
+
+/// fern trace [--json] [FILE]
+///
+/// Parse a fern file and print every step the parser took, as an HTML timeline or as JSON.
+/// Defaults to data/test.fern.
+fn trace_report(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let json = args.iter().any(|a| a == "--json");
+    let path = args.iter().find(|a| *a != "--json").map_or("data/test.fern", |a| a.as_str());
+
+    let table = fern::lexing_table()?;
+    let grammar = fern::grammar(&table)?;
+    let (tokens, data, _) = fern::lex(&table, &fs::read(path)?)?;
+    let mut parser = Parser::new(&grammar);
+    parser.trace = Some(ParseTrace::new(&grammar));
+    // Errors are part of the trace.
+    let _ = parser.parse(tokens, data);
+    let _ = parser.parse(vec![grammar.delim], Vec::new());
+
+    let trace = parser.trace.take().unwrap();
+    if json {
+        println!("{}", trace.to_json().pretty(2));
+    } else {
+        trace.html(&mut std::io::stdout().lock())?;
+    }
+    Ok(())
+}
+
+/// fern query [--tree] SELECTOR [FILE]
+///
+/// Print the nodes of a fern file's AST, or of its parse tree with --tree, that match a CSS like
+/// selector such as `Function > Name`. Defaults to data/test.fern.
+fn query(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let tree_only = args.iter().any(|a| a == "--tree");
+    let rest: Vec<&str> = args.iter().filter(|a| *a != "--tree").map(|a| a.as_str()).collect();
+    let selector = query::Selector::parse(rest.first().ok_or("Expected a selector")?)?;
+    let path = rest.get(1).copied().unwrap_or("data/test.fern");
+
+    let table = fern::lexing_table()?;
+    let grammar = fern::grammar(&table)?;
+    let (tokens, data, _) = fern::lex(&table, &fs::read(path)?)?;
+    let mut parser = Parser::new(&grammar);
+    let _ = parser.parse(tokens, data);
+    let _ = parser.parse(vec![grammar.delim], Vec::new());
+    if !parser.errors().is_empty() {
+        for e in parser.errors() {
+            eprintln!("{}", e);
+        }
+        return Err(format!("{} syntax errors in {}", parser.errors().len(), path).into());
+    }
+    let tree = parser.collect_parse_tree()?.into_tree();
+
+    let print = |tree: &dyn Queryable| {
+        for m in selector.select(tree) {
+            let value = tree.attribute(m.id, "value").map(|v| format!("[value={:?}]", v)).unwrap_or_default();
+            match m.span {
+                Some(span) => println!("{}\t{}{}\t{}..{}", m.id, tree.kind(m.id), value, span.start, span.end),
+                None => println!("{}\t{}{}", m.id, tree.kind(m.id), value),
+            }
+        }
+    };
+    if tree_only {
+        print(&tree);
+    } else {
+        let ast: FernAst = tree.into();
+        print(&ast);
+    }
+    Ok(())
+}
+
+/// fern dump [--tree] [--sexp] [FILE]
+///
+/// Print the AST of a fern file, or its parse tree with --tree, as JSON or with --sexp as an
+/// S-expression. See `libfern::serialize` for the encodings. Defaults to data/test.fern.
+fn dump(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let tree_only = args.iter().any(|a| a == "--tree");
+    let sexp = args.iter().any(|a| a == "--sexp");
+    let path = args.iter().find(|a| !a.starts_with("--")).map_or("data/test.fern", |a| a.as_str());
+
+    let table = fern::lexing_table()?;
+    let grammar = fern::grammar(&table)?;
+    let (tokens, data, _) = fern::lex(&table, &fs::read(path)?)?;
+    let mut parser = Parser::new(&grammar);
+    let _ = parser.parse(tokens, data);
+    let _ = parser.parse(vec![grammar.delim], Vec::new());
+    if !parser.errors().is_empty() {
+        for e in parser.errors() {
+            eprintln!("{}", e);
+        }
+        return Err(format!("{} syntax errors in {}", parser.errors().len(), path).into());
+    }
+    let tree = parser.collect_parse_tree()?.into_tree();
+
+    if tree_only {
+        println!("{}", if sexp { tree.to_sexp() } else { tree.to_json().pretty(2) });
+    } else {
+        let ast: FernAst = tree.into();
+        println!("{}", if sexp { ast.to_sexp() } else { ast.to_json().pretty(2) });
+    }
+    Ok(())
+}
+
+/// fern fmt [--check] [FILE...]
+///
+/// Format fern files in place, or standard input to standard output when no files are given.
+/// With --check nothing is written, and it fails if any input is not already formatted.
+fn format(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let check = args.iter().any(|a| a == "--check");
+    let paths: Vec<&str> = args.iter().filter(|a| *a != "--check").map(|a| a.as_str()).collect();
+
+    let table = fern::lexing_table()?;
+    let grammar = fern::grammar(&table)?;
+    let options = FormatOptions::default();
+    if paths.is_empty() {
+        let mut source = Vec::new();
+        std::io::stdin().read_to_end(&mut source)?;
+        let formatted = formatter::format_source(&table, &grammar, &source, &options)?;
+        if check {
+            if formatted.as_bytes() != source.as_slice() {
+                return Err("Standard input is not formatted".into());
+            }
+        } else {
+            std::io::stdout().write_all(formatted.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let mut unformatted = Vec::new();
+    for path in paths {
+        let source = fs::read(path)?;
+        let formatted = formatter::format_source(&table, &grammar, &source, &options).map_err(|e| format!("{}: {}", path, e))?;
+        // Only whitespace formats to nothing, which is not worth losing a file over.
+        if formatted.as_bytes() == source.as_slice() || formatted.is_empty() {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted.push(path);
+        } else {
+            fs::write(path, formatted)?;
+        }
+    }
+    if !unformatted.is_empty() {
+        return Err(format!("{} files are not formatted", unformatted.len()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use crate::lexer::Data;
+use crate::parsetree::{Id, ParseTree};
+use std::fmt::{Display, Formatter};
+
+/// Lossless view of a [`ParseTree`] whose tokens carry their trivia, as lexed by
+/// [`FernLexer`]. Printing it gives back the exact input, including whitespace, comments and
+/// input that is only in the tree because of error recovery. Semicolons inserted by the lexer
+/// are left out.
+///
+/// [`FernLexer`]: crate::fern::FernLexer
+pub struct Cst<'a> {
+    tree: &'a ParseTree,
+}
+
+impl<'a> Cst<'a> {
+    pub fn new(tree: &'a ParseTree) -> Self {
+        Self { tree }
+    }
+
+    /// Tokens under `id`, from left to right.
+    pub fn tokens(&self, id: Id) -> impl Iterator<Item = &'a Data> {
+        let tree = self.tree;
+        tree.nodes[id..tree.subtree_end(id)].iter().filter_map(|n| n.data.as_ref())
+    }
+
+    fn token_text(data: &Data) -> &str {
+        if data.inserted {
+            ""
+        } else {
+            &data.raw
+        }
+    }
+
+    /// Source of the node, without the trivia before its first token and after its last.
+    pub fn text(&self, id: Id) -> String {
+        let tokens: Vec<&Data> = self.tokens(id).collect();
+        let mut text = String::new();
+        for (i, data) in tokens.iter().enumerate() {
+            if i > 0 {
+                text.push_str(&data.leading);
+            }
+            text.push_str(Self::token_text(data));
+            if i + 1 < tokens.len() {
+                text.push_str(&data.trailing);
+            }
+        }
+        text
+    }
+
+    /// Source of the node, with all of its trivia.
+    pub fn full_text(&self, id: Id) -> String {
+        let mut text = String::new();
+        for data in self.tokens(id) {
+            text.push_str(&data.leading);
+            text.push_str(Self::token_text(data));
+            text.push_str(&data.trailing);
+        }
+        text
+    }
+
+    pub fn leading(&self, id: Id) -> &'a str {
+        self.tokens(id).next().map_or("", |d| d.leading.as_str())
+    }
+
+    pub fn trailing(&self, id: Id) -> &'a str {
+        self.tokens(id).last().map_or("", |d| d.trailing.as_str())
+    }
+}
+
+/// The whole input, which takes every root when parsing stopped short of the axiom, and the
+/// [trivia](ParseTree::trivia) no token keeps.
+impl<'a> Display for Cst<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut id = 0;
+        while id < self.tree.nodes.len() {
+            f.write_str(&self.full_text(id))?;
+            id = self.tree.subtree_end(id);
+        }
+        f.write_str(&self.tree.trivia)
+    }
+}
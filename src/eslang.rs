@@ -2,6 +2,7 @@ use log::{debug, info, trace, warn};
 use memmap::MmapOptions;
 
 use crate::fern::{FernLexer, FernParseTree};
+use crate::grammar::compiled::{CompiledGrammar, CompiledLexTable};
 use crate::grammar::lg::{self, LexingTable, LookupResult, State, Token};
 use crate::grammar::opg::{OpGrammar, RawGrammar};
 use crate::lexer::{split_mmap_into_chunks, Data, LexerError, LexerInterface, ParallelLexer};
@@ -61,7 +62,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     //     let mut mmap: memmap::Mmap = unsafe { MmapOptions::new().map(&file)? };
     //     let chunks = split_mmap_into_chunks(&mut mmap, 50000).unwrap();
     //     thread::scope(|s| {
-    //         let mut lexer: ParallelLexer<EslangLexer> = ParallelLexer::new(table.clone(), s, 1);
+    //         let mut lexer: ParallelLexer<EslangLexer> = ParallelLexer::new(&table, s, 1);
     //         let batch = lexer.new_batch();
     //         for task in chunks.iter().enumerate() {
     //             lexer.add_to_batch(&batch, task.1, task.0);
@@ -90,7 +91,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     // let tree: ParseTree = {
     //     let mut trees = Vec::new();
     //     for (partial_tokens, partial_data) in tokens {
-    //         let mut parser = Parser::new(grammar.clone());
+    //         let mut parser = Parser::new(&grammar);
     //         parser.parse(partial_tokens, partial_data);
     //         parser.parse(vec![grammar.delim], Vec::new());
     //         trees.push(parser.collect_parse_tree().unwrap());
@@ -120,7 +121,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
 }
 
 pub struct EslangLexer {
-    pub table: LexingTable,
+    pub table: CompiledLexTable,
     pub start_state: State,
     pub state: State,
     pub buf: String,
@@ -131,10 +132,10 @@ pub struct EslangLexer {
 }
 
 impl LexerInterface for EslangLexer {
-    fn new(table: LexingTable, start_state: usize) -> Self {
-        let whitespace_token = table.terminal_map.iter().position(|x| x == "WHITESPACE").unwrap();
+    fn new(table: &CompiledLexTable, start_state: usize) -> Self {
+        let whitespace_token = table.terminal("WHITESPACE").unwrap();
         Self {
-            table,
+            table: table.clone(),
             whitespace_token,
             had_whitespace: false,
             tokens: Vec::new(),
@@ -1,9 +1,14 @@
+use crate::ast;
+use crate::grammar::compiled::{CompiledGrammar, CompiledLexTable};
 use crate::grammar::lg::{self, LexingTable, LookupResult, State};
 use crate::grammar::opg::{OpGrammar, RawGrammar, Token};
 use crate::lexer::{Data, LexerError, LexerInterface, ParallelLexer};
-use crate::parser::{Parser, PartialParseTree};
+use crate::lower;
+use crate::parser::{parse_chunks, Parser, PartialParseTree};
 use crate::parsetree::{Node, ParseTree};
-use log::{info, trace, warn};
+use crate::query::Queryable;
+use crate::{COMP_TIME_GRAMMAR, COMP_TIME_KEYWORD_LEXICAL_GRAMMAR, COMP_TIME_LEXICAL_GRAMMAR};
+use log::{error, info, trace, warn};
 use simple_error::SimpleError;
 use std::borrow::Cow;
 use std::cmp::max;
@@ -12,6 +17,8 @@ use std::error::Error;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{sync, thread};
 
@@ -20,53 +27,92 @@ pub struct FernParseTree {
     pub root: Node,
 }
 
+/// Lexing table for fern source, with keywords split off from names. The lexical grammars are
+/// compiled into the binary, so this works from any directory.
+pub fn lexing_table() -> Result<CompiledLexTable, Box<dyn Error>> {
+    let mut table = lg::StateGraph::from(lg::LexicalGrammar::from(COMP_TIME_LEXICAL_GRAMMAR))
+        .convert_to_dfa()
+        .build_table();
+    table.terminal_map.push("UMINUS".to_string());
+    let keywords = lg::StateGraph::from(lg::LexicalGrammar::from(COMP_TIME_KEYWORD_LEXICAL_GRAMMAR))
+        .convert_to_dfa()
+        .build_table();
+    let name_token = table.terminal_map.iter().position(|x| x == "NAME").unwrap();
+    table.add_table(name_token, keywords);
+    Ok(CompiledLexTable::new(table))
+}
+
+/// The fern parsing grammar, over the terminals of `table`. Like [`lexing_table`], it does not
+/// read data/grammar at run time.
+pub fn grammar(table: &CompiledLexTable) -> Result<CompiledGrammar, Box<dyn Error>> {
+    let mut raw = RawGrammar::new(COMP_TIME_GRAMMAR, table.terminal_map.clone())?;
+    raw.delete_repeated_rhs()?;
+    Ok(CompiledGrammar::new(OpGrammar::new(raw)?))
+}
+
+/// [`grammar`] over [`lexing_table`], built the first time it is needed. The grammar numbers its
+/// symbols the same way every time it is built, so this one fits trees parsed with any other.
+pub fn shared_grammar() -> &'static CompiledGrammar {
+    static GRAMMAR: sync::OnceLock<CompiledGrammar> = sync::OnceLock::new();
+    GRAMMAR.get_or_init(|| grammar(&lexing_table().expect("the lexing table is built in")).expect("the grammar is built in"))
+}
+
+/// Lex a whole source file on the current thread. Along with the tokens comes the trivia of a
+/// source without any, such as one that is only comments, which no token can keep. See
+/// [`ParseTree::trivia`].
+pub fn lex(table: &CompiledLexTable, source: &[u8]) -> Result<(Vec<Token>, Vec<Data>, String), LexerError> {
+    let mut lexer = FernLexer::new(table, 0);
+    for c in source {
+        lexer.consume(*c)?;
+    }
+    lexer.finish()?;
+    let trivia = lexer.orphaned_trivia();
+    let (_, tokens, data) = lexer.take();
+    Ok((tokens, data, trivia))
+}
+
+/// Compile data/test.fern. When `pipelined` is set, chunks are parsed while later ones are still
+/// being lexed, instead of after the whole file is lexed.
 #[cfg(not(target_arch = "wasm32"))]
-pub fn compile() -> Result<(), Box<dyn Error>> {
+pub fn compile(pipelined: bool) -> Result<(), Box<dyn Error>> {
     use memmap::MmapOptions;
 
     use crate::split_file_into_chunks;
 
     let start = Instant::now();
 
-    let first_lg = Instant::now();
-    let mut file = File::open("data/grammar/fern.lg").unwrap();
-    let mut buf = String::new();
-    file.read_to_string(&mut buf).unwrap();
-    let g = lg::LexicalGrammar::from(&buf);
-    let nfa = lg::StateGraph::from(g.clone());
-    let mut f = File::create("nfa.dot").unwrap();
-    lg::render(&nfa, &mut f);
-    let dfa = nfa.convert_to_dfa();
-    let mut f = File::create("dfa.dot").unwrap();
-    lg::render(&dfa, &mut f);
-    let mut table = dfa.build_table();
-    table.terminal_map.push("UMINUS".to_string());
-    let first_lg = first_lg.elapsed();
-    buf.clear();
-
-    let second_lg = Instant::now();
-    let mut file = File::open("data/grammar/keywords.lg").unwrap();
-    file.read_to_string(&mut buf).unwrap();
-    let g = lg::LexicalGrammar::from(&buf);
-    let nfa = lg::StateGraph::from(g.clone());
-    let mut f = File::create("keyword_nfa.dot").unwrap();
-    lg::render(&nfa, &mut f);
-    let dfa = nfa.convert_to_dfa();
-    let mut f = File::create("keyword_dfa.dot").unwrap();
-    lg::render(&dfa, &mut f);
-    let keywords = dfa.build_table();
-    let second_lg = second_lg.elapsed();
+    let table_time = Instant::now();
+    let table = lexing_table()?;
+    let table_time = table_time.elapsed();
 
-    let name_token = table.terminal_map.iter().position(|x| x == "NAME").unwrap();
-    table.add_table(name_token, keywords);
+    let grammar_time = Instant::now();
+    let grammar = grammar(&table)?;
+    let grammar_time = grammar_time.elapsed();
 
-    let lex_time = Instant::now();
-    let tokens: LinkedList<(Vec<Token>, Vec<Data>)> = {
-        let file = File::open("data/test.fern")?;
-        let mut mmap: memmap::Mmap = unsafe { MmapOptions::new().map(&file)? };
-        let chunks = split_file_into_chunks(&mmap, 1000).unwrap();
+    let file = File::open("data/test.fern")?;
+    let mmap: memmap::Mmap = unsafe { MmapOptions::new().map(&file)? };
+    let chunks = split_file_into_chunks(&mmap, 1000).unwrap();
+
+    let mut lex_time = None;
+    let parse_time = Instant::now();
+    let (trees, errors) = if pipelined {
+        // Each chunk goes to the parser as soon as it is lexed, so lexing and parsing overlap.
         thread::scope(|s| {
-            let mut lexer: ParallelLexer<FernLexer> = ParallelLexer::new(table.clone(), s, 1);
+            let (send, recv) = crossbeam_channel::unbounded();
+            let parser = s.spawn(|| parse_chunks(&grammar, recv));
+            let mut lexer: ParallelLexer<FernLexer> = ParallelLexer::new(&table, s, 1);
+            let batch = lexer.new_batch();
+            for task in chunks.iter().enumerate() {
+                lexer.add_to_batch(&batch, task.1, task.0);
+            }
+            lexer.stream_batch(batch, send);
+            lexer.kill();
+            parser.join().unwrap()
+        })
+    } else {
+        let lex = Instant::now();
+        let tokens: LinkedList<(Vec<Token>, Vec<Data>)> = thread::scope(|s| {
+            let mut lexer: ParallelLexer<FernLexer> = ParallelLexer::new(&table, s, 1);
             let batch = lexer.new_batch();
             for task in chunks.iter().enumerate() {
                 lexer.add_to_batch(&batch, task.1, task.0);
@@ -74,37 +120,18 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
             let tokens = lexer.collect_batch(batch);
             lexer.kill();
             tokens
-        })
+        });
+        lex_time = Some(lex.elapsed());
+        parse_chunks(&grammar, tokens)
     };
-    let lex_time = lex_time.elapsed();
-
-    // info!("{:?}", table.terminal_map);
-    // info!("{:?}", &tokens);
-    // for (l, _) in &tokens {
-    //     for t in l {
-    //         info!("{}", table.terminal_map[*t]);
-    //     }
-    // }
-
-    let grammar_time = Instant::now();
-    let mut raw = RawGrammar::from("data/grammar/fern.g", table.terminal_map.clone()).unwrap();
-    raw.delete_repeated_rhs()?;
-    let grammar = OpGrammar::new(raw)?;
-    let grammar_time = grammar_time.elapsed();
-
-    let parse_time = Instant::now();
-    let tree: ParseTree = {
-        let mut trees = Vec::new();
-        for (partial_tokens, partial_data) in tokens {
-            let mut parser = Parser::new(grammar.clone());
-            for (i, (x, y)) in partial_tokens.iter().zip(&partial_data).enumerate() {
-                info!("i={}, {} {:?}", i, x, y);
-            }
-            parser.parse(partial_tokens, partial_data);
-            parser.parse(vec![grammar.delim], Vec::new());
-            trees.push(parser.collect_parse_tree().unwrap());
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("{}", e);
         }
-
+        return Err(Box::new(SimpleError::new(format!("Found {} syntax errors.", errors.len()))));
+    }
+    let tree: ParseTree = {
+        let mut trees = trees;
         trees.reverse();
         let mut first = trees.pop().unwrap();
         while let Some(tree) = trees.pop() {
@@ -124,11 +151,14 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     let mut f = File::create("ast.dot").unwrap();
     ast.dot(&mut f).unwrap();
 
-    info!("Time to build first lexical grammar: {:?}", first_lg);
-    info!("Time to build second lexical grammar: {:?}", second_lg);
-    info!("Time to lex: {:?}", lex_time);
+    info!("Time to build lexing table: {:?}", table_time);
     info!("Time to build parsing grammar: {:?}", grammar_time);
-    info!("Time to parse: {:?}", parse_time);
+    if let Some(lex_time) = lex_time {
+        info!("Time to lex: {:?}", lex_time);
+        info!("Time to parse: {:?}", parse_time - lex_time);
+    } else {
+        info!("Time to lex and parse: {:?}", parse_time);
+    }
     // info!("└─Time spent rule-searching: {:?}", time);
     info!("Total run time : {:?}", start.elapsed());
 
@@ -139,7 +169,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
 }
 
 pub struct FernLexer {
-    pub table: LexingTable,
+    pub table: CompiledLexTable,
     pub start_state: State,
     pub state: State,
     pub buf: String,
@@ -148,6 +178,12 @@ pub struct FernLexer {
     pub whitespace_token: Token,
     had_lparenfunc: i32,
     had_whitespace: bool,
+    /// Input consumed since the previous token: trivia, then the token being lexed.
+    text: Vec<u8>,
+    /// Where the token being lexed starts in `text`.
+    token_start: usize,
+    /// Set while [`LexerInterface::finish`] feeds input that is not part of the source.
+    finishing: bool,
     semi: Token,
     lbrace: Token,
     rbrace: Token,
@@ -164,29 +200,32 @@ pub struct FernLexer {
 }
 
 impl LexerInterface for FernLexer {
-    fn new(table: LexingTable, start_state: usize) -> Self {
-        let name_token = table.terminal_map.iter().position(|x| x == "NAME").unwrap();
-        let lparen = table.terminal_map.iter().position(|x| x == "LPAREN").unwrap();
-        let rparen = table.terminal_map.iter().position(|x| x == "RPAREN").unwrap();
-        let whitespace_token = table.terminal_map.iter().position(|x| x == "WHITESPACE").unwrap();
-        let minus = table.terminal_map.iter().position(|x| x == "MINUS").unwrap();
-        let unary_minus = table.terminal_map.iter().position(|x| x == "UMINUS").unwrap();
-        let fn_t = table.terminal_map.iter().position(|x| x == "FUNCTION").unwrap();
-        let return_t = table.terminal_map.iter().position(|x| x == "RETURN").unwrap();
-        let while_t = table.terminal_map.iter().position(|x| x == "WHILE").unwrap();
-        let let_t = table.terminal_map.iter().position(|x| x == "LET").unwrap();
-        let rbrace = table.terminal_map.iter().position(|x| x == "RBRACE").unwrap();
-        let lbrace = table.terminal_map.iter().position(|x| x == "LBRACE").unwrap();
-        let semi = table.terminal_map.iter().position(|x| x == "SEMI").unwrap();
-        let comment = table.terminal_map.iter().position(|x| x == "COMMENT").unwrap();
+    fn new(table: &CompiledLexTable, start_state: usize) -> Self {
+        let name_token = table.terminal("NAME").unwrap();
+        let lparen = table.terminal("LPAREN").unwrap();
+        let rparen = table.terminal("RPAREN").unwrap();
+        let whitespace_token = table.terminal("WHITESPACE").unwrap();
+        let minus = table.terminal("MINUS").unwrap();
+        let unary_minus = table.terminal("UMINUS").unwrap();
+        let fn_t = table.terminal("FUNCTION").unwrap();
+        let return_t = table.terminal("RETURN").unwrap();
+        let while_t = table.terminal("WHILE").unwrap();
+        let let_t = table.terminal("LET").unwrap();
+        let rbrace = table.terminal("RBRACE").unwrap();
+        let lbrace = table.terminal("LBRACE").unwrap();
+        let semi = table.terminal("SEMI").unwrap();
+        let comment = table.terminal("COMMENT").unwrap();
 
         Self {
-            table,
+            table: table.clone(),
             whitespace_token,
             unary_minus,
             minus,
             had_whitespace: false,
             had_lparenfunc: -1,
+            text: Vec::new(),
+            token_start: 0,
+            finishing: false,
             name_token,
             lparen,
             rparen,
@@ -213,34 +252,33 @@ impl LexerInterface for FernLexer {
             match result {
                 LookupResult::Terminal(mut t) => {
                     if let Some((table, offset)) = self.table.sub_tables.get(&t) {
-                        let mut state = 0;
-                        let buf = format!("{}", self.buf);
-                        for c in buf.chars() {
-                            match table.get(c as u8, state) {
-                                LookupResult::Terminal(token) => {
-                                    t = token + offset;
-                                    break;
-                                }
-                                LookupResult::State(s) => {
-                                    state = s;
-                                }
-                                LookupResult::Err => break,
-                            }
+                        // A keyword has to be the whole word, `int` is a name and not `in`.
+                        let mut state = Some(0);
+                        for c in self.buf.bytes() {
+                            state = match state.map(|s| table.get(c, s)) {
+                                Some(LookupResult::State(s)) => Some(s),
+                                _ => None,
+                            };
                         }
-                        if let Some(token) = table.try_get_terminal(state) {
+                        if let Some(token) = state.and_then(|s| table.try_get_terminal(s)) {
                             t = token + offset;
                         }
                     }
 
                     trace!("c, t: {}, {}", input as char, self.table.terminal_map[t]);
                     if t != self.whitespace_token && t != self.comment {
+                        let raw = String::from_utf8_lossy(&self.text.split_off(self.token_start.min(self.text.len()))).into_owned();
+                        let trivia = std::mem::take(&mut self.text);
+                        let leading = self.split_trivia(trivia);
                         let mut t2 = if self.had_whitespace { self.whitespace_token } else { t };
                         self.look_ahead(&mut t2);
                         self.look_ahead_no_whitespace(&mut t);
                         self.tokens.push(t);
                         self.data.push(Data {
                             token_index: self.tokens.len() - 1,
-                            raw: self.buf.clone(),
+                            raw,
+                            leading,
+                            ..Default::default()
                         });
                         self.had_whitespace = false;
                     } else {
@@ -251,22 +289,59 @@ impl LexerInterface for FernLexer {
                     reconsume = true;
                 }
                 LookupResult::State(s) => {
+                    if self.buf.is_empty() {
+                        self.token_start = self.text.len();
+                    }
                     self.buf.push(input as char);
+                    if !self.finishing {
+                        self.text.push(input);
+                    }
                     self.state = s;
                 }
                 LookupResult::Err => {
                     trace!("Lexing Error when transitioning state. state : {}", self.state);
+                    if !self.finishing {
+                        self.text.push(input);
+                    }
                 }
             }
         }
         return Ok(());
     }
-    fn take(self) -> (State, Vec<Token>, Vec<Data>) {
+    fn finish(&mut self) -> Result<(), LexerError> {
+        self.finishing = true;
+        let result = self.consume(b' ');
+        self.finishing = false;
+        result
+    }
+    fn orphaned_trivia(&self) -> String {
+        if self.data.is_empty() {
+            String::from_utf8_lossy(&self.text).into_owned()
+        } else {
+            String::new()
+        }
+    }
+    /// Input after the last token trails it.
+    fn take(mut self) -> (State, Vec<Token>, Vec<Data>) {
+        if let Some(last) = self.data.last_mut() {
+            last.trailing.push_str(&String::from_utf8_lossy(&self.text));
+        }
         (self.state, self.tokens, self.data)
     }
 }
 
 impl FernLexer {
+    /// Give the trivia up to and including the first newline to the previous token as its
+    /// trailing trivia, and return the rest, which leads the next token.
+    fn split_trivia(&mut self, mut trivia: Vec<u8>) -> String {
+        if let Some(previous) = self.data.last_mut() {
+            let end = trivia.iter().position(|c| *c == b'\n').map_or(trivia.len(), |i| i + 1);
+            let leading = trivia.split_off(end);
+            previous.trailing.push_str(&String::from_utf8_lossy(&trivia));
+            trivia = leading;
+        }
+        String::from_utf8_lossy(&trivia).into_owned()
+    }
     fn look_ahead(&mut self, t2: &mut Token) {
         if let Some(t1) = self.tokens.last() {
             trace!("look_ahead {}, {}", self.table.terminal_map[*t1], self.table.terminal_map[*t2]);
@@ -283,6 +358,8 @@ impl FernLexer {
                     self.data.push(Data {
                         token_index: self.tokens.len() - 1,
                         raw: ";".to_string(),
+                        inserted: true,
+                        ..Default::default()
                     });
                 }
             }
@@ -290,6 +367,28 @@ impl FernLexer {
     }
 }
 
+/// Every [`OperatorKind`], for reading them back by name.
+pub(crate) const OPERATORS: [OperatorKind; 18] = [
+    OperatorKind::Add,
+    OperatorKind::Multiply,
+    OperatorKind::Divide,
+    OperatorKind::Modulo,
+    OperatorKind::Subtract,
+    OperatorKind::Equal,
+    OperatorKind::NotEqual,
+    OperatorKind::Or,
+    OperatorKind::And,
+    OperatorKind::GreaterThan,
+    OperatorKind::GreaterThanOrEqual,
+    OperatorKind::LessThan,
+    OperatorKind::LessThanOrEqual,
+    OperatorKind::Concat,
+    OperatorKind::Power,
+    OperatorKind::Not,
+    OperatorKind::Negate,
+    OperatorKind::Length,
+];
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum OperatorKind {
     Add,
@@ -305,12 +404,51 @@ pub enum OperatorKind {
     GreaterThanOrEqual,
     LessThan,
     LessThanOrEqual,
+    Concat,
+    Power,
+    Not,
+    Negate,
+    Length,
+}
+
+impl OperatorKind {
+    /// Whether the operator takes one operand rather than two.
+    pub fn is_unary(&self) -> bool {
+        matches!(self, OperatorKind::Not | OperatorKind::Negate | OperatorKind::Length)
+    }
+
+    /// How tightly the operator binds in fern.g, from 1 for `||` to 8 for `^`. Names, literals,
+    /// calls and members bind tighter than any operator.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            OperatorKind::Or => 1,
+            OperatorKind::And => 2,
+            OperatorKind::Equal
+            | OperatorKind::NotEqual
+            | OperatorKind::GreaterThan
+            | OperatorKind::GreaterThanOrEqual
+            | OperatorKind::LessThan
+            | OperatorKind::LessThanOrEqual => 3,
+            OperatorKind::Concat => 4,
+            OperatorKind::Add | OperatorKind::Subtract => 5,
+            OperatorKind::Multiply | OperatorKind::Divide | OperatorKind::Modulo => 6,
+            OperatorKind::Not | OperatorKind::Negate | OperatorKind::Length => 7,
+            OperatorKind::Power => 8,
+        }
+    }
+
+    /// Whether `a op b op c` groups as `a op (b op c)`.
+    pub fn is_right_associative(&self) -> bool {
+        matches!(self, OperatorKind::Concat | OperatorKind::Power)
+    }
 }
 
 #[derive(Debug)]
-struct AstNode {
-    kind: AstNodeKind,
-    child_count: usize,
+pub(crate) struct AstNode {
+    pub(crate) kind: AstNodeKind,
+    pub(crate) child_count: usize,
+    /// Input tokens the node was lowered from, if it came from source.
+    pub(crate) span: Option<Range<usize>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -319,6 +457,9 @@ pub enum AstNodeKind {
     Number(String),
     String(String),
     Name(String),
+    Bool(bool),
+    Nil,
+    Member,
     Field,
     ExprList,
     FieldList,
@@ -338,591 +479,111 @@ pub enum AstNodeKind {
     Struct,
 }
 
+impl AstNodeKind {
+    /// Name of the variant, without its contents.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AstNodeKind::Operator(_) => "Operator",
+            AstNodeKind::Number(_) => "Number",
+            AstNodeKind::String(_) => "String",
+            AstNodeKind::Name(_) => "Name",
+            AstNodeKind::Bool(_) => "Bool",
+            AstNodeKind::Nil => "Nil",
+            AstNodeKind::Member => "Member",
+            AstNodeKind::Field => "Field",
+            AstNodeKind::ExprList => "ExprList",
+            AstNodeKind::FieldList => "FieldList",
+            AstNodeKind::Assign => "Assign",
+            AstNodeKind::Let => "Let",
+            AstNodeKind::LetAssign => "LetAssign",
+            AstNodeKind::Return => "Return",
+            AstNodeKind::Module => "Module",
+            AstNodeKind::StatList => "StatList",
+            AstNodeKind::FunctionCall => "FunctionCall",
+            AstNodeKind::Function => "Function",
+            AstNodeKind::If => "If",
+            AstNodeKind::ElseIf => "ElseIf",
+            AstNodeKind::Else => "Else",
+            AstNodeKind::For => "For",
+            AstNodeKind::While => "While",
+            AstNodeKind::Struct => "Struct",
+        }
+    }
+
+    /// The contents of literals and names, and the kind of operators.
+    pub fn value(&self) -> Option<String> {
+        match self {
+            AstNodeKind::Operator(op) => Some(format!("{:?}", op)),
+            AstNodeKind::Number(s) | AstNodeKind::String(s) | AstNodeKind::Name(s) => Some(s.clone()),
+            AstNodeKind::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Nodes are stored in reverse pre-order, with the root last. See [`crate::ast`] for typed
+/// views over them and for visiting and rewriting them.
 pub struct FernAst {
-    nodes: Vec<AstNode>,
-    token_map: BTreeMap<usize, String>,
+    pub(crate) nodes: Vec<AstNode>,
+    pub(crate) token_map: Arc<BTreeMap<usize, String>>,
+    /// Number of nodes in the subtree of each node, including itself, by storage position.
+    pub(crate) sizes: Vec<usize>,
 }
 
 impl Into<FernAst> for ParseTree {
+    /// Lowered with [`shared_grammar`]. Constructs that have no place in the AST are left out
+    /// and logged, see [`lower::lower`].
     fn into(self) -> FernAst {
-        let find = |tok: &str| -> Vec<usize> {
-            let mut res = Vec::new();
-            for (k, v) in &self.token_map {
-                if v == tok {
-                    res.push(*k);
-                }
-            }
-            return res;
-        };
-
-        let base_exp = find("baseExp");
-        let prefix_exp = find("prefixExp");
-        let string = find("STRING");
-        let else_t = find("ELSE");
-        let else_if = find("ELSEIF");
-        let rbrack = find("RBRACK");
-        let rbrace = find("RBRACE");
-        let name = find("NAME");
-        let number = find("NUMBER");
-        let let_t = find("LET");
-        let if_t = find("IF");
-        let fn_t = find("FUNCTION");
-        let eq = find("EQ");
-        let colon = find("COLON");
-        let or = find("logicalOrExp");
-        let and = find("logicalAndExp");
-        let semi = find("SEMI");
-        let stat = find("stat");
-        let field = find("field");
-        let stat_list = find("statList");
-        let field_list = find("fieldList");
-        let field_list_body = find("fieldListBody");
-        let axiom = find("NewAxiom");
-        let relational_exp = find("relationalExp");
-        let additive_exp = find("additiveExp");
-        let mul_exp = find("multiplicativeExp");
-        let ret_stat = find("retStat");
-        let fn_call = find("functionCall");
-        let expr_then = find("exprThen");
-        let lbrace = find("LBRACE");
-        let struct_t = find("STRUCT");
-        let else_if_block = find("elseIfBlock");
-
-        let rm_child = |mut child: isize, mut total_child: isize, existing_nodes: &mut Vec<AstNode>| -> Vec<AstNode> {
-            let desired = child;
-            let mut current_child = 0;
-            let mut seeking_end = false;
-            let mut start_index = 0;
-            let mut end_index = 0;
-            total_child -= 1;
-            for (i, n) in existing_nodes.iter().enumerate().rev() {
-                warn!("i: {}, child {}, total: {}, node: {:?}", i, child, total_child, n);
-                child -= 1;
-                child += n.child_count as isize;
-                if child - (total_child - 1) == 0 {
-                    total_child -= 1;
-                    current_child += 1;
-                    if !seeking_end {
-                        if current_child == desired {
-                            warn!("END i: {}, child {}, total: {}, node: {:?}", i, child, total_child, n);
-                            end_index = i;
-                            seeking_end = true;
-                        }
-                    } else {
-                        warn!("START i: {}, child {}, total: {}, node: {:?}", i, child, total_child, n);
-                        start_index = i;
-                        break;
-                    }
-                }
-                if child < 0 {
-                    warn!("START i: {}, child {}, total: {}, node: {:?}", i, child, total_child, n);
-                    start_index = i;
-                    break;
-                };
-            }
-            warn!("start: {}, end {}", start_index, end_index);
-
-            if start_index == end_index {
-                vec![existing_nodes.remove(start_index)]
-            } else {
-                existing_nodes.drain(start_index..end_index).collect()
-            }
-        };
-
-        let expr_map = |parent: &Node, children: &Vec<Node>| -> Option<AstNode> {
-            if base_exp.contains(&parent.token) {
-                let child = children.last().unwrap().token;
-                let data = if let Some(ref d) = children.get(0).unwrap().data {
-                    d.raw.clone()
-                } else {
-                    String::new()
-                };
-                if string.contains(&child) {
-                    return Some(AstNode {
-                        kind: AstNodeKind::String(data),
-                        child_count: 0,
-                    });
-                } else if name.contains(&child) {
-                    return Some(AstNode {
-                        kind: AstNodeKind::Name(data),
-                        child_count: 0,
-                    });
-                } else if number.contains(&child) {
-                    return Some(AstNode {
-                        kind: AstNodeKind::Number(data),
-                        child_count: 0,
-                    });
-                }
-            }
-
-            if relational_exp.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Operator(OperatorKind::Equal),
-                    child_count: 2,
-                });
-            }
-
-            if additive_exp.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Operator(OperatorKind::Add),
-                    child_count: 2,
-                });
-            }
-
-            if mul_exp.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Operator(OperatorKind::Multiply),
-                    child_count: 2,
-                });
-            }
-
-            if or.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Operator(OperatorKind::Or),
-                    child_count: 2,
-                });
-            }
-
-            if and.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Operator(OperatorKind::And),
-                    child_count: 2,
-                });
-            }
-            None
-        };
-
-        let reduce = |parent: &Node, mut children: Vec<Node>, existing_nodes: &mut Vec<AstNode>| -> Option<AstNode> {
-            if let Some(op) = expr_map(parent, &children) {
-                return Some(op);
-            }
-
-            if prefix_exp.contains(&parent.token) {
-                warn!("does nothing for prefixexp");
-                return None;
-            }
-
-            if ret_stat.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Return,
-                    child_count: 1,
-                });
-            }
-
-            if fn_call.contains(&parent.token) {
-                if children.len() > 3 {
-                    return Some(AstNode {
-                        kind: AstNodeKind::FunctionCall,
-                        child_count: 2,
-                    });
-                } else {
-                    return Some(AstNode {
-                        kind: AstNodeKind::FunctionCall,
-                        child_count: 1,
-                    });
-                }
-            }
-
-            if field.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::Field,
-                    child_count: 2,
-                });
-            }
-
-            if field_list.contains(&parent.token) {
-                return Some(AstNode {
-                    kind: AstNodeKind::FieldList,
-                    child_count: 1,
-                });
-            }
-
-            if field_list_body.contains(&parent.token) {
-                if children.len() > 1 {
-                    return Some(AstNode {
-                        kind: AstNodeKind::FieldList,
-                        child_count: 2,
-                    });
-                }
-            }
-
-            if else_if_block.contains(&parent.token) {
-                if else_t.contains(&children.last().unwrap().token) {
-                    if children.len() > 3 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Else,
-                            child_count: 1,
-                        });
-                    } else {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Else,
-                            child_count: 0,
-                        });
-                    }
-                }
-                if else_if.contains(&children.last().unwrap().token) {
-                    if children.len() == 4 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::ElseIf,
-                            child_count: 1,
-                        });
-                    } else if children.len() == 5 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::ElseIf,
-                            child_count: 2,
-                        });
-                    } else if children.len() == 6 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::ElseIf,
-                            child_count: 3,
-                        });
-                    }
-                }
-            }
-
-            if stat.contains(&parent.token) {
-                if struct_t.contains(&children.last().unwrap().token) {
-                    if children.len() > 3 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Struct,
-                            child_count: 2,
-                        });
-                    } else {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Struct,
-                            child_count: 1,
-                        });
-                    }
-                }
-
-                if lbrace.contains(&children.last().unwrap().token) {
-                    return Some(AstNode {
-                        kind: AstNodeKind::StatList,
-                        child_count: 0,
-                    });
-                }
-
-                if eq.contains(&children.last().unwrap().token) {
-                    return Some(AstNode {
-                        kind: AstNodeKind::Assign,
-                        child_count: 2,
-                    });
-                }
-
-                if fn_t.contains(&children.last().unwrap().token) {
-                    if children.len() == 6 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Function,
-                            child_count: 1,
-                        });
-                    } else if rbrack.contains(&children.get(children.len() - 4).unwrap().token) {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Function,
-                            child_count: 2,
-                        });
-                    } else if !rbrace.contains(&children.get(1).unwrap().token) {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Function,
-                            child_count: 3,
-                        });
-                    } else {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Function,
-                            child_count: 2,
-                        });
-                    }
-                }
-
-                if if_t.contains(&children.last().unwrap().token) {
-                    let mut cnt = 1;
-                    if else_if_block.contains(&children.last().unwrap().token) {
-                        cnt += 1;
-                    }
-                    if !rbrace.contains(&children.get(3).unwrap().token) {
-                        cnt += 1;
-                    }
-                    return Some(AstNode {
-                        kind: AstNodeKind::If,
-                        child_count: cnt,
-                    });
-                }
-
-                if let_t.contains(&children.last().unwrap().token) {
-                    if children.len() > 2 {
-                        return Some(AstNode {
-                            kind: AstNodeKind::LetAssign,
-                            child_count: 2,
-                        });
-                    } else {
-                        return Some(AstNode {
-                            kind: AstNodeKind::Let,
-                            child_count: 1,
-                        });
-                    }
-                }
-            }
-
-            if stat_list.contains(&parent.token) {
-                let mut cnt = 0;
-                for c in children {
-                    if !semi.contains(&c.token) {
-                        cnt += 1;
-                    }
-                }
-                return Some(AstNode {
-                    kind: AstNodeKind::StatList,
-                    child_count: cnt,
-                });
-            }
-
-            todo!("Didn't reduce parse tree node.");
-        };
-
-        let mut nodes: Vec<AstNode> = Vec::new();
-        // let mut current = Vec::new();
-        let mut operands: Vec<Node> = Vec::new();
-        // bottom up traversal of the tree.
-        for n in self.nodes.into_iter().rev() {
-            let ops: Vec<&String> = operands.iter().map(|n| self.token_map.get(&n.token).unwrap()).collect();
-            info!("operands {:?}", ops);
-            if n.child_count > 0 {
-                let ops: Vec<Node> = operands.drain(operands.len() - n.child_count..).collect();
-                let ops_str: Vec<&String> = ops.iter().map(|n| self.token_map.get(&n.token).unwrap()).collect();
-                info!("reduce {:?}: {:?} ", self.token_map.get(&n.token).unwrap(), ops_str);
-
-                if axiom.contains(&n.token) {
-                    break;
-                }
-                let mut new = match reduce(&n, ops.clone(), &mut nodes) {
-                    Some(new) => new,
-                    None => {
-                        operands.push(n);
-                        continue;
-                    }
-                };
-
-                // Flatten statlists
-                if new.kind == AstNodeKind::StatList {
-                    let mut has_child_stat_list = false;
-                    for o in &ops {
-                        if stat_list.contains(&o.token) {
-                            has_child_stat_list = true;
-                            break;
-                        }
-                    }
-                    if has_child_stat_list {
-                        // Go through the exsiting ast node stack backwards
-                        // in order to find the index of the existing statlist.
-                        let mut stat_list_pos = None;
-                        for (i, x) in nodes.iter().enumerate().rev() {
-                            if x.kind == AstNodeKind::StatList {
-                                stat_list_pos = Some(i);
-                                break;
-                            }
-                        }
-                        if let Some(i) = stat_list_pos {
-                            let mut existing_stat_list = nodes.remove(i);
-                            existing_stat_list.child_count += new.child_count - 1;
-                            new = existing_stat_list;
-                        }
-                    }
-                }
-
-                nodes.push(new);
-                operands.push(n);
-            } else {
-                operands.push(n);
-            }
-        }
-        let n: Vec<(AstNodeKind, usize)> = nodes.iter().map(|n| (n.kind.clone(), n.child_count)).collect();
-        info!("ast {:?}", n);
-        FernAst {
-            nodes,
-            token_map: self.token_map,
+        let (ast, errors) = lower::lower(shared_grammar(), &self);
+        for e in errors {
+            warn!("{}", e);
         }
+        ast
     }
 }
 
 impl FernAst {
-    fn pre_order_traverse<F: FnMut(&Vec<(Option<usize>, usize)>, usize)>(&self, mut f: F) {
-        let mut stack = Vec::from(&[(None, self.nodes.last().unwrap().child_count)]);
-
-        for (i, n) in self.nodes.iter().enumerate().rev() {
-            let last = stack.last_mut().unwrap();
-            last.1 -= 1;
-
-            f(&stack, i);
-
-            if n.child_count > 0 {
-                stack.push((Some(i), n.child_count as usize));
-            } else {
-                while let Some((_, last)) = stack.last() {
-                    if *last == 0 {
-                        stack.pop();
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
-    }
-
     pub fn dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
         let nodes = self.nodes.iter().map(|n| format!("{:?}", n.kind)).collect();
         let mut edges = VecDeque::new();
 
-        self.pre_order_traverse(|stack, current| {
-            if stack.last().unwrap().0.is_some() {
-                edges.push_front((stack.last().unwrap().0.unwrap(), current));
+        // The graph numbers nodes by where they are stored, not by id.
+        let index = |n: ast::Node| self.nodes.len() - 1 - n.id();
+        for n in self.root().iter().flat_map(|root| root.descendants()) {
+            for c in n.children() {
+                edges.push_front((index(n), index(c)));
             }
-        });
+        }
         let g = Graph { nodes, edges };
         dot::render(&g, out)
     }
 
     pub fn print(&self) {
-        info!("{:?}", self.nodes.last().unwrap().kind);
-        self.pre_order_traverse(|stack, current| {
-            // don't print first node during traversal
-            if current == self.nodes.len() - 1 {
-                return;
-            }
-
-            let n = &self.nodes[current];
-
-            let mut padding = String::new();
-            for i in 1..stack.len() - 1 {
-                let current = stack.get(i).unwrap();
-                if current.1 > 0 {
-                    padding.push_str("| ");
-                } else {
-                    padding.push_str("  ");
-                }
+        fn print_children(node: ast::Node, padding: &str) {
+            let mut children = node.children().peekable();
+            while let Some(c) = children.next() {
+                let last = children.peek().is_none();
+                info!("{}{}{:?}", padding, if last { "└─" } else { "├─" }, c.kind());
+                print_children(c, &format!("{}{}", padding, if last { "  " } else { "| " }));
             }
+        }
 
-            let last = stack.last().unwrap();
-            if last.1 > 0 {
-                info!("{}├─{:?}", padding, n.kind);
-            } else {
-                info!("{}└─{:?}", padding, n.kind);
-            }
-        });
+        if let Some(root) = self.root() {
+            info!("{:?}", root.kind());
+            print_children(root, "");
+        }
     }
 
+    /// Problems with the names and types in the AST, see [`crate::analysis`] and
+    /// [`crate::types`].
     pub fn analysis(&self) -> Vec<String> {
-        let mut table: BTreeMap<String, IdentifierKind> = BTreeMap::new();
-        let mut prefix: Vec<String> = Vec::new();
-        let mut partial_var = None;
-        let mut issues_discovered = Vec::new();
-        self.pre_order_traverse(|stack, current| {
-            let n = &self.nodes[current];
-            let (parent_id, children_left) = if let Some((p_id, child_count)) = stack.last() {
-                (p_id, child_count)
-            } else {
-                panic!("One name node with no parent??");
-            };
-
-            let mut add_to_table = |name: &String, data: IdentifierKind, table: &mut BTreeMap<String, IdentifierKind>| {
-                if table.contains_key(name) {
-                    issues_discovered.push(format!("Identifier {} already exists.", name));
-                } else {
-                    table.insert(name.clone(), data);
-                }
-            };
-
-            match n.kind {
-                AstNodeKind::Number(ref name) | AstNodeKind::String(ref name) => match self.nodes[parent_id.unwrap()].kind {
-                    AstNodeKind::LetAssign => {
-                        if !name.is_empty() {
-                            warn!("{}", name);
-                            if *children_left == 1 {
-                                issues_discovered.push(format!("Strings and / or numbers ({}) cannot be used as identifiers.", name));
-                            } else if *children_left == 0 {
-                                if let Some(left) = partial_var {
-                                    partial_var = None;
-                                    add_to_table(left, IdentifierKind::Local, &mut table);
-                                }
-                            }
-                        }
-                    }
-                    _ => (),
-                },
-                AstNodeKind::Operator(_) | AstNodeKind::FunctionCall => match self.nodes[parent_id.unwrap()].kind {
-                    AstNodeKind::LetAssign => {
-                        if let Some(left) = partial_var {
-                            partial_var = None;
-                            add_to_table(left, IdentifierKind::Local, &mut table);
-                        }
-                    }
-                    _ => (),
-                },
-                AstNodeKind::Name(ref name) => match self.nodes[parent_id.unwrap()].kind {
-                    AstNodeKind::LetAssign => {
-                        if !name.is_empty() {
-                            warn!("{}", name);
-                            if *children_left == 1 {
-                                partial_var = Some(name);
-                            } else if *children_left == 0 {
-                                if let Some(left) = partial_var {
-                                    partial_var = None;
-                                    if !table.contains_key(name) {
-                                        issues_discovered.push(format!("Identifier {} used but not declared.", name));
-                                    } else {
-                                        add_to_table(left, IdentifierKind::Local, &mut table);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    AstNodeKind::Let => {
-                        if !name.is_empty() {
-                            add_to_table(name, IdentifierKind::Local, &mut table);
-                        }
-                    }
-                    AstNodeKind::Function => match n.kind {
-                        AstNodeKind::String(ref name) | AstNodeKind::Number(ref name) => {
-                            issues_discovered.push(format!("Invalid function name {}", name));
-                        }
-                        AstNodeKind::Name(ref name) => {
-                            warn!("{}", name);
-                            add_to_table(name, IdentifierKind::FunctionName, &mut table);
-                        }
-                        _ => {}
-                    },
-                    AstNodeKind::Field => {
-                        if !name.is_empty() && *children_left == 1 {
-                            partial_var = Some(name);
-                        } else if *children_left == 0 {
-                            let n = partial_var.unwrap();
-                            partial_var = None;
-                            add_to_table(n, IdentifierKind::FunctionParam(name.clone()), &mut table);
-                        }
-                    }
-                    _ => match n.kind {
-                        AstNodeKind::Name(ref name) => {
-                            if !table.contains_key(name) {
-                                issues_discovered.push(format!("Identifier {} used but not declared.", name));
-                            }
-                        }
-                        _ => (),
-                    },
-                    _ => (),
-                },
-                _ => (),
-            }
-        });
-        warn!("SBL_TBL: {:?}", table);
-        issues_discovered
+        let resolution = self.resolve();
+        let typing = self.check(&resolution);
+        let names = resolution.errors().iter().map(|e| e.to_string());
+        names.chain(typing.errors().iter().map(|e| e.to_string())).collect()
     }
 }
 
-#[derive(Debug)]
-enum IdentifierKind {
-    FunctionName,
-    FunctionParam(String),
-    Local,
-}
-
 type Nd = (usize, String);
 type Ed = (Nd, Nd);
 struct Graph {
@@ -964,3 +625,30 @@ impl<'a> dot::GraphWalk<'a, Nd, Ed> for Graph {
         e.1.clone()
     }
 }
+
+/// Ids are positions in pre-order, the reverse of how the nodes are stored.
+impl Queryable for FernAst {
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn child_count(&self, id: usize) -> usize {
+        self.nodes[self.nodes.len() - 1 - id].child_count
+    }
+
+    fn kind(&self, id: usize) -> String {
+        self.nodes[self.nodes.len() - 1 - id].kind.name().to_string()
+    }
+
+    /// Names, literals and operators have a `value`, see [`AstNodeKind::value`].
+    fn attribute(&self, id: usize, name: &str) -> Option<String> {
+        match name {
+            "value" => self.nodes[self.nodes.len() - 1 - id].kind.value(),
+            _ => None,
+        }
+    }
+
+    fn span(&self, id: usize) -> Option<Range<usize>> {
+        self.nodes[self.nodes.len() - 1 - id].span.clone()
+    }
+}
@@ -0,0 +1,328 @@
+use crate::fern;
+use crate::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use crate::lexer::Data;
+use crate::parser::Parser;
+use crate::parsetree::ParseTree;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub struct FormatError {
+    message: String,
+}
+
+impl Error for FormatError {}
+
+impl FormatError {
+    pub fn from(s: String) -> FormatError {
+        FormatError { message: s }
+    }
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Format Error: {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Lines longer than this are broken after commas and binary operators where possible.
+    pub width: usize,
+    /// Columns a tab indentation counts for when measuring lines.
+    pub tab_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { width: 100, tab_width: 4 }
+    }
+}
+
+const BINARY_OPERATORS: &[&str] = &[
+    "EQ", "PLUS", "MINUS", "ASTERISK", "DIVIDE", "PERCENT", "CARET", "DOT2", "LT", "GT", "LTEQ", "GTEQ", "EQDOUBLE", "NEQ",
+];
+const PREFIX_OPERATORS: &[&str] = &["UMINUS", "NOT", "SHARP"];
+/// Tokens `FernLexer::look_ahead_no_whitespace` inserts a semicolon before when they follow `}`.
+const INSERTS_SEMI: &[&str] = &["LET", "NAME", "RETURN", "FUNCTION", "RBRACE"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Gap {
+    None,
+    Space,
+    Newline,
+    BlankLine,
+}
+
+struct Piece {
+    text: String,
+    space_before: bool,
+    /// Where the line may be broken after this piece. Lower is better.
+    break_rank: Option<(usize, usize)>,
+}
+
+struct Printer<'a> {
+    options: &'a FormatOptions,
+    out: String,
+    line: Vec<Piece>,
+    line_indent: usize,
+    gap: Gap,
+    depth: usize,
+    /// Whether each open brace is the body of a struct.
+    braces: Vec<bool>,
+    nesting: usize,
+    in_statement: bool,
+    struct_pending: bool,
+    last: &'a str,
+}
+
+impl<'a> Printer<'a> {
+    fn request(&mut self, gap: Gap) {
+        self.gap = self.gap.max(gap);
+    }
+
+    fn write(&mut self, text: &str, break_rank: Option<(usize, usize)>) {
+        let mut gap = self.gap;
+        if gap == Gap::BlankLine && (self.out.is_empty() && self.line.is_empty() || self.last == "LBRACE") {
+            gap = Gap::Newline;
+        }
+        if gap >= Gap::Newline && !self.line.is_empty() {
+            self.flush();
+            if gap == Gap::BlankLine {
+                self.out.push('\n');
+            }
+        }
+        if self.line.is_empty() {
+            self.line_indent = self.depth + usize::from(self.in_statement);
+        }
+        self.line.push(Piece {
+            text: text.to_string(),
+            space_before: gap == Gap::Space && !self.line.is_empty(),
+            break_rank,
+        });
+        self.gap = Gap::None;
+    }
+
+    fn width(&self, indent: usize, pieces: &[Piece]) -> usize {
+        let mut width = indent * self.options.tab_width;
+        for (i, p) in pieces.iter().enumerate() {
+            width += p.text.chars().count() + usize::from(i > 0 && p.space_before);
+        }
+        width
+    }
+
+    /// Write out the current line, broken up greedily where it is too long.
+    fn flush(&mut self) {
+        let pieces = std::mem::take(&mut self.line);
+        let mut lines = Vec::new();
+        let mut indent = self.line_indent;
+        let mut start = 0;
+        let mut i = 0;
+        while i < pieces.len() {
+            if i > start && self.width(indent, &pieces[start..=i]) > self.options.width {
+                let best = (start..i)
+                    .filter_map(|c| pieces[c].break_rank.map(|r| (r, c)))
+                    .min_by_key(|(r, c)| (*r, std::cmp::Reverse(*c)));
+                if let Some((_, c)) = best {
+                    lines.push((indent, start..c + 1));
+                    start = c + 1;
+                    indent = self.line_indent + 1;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        lines.push((indent, start..pieces.len()));
+
+        for (indent, range) in lines {
+            for _ in 0..indent {
+                self.out.push('\t');
+            }
+            for (i, p) in pieces[range].iter().enumerate() {
+                if i > 0 && p.space_before {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&p.text);
+            }
+            self.out.push('\n');
+        }
+    }
+
+    /// Comments and unlexable input between two tokens. `same_line` is whether the start of
+    /// `trivia` is on the line of the token before it.
+    fn trivia(&mut self, trivia: &str, mut same_line: bool) {
+        let mut newlines = 0;
+        let mut rest = trivia;
+        while !rest.is_empty() {
+            let c = rest.chars().next().unwrap();
+            if c == '\n' {
+                newlines += 1;
+                same_line = false;
+                rest = &rest[1..];
+            } else if c.is_whitespace() {
+                rest = &rest[c.len_utf8()..];
+            } else if rest.starts_with("//") {
+                let end = rest.find('\n').unwrap_or(rest.len());
+                if same_line {
+                    self.gap = Gap::Space;
+                } else {
+                    self.request(if newlines > 1 && !self.in_statement { Gap::BlankLine } else { Gap::Newline });
+                }
+                self.write(rest[..end].trim_end(), None);
+                self.gap = Gap::Newline;
+                self.last = "COMMENT";
+                newlines = 0;
+                rest = &rest[end..];
+            } else {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                self.request(Gap::Space);
+                self.write(&rest[..end], None);
+                self.request(Gap::Space);
+                self.last = "COMMENT";
+                newlines = 0;
+                rest = &rest[end..];
+            }
+        }
+        if newlines > 1 && self.gap >= Gap::Newline && !self.in_statement {
+            self.request(Gap::BlankLine);
+        }
+    }
+
+    fn token(&mut self, kind: &'a str, text: &str, next: Option<&str>) {
+        match kind {
+            "LBRACE" => {
+                self.request(Gap::Space);
+                self.write(text, None);
+                self.braces.push(std::mem::take(&mut self.struct_pending));
+                self.depth += 1;
+                self.in_statement = false;
+                self.request(Gap::Newline);
+            }
+            "RBRACE" => {
+                self.braces.pop();
+                self.depth = self.depth.saturating_sub(1);
+                self.in_statement = false;
+                if self.last == "LBRACE" && self.gap == Gap::Newline {
+                    self.gap = Gap::None;
+                } else {
+                    self.gap = Gap::Newline;
+                }
+                self.write(text, None);
+                match next {
+                    Some("ELSE") | Some("ELSEIF") => self.request(Gap::Space),
+                    Some("SEMI") | Some("COMMA") | Some("RPAREN") => (),
+                    _ => self.request(Gap::Newline),
+                }
+            }
+            "SEMI" => {
+                self.write(text, None);
+                self.in_statement = false;
+                self.request(Gap::Newline);
+            }
+            "COMMA" => {
+                self.write(text, Some((self.nesting, 0)));
+                if self.braces.last() == Some(&true) && self.nesting == 0 {
+                    self.in_statement = false;
+                    self.request(Gap::Newline);
+                } else {
+                    self.request(Gap::Space);
+                }
+            }
+            _ => {
+                let call = matches!(self.last, "NAME" | "RPAREN" | "RBRACK");
+                let tight = matches!(kind, "RPAREN" | "RBRACK" | "DOT" | "COLON")
+                    || matches!(self.last, "LPAREN" | "LBRACK" | "DOT" | "")
+                    || PREFIX_OPERATORS.contains(&self.last)
+                    || matches!(kind, "LPAREN" | "LBRACK") && call;
+                if !tight {
+                    self.request(Gap::Space);
+                }
+                let rank = match kind {
+                    "OR" => Some((self.nesting, 1)),
+                    "AND" => Some((self.nesting, 2)),
+                    k if BINARY_OPERATORS.contains(&k) => Some((self.nesting, 3)),
+                    _ => None,
+                };
+                self.write(text, rank);
+                match kind {
+                    "LPAREN" | "LBRACK" => self.nesting += 1,
+                    "RPAREN" | "RBRACK" => self.nesting = self.nesting.saturating_sub(1),
+                    "STRUCT" => self.struct_pending = true,
+                    _ => (),
+                }
+                self.in_statement = true;
+            }
+        }
+        self.last = kind;
+    }
+}
+
+/// Print a parse tree of Fern source with consistent indentation, brace placement and
+/// spacing, keeping its comments, including those of the tree's [trivia](ParseTree::trivia). Semicolons the lexer inserts are left out, as are written
+/// ones it would insert anyway, so formatting is idempotent.
+pub fn format(tree: &ParseTree, options: &FormatOptions) -> String {
+    let tokens: Vec<(&str, &Data)> = tree
+        .nodes
+        .iter()
+        .filter_map(|n| n.data.as_ref().map(|d| (tree.token_map.get(&n.token).map_or("", |k| k.as_str()), d)))
+        .collect();
+
+    let mut printer = Printer {
+        options,
+        out: String::new(),
+        line: Vec::new(),
+        line_indent: 0,
+        gap: Gap::None,
+        depth: 0,
+        braces: Vec::new(),
+        nesting: 0,
+        in_statement: false,
+        struct_pending: false,
+        last: "",
+    };
+    // Semicolons the lexer inserted, and written ones it would insert again after a `}`.
+    let skipped: Vec<bool> = (0..tokens.len())
+        .map(|i| {
+            let (kind, data) = tokens[i];
+            let next = tokens[i + 1..].iter().find(|(_, d)| !d.inserted).map(|(k, _)| *k);
+            data.inserted || (kind == "SEMI" && i > 0 && tokens[i - 1].0 == "RBRACE" && next.is_some_and(|k| INSERTS_SEMI.contains(&k)))
+        })
+        .collect();
+    // Trivia since the last token printed, which skipped tokens only add to.
+    let mut trivia = String::new();
+    let mut printed = false;
+    for (i, (kind, data)) in tokens.iter().enumerate() {
+        trivia.push_str(&data.leading);
+        if skipped[i] {
+            trivia.push_str(&data.trailing);
+            printer.in_statement = false;
+            continue;
+        }
+        printer.trivia(&trivia, printed);
+        let next = (i + 1..tokens.len()).find(|j| !skipped[*j]).map(|j| tokens[j].0);
+        printer.token(kind, &data.raw, next);
+        printed = true;
+        trivia = data.trailing.clone();
+    }
+    trivia.push_str(&tree.trivia);
+    printer.trivia(&trivia, printed);
+    if !printer.line.is_empty() {
+        printer.flush();
+    }
+    printer.out
+}
+
+/// Lex, parse and format Fern source. Source with syntax errors is not formatted.
+pub fn format_source(table: &CompiledLexTable, grammar: &CompiledGrammar, source: &[u8], options: &FormatOptions) -> Result<String, FormatError> {
+    let (tokens, data, trivia) = fern::lex(table, source).map_err(|e| FormatError::from(e.to_string()))?;
+    let mut parser = Parser::new(grammar);
+    let _ = parser.parse(tokens, data);
+    let _ = parser.parse(vec![grammar.delim], Vec::new());
+    if !parser.errors().is_empty() {
+        let errors: Vec<String> = parser.errors().iter().map(|e| e.to_string()).collect();
+        return Err(FormatError::from(errors.join("\n")));
+    }
+    let mut tree = parser.collect_parse_tree().map_err(|e| FormatError::from(e.to_string()))?.into_tree();
+    tree.trivia = trivia;
+    Ok(format(&tree, options))
+}
@@ -0,0 +1,200 @@
+use super::opg::{RawGrammar, Rule, Token};
+use json_parse::JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+/// Static checks over a grammar as written, before any of the OPG transformations are applied.
+/// Everything is reported by name so the output can be read against the .g file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GrammarAnalysis {
+    pub axiom: String,
+    /// Nonterminals that no derivation from the axiom ever reaches.
+    pub unreachable: BTreeSet<String>,
+    /// Nonterminals that never derive a string made only of terminals.
+    pub unproductive: BTreeSet<String>,
+    /// Terminals that are declared in the grammar or produced by the lexer but never used in a rule.
+    pub unused_terminals: BTreeSet<String>,
+    /// Groups of nonterminals that rename each other through copy rules, e.g. A : B and B : A.
+    pub renaming_cycles: Vec<Vec<String>>,
+    /// Left terminal sets, the terminals that can begin a sentential form of each nonterminal.
+    pub left_terminals: BTreeMap<String, BTreeSet<String>>,
+    /// Right terminal sets, the terminals that can end a sentential form of each nonterminal.
+    pub right_terminals: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl GrammarAnalysis {
+    /// Analyse `g`. `lexical_sync` is the lexer's terminal map, so terminals the lexer produces but
+    /// the grammar never mentions are reported too.
+    pub fn new(g: &RawGrammar, lexical_sync: &[String]) -> Self {
+        let name = |t: &Token| g.token_raw.get(t).unwrap().clone();
+        let names = |set: &BTreeSet<Token>| set.iter().map(name).collect::<BTreeSet<String>>();
+
+        let reachable = Self::reachable(g);
+        let productive = Self::productive(g);
+
+        let mut used: BTreeSet<String> = BTreeSet::new();
+        for r in &g.rules {
+            used.extend(r.right.iter().filter(|t| g.terminals.contains(t)).map(name));
+        }
+        let mut unused_terminals: BTreeSet<String> = g.terminals.iter().map(name).collect();
+        unused_terminals.extend(lexical_sync.iter().cloned());
+        unused_terminals.retain(|t| !used.contains(t));
+
+        let left = Self::terminal_sets(g, |r| r.right.clone());
+        let right = Self::terminal_sets(g, |r| r.right.iter().rev().copied().collect());
+
+        GrammarAnalysis {
+            axiom: name(&g.axiom),
+            unreachable: g.non_terminals.iter().filter(|n| !reachable.contains(n)).map(name).collect(),
+            unproductive: g.non_terminals.iter().filter(|n| !productive.contains(n)).map(name).collect(),
+            unused_terminals,
+            renaming_cycles: Self::renaming_cycles(g).iter().map(|cycle| cycle.iter().map(name).collect()).collect(),
+            left_terminals: left.iter().map(|(n, set)| (name(n), names(set))).collect(),
+            right_terminals: right.iter().map(|(n, set)| (name(n), names(set))).collect(),
+        }
+    }
+
+    /// True if nothing in the grammar looks like a mistake. Terminal sets are informational only.
+    pub fn is_clean(&self) -> bool {
+        self.unreachable.is_empty() && self.unproductive.is_empty() && self.unused_terminals.is_empty() && self.renaming_cycles.is_empty()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let list = |set: &BTreeSet<String>| JsonValue::from(set.iter().cloned().collect::<Vec<String>>());
+        let sets = |map: &BTreeMap<String, BTreeSet<String>>| {
+            let mut object = JsonValue::new_object();
+            for (n, set) in map {
+                object[n.as_str()] = list(set);
+            }
+            object
+        };
+        object! {
+            axiom: self.axiom.clone(),
+            unreachable: list(&self.unreachable),
+            unproductive: list(&self.unproductive),
+            unused_terminals: list(&self.unused_terminals),
+            renaming_cycles: self.renaming_cycles.clone(),
+            left_terminals: sets(&self.left_terminals),
+            right_terminals: sets(&self.right_terminals)
+        }
+    }
+
+    fn reachable(g: &RawGrammar) -> BTreeSet<Token> {
+        let mut reachable = BTreeSet::from([g.axiom]);
+        let mut stack = vec![g.axiom];
+        while let Some(n) = stack.pop() {
+            for r in g.rules.iter().filter(|r| r.left == n) {
+                for t in &r.right {
+                    if g.non_terminals.contains(t) && reachable.insert(*t) {
+                        stack.push(*t);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+
+    fn productive(g: &RawGrammar) -> BTreeSet<Token> {
+        let mut productive = BTreeSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for r in &g.rules {
+                if !productive.contains(&r.left) && r.right.iter().all(|t| g.terminals.contains(t) || productive.contains(t)) {
+                    productive.insert(r.left);
+                    changed = true;
+                }
+            }
+        }
+        productive
+    }
+
+    /// Walk each right hand side in the order given by `symbols`, collecting the terminal sets of
+    /// leading nonterminals up to and including the first terminal.
+    fn terminal_sets<F: Fn(&Rule) -> Vec<Token>>(g: &RawGrammar, symbols: F) -> BTreeMap<Token, BTreeSet<Token>> {
+        let mut sets: BTreeMap<Token, BTreeSet<Token>> = BTreeMap::new();
+        for n in &g.non_terminals {
+            sets.insert(*n, BTreeSet::new());
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for r in &g.rules {
+                let mut found = BTreeSet::new();
+                for t in symbols(r) {
+                    if g.terminals.contains(&t) {
+                        found.insert(t);
+                        break;
+                    } else if let Some(set) = sets.get(&t) {
+                        found.extend(set.iter());
+                    }
+                }
+                let set = sets.get_mut(&r.left).unwrap();
+                let len = set.len();
+                set.extend(found);
+                if set.len() > len {
+                    changed = true;
+                }
+            }
+        }
+        sets
+    }
+
+    fn renaming_cycles(g: &RawGrammar) -> Vec<Vec<Token>> {
+        let mut renames: BTreeMap<Token, BTreeSet<Token>> = BTreeMap::new();
+        for n in &g.non_terminals {
+            renames.insert(*n, BTreeSet::new());
+        }
+        for r in &g.rules {
+            if r.right.len() == 1 && g.non_terminals.contains(&r.right[0]) {
+                renames.get_mut(&r.left).unwrap().insert(r.right[0]);
+            }
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in &g.non_terminals {
+                let mut closure = renames.get(n).unwrap().clone();
+                for x in renames.get(n).unwrap() {
+                    closure.extend(renames.get(x).unwrap().iter());
+                }
+                if closure.len() > renames.get(n).unwrap().len() {
+                    changed = true;
+                    renames.insert(*n, closure);
+                }
+            }
+        }
+
+        let mut cycles: Vec<Vec<Token>> = Vec::new();
+        for (n, reach) in &renames {
+            if !reach.contains(n) || cycles.iter().any(|c| c.contains(n)) {
+                continue;
+            }
+            cycles.push(reach.iter().filter(|m| renames.get(m).unwrap().contains(n)).copied().collect());
+        }
+        cycles
+    }
+}
+
+impl Display for GrammarAnalysis {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<String>>().join(" ");
+        writeln!(f, "axiom: {}", self.axiom)?;
+        writeln!(f, "unreachable nonterminals: {}", join(&self.unreachable))?;
+        writeln!(f, "unproductive nonterminals: {}", join(&self.unproductive))?;
+        writeln!(f, "unused terminals: {}", join(&self.unused_terminals))?;
+        writeln!(f, "renaming cycles:")?;
+        for cycle in &self.renaming_cycles {
+            writeln!(f, "  {}", cycle.join(" -> "))?;
+        }
+        writeln!(f, "left terminal sets:")?;
+        for (n, set) in &self.left_terminals {
+            writeln!(f, "  LT({}) = {{{}}}", n, join(set))?;
+        }
+        writeln!(f, "right terminal sets:")?;
+        for (n, set) in &self.right_terminals {
+            writeln!(f, "  RT({}) = {{{}}}", n, join(set))?;
+        }
+        Ok(())
+    }
+}
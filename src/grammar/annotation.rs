@@ -0,0 +1,343 @@
+//! AST construction from annotations in .g files.
+//!
+//! Rules say what they become in the AST with annotations, so a grammar gets an AST without
+//! writing a lowering pass for it. An annotation on a symbol follows its name after a `.`, and
+//! the node a rule produces follows its right hand side as `%ast Kind`, next to `%prec`:
+//!
+//! ```text
+//! stat : LET baseExp EQ expr %ast LetAssign
+//!     | WHILE expr LBRACE statList.StatList? RBRACE %ast While
+//!     ;
+//! baseExp : NAME.Name
+//!     | NUMBER.Number
+//!     ;
+//! exprList : expr COMMA expr %ast ExprList
+//!     | exprList.flatten COMMA expr %ast ExprList
+//!     ;
+//! ```
+//!
+//! Every nonterminal of a rule contributes what its own rule produced, and terminals contribute
+//! nothing, unless they are annotated with one of:
+//!
+//! - `.keep`: keep a terminal as a leaf named after the terminal, with its text as the payload.
+//! - `.Kind`: the same, with the leaf named `Kind`. Kinds are any other word, starting with a
+//!   letter.
+//! - `.elide`: leave out a nonterminal and everything under it.
+//! - `.flatten`: when a nonterminal produced a node of the same kind as the rule's own `%ast`,
+//!   take that node's children instead, which turns left or right recursive lists into one node.
+//! - `.Kind` on a nonterminal: put what it contributes in a `Kind` node, flattened the same way,
+//!   so a body is one `StatList` whether it holds one statement or several. Several kinds wrap
+//!   from the inside out: `baseExp.Field.FieldList` is a `FieldList` holding one `Field`.
+//!
+//! `.flatten` and kinds only take apart nodes a rule made with `%ast`. Nodes made by a kind
+//! annotation stay whole, so a block `{ ... }` is still one node inside a list of statements.
+//!
+//! A kind without a symbol, such as `.StatList`, is an empty node. In EBNF, an optional group
+//! whose alternatives are all nonterminals in the same kind leaves one where it is left out, so
+//! `LBRACE statList.StatList? RBRACE` always has a `StatList`.
+//!
+//! A rule with `%ast Kind` makes a `Kind` node of what its symbols contribute. A rule without
+//! one passes what its symbols contribute on to its parent, so chains such as
+//! `expr : logicalOrExp` and punctuation like `prefixExp : LPAREN expr RPAREN` disappear.
+//!
+//! Rules whose right hand side is a single nonterminal are removed by
+//! [`RawGrammar::delete_repeated_rhs`], so they cannot have a `%ast`. The one of the axiom is
+//! the exception for annotations on its symbol, which apply to the whole tree: with
+//! `chunk : statList.Module`, the root is a `Module`. Nodes that error recovery put in the parse
+//! tree become `Error` nodes, and so do nodes around them that no rule matches because of it.
+//!
+//! [`RawGrammar::delete_repeated_rhs`]: super::opg::RawGrammar::delete_repeated_rhs
+
+use super::opg::{OpGrammar, Rule, Token, TokenTypes};
+use super::GrammarError;
+use crate::lexer::Data;
+use crate::parsetree::{self, ParseTree};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// What becomes of one symbol of a rule in the AST, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Annotation {
+    Keep,
+    Elide,
+    Flatten,
+    Leaf(String),
+    /// Kinds of the nodes around what a nonterminal contributes, innermost first.
+    Wrap(Vec<String>),
+}
+
+impl Annotation {
+    /// Parse the annotations after `symbol` in a rule, such as `.flatten`. None if there are
+    /// none.
+    pub fn parse(symbol: &str, suffix: &str, terminal: bool) -> Result<Option<Self>, GrammarError> {
+        if suffix.is_empty() {
+            return Ok(None);
+        }
+        let is_kind = |word: &str| word.starts_with(|c: char| c.is_ascii_alphabetic()) && !matches!(word, "keep" | "elide" | "flatten");
+        let words: Vec<&str> = suffix.split('.').skip(1).collect();
+        let word = match words.as_slice() {
+            [word] => *word,
+            [_, _, ..] if !terminal && words.iter().all(|w| is_kind(w)) => {
+                return Ok(Some(Annotation::Wrap(words.iter().map(|w| w.to_string()).collect())));
+            }
+            _ => return Err(GrammarError::from(format!("Expected one annotation on {}, found {}", symbol, suffix))),
+        };
+        let annotation = match word {
+            "keep" => Annotation::Keep,
+            "elide" => Annotation::Elide,
+            "flatten" => Annotation::Flatten,
+            kind if is_kind(kind) && terminal => Annotation::Leaf(kind.to_string()),
+            kind if is_kind(kind) => Annotation::Wrap(vec![kind.to_string()]),
+            _ => return Err(GrammarError::from(format!("Invalid annotation on {} : {}", symbol, suffix))),
+        };
+        match annotation {
+            Annotation::Keep if !terminal => Err(GrammarError::from(format!("Only terminals can be kept as leaves : {}{}", symbol, suffix))),
+            annotation => Ok(Some(annotation)),
+        }
+    }
+}
+
+/// Build the AST `grammar` describes for a parse tree. Nodes are named by `%ast` and leaf
+/// annotations, and leaves keep the [`Data`] of their token, so spans and source text work the
+/// same as on the parse tree.
+///
+/// A parse tree node is matched against the rules as written in the .g file, by terminals and
+/// by the nonterminals each child can stand for. When the grammar is ambiguous, the first rule
+/// that matches in the file wins.
+pub fn build_ast(grammar: &OpGrammar, tree: &ParseTree) -> ParseTree {
+    annotate(grammar, tree).0
+}
+
+/// [`build_ast`], along with the tokens of the parse tree every node was built from: all of
+/// the rule for nodes made by `%ast`, all of the symbol for kinds on a nonterminal, and the
+/// token for leaves. Empty nodes have none.
+pub fn annotate(grammar: &OpGrammar, tree: &ParseTree) -> (ParseTree, Vec<Option<Range<usize>>>) {
+    let mut builder = Builder {
+        grammar,
+        rules: HashMap::new(),
+        kinds: BTreeMap::new(),
+        nodes: Vec::new(),
+    };
+    for r in &grammar.ast_rules {
+        builder.rules.entry(r.right.len()).or_default().push(r);
+    }
+
+    // Nodes come after all of their descendants in reverse pre-order, and the first child is on
+    // top of the stack when its parent comes up.
+    let mut stack: Vec<Child> = Vec::new();
+    for id in (0..tree.nodes.len()).rev() {
+        let children: Vec<Child> = stack
+            .split_off(stack.len() - tree.nodes[id].child_count.min(stack.len()))
+            .into_iter()
+            .rev()
+            .collect();
+        let span = match &tree.nodes[id].data {
+            Some(d) => Some(d.token_index..d.token_index + 1),
+            None => children.iter().fold(None, |span, c| union(span, &c.span)),
+        };
+        let built = builder.node(tree, id, &span, children);
+        stack.push(Child { id, built, span });
+    }
+
+    let span = stack.iter().fold(None, |span, c| union(span, &c.span));
+    let mut roots: VecDeque<usize> = stack.into_iter().flat_map(|c| c.built).collect();
+    // The axiom's own copy rule has no node in the parse tree, so it applies to all of it.
+    let axiom = grammar.ast_rules.iter().find(|r| r.left == grammar.old_axiom && r.right.len() == 1);
+    if let Some(Some(Annotation::Wrap(kinds))) = axiom.map(|r| &r.annotations[0]) {
+        roots = builder.wrap(kinds, roots, &span);
+    }
+
+    let mut ast = ParseTree::new(Arc::new(BTreeMap::new()));
+    let mut spans = Vec::new();
+    let mut pending: Vec<usize> = roots.into();
+    while let Some(n) = pending.pop() {
+        let node = &mut builder.nodes[n];
+        ast.nodes.push(parsetree::Node {
+            token: node.kind,
+            child_count: node.children.len(),
+            data: node.data.take(),
+        });
+        spans.push(node.span.take());
+        pending.extend(node.children.iter().rev());
+    }
+    ast.token_map = Arc::new(builder.kinds.into_iter().map(|(name, kind)| (kind, name)).collect());
+    (ast, spans)
+}
+
+fn union(span: Option<Range<usize>>, other: &Option<Range<usize>>) -> Option<Range<usize>> {
+    match (span, other) {
+        (Some(s), Some(o)) => Some(s.start.min(o.start)..s.end.max(o.end)),
+        (s, o) => s.or_else(|| o.clone()),
+    }
+}
+
+/// A child of the parse tree node being built: its id, the nodes it contributes and its tokens.
+struct Child {
+    id: usize,
+    built: VecDeque<usize>,
+    span: Option<Range<usize>>,
+}
+
+struct Builder<'g> {
+    grammar: &'g OpGrammar,
+    /// Rules as written in the .g file, by the length of their right hand side.
+    rules: HashMap<usize, Vec<&'g Rule>>,
+    kinds: BTreeMap<String, Token>,
+    nodes: Vec<BuiltNode>,
+}
+
+struct BuiltNode {
+    kind: Token,
+    data: Option<Data>,
+    children: VecDeque<usize>,
+    /// Made by a rule's `%ast`, so `.flatten` and kinds may take it apart.
+    open: bool,
+    span: Option<Range<usize>>,
+}
+
+impl<'g> Builder<'g> {
+    /// Whether a parse tree node labelled `token` can stand for the nonterminal `symbol` of the
+    /// original grammar. Nonterminals made by the grammar transformations stand for a set.
+    fn stands_for(&self, token: Token, symbol: Token) -> bool {
+        match self.grammar.new_non_terminal_reverse.get(&token) {
+            Some(set) => set.contains(&symbol),
+            None => token == symbol,
+        }
+    }
+
+    fn is_terminal(&self, token: Token) -> bool {
+        self.grammar.token_types.get(&token) == Some(&TokenTypes::Terminal)
+    }
+
+    fn find_rule(&self, tree: &ParseTree, id: usize, children: &[Child]) -> Option<&'g Rule> {
+        let candidates = self.rules.get(&children.len())?;
+        candidates.iter().copied().find(|r| {
+            self.stands_for(tree.nodes[id].token, r.left)
+                && r.right.iter().zip(children).all(|(symbol, child)| {
+                    let token = tree.nodes[child.id].token;
+                    if self.is_terminal(*symbol) {
+                        token == *symbol
+                    } else {
+                        !self.is_terminal(token) && self.stands_for(token, *symbol)
+                    }
+                })
+        })
+    }
+
+    fn kind(&mut self, name: &str) -> Token {
+        let next = self.kinds.len();
+        *self.kinds.entry(name.to_string()).or_insert(next)
+    }
+
+    fn push(&mut self, kind: &str, data: Option<Data>, children: VecDeque<usize>, open: bool, span: &Option<Range<usize>>) -> usize {
+        let kind = self.kind(kind);
+        self.nodes.push(BuiltNode {
+            kind,
+            data,
+            children,
+            open,
+            span: span.clone(),
+        });
+        self.nodes.len() - 1
+    }
+
+    /// The children of `built` if it is a single open node of kind `kind`, otherwise `built`.
+    fn flatten(&mut self, built: VecDeque<usize>, kind: Token) -> VecDeque<usize> {
+        match built.front() {
+            Some(&n) if built.len() == 1 && self.nodes[n].kind == kind && self.nodes[n].open => std::mem::take(&mut self.nodes[n].children),
+            _ => built,
+        }
+    }
+
+    /// `built` in nodes of `kinds`, innermost first, which span `span`.
+    fn wrap(&mut self, kinds: &[String], mut built: VecDeque<usize>, span: &Option<Range<usize>>) -> VecDeque<usize> {
+        for (i, kind) in kinds.iter().enumerate() {
+            if i == 0 {
+                let token = self.kind(kind);
+                built = self.flatten(built, token);
+            }
+            built = VecDeque::from([self.push(kind, None, built, false, span)]);
+        }
+        built
+    }
+
+    /// Nodes the parse tree node `id`, which spans `span`, contributes to its parent, given those
+    /// of its children.
+    fn node(&mut self, tree: &ParseTree, id: usize, span: &Option<Range<usize>>, children: Vec<Child>) -> VecDeque<usize> {
+        let token = tree.nodes[id].token;
+        if children.is_empty() {
+            // Tokens are turned into leaves by the rule they are in.
+            return VecDeque::new();
+        }
+        let rule = if token == self.grammar.error {
+            None
+        } else {
+            self.find_rule(tree, id, &children)
+        };
+        let own_kind = rule.and_then(|r| r.ast.as_ref()).and_then(|kind| self.kinds.get(kind)).copied();
+        let broken = token == self.grammar.error || rule.is_none() && children.iter().any(|c| tree.nodes[c.id].token == self.grammar.error);
+
+        let count = children.len();
+        let mut contributed = VecDeque::new();
+        for (
+            i,
+            Child {
+                id: child,
+                built,
+                span: child_span,
+            },
+        ) in children.into_iter().enumerate()
+        {
+            self.empty_nodes(rule, i, &mut contributed);
+            let annotation = rule.and_then(|r| r.annotations.get(i)).and_then(|a| a.as_ref());
+            let child_token = tree.nodes[child].token;
+            if self.is_terminal(child_token) {
+                let kind = match annotation {
+                    Some(Annotation::Keep) => self.grammar.token_raw[&child_token].clone(),
+                    Some(Annotation::Leaf(kind)) => kind.clone(),
+                    _ => continue,
+                };
+                let leaf = self.push(&kind, tree.nodes[child].data.clone(), VecDeque::new(), false, &child_span);
+                contributed.push_back(leaf);
+                continue;
+            }
+            let built = match (annotation, own_kind) {
+                (Some(Annotation::Elide), _) => continue,
+                (Some(Annotation::Flatten), Some(kind)) => self.flatten(built, kind),
+                (Some(Annotation::Wrap(kinds)), _) => self.wrap(kinds, built, &child_span),
+                _ => built,
+            };
+            append(&mut contributed, built);
+        }
+        self.empty_nodes(rule, count, &mut contributed);
+
+        match (broken, rule.and_then(|r| r.ast.as_ref())) {
+            (true, _) => VecDeque::from([self.push("Error", None, contributed, false, span)]),
+            (false, Some(kind)) => VecDeque::from([self.push(kind, None, contributed, true, span)]),
+            (false, None) => contributed,
+        }
+    }
+
+    /// Add the empty nodes `rule` has before its symbol at `position` to `contributed`.
+    fn empty_nodes(&mut self, rule: Option<&Rule>, position: usize, contributed: &mut VecDeque<usize>) {
+        for (_, kinds) in rule.iter().flat_map(|r| &r.empty_nodes).filter(|(p, _)| *p == position) {
+            let empty = self.wrap(kinds, VecDeque::new(), &None);
+            append(contributed, empty);
+        }
+    }
+}
+
+/// Append `more` to `nodes`, moving the shorter of the two so long lists are built in linear
+/// time whichever way they recurse.
+fn append(nodes: &mut VecDeque<usize>, mut more: VecDeque<usize>) {
+    if more.len() > nodes.len() {
+        std::mem::swap(nodes, &mut more);
+        for n in more.into_iter().rev() {
+            nodes.push_front(n);
+        }
+    } else {
+        nodes.extend(more);
+    }
+}
@@ -0,0 +1,112 @@
+use super::lg::LexingTable;
+use super::opg::{OpGrammar, Token};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A frozen [`OpGrammar`] together with the lookups every parser needs, built once. Cloning only
+/// bumps a reference count, so parsers on any number of threads share the same instance.
+#[derive(Clone, Debug)]
+pub struct CompiledGrammar(Arc<GrammarData>);
+
+#[derive(Debug)]
+struct GrammarData {
+    grammar: OpGrammar,
+    terminals: bittyset::BitSet<Token>,
+    non_terminals: bittyset::BitSet<Token>,
+    short_names: Arc<BTreeMap<Token, String>>,
+}
+
+impl CompiledGrammar {
+    pub fn new(grammar: OpGrammar) -> Self {
+        let mut terminals = bittyset::BitSet::<Token>::new();
+        grammar.terminals.iter().for_each(|x| {
+            terminals.insert(*x);
+        });
+        let mut non_terminals = bittyset::BitSet::<Token>::new();
+        grammar.non_terminals.iter().for_each(|x| {
+            non_terminals.insert(*x);
+        });
+        // Error nodes stand in for whatever nonterminal the parser failed to build.
+        non_terminals.insert(grammar.error);
+        let short_names = grammar
+            .token_raw
+            .iter()
+            .map(|(k, v)| (*k, v.rsplit_once('_').map_or(v.as_str(), |(_, s)| s).to_string()))
+            .collect();
+
+        Self(Arc::new(GrammarData {
+            grammar,
+            terminals,
+            non_terminals,
+            short_names: Arc::new(short_names),
+        }))
+    }
+
+    pub fn is_terminal(&self, t: Token) -> bool {
+        self.0.terminals.contains(t)
+    }
+
+    pub fn is_non_terminal(&self, t: Token) -> bool {
+        self.0.non_terminals.contains(t)
+    }
+
+    /// Token names without the prefix of nonterminals generated by the grammar transformations,
+    /// as shown in parse trees.
+    pub fn short_names(&self) -> &Arc<BTreeMap<Token, String>> {
+        &self.0.short_names
+    }
+}
+
+impl Deref for CompiledGrammar {
+    type Target = OpGrammar;
+
+    fn deref(&self) -> &OpGrammar {
+        &self.0.grammar
+    }
+}
+
+impl From<OpGrammar> for CompiledGrammar {
+    fn from(grammar: OpGrammar) -> Self {
+        Self::new(grammar)
+    }
+}
+
+/// A frozen [`LexingTable`], shared between lexers the same way as [`CompiledGrammar`].
+#[derive(Clone, Debug)]
+pub struct CompiledLexTable(Arc<LexTableData>);
+
+#[derive(Debug)]
+struct LexTableData {
+    table: LexingTable,
+    terminals: HashMap<String, Token>,
+}
+
+impl CompiledLexTable {
+    pub fn new(table: LexingTable) -> Self {
+        let mut terminals = HashMap::new();
+        for (t, name) in table.terminal_map.iter().enumerate() {
+            terminals.entry(name.clone()).or_insert(t);
+        }
+        Self(Arc::new(LexTableData { table, terminals }))
+    }
+
+    /// Token id of the terminal called `name`.
+    pub fn terminal(&self, name: &str) -> Option<Token> {
+        self.0.terminals.get(name).copied()
+    }
+}
+
+impl Deref for CompiledLexTable {
+    type Target = LexingTable;
+
+    fn deref(&self) -> &LexingTable {
+        &self.0.table
+    }
+}
+
+impl From<LexingTable> for CompiledLexTable {
+    fn from(table: LexingTable) -> Self {
+        Self::new(table)
+    }
+}
@@ -0,0 +1,175 @@
+use super::lg::LexicalGrammar;
+use super::opg::{OpGrammar, Rule, Token};
+use super::GrammarError;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tinyrand::{RandRange, Seeded, StdRand};
+
+/// Generates random sentences of an [`OpGrammar`] by expanding `rules` from the axiom. Every
+/// nonterminal below `max_depth` is expanded with any of its rules; past it, only rules that
+/// still fit into the remaining depth are picked, so derivations always terminate.
+pub struct SentenceGenerator<'a> {
+    g: &'a OpGrammar,
+    rng: StdRand,
+    max_depth: usize,
+    /// Rules of each nonterminal with the height of the shortest derivation tree through them.
+    rules: HashMap<Token, Vec<(usize, &'a Rule)>>,
+}
+
+impl<'a> SentenceGenerator<'a> {
+    pub fn new(g: &'a OpGrammar, seed: u64, max_depth: usize) -> Self {
+        Self::excluding(g, seed, max_depth, &[])
+    }
+
+    /// Like [`SentenceGenerator::new`], but never picks a rule using one of the `excluded`
+    /// terminals, e.g. terminals the lexer in use cannot produce.
+    pub fn excluding(g: &'a OpGrammar, seed: u64, max_depth: usize, excluded: &[Token]) -> Self {
+        let mut height: HashMap<Token, usize> = HashMap::new();
+        let rule_height = |r: &Rule, height: &HashMap<Token, usize>| -> Option<usize> {
+            let mut h = 0;
+            for t in &r.right {
+                if excluded.contains(t) {
+                    return None;
+                } else if !g.terminals.contains(t) {
+                    h = h.max(*height.get(t)?);
+                }
+            }
+            Some(h + 1)
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for r in &g.rules {
+                if let Some(h) = rule_height(r, &height) {
+                    if height.get(&r.left).is_none_or(|old| h < *old) {
+                        height.insert(r.left, h);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut rules: HashMap<Token, Vec<(usize, &'a Rule)>> = HashMap::new();
+        for r in &g.rules {
+            // Rules through unproductive nonterminals or excluded terminals can never finish a sentence.
+            if let Some(h) = rule_height(r, &height) {
+                rules.entry(r.left).or_default().push((h, r));
+            }
+        }
+
+        Self {
+            g,
+            rng: StdRand::seed(seed),
+            max_depth,
+            rules,
+        }
+    }
+
+    /// A random sentence derived from the axiom.
+    pub fn sentence(&mut self) -> Result<Vec<Token>, GrammarError> {
+        self.derive(self.g.axiom)
+    }
+
+    /// A random sentence derived from nonterminal `n`.
+    pub fn derive(&mut self, n: Token) -> Result<Vec<Token>, GrammarError> {
+        let mut out = Vec::new();
+        self.expand(n, self.max_depth, &mut out)?;
+        Ok(out)
+    }
+
+    fn expand(&mut self, n: Token, depth: usize, out: &mut Vec<Token>) -> Result<(), GrammarError> {
+        let rules = match self.rules.get(&n) {
+            Some(rules) => rules,
+            None => {
+                return Err(GrammarError::from(format!(
+                    "Nonterminal {} does not derive any sentence.",
+                    self.g.token_raw.get(&n).unwrap()
+                )))
+            }
+        };
+        let mut candidates: Vec<&'a Rule> = rules.iter().filter(|(h, _)| *h <= depth).map(|(_, r)| *r).collect();
+        if candidates.is_empty() {
+            let min = rules.iter().map(|(h, _)| *h).min().unwrap();
+            candidates = rules.iter().filter(|(h, _)| *h == min).map(|(_, r)| *r).collect();
+        }
+        let rule = candidates[self.rng.next_range(0..candidates.len())];
+        for t in &rule.right {
+            if self.g.terminals.contains(t) {
+                out.push(*t);
+            } else {
+                self.expand(*t, depth.saturating_sub(1), out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns a sentence of terminals back into source text using examples generated from the
+/// lexical grammars the terminals were defined in.
+pub struct SentenceRenderer {
+    grammars: Vec<LexicalGrammar>,
+    /// Literal tokens such as keywords, by text, so other tokens never render as one of them.
+    literals: HashMap<String, String>,
+    fixed: HashMap<String, String>,
+    attached: HashSet<String>,
+    rng: StdRand,
+}
+
+impl SentenceRenderer {
+    pub fn new(grammars: Vec<LexicalGrammar>, seed: u64) -> Self {
+        let mut literals = HashMap::new();
+        for g in &grammars {
+            for name in g.token_names() {
+                if let Some(text) = g.literal(&name) {
+                    literals.insert(text, name);
+                }
+            }
+        }
+        Self {
+            grammars,
+            literals,
+            fixed: HashMap::new(),
+            attached: HashSet::new(),
+            rng: StdRand::seed(seed),
+        }
+    }
+
+    /// Always render terminal `name` as `text`, for terminals that are not in any lexical grammar
+    /// or need specific text. If `attach` is set, no space is put between it and the next token.
+    pub fn fix(&mut self, name: &str, text: &str, attach: bool) {
+        self.fixed.insert(name.to_string(), text.to_string());
+        if attach {
+            self.attached.insert(name.to_string());
+        }
+    }
+
+    pub fn render(&mut self, sentence: &[Token], token_raw: &BTreeMap<Token, String>) -> Result<String, GrammarError> {
+        let mut out = String::new();
+        for (i, t) in sentence.iter().enumerate() {
+            let name = token_raw.get(t).unwrap();
+            out.push_str(self.example(name)?.as_str());
+            if i + 1 < sentence.len() && !self.attached.contains(name) {
+                out.push(' ');
+            }
+        }
+        Ok(out)
+    }
+
+    fn example(&mut self, name: &str) -> Result<String, GrammarError> {
+        if let Some(text) = self.fixed.get(name) {
+            return Ok(text.clone());
+        }
+        let g = match self.grammars.iter().find(|g| g.token_names().iter().any(|n| n == name)) {
+            Some(g) => g,
+            None => return Err(GrammarError::from(format!("No lexical grammar defines terminal {}.", name))),
+        };
+        // Retry until the example is not taken by a keyword, as the lexer would read it as one.
+        for _ in 0..16 {
+            let text = g.example(name, &mut self.rng).unwrap();
+            if self.literals.get(&text).is_none_or(|owner| owner == name) {
+                return Ok(text);
+            }
+        }
+        Err(GrammarError::from(format!("Could not generate an example for {} that is not a keyword.", name)))
+    }
+}
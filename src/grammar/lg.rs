@@ -16,6 +16,7 @@ use regex_syntax::ast::Alternation;
 use regex_syntax::hir::Class;
 use regex_syntax::hir::Hir;
 use regex_syntax::hir::HirKind;
+use tinyrand::{RandRange, StdRand};
 
 pub type State = usize;
 pub type Token = usize;
@@ -48,6 +49,57 @@ impl LexicalGrammar {
         Self { pairs }
     }
 
+    pub fn token_names(&self) -> Vec<String> {
+        self.pairs.keys().cloned().collect()
+    }
+
+    /// The fixed text of a token whose expression is a plain literal, such as a keyword.
+    pub fn literal(&self, name: &str) -> Option<String> {
+        match self.pairs.get(name)?.kind() {
+            HirKind::Literal(literal) => Some(String::from_utf8_lossy(&literal.0).to_string()),
+            _ => None,
+        }
+    }
+
+    /// A random string matched by the expression of token `name`. Unbounded repetitions are
+    /// cut off a few iterations past their minimum.
+    pub fn example(&self, name: &str, rng: &mut StdRand) -> Option<String> {
+        let mut out = String::new();
+        Self::example_of(self.pairs.get(name)?, rng, &mut out);
+        Some(out)
+    }
+
+    fn example_of(hir: &Hir, rng: &mut StdRand, out: &mut String) {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => (),
+            HirKind::Literal(literal) => out.push_str(&String::from_utf8_lossy(&literal.0)),
+            HirKind::Class(Class::Unicode(class)) => {
+                let range = class.ranges()[rng.next_range(0..class.ranges().len())];
+                let c = rng.next_range(range.start() as u32..range.end() as u32 + 1);
+                out.push(char::from_u32(c).unwrap_or(range.start()));
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                let range = class.ranges()[rng.next_range(0..class.ranges().len())];
+                out.push(rng.next_range(range.start() as u32..range.end() as u32 + 1) as u8 as char);
+            }
+            HirKind::Repetition(rep) => {
+                let max = rep.max.unwrap_or(rep.min + 3);
+                for _ in 0..rng.next_range(rep.min..max + 1) {
+                    Self::example_of(&rep.sub, rng, out);
+                }
+            }
+            HirKind::Capture(capture) => Self::example_of(&capture.sub, rng, out),
+            HirKind::Concat(concat) => {
+                for sub in concat {
+                    Self::example_of(sub, rng, out);
+                }
+            }
+            HirKind::Alternation(alternation) => {
+                Self::example_of(&alternation[rng.next_range(0..alternation.len())], rng, out);
+            }
+        }
+    }
+
     fn scanner(input: &str) -> HashMap<String, String> {
         let mut pairs: HashMap<String, String> = HashMap::new();
         let mut current_token = String::new();
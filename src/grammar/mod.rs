@@ -6,8 +6,13 @@ use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Write;
 
+pub mod analysis;
+pub mod annotation;
+pub mod compiled;
+pub mod generate;
 pub mod lg;
 pub mod opg;
+pub mod optable;
 pub mod transform;
 
 #[derive(Debug)]
@@ -1,3 +1,5 @@
+use super::annotation::Annotation;
+use super::optable::OpTable;
 use super::transform::*;
 use super::GrammarError;
 use crate::grammar::print_op_table;
@@ -16,7 +18,6 @@ use std::io::{BufReader, Read};
 use std::io::{Seek, Write};
 use std::ops::Deref;
 use std::prelude::rust_2015;
-use std::slice::Iter;
 
 pub type Token = usize;
 
@@ -24,7 +25,16 @@ pub type Token = usize;
 pub struct Rule {
     pub left: Token,
     pub right: Vec<Token>,
-    pub nesting_rules: Vec<Vec<i16>>,
+    /// What becomes of every symbol of `right` in the AST, written after the symbol as `.keep`,
+    /// `.flatten` and so on. See [`annotation`](super::annotation).
+    pub annotations: Vec<Option<Annotation>>,
+    /// Terminal named by a trailing `%prec` annotation, if any.
+    pub precedence: Option<Token>,
+    /// AST node named by a trailing `%ast` annotation, if any.
+    pub ast: Option<String>,
+    /// Empty AST nodes written as a kind without a symbol, such as `.StatList`, by the position
+    /// in `right` they come before, with their kinds innermost first.
+    pub empty_nodes: Vec<(usize, Vec<String>)>,
 }
 
 /// Ad-hoc hand written parser for loading in .g grammar files.
@@ -43,15 +53,13 @@ pub struct RawGrammar {
     pub reduction_tree: ReductionTree,
     pub foobar: HashMap<Token, ReductionTree>,
     pub old_axiom: Token,
+    pub precedence: PrecedenceTable,
+    /// Terminals declared with `%sync`, where the parser resumes after a syntax error.
+    pub sync: Vec<Token>,
+    pub ebnf_origins: Vec<EbnfOrigin>,
     id_counter: IdCounter,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum ReductionNode {
-    Node(Token, Vec<ReductionNode>),
-    Rule(Rule),
-}
-
 #[derive(Clone, Debug, Copy)]
 enum GeneralState {
     ParserSymbols,
@@ -72,6 +80,11 @@ enum RuleParserState {
     InRuleRight,
     InRuleIdentifierRight,
     InRuleLeft,
+    InPrecKeyword,
+    AwaitingPrecIdentifier,
+    InPrecIdentifier,
+    AwaitingAstIdentifier,
+    InAstIdentifier,
 }
 
 #[derive(Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
@@ -104,6 +117,14 @@ impl RawGrammar {
         Ok(RawGrammar::new(buf.as_str(), lexical_sync)?)
     }
     pub fn new(s: &str, lexical_sync: Vec<String>) -> Result<RawGrammar, GrammarError> {
+        if has_ebnf(s) {
+            let (plain, origins) = expand_ebnf(s)?;
+            trace!("Expanded EBNF grammar:\n{}", plain);
+            let mut g = RawGrammar::new(plain.as_str(), lexical_sync)?;
+            g.resolve_ebnf_origins(origins);
+            return Ok(g);
+        }
+
         let mut state = GeneralState::ParserSymbols;
         let mut symbol_parser_state = SymbolParserState::InData;
         let mut rule_parser_state = RuleParserState::InData;
@@ -111,12 +132,17 @@ impl RawGrammar {
         let mut buf = String::new();
         let mut nesting_buf = String::new();
         let mut awaiting: Option<TokenTypes> = None;
+        let mut awaiting_precedence: Option<OperatorAssociativity> = None;
+        let mut precedence_decls: Vec<(OperatorAssociativity, Vec<String>)> = Vec::new();
+        let mut awaiting_sync = false;
+        let mut sync_names: Vec<String> = Vec::new();
         let mut token_reverse: BTreeMap<String, (Token, TokenTypes)> = BTreeMap::new();
         let mut axiom: Option<Token> = None;
-        let mut id_counter = IdCounter::new(lexical_sync.len() - 1);
+        let mut id_counter = IdCounter::new(lexical_sync.len().saturating_sub(1));
 
         let mut rules: Vec<Rule> = Vec::new();
         let mut rule: Option<Rule> = None;
+        let mut prec_buf = String::new();
 
         for c in s.chars() {
             match state {
@@ -127,6 +153,8 @@ impl RawGrammar {
                             continue;
                         } else if let None = awaiting {
                             symbol_parser_state = SymbolParserState::InKeyword;
+                            awaiting_precedence = None;
+                            awaiting_sync = false;
                         }
                     }
                     ' ' | '\n' | '\t' => {
@@ -138,11 +166,24 @@ impl RawGrammar {
                                     awaiting = Some(TokenTypes::NonTerminal);
                                 } else if buf.eq("axiom") {
                                     awaiting = Some(TokenTypes::Axiom);
+                                } else if buf.eq("sync") {
+                                    awaiting_sync = true;
+                                } else if let Some(assoc) = OperatorAssociativity::from_keyword(buf.as_str()) {
+                                    awaiting_precedence = Some(assoc);
+                                    precedence_decls.push((assoc, Vec::new()));
                                 } else {
                                     return Err(GrammarError::from(format!("Invalid keyword : {}", buf.as_str())));
                                 }
                                 buf.clear();
                             }
+                            SymbolParserState::InIdent if awaiting.is_none() && awaiting_precedence.is_some() => {
+                                precedence_decls.last_mut().unwrap().1.push(buf.clone());
+                                buf.clear();
+                            }
+                            SymbolParserState::InIdent if awaiting.is_none() && awaiting_sync => {
+                                sync_names.push(buf.clone());
+                                buf.clear();
+                            }
                             SymbolParserState::InIdent => {
                                 if let Some(t) = awaiting {
                                     match t {
@@ -168,6 +209,10 @@ impl RawGrammar {
                             }
                             SymbolParserState::InData => (),
                         }
+                        if c == '\n' {
+                            awaiting_precedence = None;
+                            awaiting_sync = false;
+                        }
                         symbol_parser_state = SymbolParserState::InData;
                     }
                     'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => {
@@ -188,32 +233,47 @@ impl RawGrammar {
                             rule_parser_state = RuleParserState::AwaitingRuleRight;
                         }
                         RuleParserState::InRuleIdentifierRight => {
-                            let (id, _) = token_reverse.get(&*buf).unwrap();
-                            rule.as_mut().unwrap().right.push(*id);
+                            Self::push_symbol(rule.as_mut().unwrap(), &token_reverse, &buf, &nesting_buf)?;
                             rule_parser_state = RuleParserState::InRuleRight;
-                            let mut b = String::new();
-                            let mut nesting: Vec<i16> = Vec::new();
-                            if !nesting_buf.is_empty() {
-                                for c in nesting_buf.chars() {
-                                    if c == '.' {
-                                        if !b.is_empty() {
-                                            nesting.push(b.parse().unwrap());
-                                        }
-                                        b.clear();
-                                    } else {
-                                        b.push(c);
-                                    }
+                            nesting_buf.clear();
+                        }
+                        RuleParserState::InPrecKeyword => {
+                            rule_parser_state = match prec_buf.as_str() {
+                                "prec" if rule.as_ref().unwrap().precedence.is_some() => {
+                                    return Err(GrammarError::from("Rule has more than one %prec annotation.".to_string()));
                                 }
-                                if !b.is_empty() {
-                                    nesting.push(b.parse().unwrap());
+                                "ast" if rule.as_ref().unwrap().ast.is_some() => {
+                                    return Err(GrammarError::from("Rule has more than one %ast annotation.".to_string()));
                                 }
-                            } else {
-                                nesting.push(-1);
-                            }
-                            nesting_buf.clear();
-                            rule.as_mut().unwrap().nesting_rules.push(nesting);
+                                "prec" => RuleParserState::AwaitingPrecIdentifier,
+                                "ast" => RuleParserState::AwaitingAstIdentifier,
+                                _ => return Err(GrammarError::from(format!("Invalid keyword in rule : {}", prec_buf))),
+                            };
+                            prec_buf.clear();
+                        }
+                        RuleParserState::InPrecIdentifier => {
+                            rule.as_mut().unwrap().precedence = Some(Self::prec_terminal(&token_reverse, &prec_buf)?);
+                            prec_buf.clear();
+                            rule_parser_state = RuleParserState::InRuleRight;
+                        }
+                        RuleParserState::InAstIdentifier => {
+                            rule.as_mut().unwrap().ast = Some(prec_buf.clone());
+                            prec_buf.clear();
+                            rule_parser_state = RuleParserState::InRuleRight;
+                        }
+                        RuleParserState::InRuleRight
+                        | RuleParserState::AwaitingRuleRight
+                        | RuleParserState::InData
+                        | RuleParserState::AwaitingPrecIdentifier
+                        | RuleParserState::AwaitingAstIdentifier => (),
+                    },
+                    '%' => match rule_parser_state {
+                        RuleParserState::InRuleRight => {
+                            rule_parser_state = RuleParserState::InPrecKeyword;
+                        }
+                        _ => {
+                            return Err(GrammarError::from("%prec must follow the right hand side of a rule.".to_string()));
                         }
-                        RuleParserState::InRuleRight | RuleParserState::AwaitingRuleRight | RuleParserState::InData => (),
                     },
                     ':' | '|' => match rule_parser_state {
                         RuleParserState::InData => {
@@ -222,7 +282,10 @@ impl RawGrammar {
                         RuleParserState::InRuleLeft | RuleParserState::AwaitingRuleRight => {
                             rule_parser_state = RuleParserState::InRuleRight;
                             rule.as_mut().unwrap().right.clear();
-                            rule.as_mut().unwrap().nesting_rules.clear();
+                            rule.as_mut().unwrap().annotations.clear();
+                            rule.as_mut().unwrap().precedence = None;
+                            rule.as_mut().unwrap().ast = None;
+                            rule.as_mut().unwrap().empty_nodes.clear();
                             nesting_buf.clear();
                         }
                         RuleParserState::InRuleRight => {
@@ -231,6 +294,12 @@ impl RawGrammar {
                         RuleParserState::InRuleIdentifierRight => {
                             return Err(GrammarError::from("Illegal char : in right rule".to_string()));
                         }
+                        RuleParserState::InPrecKeyword | RuleParserState::AwaitingPrecIdentifier | RuleParserState::InPrecIdentifier => {
+                            return Err(GrammarError::from("Expected terminal after %prec.".to_string()));
+                        }
+                        RuleParserState::AwaitingAstIdentifier | RuleParserState::InAstIdentifier => {
+                            return Err(GrammarError::from("Expected node kind after %ast.".to_string()));
+                        }
                     },
                     '\n' => match rule_parser_state {
                         RuleParserState::InData | RuleParserState::AwaitingRuleRight => (),
@@ -244,34 +313,31 @@ impl RawGrammar {
                         }
                         RuleParserState::InRuleIdentifierRight => {
                             rule_parser_state = RuleParserState::AwaitingRuleRight;
-                            let (id, _) = token_reverse.get(&*buf).unwrap();
-                            rule.as_mut().unwrap().right.push(*id);
-                            let mut b = String::new();
-                            let mut nesting: Vec<i16> = Vec::new();
-                            if !nesting_buf.is_empty() {
-                                for c in nesting_buf.chars() {
-                                    if c == '.' {
-                                        if !b.is_empty() {
-                                            nesting.push(b.parse().unwrap());
-                                        }
-                                        b.clear();
-                                    } else {
-                                        b.push(c);
-                                    }
-                                }
-                                if !b.is_empty() {
-                                    nesting.push(b.parse().unwrap());
-                                }
-                            } else {
-                                nesting.push(-1);
-                            }
+                            Self::push_symbol(rule.as_mut().unwrap(), &token_reverse, &buf, &nesting_buf)?;
                             nesting_buf.clear();
-                            rule.as_mut().unwrap().nesting_rules.push(nesting);
                             rules.push(rule.as_mut().unwrap().clone());
                         }
                         RuleParserState::InRuleLeft => {
                             return Err(GrammarError::from("Unexected new line after left rule.".to_string()));
                         }
+                        RuleParserState::InPrecIdentifier => {
+                            rule_parser_state = RuleParserState::AwaitingRuleRight;
+                            rule.as_mut().unwrap().precedence = Some(Self::prec_terminal(&token_reverse, &prec_buf)?);
+                            prec_buf.clear();
+                            rules.push(rule.as_mut().unwrap().clone());
+                        }
+                        RuleParserState::InAstIdentifier => {
+                            rule_parser_state = RuleParserState::AwaitingRuleRight;
+                            rule.as_mut().unwrap().ast = Some(prec_buf.clone());
+                            prec_buf.clear();
+                            rules.push(rule.as_mut().unwrap().clone());
+                        }
+                        RuleParserState::InPrecKeyword | RuleParserState::AwaitingPrecIdentifier => {
+                            return Err(GrammarError::from("Expected terminal after %prec.".to_string()));
+                        }
+                        RuleParserState::AwaitingAstIdentifier => {
+                            return Err(GrammarError::from("Expected node kind after %ast.".to_string()));
+                        }
                     },
                     ';' => {
                         rule_parser_state = RuleParserState::InData;
@@ -286,21 +352,34 @@ impl RawGrammar {
                         RuleParserState::InRuleRight => {
                             rule_parser_state = RuleParserState::InRuleIdentifierRight;
                             buf.clear();
-                            buf.push(c);
+                            // A kind without a symbol is an empty node, see push_symbol.
+                            if c == '.' {
+                                nesting_buf.push(c);
+                            } else {
+                                buf.push(c);
+                            }
                         }
                         RuleParserState::InRuleLeft => buf.push(c),
-                        RuleParserState::InRuleIdentifierRight => match c {
-                            'A'..='Z' | 'a'..='z' => buf.push(c),
-                            '0'..='9' | '_' | '.' => {
+                        RuleParserState::InRuleIdentifierRight => {
+                            // Annotations start at the first '.', so names such as DOT2 stay intact.
+                            if c == '.' || !nesting_buf.is_empty() {
                                 nesting_buf.push(c);
+                            } else {
+                                buf.push(c);
                             }
-                            _ => {
-                                panic!("Shouldn't happen")
-                            }
-                        },
+                        }
                         RuleParserState::AwaitingRuleRight => {
                             return Err(GrammarError::from("Expected :, | or ;, found start of identifier.".to_string()));
                         }
+                        RuleParserState::InPrecKeyword | RuleParserState::InPrecIdentifier | RuleParserState::InAstIdentifier => prec_buf.push(c),
+                        RuleParserState::AwaitingPrecIdentifier => {
+                            rule_parser_state = RuleParserState::InPrecIdentifier;
+                            prec_buf.push(c);
+                        }
+                        RuleParserState::AwaitingAstIdentifier => {
+                            rule_parser_state = RuleParserState::InAstIdentifier;
+                            prec_buf.push(c);
+                        }
                     },
                     _ => {
                         return Err(GrammarError::from(format!("Invalid character in grammar definition: {}", c)));
@@ -320,17 +399,58 @@ impl RawGrammar {
         }
         let axiom: Token = axiom.expect("Need to specify and axiom.");
 
-        let mut ast_rules = Vec::new();
+        let mut precedence = PrecedenceTable::default();
+        for (assoc, names) in precedence_decls {
+            let mut level = Vec::new();
+            for name in names {
+                level.push(Self::prec_terminal(&token_reverse, &name)?);
+            }
+            precedence.push_level(assoc, level);
+        }
+        let mut sync = Vec::new();
+        for name in &sync_names {
+            match token_reverse.get(name.as_str()) {
+                Some((id, TokenTypes::Terminal)) => sync.push(*id),
+                _ => return Err(GrammarError::from(format!("Only declared terminals can be sync tokens : {}", name))),
+            }
+        }
+        // The op table only sees the rightmost terminal of a rule being reduced, not the rule, so a
+        // `%prec` annotation can only apply if every rule ending in that terminal carries it.
+        let last_terminal = |r: &Rule| r.right.iter().rev().find(|t| token_types.get(t) == Some(&TokenTypes::Terminal)).copied();
+        let show = |r: &Rule| {
+            let right: Vec<&str> = r.right.iter().map(|t| token_raw[t].as_str()).collect();
+            format!("{} : {}", token_raw[&r.left], right.join(" "))
+        };
         for r in &rules {
-            'outer: for x in &r.nesting_rules {
-                for y in x {
-                    if *y != -1 {
-                        ast_rules.push(r.clone());
-                        break 'outer;
-                    }
+            let (Some(prec), Some(last)) = (r.precedence, last_terminal(r)) else {
+                continue;
+            };
+            if let Some(other) = rules.iter().find(|o| o.precedence != r.precedence && last_terminal(o) == Some(last)) {
+                return Err(GrammarError::from(format!(
+                    "%prec {} cannot apply to {}, because {} also ends in {}.",
+                    token_raw[&prec],
+                    show(r),
+                    show(other),
+                    token_raw[&last]
+                )));
+            }
+            precedence.overrides.insert(last, prec);
+        }
+
+        for r in &rules {
+            if r.right.is_empty() {
+                return Err(GrammarError::from(format!("Rule of {} has nothing but empty nodes.", token_raw[&r.left])));
+            }
+            if let (Some(kind), [symbol]) = (&r.ast, r.right.as_slice()) {
+                if token_types.get(symbol) == Some(&TokenTypes::NonTerminal) {
+                    return Err(GrammarError::from(format!(
+                        "%ast {} has no effect on {} : {}, whose right hand side is a single nonterminal.",
+                        kind, token_raw[&r.left], token_raw[symbol]
+                    )));
                 }
             }
         }
+        let ast_rules = rules.clone();
 
         let mut foobar: HashMap<Token, ReductionTree> = HashMap::new();
 
@@ -346,7 +466,7 @@ impl RawGrammar {
             }
             let mut output = Vec::new();
             for (i, t) in r.right.iter().enumerate() {
-                output.push(format!("({}, {:?}), ", token_raw.get(t).unwrap().clone(), r.nesting_rules.get(i).unwrap()));
+                output.push(format!("({}, {:?}), ", token_raw.get(t).unwrap().clone(), r.annotations.get(i).unwrap()));
             }
             trace!("Rule : {} -> {:?}", &token_raw.get(&r.left).unwrap(), output,);
         }
@@ -360,6 +480,9 @@ impl RawGrammar {
                 terminals.push(*id);
             }
         }
+        // Everything built from these lists should not depend on the order of a hash map.
+        non_terminals.sort();
+        terminals.sort();
 
         Ok(RawGrammar {
             rules,
@@ -377,123 +500,225 @@ impl RawGrammar {
             reduction_tree: r_tree,
             foobar,
             old_axiom: axiom,
+            precedence,
+            sync,
+            ebnf_origins: Vec::new(),
         })
     }
     pub fn gen_id(&mut self) -> Token {
         self.id_counter.gen_id()
     }
+
+    /// Add the symbol `name`, followed by the annotations in `nesting_buf`, to the right hand side.
+    fn push_symbol(rule: &mut Rule, token_reverse: &BTreeMap<String, (Token, TokenTypes)>, name: &str, nesting_buf: &str) -> Result<(), GrammarError> {
+        if name.is_empty() {
+            return match Annotation::parse(name, nesting_buf, false)? {
+                Some(Annotation::Wrap(kinds)) => {
+                    rule.empty_nodes.push((rule.right.len(), kinds));
+                    Ok(())
+                }
+                _ => Err(GrammarError::from(format!("Expected a node kind, found {}", nesting_buf))),
+            };
+        }
+        let (id, token_type) = token_reverse
+            .get(name)
+            .ok_or_else(|| GrammarError::from(format!("Undeclared symbol in rule : {}", name)))?;
+        rule.right.push(*id);
+        rule.annotations
+            .push(Annotation::parse(name, nesting_buf, *token_type == TokenTypes::Terminal)?);
+        Ok(())
+    }
+
+    fn prec_terminal(token_reverse: &BTreeMap<String, (Token, TokenTypes)>, name: &str) -> Result<Token, GrammarError> {
+        match token_reverse.get(name) {
+            Some((id, TokenTypes::Terminal)) => Ok(*id),
+            Some(_) => Err(GrammarError::from(format!("Precedence can only be declared for terminals : {}", name))),
+            None => Err(GrammarError::from(format!("Precedence declared for undeclared terminal : {}", name))),
+        }
+    }
 }
 
+/// Associativity of an operator as declared with `%left`, `%right` or `%nonassoc`.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OperatorAssociativity {
+    Left,
+    Right,
+    NonAssoc,
+}
+
+impl OperatorAssociativity {
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword {
+            "left" => Some(Self::Left),
+            "right" => Some(Self::Right),
+            "nonassoc" => Some(Self::NonAssoc),
+            _ => None,
+        }
+    }
+
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::NonAssoc => "nonassoc",
+        }
+    }
+}
+
+/// Yacc style precedence declarations. Every `%left`, `%right` or `%nonassoc` line is one level,
+/// later lines bind tighter than earlier ones.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PrecedenceTable {
+    pub levels: Vec<(OperatorAssociativity, Vec<Token>)>,
+    pub terminals: HashMap<Token, (usize, OperatorAssociativity)>,
+    /// Rightmost terminal of rules annotated with `%prec` -> terminal whose precedence the rules
+    /// take. Every rule ending in one of these terminals has the same annotation.
+    pub overrides: HashMap<Token, Token>,
+}
+
+impl PrecedenceTable {
+    pub fn push_level(&mut self, assoc: OperatorAssociativity, terminals: Vec<Token>) {
+        let level = self.levels.len();
+        for t in &terminals {
+            self.terminals.insert(*t, (level, assoc));
+        }
+        self.levels.push((assoc, terminals));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Pick the relation between `left`, the topmost terminal on the stack, and the incoming
+    /// terminal `right`. `left` is reduced at the precedence of the rule it ends, so a `%prec`
+    /// annotation on that rule takes effect here. Returns None if either terminal has no
+    /// declared precedence.
+    pub fn resolve(&self, left: Token, right: Token) -> Option<Associativity> {
+        let reduced = self.overrides.get(&left).unwrap_or(&left);
+        let (left_level, _) = self.terminals.get(reduced)?;
+        let (right_level, assoc) = self.terminals.get(&right)?;
+        if left_level > right_level {
+            Some(Right)
+        } else if left_level < right_level {
+            Some(Left)
+        } else {
+            match assoc {
+                OperatorAssociativity::Left => Some(Right),
+                OperatorAssociativity::Right => Some(Left),
+                OperatorAssociativity::NonAssoc => Some(Associativity::None),
+            }
+        }
+    }
+}
+
+/// Trie over the right hand sides of rules, used to find the rule a handle reduces by. The first
+/// level is indexed by token id and the children of every node are kept sorted by token id.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ReductionTree {
-    root_nodes: HashMap<Token, Vec<ReductionNode>>,
+    roots: Vec<u32>,
+    nodes: Vec<ReductionNode>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ReductionNode {
+    rule: Option<u32>,
+    children: Vec<(Token, u32)>,
 }
 
+const NO_ROOT: u32 = u32::MAX;
+
 impl ReductionTree {
     pub fn new() -> Self {
-        Self { root_nodes: HashMap::new() }
+        Self {
+            roots: Vec::new(),
+            nodes: Vec::new(),
+            rules: Vec::new(),
+        }
     }
 
-    pub fn match_rule(&self, rhs: &[&Token], tokens_raw: &BTreeMap<Token, String>) -> Option<&Rule> {
-        if rhs.len() == 0 {
-            return None;
+    /// Follows `rhs` down the trie, returning the rule of the deepest node reached. When no child
+    /// matches the next token, the rule ending at the current node is returned, if any.
+    pub fn match_rule<I: IntoIterator<Item = Token>>(&self, rhs: I) -> Option<&Rule> {
+        let mut iter = rhs.into_iter();
+        let mut current = match iter.next().and_then(|t| self.roots.get(t)) {
+            Some(&n) if n != NO_ROOT => &self.nodes[n as usize],
+            _ => return None,
+        };
+        for t in iter {
+            match current.children.binary_search_by_key(&t, |(token, _)| *token) {
+                Ok(i) => current = &self.nodes[current.children[i].1 as usize],
+                Err(_) => break,
+            }
         }
-        let mut iter = rhs.iter();
-        let first = iter.next().unwrap();
-        let mut current = if let Some(next_nodes) = self.root_nodes.get(&first) {
-            debug!("Matched : {}", tokens_raw.get(&first).unwrap());
-            next_nodes
+        current.rule.map(|r| &self.rules[r as usize])
+    }
+
+    /// Like [`ReductionTree::match_rule`], for handles containing `error` nodes left by error
+    /// recovery, which match any nonterminal accepted by `is_non_terminal`. This backtracks over
+    /// the candidates, so it is only used once the parser has seen an error.
+    pub fn match_rule_with_errors<F: Fn(Token) -> bool>(&self, rhs: &[Token], error: Token, is_non_terminal: F) -> Option<&Rule> {
+        let (first, rest) = rhs.split_first()?;
+        let rule = if *first == error {
+            self.roots
+                .iter()
+                .enumerate()
+                .filter(|(t, n)| **n != NO_ROOT && is_non_terminal(*t))
+                .find_map(|(_, n)| self.match_from(*n, rest, error, &is_non_terminal))
         } else {
-            return None;
+            match self.roots.get(*first) {
+                Some(&n) if n != NO_ROOT => self.match_from(n, rest, error, &is_non_terminal),
+                _ => None,
+            }
         };
+        rule.map(|r| &self.rules[r as usize])
+    }
 
-        for t in iter {
-            let mut found_node: Option<usize> = None;
-            for (i, exiting_node) in current.iter().enumerate() {
-                match exiting_node {
-                    ReductionNode::Node(n, _) => {
-                        if *n == **t {
-                            found_node = Some(i);
-                            break;
-                        }
-                    }
-                    ReductionNode::Rule(_) => {
-                        if found_node.is_none() {
-                            found_node = Some(i);
-                        }
-                    }
-                }
-            }
-            current = if let Some(i) = found_node {
-                match current.get(i).unwrap() {
-                    ReductionNode::Node(t, vec) => {
-                        debug!("Matched : {}", tokens_raw.get(t).unwrap());
-                        vec
-                    }
-                    ReductionNode::Rule(r) => {
-                        debug!("Found : {:?}", r);
+    fn match_from<F: Fn(Token) -> bool>(&self, node: u32, rhs: &[Token], error: Token, is_non_terminal: &F) -> Option<u32> {
+        let current = &self.nodes[node as usize];
+        if let Some((t, rest)) = rhs.split_first() {
+            for (child_token, child) in &current.children {
+                if *child_token == *t || (*t == error && is_non_terminal(*child_token)) {
+                    if let Some(r) = self.match_from(*child, rest, error, is_non_terminal) {
                         return Some(r);
                     }
                 }
-            } else {
-                return None;
-            };
-        }
-
-        for (_i, exiting_node) in current.iter().enumerate() {
-            match exiting_node {
-                ReductionNode::Rule(r) => {
-                    debug!("Found : {:?}", r);
-                    return Some(r);
-                }
-                _ => (),
             }
         }
-        None
+        current.rule
     }
 
     pub fn add_rule(&mut self, r: &Rule) {
-        if r.right.len() == 0 {
-            return;
+        let mut iter = r.right.iter();
+        let first = match iter.next() {
+            Some(t) => *t,
+            None => return,
+        };
+        if self.roots.len() <= first {
+            self.roots.resize(first + 1, NO_ROOT);
         }
-        let first = r.right.first().unwrap();
-        if !self.root_nodes.contains_key(first) {
-            self.root_nodes.insert(*first, vec![ReductionNode::Node(*first, Vec::new())]);
+        if self.roots[first] == NO_ROOT {
+            self.roots[first] = self.nodes.len() as u32;
+            self.nodes.push(ReductionNode::default());
         }
 
-        let mut iter = r.right.iter();
-        let current = self.root_nodes.get_mut(iter.next().unwrap()).unwrap();
-        Self::build_next(iter, current, r);
-    }
-
-    fn build_next(mut new_term_iter: Iter<Token>, current: &mut Vec<ReductionNode>, r: &Rule) {
-        let next_term = new_term_iter.next();
-        if let Some(t) = next_term {
-            let mut found_node: Option<usize> = None;
-            for (i, exiting_node) in current.iter().enumerate() {
-                match exiting_node {
-                    ReductionNode::Node(n, _vec) => {
-                        if *n == *t {
-                            found_node = Some(i);
-                            break;
-                        }
-                    }
-                    ReductionNode::Rule(_) => {}
+        let mut current = self.roots[first] as usize;
+        for t in iter {
+            let children = &self.nodes[current].children;
+            current = match children.binary_search_by_key(t, |(token, _)| *token) {
+                Ok(i) => children[i].1 as usize,
+                Err(i) => {
+                    let n = self.nodes.len();
+                    self.nodes[current].children.insert(i, (*t, n as u32));
+                    self.nodes.push(ReductionNode::default());
+                    n
                 }
-            }
-            let next = if let Some(i) = found_node {
-                current.get_mut(i).unwrap()
-            } else {
-                current.push(ReductionNode::Node(*t, Vec::new()));
-                current.last_mut().unwrap()
             };
-            if let ReductionNode::Node(_, vec) = next {
-                Self::build_next(new_term_iter, vec, r);
-            } else {
-                unreachable!();
-            }
-        } else {
-            current.push(ReductionNode::Rule(r.clone()));
+        }
+        // The first rule added for a right hand side wins.
+        if self.nodes[current].rule.is_none() {
+            self.nodes[current].rule = Some(self.rules.len() as u32);
+            self.rules.push(r.clone());
         }
     }
 }
@@ -503,14 +728,20 @@ impl Rule {
         Self {
             left: 0,
             right: Vec::new(),
-            nesting_rules: Vec::new(),
+            annotations: Vec::new(),
+            precedence: None,
+            ast: None,
+            empty_nodes: Vec::new(),
         }
     }
     pub fn from(left: Token) -> Self {
         Self {
             left,
             right: Vec::new(),
-            nesting_rules: Vec::new(),
+            annotations: Vec::new(),
+            precedence: None,
+            ast: None,
+            empty_nodes: Vec::new(),
         }
     }
 }
@@ -543,7 +774,11 @@ pub struct OpGrammar {
     pub new_reduction_tree: ReductionTree,
     pub foobar: HashMap<Token, ReductionTree>,
     pub old_axiom: Token,
-    op_table: HashMap<Token, HashMap<Token, Associativity>>,
+    pub precedence: PrecedenceTable,
+    pub sync: Vec<Token>,
+    /// Nonterminal the parser puts in place of input it could not parse.
+    pub error: Token,
+    op_table: OpTable,
 }
 
 #[allow(unused)]
@@ -561,6 +796,9 @@ impl OpGrammar {
 
         g.token_raw.insert(delim, String::from("_DELIM"));
         g.token_reverse.insert(String::from("_DELIM"), (delim, TokenTypes::NonTerminal));
+        let error = g.gen_id();
+        g.token_raw.insert(error, String::from("_Error"));
+        g.token_reverse.insert(String::from("_Error"), (error, TokenTypes::NonTerminal));
 
         // Validate that the grammar is in OPG form
         let repeated_rules = g.get_repeated_rhs();
@@ -569,6 +807,12 @@ impl OpGrammar {
                 "Cannot build OP Grammar from grammar with repeated right hand side.".to_string(),
             ));
         }
+        if let Some(r) = g.rules.iter().find(|r| g.adjacent_non_terminals(r).is_some()) {
+            return Err(GrammarError::from(format!(
+                "Cannot build OP Grammar from grammar with adjacent non-terminals in {}, see RawGrammar::to_operator_form.",
+                g.describe_rule(r)
+            )));
+        }
 
         let mut rewrite_rules: HashMap<Token, Vec<Token>> = HashMap::new();
         for t in &g.non_terminals {
@@ -724,17 +968,23 @@ impl OpGrammar {
             op_table.insert(*t, template.clone());
         }
 
+        let mut derived: HashMap<(Token, Token), HashSet<Associativity>> = HashMap::new();
+        let mut set_relation = |left: Token, right: Token, relation: Associativity| {
+            op_table.get_mut(&left).unwrap().insert(right, relation);
+            derived.entry((left, right)).or_default().insert(relation);
+        };
+
         for r in &g.rules {
             for i in 0..r.right.len() {
                 if i + 1 < r.right.len() {
                     if g.terminals.contains(r.right.get(i).unwrap()) && g.terminals.contains(r.right.get(i + 1).unwrap()) {
-                        op_table.get_mut(r.right.get(i).unwrap()).unwrap().insert(*r.right.get(i + 1).unwrap(), Equal);
+                        set_relation(r.right[i], r.right[i + 1], Equal);
                     }
                     if g.terminals.contains(r.right.get(i).unwrap()) && g.non_terminals.contains(r.right.get(i + 1).unwrap()) {
                         if first_ops.contains_key(r.right.get(i + 1).unwrap()) {
                             let first_op_a = first_ops.get(r.right.get(i + 1).unwrap()).unwrap();
                             for q2 in first_op_a {
-                                op_table.get_mut(r.right.get(i).unwrap()).unwrap().insert(*q2, Left);
+                                set_relation(r.right[i], *q2, Left);
                             }
                         }
                     }
@@ -742,7 +992,7 @@ impl OpGrammar {
                         if last_ops.contains_key(r.right.get(i).unwrap()) {
                             let last_op_a = last_ops.get(r.right.get(i).unwrap()).unwrap();
                             for q2 in last_op_a {
-                                op_table.get_mut(q2).unwrap().insert(*r.right.get(i + 1).unwrap(), Right);
+                                set_relation(*q2, r.right[i + 1], Right);
                             }
                         }
                     }
@@ -751,13 +1001,37 @@ impl OpGrammar {
                             && g.non_terminals.contains(r.right.get(i + 1).unwrap())
                             && g.terminals.contains(r.right.get(i + 2).unwrap())
                         {
-                            op_table.get_mut(r.right.get(i).unwrap()).unwrap().insert(*r.right.get(i + 2).unwrap(), Equal);
+                            set_relation(r.right[i], r.right[i + 2], Equal);
                         }
                     }
                 }
             }
         }
 
+        // Conflicting relations are resolved with the declared operator precedence, the same way
+        // yacc resolves shift / reduce conflicts. Without a declaration the last relation wins.
+        for ((left, right), relations) in &derived {
+            if relations.len() < 2 || relations.contains(&Equal) {
+                continue;
+            }
+            if let Some(relation) = g.precedence.resolve(*left, *right) {
+                trace!(
+                    "Resolved conflict {} {} as {:?}",
+                    g.token_raw.get(left).unwrap(),
+                    g.token_raw.get(right).unwrap(),
+                    relation
+                );
+                op_table.get_mut(left).unwrap().insert(*right, relation);
+            } else {
+                debug!(
+                    "Unresolved precedence conflict between {} and {} : {:?}",
+                    g.token_raw.get(left).unwrap(),
+                    g.token_raw.get(right).unwrap(),
+                    relations
+                );
+            }
+        }
+
         op_table.insert(
             delim,
             template
@@ -775,6 +1049,8 @@ impl OpGrammar {
         g.terminals.push(delim);
 
         print_op_table(&g.token_raw, &g.token_reverse, &g.terminals, &op_table);
+        let op_table = OpTable::new(&g.terminals, delim, &op_table);
+        debug!("Op table compressed into precedence functions : {}", op_table.functions().is_some());
 
         let mut tree = ReductionTree::new();
         for r in &g.rules {
@@ -799,6 +1075,9 @@ impl OpGrammar {
             new_reduction_tree: tree,
             foobar: g.foobar,
             old_axiom: g.old_axiom,
+            precedence: g.precedence,
+            sync: g.sync,
+            error,
         })
     }
 
@@ -839,6 +1118,16 @@ impl OpGrammar {
             f.write(format!("%terminal {}\n", self.token_raw.get(&t).unwrap()).as_bytes());
         }
 
+        if !self.precedence.is_empty() {
+            f.write("\n".as_bytes());
+        }
+        for (assoc, level) in &self.precedence.levels {
+            f.write(format!("%{} {}\n", assoc.keyword(), Self::token_list_to_string(level, &self.token_raw).join(" ")).as_bytes());
+        }
+        if !self.sync.is_empty() {
+            f.write(format!("\n%sync {}\n", Self::token_list_to_string(&self.sync, &self.token_raw).join(" ")).as_bytes());
+        }
+
         f.write("\n%%\n\n".as_bytes());
 
         let mut map: HashMap<Token, Vec<&Vec<Token>>> = HashMap::new();
@@ -881,6 +1170,10 @@ impl OpGrammar {
     }
 
     pub fn get_precedence(&self, left: Token, right: Token) -> Associativity {
-        return self.op_table.get(&left).unwrap().get(&right).unwrap().clone();
+        return self.op_table.get(left, right);
+    }
+
+    pub fn op_table(&self) -> &OpTable {
+        &self.op_table
     }
 }
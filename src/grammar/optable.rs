@@ -0,0 +1,199 @@
+use super::opg::{Associativity, Token};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const NO_ID: u16 = u16::MAX;
+
+/// Operator precedence relations between terminals, indexed by compact terminal ids instead of
+/// token ids. The relations are kept as a dense matrix with one `u8` cell per pair of terminals,
+/// unless they can be encoded as Floyd precedence functions, in which case only the functions
+/// and a bitmap of the pairs that have a relation at all are kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpTable {
+    ids: Vec<u16>,
+    width: usize,
+    delim: usize,
+    matrix: Vec<u8>,
+    functions: Option<PrecedenceFunctions>,
+}
+
+/// `a ⋖ b` iff `f[a] < g[b]`, `a ≐ b` iff `f[a] == g[b]` and `a ⋗ b` iff `f[a] > g[b]`, for
+/// every pair of terminals marked in `defined`. The delimiter relates to every terminal by
+/// convention rather than by the grammar, so its row and column are kept as they are.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrecedenceFunctions {
+    pub f: Vec<u16>,
+    pub g: Vec<u16>,
+    defined: Vec<u64>,
+    delim_row: Vec<u8>,
+    delim_column: Vec<u8>,
+}
+
+impl PrecedenceFunctions {
+    fn is_defined(&self, cell: usize) -> bool {
+        self.defined[cell / 64] & (1 << (cell % 64)) != 0
+    }
+}
+
+impl OpTable {
+    /// Build the table over `terminals`, which must include `delim`.
+    pub fn new(terminals: &[Token], delim: Token, relations: &HashMap<Token, HashMap<Token, Associativity>>) -> Self {
+        let width = terminals.len();
+        let mut ids = vec![NO_ID; terminals.iter().max().map_or(0, |t| t + 1)];
+        for (id, t) in terminals.iter().enumerate() {
+            ids[*t] = id as u16;
+        }
+        let mut matrix = vec![Associativity::None as u8; width * width];
+        for (i, left) in terminals.iter().enumerate() {
+            for (j, right) in terminals.iter().enumerate() {
+                if let Some(relation) = relations.get(left).and_then(|row| row.get(right)) {
+                    matrix[i * width + j] = *relation as u8;
+                }
+            }
+        }
+
+        let mut table = OpTable {
+            delim: ids[delim] as usize,
+            ids,
+            width,
+            matrix,
+            functions: None,
+        };
+        if let Some(functions) = table.precedence_functions() {
+            table.functions = Some(functions);
+            table.matrix = Vec::new();
+        }
+        table
+    }
+
+    pub fn get(&self, left: Token, right: Token) -> Associativity {
+        let (i, j) = match (self.id(left), self.id(right)) {
+            (Some(i), Some(j)) => (i, j),
+            _ => return Associativity::None,
+        };
+        let cell = i * self.width + j;
+        match &self.functions {
+            Some(p) if i == self.delim => Self::relation(p.delim_row[j]),
+            Some(p) if j == self.delim => Self::relation(p.delim_column[i]),
+            Some(p) if !p.is_defined(cell) => Associativity::None,
+            Some(p) => match p.f[i].cmp(&p.g[j]) {
+                std::cmp::Ordering::Less => Associativity::Left,
+                std::cmp::Ordering::Equal => Associativity::Equal,
+                std::cmp::Ordering::Greater => Associativity::Right,
+            },
+            None => Self::relation(self.matrix[cell]),
+        }
+    }
+
+    pub fn functions(&self) -> Option<&PrecedenceFunctions> {
+        self.functions.as_ref()
+    }
+
+    fn id(&self, t: Token) -> Option<usize> {
+        match self.ids.get(t) {
+            Some(&id) if id != NO_ID => Some(id as usize),
+            _ => None,
+        }
+    }
+
+    fn relation(cell: u8) -> Associativity {
+        match cell {
+            x if x == Associativity::Left as u8 => Associativity::Left,
+            x if x == Associativity::Right as u8 => Associativity::Right,
+            x if x == Associativity::Equal as u8 => Associativity::Equal,
+            x if x == Associativity::Undefined as u8 => Associativity::Undefined,
+            _ => Associativity::None,
+        }
+    }
+
+    /// Floyd's construction: node `i` stands for `f[i]` and node `width + j` for `g[j]`. Terminals
+    /// of equal precedence share a node, an edge `u -> v` means `u > v`, and each function is
+    /// the length of the longest path from its node. Fails if the graph has a cycle.
+    fn precedence_functions(&self) -> Option<PrecedenceFunctions> {
+        let n = self.width;
+        let mut group: Vec<usize> = (0..2 * n).collect();
+        fn find(group: &mut [usize], x: usize) -> usize {
+            let mut root = x;
+            while group[root] != root {
+                root = group[root];
+            }
+            group[x] = root;
+            root
+        }
+
+        let mut defined = vec![0u64; (n * n).div_ceil(64)];
+        for cell in 0..n * n {
+            if cell / n == self.delim || cell % n == self.delim {
+                continue;
+            }
+            match Self::relation(self.matrix[cell]) {
+                Associativity::None => continue,
+                Associativity::Undefined => return None,
+                Associativity::Equal => {
+                    let (a, b) = (find(&mut group, cell / n), find(&mut group, n + cell % n));
+                    group[a] = b;
+                }
+                _ => (),
+            }
+            defined[cell / 64] |= 1 << (cell % 64);
+        }
+
+        let mut edges: Vec<Vec<usize>> = vec![Vec::new(); 2 * n];
+        for cell in 0..n * n {
+            if cell / n == self.delim || cell % n == self.delim {
+                continue;
+            }
+            let (f, g) = (find(&mut group, cell / n), find(&mut group, n + cell % n));
+            match Self::relation(self.matrix[cell]) {
+                Associativity::Left => edges[g].push(f),
+                Associativity::Right => edges[f].push(g),
+                _ => continue,
+            }
+            if f == g {
+                return None;
+            }
+        }
+
+        // Longest path from every node, iteratively so large tables do not overflow the stack.
+        const UNVISITED: usize = usize::MAX;
+        const VISITING: usize = usize::MAX - 1;
+        let mut value = vec![UNVISITED; 2 * n];
+        for start in 0..2 * n {
+            let start = find(&mut group, start);
+            if value[start] != UNVISITED {
+                continue;
+            }
+            let mut stack = vec![(start, 0)];
+            value[start] = VISITING;
+            while let Some((node, next)) = stack.pop() {
+                if let Some(&child) = edges[node].get(next) {
+                    stack.push((node, next + 1));
+                    match value[child] {
+                        VISITING => return None,
+                        UNVISITED => {
+                            value[child] = VISITING;
+                            stack.push((child, 0));
+                        }
+                        _ => (),
+                    }
+                } else {
+                    value[node] = edges[node].iter().map(|c| value[*c] + 1).max().unwrap_or(0);
+                }
+            }
+        }
+
+        // Only the roots of the groups have values, the other members share them.
+        let f: Vec<usize> = (0..n).map(|i| value[find(&mut group, i)]).collect();
+        let g: Vec<usize> = (0..n).map(|j| value[find(&mut group, n + j)]).collect();
+        if f.iter().chain(&g).any(|v| *v > u16::MAX as usize) {
+            return None;
+        }
+        Some(PrecedenceFunctions {
+            f: f.into_iter().map(|v| v as u16).collect(),
+            g: g.into_iter().map(|v| v as u16).collect(),
+            defined,
+            delim_row: self.matrix[self.delim * n..(self.delim + 1) * n].to_vec(),
+            delim_column: (0..n).map(|i| self.matrix[i * n + self.delim]).collect(),
+        })
+    }
+}
@@ -15,7 +15,7 @@ impl RawGrammar {
         // } else {
         //     return Err(GrammarError::from("Cannot delete repeated rules as there are no repeated rules.".to_string()));
         // };
-        let repeated_rules = self.get_repeated_rhs().unwrap_or(HashMap::new());
+        let repeated_rules = self.get_repeated_rhs().unwrap_or(BTreeMap::new());
 
         let new_axiom = self.gen_id();
         self.token_raw.insert(new_axiom, String::from("_NewAxiom"));
@@ -23,15 +23,11 @@ impl RawGrammar {
         for (_, rules) in &repeated_rules {
             trace!("Repeated rhs among the following rules:");
             for r in rules {
-                let mut rhs_formatted = String::new();
-                for t in &r.right {
-                    rhs_formatted.push_str(self.token_raw.get(t).unwrap());
-                }
-                trace!("{} -> {}", self.token_raw.get(&r.left).unwrap(), rhs_formatted);
+                trace!("{}", self.describe_rule(r));
             }
         }
 
-        let mut dict_rules: HashMap<Vec<Token>, BTreeSet<Token>> = HashMap::new();
+        let mut dict_rules: BTreeMap<Vec<Token>, BTreeSet<Token>> = BTreeMap::new();
         for r in &self.rules {
             let mut left = BTreeSet::new();
             left.insert(r.left);
@@ -67,10 +63,10 @@ impl RawGrammar {
 
         // Delete copy rules
         trace!("Deleting copy rules");
-        let mut copy: HashMap<Token, HashSet<Token>> = HashMap::new();
-        let mut rhs_dict: HashMap<Token, Vec<Vec<Token>>> = HashMap::new();
+        let mut copy: BTreeMap<Token, BTreeSet<Token>> = BTreeMap::new();
+        let mut rhs_dict: BTreeMap<Token, Vec<Vec<Token>>> = BTreeMap::new();
         for n in &self.non_terminals {
-            copy.insert(*n, HashSet::new());
+            copy.insert(*n, BTreeSet::new());
         }
 
         for r in &self.rules {
@@ -163,8 +159,8 @@ impl RawGrammar {
             v.insert(x);
         }
 
-        let mut new_dict_rules: HashMap<Vec<Vec<Token>>, BTreeSet<Token>> = HashMap::new();
-        let mut copied_dict: HashMap<Vec<Token>, BTreeSet<Token>> = HashMap::new();
+        let mut new_dict_rules: BTreeMap<Vec<Vec<Token>>, BTreeSet<Token>> = BTreeMap::new();
+        let mut copied_dict: BTreeMap<Vec<Token>, BTreeSet<Token>> = BTreeMap::new();
 
         // Initialize the new set of productions P with the terminal rules of the original grammar
         // and avoid doing the next checks and expansions for these rules, deleting them from the
@@ -201,14 +197,14 @@ impl RawGrammar {
         //      builder.push_str("]\n");
         //      f.write(builder.as_bytes());
         //  }
-        let mut non_terms_chunked: HashMap<BTreeSet<Token>, Vec<BTreeSet<Token>>> = HashMap::new();
+        let mut non_terms_chunked: BTreeMap<BTreeSet<Token>, Vec<BTreeSet<Token>>> = BTreeMap::new();
         // for x in &v {
         //     non_terms_chunked.insert(x.clone(), vec![x.clone()]);
         // }
 
         // Add the new rules by expanding nonterminals in the rhs
         trace!("big scary dict recursive part");
-        let mut dict_rules_for_iteration: HashMap<Vec<Vec<Token>>, BTreeSet<BTreeSet<Token>>> = HashMap::new();
+        let mut dict_rules_for_iteration: BTreeMap<Vec<Vec<Token>>, BTreeSet<BTreeSet<Token>>> = BTreeMap::new();
         let mut recursive_part = || {
             let mut should_continue: bool = true;
             while should_continue {
@@ -425,7 +421,7 @@ impl RawGrammar {
     }
 
     fn add_new_rules(
-        dict_rules_for_iteration: &mut HashMap<Vec<Vec<Token>>, BTreeSet<BTreeSet<Token>>>,
+        dict_rules_for_iteration: &mut BTreeMap<Vec<Vec<Token>>, BTreeSet<BTreeSet<Token>>>,
         key_rhs: &[Token],
         value_lhs: &BTreeSet<Token>,
         non_terminals: &Vec<Token>,
@@ -476,9 +472,9 @@ impl RawGrammar {
         }
     }
 
-    pub fn get_repeated_rhs(&mut self) -> Option<HashMap<Vec<Token>, Vec<Rule>>> {
-        let mut repeated_rules: HashMap<Vec<Token>, Vec<Rule>> = HashMap::new();
-        let mut rhs_rule_map: HashMap<Vec<Token>, Vec<Rule>> = HashMap::new();
+    pub fn get_repeated_rhs(&mut self) -> Option<BTreeMap<Vec<Token>, Vec<Rule>>> {
+        let mut repeated_rules: BTreeMap<Vec<Token>, Vec<Rule>> = BTreeMap::new();
+        let mut rhs_rule_map: BTreeMap<Vec<Token>, Vec<Rule>> = BTreeMap::new();
         for r in &self.rules {
             if !rhs_rule_map.contains_key(&r.right) {
                 rhs_rule_map.insert(r.right.clone(), Vec::from([r.clone()]));
@@ -498,3 +494,719 @@ impl RawGrammar {
         }
     }
 }
+
+/// One substitution made by [`RawGrammar::to_operator_form`]: `rule` had two adjacent
+/// nonterminals, and `expanded` was replaced by each of its alternatives to give `replacements`.
+#[derive(Clone, Debug)]
+pub struct OperatorFormRewrite {
+    pub rule: Rule,
+    pub expanded: Token,
+    pub replacements: Vec<Rule>,
+}
+
+/// Everything [`RawGrammar::to_operator_form`] changed in the grammar.
+#[derive(Clone, Debug, Default)]
+pub struct OperatorFormReport {
+    pub rewrites: Vec<OperatorFormRewrite>,
+    /// Nonterminals that became unreachable from the axiom once every use of them was expanded.
+    pub removed_non_terminals: Vec<Token>,
+}
+
+impl OperatorFormReport {
+    pub fn is_empty(&self) -> bool {
+        self.rewrites.is_empty() && self.removed_non_terminals.is_empty()
+    }
+
+    pub fn describe(&self, g: &RawGrammar) -> String {
+        let mut b = String::new();
+        for rewrite in &self.rewrites {
+            b.push_str(
+                format!(
+                    "{}\n  expanded {} into:\n",
+                    g.describe_rule(&rewrite.rule),
+                    g.token_raw.get(&rewrite.expanded).unwrap()
+                )
+                .as_str(),
+            );
+            for r in &rewrite.replacements {
+                b.push_str(format!("    {}\n", g.describe_rule(r)).as_str());
+            }
+        }
+        for n in &self.removed_non_terminals {
+            b.push_str(format!("removed unreachable nonterminal {}\n", g.token_raw.get(n).unwrap()).as_str());
+        }
+        b
+    }
+}
+
+impl RawGrammar {
+    /// Position of the first pair of adjacent nonterminals on the right hand side of `rule`.
+    pub fn adjacent_non_terminals(&self, rule: &Rule) -> Option<usize> {
+        rule.right
+            .windows(2)
+            .position(|w| self.non_terminals.contains(&w[0]) && self.non_terminals.contains(&w[1]))
+    }
+
+    /// Rewrite every rule containing two adjacent nonterminals `B C` into operator normal form by
+    /// substituting the alternatives of `C` (or of `B` when `C` is left recursive) until no such
+    /// pair is left. Must run before [`RawGrammar::delete_repeated_rhs`], as substitution can
+    /// introduce repeated right hand sides.
+    pub fn to_operator_form(&mut self) -> Result<OperatorFormReport, GrammarError> {
+        let mut report = OperatorFormReport::default();
+        if self.rules.iter().all(|r| self.adjacent_non_terminals(r).is_none()) {
+            return Ok(report);
+        }
+
+        let left_corners = self.corners(|r| r.right.first());
+        let right_corners = self.corners(|r| r.right.last());
+        let reachable_before = self.reachable_non_terminals();
+
+        // Each substitution moves one step along an acyclic chain of left or right corners, so a
+        // rule that is still being expanded after this many steps never will be.
+        let max_depth = self.non_terminals.len() * 2 + 2;
+        let original = self.rules.clone();
+        let mut pending: Vec<(Rule, usize)> = original.iter().rev().map(|r| (r.clone(), 0)).collect();
+        let mut rules: Vec<Rule> = Vec::new();
+
+        while let Some((rule, depth)) = pending.pop() {
+            let i = if let Some(i) = self.adjacent_non_terminals(&rule) {
+                i
+            } else {
+                if !rules.iter().any(|r| r.left == rule.left && r.right == rule.right) {
+                    rules.push(rule);
+                }
+                continue;
+            };
+            let (b, c) = (rule.right[i], rule.right[i + 1]);
+            let position = if !left_corners.get(&c).unwrap().contains(&c) {
+                i + 1
+            } else if !right_corners.get(&b).unwrap().contains(&b) {
+                i
+            } else {
+                return Err(GrammarError::from(format!(
+                    "Cannot rewrite {} into operator form, {} is right recursive and {} is left recursive.",
+                    self.describe_rule(&rule),
+                    self.token_raw.get(&b).unwrap(),
+                    self.token_raw.get(&c).unwrap()
+                )));
+            };
+            if depth > max_depth {
+                return Err(GrammarError::from(format!(
+                    "Cannot rewrite {} into operator form, substitution does not terminate.",
+                    self.describe_rule(&rule)
+                )));
+            }
+
+            let expanded = rule.right[position];
+            let mut replacements = Vec::new();
+            for alternative in original.iter().filter(|r| r.left == expanded) {
+                replacements.push(Self::substitute(&rule, position, alternative));
+            }
+            trace!("Expanding {} in {}", self.token_raw.get(&expanded).unwrap(), self.describe_rule(&rule));
+            for r in replacements.iter().rev() {
+                pending.push((r.clone(), depth + 1));
+            }
+            report.rewrites.push(OperatorFormRewrite { rule, expanded, replacements });
+        }
+        self.rules = rules;
+
+        let reachable_after = self.reachable_non_terminals();
+        for n in reachable_before.difference(&reachable_after) {
+            report.removed_non_terminals.push(*n);
+        }
+        self.rules.retain(|r| !report.removed_non_terminals.contains(&r.left));
+        self.non_terminals.retain(|n| !report.removed_non_terminals.contains(n));
+        for n in &report.removed_non_terminals {
+            self.token_types.remove(n);
+        }
+
+        info!(
+            "Rewrote {} rules into operator form, removed {} nonterminals.",
+            report.rewrites.len(),
+            report.removed_non_terminals.len()
+        );
+        debug!("Operator form rewrites:\n{}", report.describe(self));
+        Ok(report)
+    }
+
+    fn substitute(rule: &Rule, position: usize, alternative: &Rule) -> Rule {
+        let annotation = |r: &Rule, i: usize| r.annotations.get(i).cloned().flatten();
+        let mut new_rule = Rule::from(rule.left);
+        new_rule.precedence = rule.precedence;
+        new_rule.ast = rule.ast.clone();
+        for i in 0..rule.right.len() {
+            if i == position {
+                for j in 0..alternative.right.len() {
+                    new_rule.right.push(alternative.right[j]);
+                    new_rule.annotations.push(annotation(alternative, j));
+                }
+            } else {
+                new_rule.right.push(rule.right[i]);
+                new_rule.annotations.push(annotation(rule, i));
+            }
+        }
+        new_rule
+    }
+
+    /// For every nonterminal, the nonterminals that can appear at the edge of its derivations
+    /// picked by `edge` (the first or last symbol of a right hand side).
+    fn corners<F: Fn(&Rule) -> Option<&Token>>(&self, edge: F) -> HashMap<Token, BTreeSet<Token>> {
+        let mut corners: HashMap<Token, BTreeSet<Token>> = HashMap::new();
+        for n in &self.non_terminals {
+            corners.insert(*n, BTreeSet::new());
+        }
+        for r in &self.rules {
+            if let Some(t) = edge(r) {
+                if self.non_terminals.contains(t) {
+                    corners.get_mut(&r.left).unwrap().insert(*t);
+                }
+            }
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in &self.non_terminals {
+                let mut closure = corners.get(n).unwrap().clone();
+                for x in corners.get(n).unwrap() {
+                    closure.extend(corners.get(x).unwrap().iter());
+                }
+                if closure.len() > corners.get(n).unwrap().len() {
+                    changed = true;
+                    corners.insert(*n, closure);
+                }
+            }
+        }
+        corners
+    }
+
+    fn reachable_non_terminals(&self) -> BTreeSet<Token> {
+        let mut reachable = BTreeSet::from([self.axiom]);
+        let mut stack = vec![self.axiom];
+        while let Some(n) = stack.pop() {
+            for r in self.rules.iter().filter(|r| r.left == n) {
+                for t in &r.right {
+                    if self.non_terminals.contains(t) && reachable.insert(*t) {
+                        stack.push(*t);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+}
+
+/// Where a rule produced by [`expand_ebnf`] came from, so diagnostics can point at the
+/// rule the user actually wrote instead of the generated one.
+#[derive(Clone, Debug)]
+pub struct EbnfOrigin {
+    pub line: usize,
+    pub source: String,
+    pub rules: Vec<Rule>,
+    generated: Vec<(String, Vec<String>)>,
+}
+
+impl EbnfOrigin {
+    pub fn contains(&self, rule: &Rule) -> bool {
+        self.rules.iter().any(|r| r.left == rule.left && r.right == rule.right)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum EbnfToken {
+    Ident(String),
+    Colon,
+    Bar,
+    Semi,
+    LParen,
+    RParen,
+    Quantifier(char),
+    Prec(String),
+    Ast(String),
+}
+
+#[derive(Clone, Debug)]
+enum EbnfItem {
+    Symbol(String),
+    Group(Vec<Vec<EbnfItem>>, Option<char>),
+}
+
+struct EbnfExpander<'a> {
+    lhs: String,
+    terminals: &'a HashSet<String>,
+    new_non_terminals: Vec<String>,
+    new_rules: Vec<(String, Vec<Vec<String>>)>,
+}
+
+/// Returns true if the rules section of a .g file uses `?`, `*`, `+` or parenthesised groups.
+pub fn has_ebnf(s: &str) -> bool {
+    match s.split_once("%%") {
+        Some((_, rules)) => rules.contains(['(', ')', '?', '*', '+']),
+        None => false,
+    }
+}
+
+/// Desugar EBNF rule bodies into plain rules that `RawGrammar::new` can read.
+///
+/// `X?` duplicates the alternative with and without `X`, the same way fern.g spells out optional
+/// pieces by hand. Without `X` it keeps the empty node `X.Kind?` stands for, see
+/// [`annotation`](super::annotation). `X*` and `X+` introduce a fresh nonterminal for the repetition, written as a
+/// left recursive list over the part of the alternative before it when the repeated body starts
+/// with a terminal (`A : B (COMMA C)*` becomes `A : ARepA` and `ARepA : B | ARepA COMMA C`),
+/// otherwise as a right recursive list over the part after it. Either way the generated rules
+/// keep terminals between nonterminals wherever the original rule did.
+pub fn expand_ebnf(s: &str) -> Result<(String, Vec<EbnfOrigin>), GrammarError> {
+    let (header, body) = match s.split_once("%%") {
+        Some(parts) => parts,
+        None => return Err(GrammarError::from("Grammar has no rules section.".to_string())),
+    };
+    let header_lines = header.matches('\n').count() + 1;
+
+    let mut terminals: HashSet<String> = HashSet::new();
+    let mut declared: HashSet<String> = HashSet::new();
+    let mut words = header.split_whitespace();
+    while let Some(w) = words.next() {
+        if w == "%terminal" || w == "%nonterminal" {
+            if let Some(name) = words.next() {
+                if w == "%terminal" {
+                    terminals.insert(name.to_string());
+                }
+                declared.insert(name.to_string());
+            }
+        }
+    }
+
+    let tokens = tokenize_ebnf(body, header_lines)?;
+    let mut iter = tokens.into_iter().peekable();
+    let mut rules_text = String::new();
+    let mut new_non_terminals: Vec<String> = Vec::new();
+    let mut origins = Vec::new();
+
+    while let Some((t, line)) = iter.next() {
+        let lhs = match t {
+            EbnfToken::Ident(name) => name,
+            t => return Err(GrammarError::from(format!("Line {}: expected rule name, found {:?}.", line, t))),
+        };
+        match iter.next() {
+            Some((EbnfToken::Colon, _)) => (),
+            _ => return Err(GrammarError::from(format!("Line {}: expected : after {}.", line, lhs))),
+        }
+
+        let mut expander = EbnfExpander {
+            lhs: lhs.clone(),
+            terminals: &terminals,
+            new_non_terminals: Vec::new(),
+            new_rules: Vec::new(),
+        };
+        let mut plain: Vec<(Vec<String>, Option<String>, Option<String>)> = Vec::new();
+        loop {
+            let line = iter.peek().map(|(_, l)| *l).unwrap_or(line);
+            let (alternatives, prec, ast) = parse_ebnf_alternative(&mut iter, line)?;
+            let source = format!("{} : {}", lhs, ebnf_to_string(&alternatives));
+            let before = expander.new_rules.len();
+            let expanded = expander.expand(&alternatives);
+            if expanded.iter().any(|seq| seq.is_empty()) {
+                return Err(GrammarError::from(format!(
+                    "Line {}: expansion of '{}' has an empty right hand side.",
+                    line, source
+                )));
+            }
+
+            let mut generated: Vec<(String, Vec<String>)> = Vec::new();
+            for seq in &expanded {
+                generated.push((lhs.clone(), seq.clone()));
+                plain.push((seq.clone(), prec.clone(), ast.clone()));
+            }
+            for (left, rhs_list) in &expander.new_rules[before..] {
+                for rhs in rhs_list {
+                    generated.push((left.clone(), rhs.clone()));
+                }
+            }
+            if generated.len() > 1 || before != expander.new_rules.len() {
+                origins.push(EbnfOrigin {
+                    line,
+                    source,
+                    rules: Vec::new(),
+                    generated: generated
+                        .into_iter()
+                        .map(|(l, r)| (l, r.iter().map(|x| strip_nesting(x)).filter(|x| !x.is_empty()).map(String::from).collect()))
+                        .collect(),
+                });
+            }
+
+            match iter.next() {
+                Some((EbnfToken::Bar, _)) => continue,
+                Some((EbnfToken::Semi, _)) => break,
+                Some((t, line)) => return Err(GrammarError::from(format!("Line {}: unexpected {:?} in rule {}.", line, t, lhs))),
+                None => return Err(GrammarError::from(format!("Rule {} is missing a terminating ;", lhs))),
+            }
+        }
+
+        write_rule(
+            &mut rules_text,
+            &lhs,
+            plain.iter().map(|(seq, prec, ast)| (seq.as_slice(), prec.as_deref(), ast.as_deref())),
+        );
+        for (left, rhs_list) in &expander.new_rules {
+            write_rule(&mut rules_text, left, rhs_list.iter().map(|seq| (seq.as_slice(), None, None)));
+        }
+        for n in expander.new_non_terminals {
+            if declared.contains(&n) {
+                return Err(GrammarError::from(format!("Generated nonterminal {} clashes with a declared symbol.", n)));
+            }
+            new_non_terminals.push(n);
+        }
+    }
+
+    let mut output = String::from(header.trim_end());
+    output.push('\n');
+    for n in &new_non_terminals {
+        output.push_str(format!("%nonterminal {}\n", n).as_str());
+    }
+    output.push_str("\n%%\n\n");
+    output.push_str(rules_text.as_str());
+    Ok((output, origins))
+}
+
+fn write_rule<'a, I: Iterator<Item = (&'a [String], Option<&'a str>, Option<&'a str>)>>(out: &mut String, lhs: &str, alternatives: I) {
+    out.push_str(lhs);
+    out.push_str(" : ");
+    for (i, (seq, prec, ast)) in alternatives.enumerate() {
+        if i > 0 {
+            out.push_str("\t| ");
+        }
+        out.push_str(seq.join(" ").as_str());
+        if let Some(prec) = prec {
+            out.push_str(format!(" %prec {}", prec).as_str());
+        }
+        if let Some(ast) = ast {
+            out.push_str(format!(" %ast {}", ast).as_str());
+        }
+        out.push('\n');
+    }
+    out.push_str("\t;\n\n");
+}
+
+fn strip_nesting(name: &str) -> &str {
+    let end = name.find('.').unwrap_or(name.len());
+    &name[..end]
+}
+
+fn tokenize_ebnf(s: &str, first_line: usize) -> Result<Vec<(EbnfToken, usize)>, GrammarError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    let mut line = first_line;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            ' ' | '\t' | '\r' => (),
+            ':' => tokens.push((EbnfToken::Colon, line)),
+            '|' => tokens.push((EbnfToken::Bar, line)),
+            ';' => tokens.push((EbnfToken::Semi, line)),
+            '(' => tokens.push((EbnfToken::LParen, line)),
+            ')' => tokens.push((EbnfToken::RParen, line)),
+            '?' | '*' | '+' => tokens.push((EbnfToken::Quantifier(c), line)),
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '.' | '%' => {
+                let mut word = String::from(c);
+                while let Some(next) = chars.peek() {
+                    if next.is_ascii_alphanumeric() || *next == '_' || *next == '.' {
+                        word.push(chars.next().unwrap());
+                    } else {
+                        break;
+                    }
+                }
+                if word == "%prec" || word == "%ast" {
+                    let mut name = String::new();
+                    while let Some(next) = chars.peek() {
+                        if next.is_ascii_whitespace() && name.is_empty() {
+                            chars.next();
+                        } else if next.is_ascii_alphanumeric() || *next == '_' {
+                            name.push(chars.next().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+                    tokens.push((if word == "%prec" { EbnfToken::Prec(name) } else { EbnfToken::Ast(name) }, line));
+                } else if word.starts_with('%') {
+                    return Err(GrammarError::from(format!("Line {}: invalid keyword in rule : {}", line, word)));
+                } else {
+                    tokens.push((EbnfToken::Ident(word), line));
+                }
+            }
+            _ => return Err(GrammarError::from(format!("Line {}: invalid character in grammar definition: {}", line, c))),
+        }
+    }
+    Ok(tokens)
+}
+
+/// The items of an alternative, with its `%prec` terminal and `%ast` node kind.
+type EbnfAlternative = (Vec<EbnfItem>, Option<String>, Option<String>);
+
+/// Parse one top level alternative, stopping before the `|` or `;` that ends it.
+fn parse_ebnf_alternative<I: Iterator<Item = (EbnfToken, usize)>>(iter: &mut std::iter::Peekable<I>, line: usize) -> Result<EbnfAlternative, GrammarError> {
+    let items = parse_ebnf_sequence(iter, line)?;
+    let (mut prec, mut ast) = (None, None);
+    loop {
+        match iter.peek() {
+            Some((EbnfToken::Prec(name), _)) if prec.is_none() => prec = Some(name.clone()),
+            Some((EbnfToken::Ast(name), _)) if ast.is_none() => ast = Some(name.clone()),
+            _ => return Ok((items, prec, ast)),
+        }
+        iter.next();
+    }
+}
+
+fn parse_ebnf_sequence<I: Iterator<Item = (EbnfToken, usize)>>(iter: &mut std::iter::Peekable<I>, line: usize) -> Result<Vec<EbnfItem>, GrammarError> {
+    let mut items = Vec::new();
+    loop {
+        let item = match iter.peek() {
+            Some((EbnfToken::Ident(_), _)) => match iter.next() {
+                Some((EbnfToken::Ident(name), _)) => EbnfItem::Symbol(name),
+                _ => unreachable!(),
+            },
+            Some((EbnfToken::LParen, _)) => {
+                iter.next();
+                let mut alternatives = vec![parse_ebnf_sequence(iter, line)?];
+                loop {
+                    match iter.next() {
+                        Some((EbnfToken::Bar, _)) => alternatives.push(parse_ebnf_sequence(iter, line)?),
+                        Some((EbnfToken::RParen, _)) => break,
+                        _ => return Err(GrammarError::from(format!("Line {}: unclosed group.", line))),
+                    }
+                }
+                EbnfItem::Group(alternatives, None)
+            }
+            Some((EbnfToken::Quantifier(q), l)) => {
+                return Err(GrammarError::from(format!("Line {}: {} does not follow a symbol or group.", l, q)));
+            }
+            _ => return Ok(items),
+        };
+        if let Some((EbnfToken::Quantifier(q), _)) = iter.peek() {
+            let q = *q;
+            iter.next();
+            items.push(match item {
+                EbnfItem::Group(alternatives, None) => EbnfItem::Group(alternatives, Some(q)),
+                item => EbnfItem::Group(vec![vec![item]], Some(q)),
+            });
+        } else {
+            items.push(item);
+        }
+    }
+}
+
+fn ebnf_to_string(items: &[EbnfItem]) -> String {
+    let mut out = Vec::new();
+    for item in items {
+        match item {
+            EbnfItem::Symbol(s) => out.push(s.clone()),
+            EbnfItem::Group(alternatives, q) => {
+                let inner: Vec<String> = alternatives.iter().map(|a| ebnf_to_string(a)).collect();
+                let q = q.map(|q| q.to_string()).unwrap_or_default();
+                if alternatives.len() == 1 && alternatives[0].len() == 1 {
+                    if let EbnfItem::Symbol(_) = alternatives[0][0] {
+                        out.push(format!("{}{}", inner[0], q));
+                        continue;
+                    }
+                }
+                out.push(format!("({}){}", inner.join(" | "), q));
+            }
+        }
+    }
+    out.join(" ")
+}
+
+impl<'a> EbnfExpander<'a> {
+    fn expand(&mut self, items: &[EbnfItem]) -> Vec<Vec<String>> {
+        let repetition = items.iter().position(|i| matches!(i, EbnfItem::Group(_, Some('*' | '+'))));
+        let k = match repetition {
+            Some(k) => k,
+            None => return self.expand_flat(items),
+        };
+
+        let prefixes = self.expand_flat(&items[..k]);
+        let suffixes = self.expand(&items[k + 1..]);
+        let (alternatives, q) = match &items[k] {
+            EbnfItem::Group(alternatives, Some(q)) => (alternatives, *q),
+            _ => unreachable!(),
+        };
+        let mut bodies = Vec::new();
+        for a in alternatives {
+            bodies.extend(self.expand(a));
+        }
+
+        let name = self.gen_name();
+        let r = vec![name.clone()];
+        let mut rules = Vec::new();
+        let mut result = Vec::new();
+        let starts_with_terminal = bodies.iter().all(|b| b.first().is_some_and(|t| self.is_terminal(t)));
+        let ends_with_terminal = bodies.iter().all(|b| b.last().is_some_and(|t| self.is_terminal(t)));
+        if starts_with_terminal || !ends_with_terminal {
+            // name : prefix | name body, so name derives prefix body*.
+            let has_empty = prefixes.iter().any(|p| p.is_empty());
+            for p in prefixes.iter().filter(|p| !p.is_empty()) {
+                rules.push(p.clone());
+            }
+            if has_empty {
+                rules.extend(bodies.iter().cloned());
+            }
+            for b in &bodies {
+                rules.push([r.as_slice(), b.as_slice()].concat());
+            }
+            for s in &suffixes {
+                if q == '*' {
+                    result.push([r.as_slice(), s.as_slice()].concat());
+                    if has_empty {
+                        result.push(s.clone());
+                    }
+                } else if has_empty && prefixes.len() == 1 {
+                    result.push([r.as_slice(), s.as_slice()].concat());
+                } else {
+                    for b in &bodies {
+                        result.push([r.as_slice(), b.as_slice(), s.as_slice()].concat());
+                        if has_empty {
+                            result.push([b.as_slice(), s.as_slice()].concat());
+                        }
+                    }
+                }
+            }
+        } else {
+            // name : suffix | body name, so name derives body* suffix.
+            let has_empty = suffixes.iter().any(|s| s.is_empty());
+            for s in suffixes.iter().filter(|s| !s.is_empty()) {
+                rules.push(s.clone());
+            }
+            if has_empty {
+                rules.extend(bodies.iter().cloned());
+            }
+            for b in &bodies {
+                rules.push([b.as_slice(), r.as_slice()].concat());
+            }
+            for p in &prefixes {
+                if q == '*' {
+                    result.push([p.as_slice(), r.as_slice()].concat());
+                    if has_empty {
+                        result.push(p.clone());
+                    }
+                } else if has_empty && suffixes.len() == 1 {
+                    result.push([p.as_slice(), r.as_slice()].concat());
+                } else {
+                    for b in &bodies {
+                        result.push([p.as_slice(), b.as_slice(), r.as_slice()].concat());
+                        if has_empty {
+                            result.push([p.as_slice(), b.as_slice()].concat());
+                        }
+                    }
+                }
+            }
+        }
+        dedup(&mut rules);
+        self.new_rules.push((name, rules));
+        dedup(&mut result);
+        result
+    }
+
+    /// Expand a sequence that contains no top level repetition.
+    fn expand_flat(&mut self, items: &[EbnfItem]) -> Vec<Vec<String>> {
+        let mut result: Vec<Vec<String>> = vec![Vec::new()];
+        for item in items {
+            let options = match item {
+                EbnfItem::Symbol(s) => vec![vec![s.clone()]],
+                EbnfItem::Group(alternatives, q) => {
+                    let mut options = Vec::new();
+                    if *q == Some('?') {
+                        options.push(self.left_out(alternatives));
+                    }
+                    for a in alternatives {
+                        options.extend(self.expand(a));
+                    }
+                    options
+                }
+            };
+            let mut next = Vec::new();
+            for r in &result {
+                for o in &options {
+                    next.push([r.as_slice(), o.as_slice()].concat());
+                }
+            }
+            result = next;
+        }
+        dedup(&mut result);
+        result
+    }
+
+    fn is_terminal(&self, name: &str) -> bool {
+        self.terminals.contains(strip_nesting(name))
+    }
+
+    /// What an optional group leaves in its place: an empty node when every alternative is a
+    /// nonterminal in the same kind, as in `(exprList.ExprList | expr.ExprList)?`, otherwise
+    /// nothing.
+    fn left_out(&self, alternatives: &[Vec<EbnfItem>]) -> Vec<String> {
+        let kinds: Option<Vec<&str>> = alternatives
+            .iter()
+            .map(|a| match a.as_slice() {
+                [EbnfItem::Symbol(s)] if !self.is_terminal(s) => s
+                    .rsplit_once('.')
+                    .map(|(_, kind)| kind)
+                    .filter(|kind| !matches!(*kind, "keep" | "elide" | "flatten")),
+                _ => None,
+            })
+            .collect();
+        match kinds {
+            Some(kinds) if kinds.windows(2).all(|k| k[0] == k[1]) => vec![format!(".{}", kinds[0])],
+            _ => Vec::new(),
+        }
+    }
+
+    fn gen_name(&mut self) -> String {
+        let mut n = self.new_non_terminals.len();
+        let mut suffix = String::new();
+        loop {
+            suffix.insert(0, (b'A' + (n % 26) as u8) as char);
+            if n < 26 {
+                break;
+            }
+            n = n / 26 - 1;
+        }
+        let name = format!("{}Rep{}", self.lhs, suffix);
+        self.new_non_terminals.push(name.clone());
+        name
+    }
+}
+
+fn dedup(list: &mut Vec<Vec<String>>) {
+    let mut seen = HashSet::new();
+    list.retain(|x| seen.insert(x.clone()));
+}
+
+impl RawGrammar {
+    pub(super) fn resolve_ebnf_origins(&mut self, mut origins: Vec<EbnfOrigin>) {
+        for origin in &mut origins {
+            for (left, right) in &origin.generated {
+                let left = self.token_reverse.get(left.as_str()).unwrap().0;
+                let right: Vec<Token> = right.iter().map(|t| self.token_reverse.get(t.as_str()).unwrap().0).collect();
+                if let Some(r) = self.rules.iter().find(|r| r.left == left && r.right == right) {
+                    origin.rules.push(r.clone());
+                }
+            }
+        }
+        self.ebnf_origins = origins;
+    }
+
+    /// Find the EBNF rule a generated rule was expanded from.
+    pub fn rule_origin(&self, rule: &Rule) -> Option<&EbnfOrigin> {
+        self.ebnf_origins.iter().find(|o| o.contains(rule))
+    }
+
+    /// Format a rule for diagnostics, naming the EBNF rule it came from if it was generated.
+    pub fn describe_rule(&self, rule: &Rule) -> String {
+        let mut b = format!(
+            "{} -> {}",
+            self.token_raw.get(&rule.left).unwrap(),
+            OpGrammar::token_list_to_string(&rule.right, &self.token_raw).join(" ")
+        );
+        if let Some(origin) = self.rule_origin(rule) {
+            b.push_str(format!(" (expanded from line {}: {})", origin.line, origin.source).as_str());
+        }
+        b
+    }
+}
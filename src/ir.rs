@@ -1,22 +1,32 @@
-use log::info;
-use simple_error::SimpleError;
-
-use crate::{
-    fern_ast::{Operator, TypeExpr},
-    parser::fern_ast::AstNode,
-};
-use std::{
-    collections::{BTreeMap, VecDeque},
-    fmt::Display,
-};
+use crate::ast::{self, AstView, ElseIfStmt, ElseStmt, FunctionDecl, IfStmt, Node, Stmt};
+use crate::fern::{AstNodeKind, FernAst, OperatorKind};
+use crate::types::{Type, Typing};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 
 // This is where we transition from the parser into the ir code
 // generation phase. We group all code by function (nested functions
 // are outside the cope of this language) and then transform that code
 // into static single assignment form.
 
-pub struct Module {
-    top_level_stmts: Vec<Statement>,
+#[derive(Debug)]
+pub struct IrError {
+    message: String,
+}
+
+impl Error for IrError {}
+
+impl IrError {
+    pub fn from(s: String) -> IrError {
+        IrError { message: s }
+    }
+}
+
+impl Display for IrError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IR Error: {}", self.message)
+    }
 }
 
 #[derive(Debug)]
@@ -26,21 +36,34 @@ pub enum Statement {
     Return(Option<Value>),
 }
 
-#[derive(Debug)]
-pub struct Assign {}
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Statement::Let(x) => write!(f, "{}", x),
+            Statement::Goto(x) => write!(f, "goto {}", x.name),
+            Statement::Return(Some(x)) => write!(f, "return {}", x),
+            Statement::Return(None) => write!(f, "return"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Expr {
-    Binary(Value, Operator, Value),
-    Unary(Operator, Value),
+    Binary(Value, OperatorKind, Value),
+    Unary(OperatorKind, Value),
+    Call(Value, Vec<Value>),
     Single(Value),
 }
 
 impl Display for Expr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Expr::Binary(left, op, right) => write!(f, "{} {:?} {}", left, op, right),
             Expr::Unary(op, right) => write!(f, "{:?} {}", op, right),
+            Expr::Call(callee, args) => {
+                let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+                write!(f, "call {}({})", callee, args.join(", "))
+            }
             Expr::Single(x) => write!(f, "{}", x),
         }
     }
@@ -50,13 +73,17 @@ impl Display for Expr {
 pub enum Value {
     Identifier(String),
     Number(i64),
+    String(String),
+    Bool(bool),
 }
 
 impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Identifier(x) => write!(f, "{}", x),
             Value::Number(x) => write!(f, "{}", x),
+            Value::String(x) => write!(f, "{}", x),
+            Value::Bool(x) => write!(f, "{}", x),
         }
     }
 }
@@ -64,23 +91,27 @@ impl Display for Value {
 #[derive(Debug)]
 pub struct Let {
     pub ident: Identifier,
+    pub ty: Type,
     pub val: Option<Expr>,
 }
 
 impl Let {
-    pub fn new(ident: Identifier, val: Option<Expr>) -> Self {
-        Self { ident, val }
+    pub fn new(ident: Identifier, ty: Type, val: Option<Expr>) -> Self {
+        Self { ident, ty, val }
     }
 }
 
-#[derive(Debug)]
-pub struct If {}
-
-#[derive(Debug)]
-pub struct Fn {
-    name: String,
-    params: Vec<Identifier>,
-    body: AstNode,
+impl Display for Let {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "let {}", self.ident.name)?;
+        if self.ty != Type::Unknown {
+            write!(f, ": {}", self.ty)?;
+        }
+        match &self.val {
+            Some(val) => write!(f, " = {}", val),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Eq, PartialOrd, Ord, PartialEq, Hash, Clone, Debug)]
@@ -88,21 +119,24 @@ pub struct Identifier {
     pub name: String,
 }
 
-#[derive(Eq, PartialEq, Hash, Debug)]
-enum Type {
-    Default,
-    I32,
+impl Identifier {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
 }
 
+/// What a [`Block`] is. An `elif` is an `Else` holding the code for its condition followed by
+/// an `If`, so only the first condition of a chain is computed up front.
+#[derive(Debug)]
 pub enum BlockType {
     Module,
-    Function,
+    Function(Vec<Identifier>),
     If(Value),
-    ElseIf,
     Else,
     Code(VecDeque<Statement>),
 }
 
+#[derive(Debug)]
 pub struct Block {
     pub block_type: BlockType,
     pub prefix: String,
@@ -110,14 +144,14 @@ pub struct Block {
     pub children: Vec<Block>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum SymbolType {
     Function,
     Variable,
     Constant,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct SymbolData {
     symbol_type: SymbolType,
 }
@@ -128,9 +162,31 @@ impl SymbolData {
     }
 }
 
-impl Identifier {
-    pub fn new(name: String) -> Self {
-        Self { name }
+/// One block per line, indented under its parent, with the statements of code blocks under
+/// them.
+impl Display for Block {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut stack = vec![(self, 0)];
+        while let Some((block, depth)) = stack.pop() {
+            let indent = "  ".repeat(depth);
+            match &block.block_type {
+                BlockType::Module => writeln!(f, "{}module {}", indent, block.prefix)?,
+                BlockType::Function(params) => {
+                    let params: Vec<&str> = params.iter().map(|x| x.name.as_str()).collect();
+                    writeln!(f, "{}fn {}({})", indent, block.prefix, params.join(", "))?
+                }
+                BlockType::If(condition) => writeln!(f, "{}if {} {}", indent, block.prefix, condition)?,
+                BlockType::Else => writeln!(f, "{}else {}", indent, block.prefix)?,
+                BlockType::Code(stmts) => {
+                    writeln!(f, "{}code {}", indent, block.prefix)?;
+                    for stmt in stmts {
+                        writeln!(f, "{}  {}", indent, stmt)?;
+                    }
+                }
+            }
+            stack.extend(block.children.iter().rev().map(|c| (c, depth + 1)));
+        }
+        Ok(())
     }
 }
 
@@ -144,371 +200,295 @@ impl Block {
         }
     }
 
-    pub fn from(root: Box<AstNode>) -> Result<Self, SimpleError> {
+    /// The IR of `ast`, where each let has the type `typing` gives the expression it computes.
+    pub fn from(ast: &FernAst, typing: &Typing) -> Result<Self, IrError> {
         let mut root_block = Block::new("root".to_string(), BlockType::Module, BTreeMap::new());
-        let stmts = if let AstNode::Module(statlist) = *root {
-            if let AstNode::StatList(list) = *statlist {
-                list
-            } else {
-                panic!("Malformed module");
-            }
-        } else {
-            panic!("Module is not root of ast.");
+        let root = match ast.root() {
+            Some(root) if *root.kind() == AstNodeKind::Module => root,
+            Some(_) => return Err(IrError::from("Module is not root of ast.".to_string())),
+            None => return Ok(root_block),
         };
 
-        let mut backlog = VecDeque::new();
-        for stmt in stmts {
+        // Functions are all declared before any of them is compiled, so they can call each
+        // other in any order.
+        let mut backlog = Vec::new();
+        for stmt in ast::Block::cast(root).unwrap().statements() {
             match stmt {
-                AstNode::Let(_, _, _) => {
-                    panic!("Top level statements are not supported.")
+                Stmt::Function(func) => {
+                    let name = func
+                        .name()
+                        .ok_or_else(|| IrError::from("Function name must be a valid identifier.".to_string()))?;
+                    let name = format!("{}.{}", root_block.prefix, name.name());
+                    root_block.stable.insert(Identifier::new(name), SymbolData::new(SymbolType::Function));
+                    backlog.push(func);
                 }
-                AstNode::Module(_) => panic!("Nested module not supported."),
-                AstNode::Function(ref name, _, _) => {
-                    if let AstNode::Name(mut name) = *name.clone() {
-                        name = format!("{}.{}", root_block.prefix, name);
-                        root_block.stable.insert(Identifier::new(name), SymbolData::new(SymbolType::Function));
-                        backlog.push_front(stmt);
-                    } else {
-                        panic!("Function name must be a valid identifier.");
-                    }
+                Stmt::Let(_) | Stmt::Assign(_) | Stmt::Return(_) | Stmt::If(_) | Stmt::Call(_) => {
+                    return Err(IrError::from("Top level statements are not supported.".to_string()))
+                }
+                _ => {
+                    return Err(IrError::from(format!(
+                        "Bad top level stmt. Should be a function, is {}.",
+                        stmt.node().kind().name()
+                    )))
                 }
-                _ => panic!("Bad top level stmt. Should be let, function or module. is {:?}", stmt),
             }
         }
 
         for func in backlog {
-            root_block.add_func(func);
+            root_block.add_func(func, typing)?;
         }
 
-        return Ok(root_block);
+        Ok(root_block)
     }
 
-    pub fn add_func(&mut self, val: AstNode) {
-        let (name, params, body) = if let AstNode::Function(name, params, body) = val {
-            if let AstNode::Name(name) = *name {
-                (name, params, body)
-            } else {
-                panic!("Function name must be a valid identifier.");
-            }
-        } else {
-            panic!("Trying to add function when ast node is not a function.");
-        };
-
-        let prefix = format!("{}.{}", self.prefix.clone(), name.as_str());
-        let mut f = Block::new(prefix, BlockType::Function, self.stable.clone());
+    pub fn add_func(&mut self, func: FunctionDecl, typing: &Typing) -> Result<(), IrError> {
+        let name = func
+            .name()
+            .ok_or_else(|| IrError::from("Function name must be a valid identifier.".to_string()))?;
+        let prefix = format!("{}.{}", self.prefix, name.name());
 
         // Add func params to symbol table
-        let mut stack = VecDeque::new();
-        if let Some(params) = params {
-            if let AstNode::ExprList(list) = *params {
-                stack.push_front(list);
-            }
-
-            while !stack.is_empty() {
-                let mut current = stack.pop_front().unwrap();
-                let first = current.pop_front().unwrap();
-                match first {
-                    AstNode::ExprList(list) => {
-                        if let AstNode::Name(mut name) = current.pop_front().unwrap() {
-                            name = format!("{}.{}", f.prefix, name);
-                            f.stable.insert(Identifier::new(name), SymbolData::new(SymbolType::Variable));
-                        } else {
-                            panic!("Bad ast function params exprlist");
-                        }
-                        stack.push_front(list);
-                    }
-                    AstNode::Name(mut name) => {
-                        name = format!("{}.{}", f.prefix, name);
-                        f.stable.insert(Identifier::new(name), SymbolData::new(SymbolType::Variable));
-                        if let AstNode::Name(mut name) = current.pop_front().unwrap() {
-                            name = format!("{}.{}", f.prefix, name);
-                            f.stable.insert(Identifier::new(name), SymbolData::new(SymbolType::Variable));
-                        } else {
-                            panic!("Bad ast function params name");
-                        }
-                    }
-                    _ => panic!("Invalid func parameters."),
-                }
-            }
-        }
-
-        if let Some(body) = body {
-            if let AstNode::StatList(list) = *body {
-                let mut blocks = Self::parse_stmt_list(&f.prefix, &mut f.stable, list);
-                for x in f.stable.keys() {
-                    info!("{:?}", x);
-                }
-                f.children.append(&mut blocks);
-            } else {
-                panic!("body not statlist");
-            }
+        let mut stable = self.stable.clone();
+        let mut params = Vec::new();
+        for param in func.params() {
+            let name = param.name().ok_or_else(|| IrError::from(format!("Invalid parameter of {}.", prefix)))?;
+            let ident = Identifier::new(format!("{}.{}", prefix, name.name()));
+            stable.insert(ident.clone(), SymbolData::new(SymbolType::Variable));
+            params.push(ident);
         }
 
+        let mut f = Block::new(prefix, BlockType::Function(params), stable);
+        f.children = Self::parse_stmt_list(&f.prefix, &mut f.stable, typing, func.statements())?;
         self.children.push(f);
+        Ok(())
     }
 
-    pub fn cat(a: String, b: String) -> String {
-        format!("{}{}", a, b)
-    }
-
-    pub fn parse_stmt_list(prefix: &String, stable: &mut BTreeMap<Identifier, SymbolData>, list: VecDeque<AstNode>) -> Vec<Block> {
+    pub fn parse_stmt_list(prefix: &str, stable: &mut BTreeMap<Identifier, SymbolData>, typing: &Typing, list: Vec<Stmt>) -> Result<Vec<Block>, IrError> {
         let mut result: Vec<Block> = Vec::new();
-        let mut current: Option<Block> = None;
-
-        let push_stmts =
-            |result: &mut Vec<Block>, mut stmts: VecDeque<Statement>, current: &mut Option<Block>, stable: &mut BTreeMap<Identifier, SymbolData>| {
-                if let Some(ref mut unwrapped_current) = current {
-                    // The result must be pushed outside the match
-                    // because the borrow checker complains otherwise.
-                    let should_push_to_result = match &mut unwrapped_current.block_type {
-                        BlockType::Code(list) => {
-                            list.append(&mut stmts);
-                            false
-                        }
-                        _ => true,
-                    };
-                    if should_push_to_result {
-                        result.push(current.take().unwrap());
-                        *current = Some(Block::new(format!("{}.code{}", prefix, result.len()), BlockType::Code(stmts), stable.clone()));
-                    }
-                } else {
-                    *current = Some(Block::new(format!("{}.code{}", prefix, result.len()), BlockType::Code(stmts), stable.clone()));
-                };
-            };
+        let mut current: VecDeque<Statement> = VecDeque::new();
 
         for (i, stmt) in list.into_iter().enumerate() {
             match stmt {
-                AstNode::Let(name, type_expr, val) => {
-                    let mut final_stmts = VecDeque::new();
-                    let let_stmts = Block::parse_let(name, type_expr, val);
-                    for mut x in let_stmts {
-                        x.ident.name = format!("{}.{}", prefix, x.ident.name);
-                        stable.insert(x.ident.clone(), SymbolData::new(SymbolType::Variable));
-                        final_stmts.push_back(Statement::Let(x));
-                    }
-                    push_stmts(&mut result, final_stmts, &mut current, stable);
-                }
-                AstNode::Assign(_name, _val) => {
-                    // let let_stmts = Block::parse_let(name, None, Some(val);
-                    // for mut x in let_stmts {
-                    //     x.ident.name = format!("{}.{}", prefix, x.ident.name);
-                    //     stable.insert(x.ident.clone(), SymbolData::new(SymbolType::Variable));
-                    //     result.push_back(Statement::Let(x));
-                    // }
-                }
-                AstNode::Return(val) => {
-                    let mut final_stmts = VecDeque::new();
-                    if let Some(val) = val {
-                        let let_stmts = Block::parse_let(Box::from(AstNode::Name(format!("{}_return", i))), None, Some(val));
-                        for mut x in let_stmts {
-                            x.ident.name = format!("{}.{}", prefix, x.ident.name);
-                            let return_val = x.ident.name.clone();
-                            stable.insert(x.ident.clone(), SymbolData::new(SymbolType::Variable));
-                            final_stmts.push_back(Statement::Let(x));
-                            final_stmts.push_back(Statement::Return(Some(Value::Identifier(return_val))));
+                Stmt::Let(stmt) => {
+                    let name = stmt.name().ok_or_else(|| IrError::from("Invalid identifier in let statement.".to_string()))?;
+                    let ident = Identifier::new(format!("{}.{}", prefix, name.name()));
+                    // The value is computed before the name it is bound to comes into scope. The
+                    // variable has the type it is declared with, not that of its value.
+                    let ty = Self::type_of(typing, name.node());
+                    let lets = match stmt.value() {
+                        Some(val) => {
+                            let mut lets = Self::expr_to_ssa(prefix, stable, typing, &ident.name, val)?;
+                            if let Some(last) = lets.last_mut() {
+                                last.ty = ty;
+                            }
+                            lets
                         }
-                    } else {
-                        final_stmts.push_back(Statement::Return(None));
-                    }
-                    push_stmts(&mut result, final_stmts, &mut current, stable);
+                        None => vec![Let::new(ident.clone(), ty, None)],
+                    };
+                    stable.insert(ident, SymbolData::new(SymbolType::Variable));
+                    current.extend(lets.into_iter().map(Statement::Let));
                 }
-                AstNode::If(condition, body, elseif) => {
-                    // compute condtion and then add if block after the code block.
-                    let mut final_stmts = VecDeque::new();
-                    let cond_var = format!("{}_cond", i);
-                    let let_stmts = Block::parse_let(Box::from(AstNode::Name(cond_var.clone())), None, Some(condition));
-                    for mut x in let_stmts {
-                        x.ident.name = format!("{}.{}", prefix, x.ident.name);
-                        stable.insert(x.ident.clone(), SymbolData::new(SymbolType::Variable));
-                        final_stmts.push_back(Statement::Let(x));
-                    }
-                    push_stmts(&mut result, final_stmts, &mut current, stable);
-                    if let Some(b) = current.take() {
-                        result.push(b);
+                Stmt::Assign(stmt) => {
+                    let target = match stmt.target() {
+                        Some(ast::Expr::Name(name)) => Self::resolve(prefix, stable, name.name())?.to_string(),
+                        _ => return Err(IrError::from("Only names can be assigned to.".to_string())),
+                    };
+                    let val = stmt.value().ok_or_else(|| IrError::from(format!("Assignment to {} has no value.", target)))?;
+                    let lets = Self::expr_to_ssa(prefix, stable, typing, &target, val)?;
+                    current.extend(lets.into_iter().map(Statement::Let));
+                }
+                Stmt::Return(stmt) => match stmt.value() {
+                    Some(val) => {
+                        let name = format!("{}.{}_return", prefix, i);
+                        let (lets, val) = Self::value_to_ssa(prefix, stable, typing, &name, val)?;
+                        current.extend(lets.into_iter().map(Statement::Let));
+                        current.push_back(Statement::Return(Some(val)));
                     }
-                    let block = Self::parse_if(
-                        format!("{}.if{}", prefix, result.len()),
-                        stable.clone(),
-                        Value::Identifier(cond_var),
-                        body,
-                        elseif,
-                    );
-                    result.push(block);
+                    None => current.push_back(Statement::Return(None)),
+                },
+                Stmt::Call(call) => {
+                    let name = format!("{}.{}_call", prefix, i);
+                    let lets = Self::expr_to_ssa(prefix, stable, typing, &name, ast::Expr::Call(call))?;
+                    current.extend(lets.into_iter().map(Statement::Let));
                 }
-                _ => panic!("Invalid statment"),
+                Stmt::If(stmt) => Self::parse_if(prefix, stable, typing, i, stmt.node(), &mut current, &mut result)?,
+                _ => return Err(IrError::from(format!("{} statements are not supported.", stmt.node().kind().name()))),
             }
         }
 
         // Get any stragglers in there
-        if let Some(b) = current {
-            result.push(b);
+        Self::push_code(prefix, stable, &mut current, &mut result);
+        Ok(result)
+    }
+
+    /// Statements in a row share a code block, which ends where another kind of block starts.
+    fn push_code(prefix: &str, stable: &BTreeMap<Identifier, SymbolData>, current: &mut VecDeque<Statement>, result: &mut Vec<Block>) {
+        if !current.is_empty() {
+            let code = BlockType::Code(std::mem::take(current));
+            result.push(Block::new(format!("{}.code{}", prefix, result.len()), code, stable.clone()));
         }
-        return result;
     }
+
+    /// Blocks for an `if` or `elif` and the branches chained to it. The condition is computed
+    /// at the end of `current`, which then goes in a code block ahead of them.
     pub fn parse_if(
-        prefix: String,
-        mut stable: BTreeMap<Identifier, SymbolData>,
-        condition: Value,
-        body: Option<Box<AstNode>>,
-        _elseif: Option<Box<AstNode>>,
-    ) -> Block {
-        let mut blocks: Vec<Block> = Vec::new();
-
-        if let Some(body) = body {
-            if let AstNode::StatList(list) = *body {
-                blocks = Self::parse_stmt_list(&prefix, &mut stable, list);
-            } else {
-                panic!("if body not statlist");
-            }
+        prefix: &str,
+        stable: &BTreeMap<Identifier, SymbolData>,
+        typing: &Typing,
+        i: usize,
+        node: Node,
+        current: &mut VecDeque<Statement>,
+        result: &mut Vec<Block>,
+    ) -> Result<(), IrError> {
+        let (condition, body, branch) = match (IfStmt::cast(node), ElseIfStmt::cast(node)) {
+            (Some(stmt), _) => (stmt.condition(), stmt.statements(), stmt.else_branch()),
+            (_, Some(stmt)) => (stmt.condition(), stmt.statements(), stmt.else_branch()),
+            _ => return Err(IrError::from(format!("Expected an if statement, found {}.", node.kind().name()))),
+        };
+        let condition = condition.ok_or_else(|| IrError::from("If statement has no condition.".to_string()))?;
+
+        // compute condtion and then add if block after the code block.
+        let cond_var = format!("{}.{}_cond", prefix, i);
+        let (lets, condition) = Self::value_to_ssa(prefix, stable, typing, &cond_var, condition)?;
+        current.extend(lets.into_iter().map(Statement::Let));
+        Self::push_code(prefix, stable, current, result);
+
+        let if_prefix = format!("{}.if{}", prefix, result.len());
+        let mut block = Block::new(if_prefix, BlockType::If(condition), stable.clone());
+        block.children = Self::parse_stmt_list(&block.prefix, &mut stable.clone(), typing, body)?;
+        result.push(block);
+
+        if let Some(branch) = branch {
+            let else_prefix = format!("{}.else{}", prefix, result.len());
+            let mut block = Block::new(else_prefix, BlockType::Else, stable.clone());
+            block.children = match ElseStmt::cast(branch) {
+                Some(stmt) => Self::parse_stmt_list(&block.prefix, &mut stable.clone(), typing, stmt.statements())?,
+                None => {
+                    let mut children = Vec::new();
+                    let mut cond = VecDeque::new();
+                    Self::parse_if(&block.prefix, stable, typing, 0, branch, &mut cond, &mut children)?;
+                    children
+                }
+            };
+            result.push(block);
         }
-
-        let mut result = Block::new(prefix, BlockType::If(condition), stable.clone());
-        result.children = blocks;
-        result
+        Ok(())
     }
 
-    pub fn parse_let(name: Box<AstNode>, _type_expr: Option<TypeExpr>, val: Option<Box<AstNode>>) -> Vec<Let> {
-        let mut result = Vec::new();
-        if let AstNode::Name(name) = *name {
-            if let Some(val) = val {
-                let mut intermediate = Self::expr_to_ssa(name, *val);
-                result.append(&mut intermediate);
-            } else {
-                result.push(Let {
-                    ident: Identifier { name },
-                    val: None,
-                });
+    /// The name `name` refers to from a block with `prefix`, qualified with the prefix of the
+    /// block it was declared in.
+    fn resolve(prefix: &str, stable: &BTreeMap<Identifier, SymbolData>, name: &str) -> Result<Value, IrError> {
+        let mut scope = prefix;
+        loop {
+            let ident = Identifier::new(format!("{}.{}", scope, name));
+            if stable.contains_key(&ident) {
+                return Ok(Value::Identifier(ident.name));
+            }
+            match scope.rfind('.') {
+                Some(i) => scope = &scope[..i],
+                None => return Err(IrError::from(format!("Identifier {} used but not declared.", name))),
             }
-        } else {
-            panic!("Invalid identifier in let statement");
         }
-        result
     }
 
-    pub fn ast_node_to_value(node: AstNode) -> Option<Value> {
-        match node {
-            AstNode::Unary(_, _) => todo!(),
-            AstNode::Number(num) => Some(Value::Number(num)),
-            AstNode::String(s) => Some(Value::Identifier(s)),
-            AstNode::Name(s) => Some(Value::Identifier(s)),
-            AstNode::FunctionCall(_, _)
-            | AstNode::Let(_, _, _)
-            | AstNode::Return(_)
-            | AstNode::Module(_)
-            | AstNode::StatList(_)
-            | AstNode::Function(_, _, _)
-            | AstNode::If(_, _, _)
-            | AstNode::ExprThen(_, _)
-            | AstNode::ElseIf(_, _, _)
-            | AstNode::Else(_)
-            | AstNode::For(_, _, _)
-            | AstNode::Binary(_, _, _)
-            | AstNode::ExprList(_)
-            | AstNode::Assign(_, _)
-            | AstNode::While(_, _) => None,
-        }
+    /// The type `typing` gives `node`, which is unknown if it could not be inferred.
+    fn type_of(typing: &Typing, node: Node) -> Type {
+        typing.type_of(node.id()).cloned().unwrap_or(Type::Unknown)
     }
 
-    pub fn expr_to_ssa(result_identifier: String, root: AstNode) -> Vec<Let> {
-        let mut stack: Vec<(String, AstNode)> = Vec::new();
-        let mut result: Vec<Let> = Vec::new();
-        stack.push((result_identifier.clone(), root));
-
-        let is_leaf = |x: &AstNode| -> bool {
-            match x {
-                AstNode::Unary(_, _) | AstNode::Number(_) | AstNode::String(_) | AstNode::Name(_) | AstNode::FunctionCall(_, _) => true,
-                AstNode::Let(_, _, _)
-                | AstNode::Return(_)
-                | AstNode::Module(_)
-                | AstNode::StatList(_)
-                | AstNode::Function(_, _, _)
-                | AstNode::If(_, _, _)
-                | AstNode::ExprThen(_, _)
-                | AstNode::ElseIf(_, _, _)
-                | AstNode::Else(_)
-                | AstNode::For(_, _, _)
-                | AstNode::Binary(_, _, _)
-                | AstNode::ExprList(_)
-                | AstNode::Assign(_, _)
-                | AstNode::While(_, _) => false,
-            }
-        };
+    /// The value of a name or literal.
+    fn leaf_to_value(prefix: &str, stable: &BTreeMap<Identifier, SymbolData>, expr: &ast::Expr) -> Result<Option<Value>, IrError> {
+        Ok(Some(match expr {
+            ast::Expr::Name(name) => Self::resolve(prefix, stable, name.name())?,
+            ast::Expr::Number(num) => Value::Number(
+                num.value()
+                    .parse()
+                    .map_err(|_| IrError::from(format!("Number {} is not a 64 bit integer.", num.value())))?,
+            ),
+            ast::Expr::String(s) => Value::String(s.value().to_string()),
+            ast::Expr::Bool(b) => Value::Bool(b.value()),
+            _ => return Ok(None),
+        }))
+    }
 
+    /// Lets that compute `root` into `result_identifier`, with a temporary for each
+    /// intermediate result.
+    pub fn expr_to_ssa(
+        prefix: &str,
+        stable: &BTreeMap<Identifier, SymbolData>,
+        typing: &Typing,
+        result_identifier: &str,
+        root: ast::Expr,
+    ) -> Result<Vec<Let>, IrError> {
+        let mut result = Vec::new();
         let mut cnt = 0;
-        let mut new_name = || {
-            cnt += 1;
-            format!("{}_{}", cnt, result_identifier)
-        };
-        while !stack.is_empty() {
-            let (name, current) = stack.pop().unwrap();
-
-            match current {
-                AstNode::Binary(left, op, right) => {
-                    let is_left_leaf = is_leaf(&left);
-                    let is_right_leaf = is_leaf(&right);
-
-                    if is_left_leaf && is_right_leaf {
-                        let left = Self::ast_node_to_value(*left).unwrap();
-                        let right = Self::ast_node_to_value(*right).unwrap();
-
-                        result.push(Let::new(Identifier::new(name), Some(Expr::Binary(left, op, right))));
-                    } else if !is_right_leaf && !is_right_leaf {
-                        let left_name = new_name();
-                        let right_name = new_name();
-
-                        result.push(Let::new(
-                            Identifier::new(name),
-                            Some(Expr::Binary(Value::Identifier(left_name.clone()), op, Value::Identifier(right_name.clone()))),
-                        ));
-                        stack.push((left_name, *left));
-                        stack.push((right_name, *right));
-                    } else if !is_left_leaf {
-                        let right = Self::ast_node_to_value(*right).unwrap();
-                        let left_name = new_name();
-                        result.push(Let::new(
-                            Identifier::new(name),
-                            Some(Expr::Binary(Value::Identifier(left_name.clone()), op, right)),
-                        ));
-                        stack.push((left_name, *left))
-                    } else if !is_right_leaf {
-                        todo!();
-                    }
-                }
-                AstNode::Unary(op, node) => {
-                    let node_is_leaf = is_leaf(&node);
-                    if node_is_leaf {
-                        let val = Self::ast_node_to_value(*node).unwrap();
-                        result.push(Let::new(Identifier::new(name), Some(Expr::Unary(op, val))));
-                    } else {
-                        todo!();
-                    }
-                }
-                AstNode::Number(num) => result.push(Let::new(Identifier::new(name), Some(Expr::Single(Value::Number(num))))),
-                AstNode::String(_) => todo!(),
-                AstNode::Name(name) => result.push(Let::new(Identifier::new(name.clone()), Some(Expr::Single(Value::Identifier(name))))),
-                AstNode::ExprList(_) => todo!(),
-                AstNode::Assign(_, _) => todo!(),
-                AstNode::FunctionCall(_, _) => todo!(),
-                AstNode::Let(_, _, _)
-                | AstNode::Return(_)
-                | AstNode::Module(_)
-                | AstNode::StatList(_)
-                | AstNode::Function(_, _, _)
-                | AstNode::If(_, _, _)
-                | AstNode::ExprThen(_, _)
-                | AstNode::ElseIf(_, _, _)
-                | AstNode::Else(_)
-                | AstNode::For(_, _, _)
-                | AstNode::While(_, _) => todo!(),
+        let ty = Self::type_of(typing, root.node());
+        let expr = Self::expr(prefix, stable, typing, result_identifier, &mut cnt, root, &mut result)?;
+        result.push(Let::new(Identifier::new(result_identifier.to_string()), ty, Some(expr)));
+        Ok(result)
+    }
+
+    /// Like [`Block::expr_to_ssa`], but names and literals are used as they are rather than
+    /// copied into `result_identifier`.
+    fn value_to_ssa(
+        prefix: &str,
+        stable: &BTreeMap<Identifier, SymbolData>,
+        typing: &Typing,
+        result_identifier: &str,
+        root: ast::Expr,
+    ) -> Result<(Vec<Let>, Value), IrError> {
+        match Self::leaf_to_value(prefix, stable, &root)? {
+            Some(val) => Ok((Vec::new(), val)),
+            None => {
+                let lets = Self::expr_to_ssa(prefix, stable, typing, result_identifier, root)?;
+                Ok((lets, Value::Identifier(result_identifier.to_string())))
             }
         }
-        result.reverse();
-        result
     }
 
-    pub fn parse_assign(_name: Box<AstNode>, _val: Option<Box<AstNode>>) {
-        info!("parsing assing");
+    fn expr(
+        prefix: &str,
+        stable: &BTreeMap<Identifier, SymbolData>,
+        typing: &Typing,
+        result_identifier: &str,
+        cnt: &mut usize,
+        node: ast::Expr,
+        result: &mut Vec<Let>,
+    ) -> Result<Expr, IrError> {
+        let missing = || IrError::from(format!("Incomplete expression in {}.", result_identifier));
+        let mut value = |node: Option<ast::Expr>, result: &mut Vec<Let>| -> Result<Value, IrError> {
+            let node = node.ok_or_else(missing)?;
+            if let Some(val) = Self::leaf_to_value(prefix, stable, &node)? {
+                return Ok(val);
+            }
+            *cnt += 1;
+            let name = format!("{}.{}_{}", prefix, cnt, result_identifier.rsplit('.').next().unwrap_or_default());
+            let ty = Self::type_of(typing, node.node());
+            let expr = Self::expr(prefix, stable, typing, result_identifier, cnt, node, result)?;
+            result.push(Let::new(Identifier::new(name.clone()), ty, Some(expr)));
+            Ok(Value::Identifier(name))
+        };
+        Ok(match node {
+            ast::Expr::Binary(e) => {
+                let left = value(e.lhs(), result)?;
+                let right = value(e.rhs(), result)?;
+                Expr::Binary(left, e.op(), right)
+            }
+            ast::Expr::Unary(e) => Expr::Unary(e.op(), value(e.operand(), result)?),
+            ast::Expr::Call(e) => {
+                let callee = match e.callee() {
+                    Some(ast::Expr::Name(name)) => Self::resolve(prefix, stable, name.name())?,
+                    _ => return Err(IrError::from("Only named functions can be called.".to_string())),
+                };
+                let mut args = Vec::new();
+                for arg in e.arguments() {
+                    args.push(value(Some(arg), result)?);
+                }
+                Expr::Call(callee, args)
+            }
+            ast::Expr::Member(_) => return Err(IrError::from("Member access is not supported.".to_string())),
+            ast::Expr::Nil(_) => return Err(IrError::from("Nil is not supported.".to_string())),
+            leaf => Expr::Single(Self::leaf_to_value(prefix, stable, &leaf)?.ok_or_else(missing)?),
+        })
     }
-
-    pub fn add_if(&mut self, _val: AstNode) {}
 }
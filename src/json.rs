@@ -1,6 +1,7 @@
 use log::{info, trace, warn};
 
 use crate::fern::{FernLexer, FernParseTree};
+use crate::grammar::compiled::{CompiledGrammar, CompiledLexTable};
 use crate::grammar::lg::{self, LexingTable, LookupResult, State, Token};
 use crate::grammar::opg::{OpGrammar, RawGrammar};
 use crate::lexer::{Data, LexerError, LexerInterface, ParallelLexer};
@@ -32,7 +33,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     let dfa = nfa.convert_to_dfa();
     let mut f = File::create("dfa.dot").unwrap();
     lg::render(&dfa, &mut f);
-    let table = dfa.build_table();
+    let table = CompiledLexTable::new(dfa.build_table());
     let lg = lg.elapsed();
 
     let lex_time = Instant::now();
@@ -41,7 +42,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
         let mut mmap: memmap::Mmap = unsafe { MmapOptions::new().map(&file)? };
         let chunks = split_file_into_chunks(&mmap, 10000).unwrap();
         thread::scope(|s| {
-            let mut lexer: ParallelLexer<JsonLexer> = ParallelLexer::new(table.clone(), s, 4);
+            let mut lexer: ParallelLexer<JsonLexer> = ParallelLexer::new(&table, s, 4);
             let batch = lexer.new_batch();
             for task in chunks.iter().enumerate() {
                 lexer.add_to_batch(&batch, task.1, task.0);
@@ -63,7 +64,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     let grammar_time = Instant::now();
     let mut raw = RawGrammar::from("data/grammar/json.g", table.terminal_map.clone())?;
     raw.delete_repeated_rhs()?;
-    let grammar = OpGrammar::new(raw)?;
+    let grammar = CompiledGrammar::new(OpGrammar::new(raw)?);
     let grammar_time = grammar_time.elapsed();
     grammar.to_file("data/grammar/json-fnf.g");
 
@@ -71,9 +72,9 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
     let tree: ParseTree = {
         let mut trees = Vec::new();
         for (partial_tokens, partial_data) in tokens {
-            let mut parser = Parser::new(grammar.clone());
-            parser.parse(partial_tokens, partial_data);
-            parser.parse(vec![grammar.delim], Vec::new());
+            let mut parser = Parser::new(&grammar);
+            let _ = parser.parse(partial_tokens, partial_data);
+            let _ = parser.parse(vec![grammar.delim], Vec::new());
             trees.push(parser.collect_parse_tree().unwrap());
         }
 
@@ -100,7 +101,7 @@ pub fn compile() -> Result<(), Box<dyn Error>> {
 }
 
 pub struct JsonLexer {
-    pub table: LexingTable,
+    pub table: CompiledLexTable,
     pub start_state: State,
     pub state: State,
     pub buf: String,
@@ -111,10 +112,10 @@ pub struct JsonLexer {
 }
 
 impl LexerInterface for JsonLexer {
-    fn new(table: LexingTable, start_state: usize) -> Self {
-        let whitespace_token = table.terminal_map.iter().position(|x| x == "WHITESPACE").unwrap();
+    fn new(table: &CompiledLexTable, start_state: usize) -> Self {
+        let whitespace_token = table.terminal("WHITESPACE").unwrap();
         Self {
-            table,
+            table: table.clone(),
             whitespace_token,
             had_whitespace: false,
             tokens: Vec::new(),
@@ -137,6 +138,7 @@ impl LexerInterface for JsonLexer {
                         self.data.push(Data {
                             token_index: self.tokens.len() - 1,
                             raw: self.buf.clone(),
+                            ..Default::default()
                         });
                         self.had_whitespace = false;
                     } else {
@@ -1,5 +1,5 @@
+use crate::grammar::compiled::CompiledLexTable;
 use crate::grammar::lg::LexicalGrammar;
-use crate::grammar::lg::LexingTable;
 use crate::grammar::lg::LookupResult;
 use crate::grammar::lg::State;
 use crate::grammar::lg::Token;
@@ -12,7 +12,7 @@ use crossbeam_skiplist::SkipMap;
 use log::info;
 use log::trace;
 use log::warn;
-use std::collections::{HashMap, LinkedList};
+use std::collections::{BTreeSet, HashMap, LinkedList};
 use std::error::Error;
 use std::fmt::Debug;
 use std::fmt::{Display, Formatter};
@@ -47,10 +47,17 @@ impl<'a> Display for LexerError {
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
 pub struct Data {
     pub raw: String,
     pub token_index: usize,
+    /// Whitespace, comments and unlexable input between the previous token's trailing trivia
+    /// and this token. Only filled in by lexers that keep trivia.
+    pub leading: String,
+    /// Trivia after the token, up to and including the end of its line.
+    pub trailing: String,
+    /// The token was inserted by the lexer and `raw` is not part of the input.
+    pub inserted: bool,
 }
 
 pub struct LexerOutput {
@@ -63,6 +70,7 @@ pub struct LexerPartialOutput {
     data: Vec<Data>,
     finish_state: State,
     success: bool,
+    trivia: String,
 }
 
 pub struct WorkUnit<'a>(usize, &'a [u8], Arc<SkipMap<usize, RwLock<LexerOutput>>>);
@@ -78,12 +86,20 @@ pub struct ParallelLexer<'a, Lexer> {
 
 pub struct Batch {
     output: Arc<SkipMap<usize, RwLock<LexerOutput>>>,
-    size: usize,
+    orders: BTreeSet<usize>,
 }
 
 pub trait LexerInterface {
-    fn new(table: LexingTable, start_state: usize) -> Self;
+    fn new(table: &CompiledLexTable, start_state: usize) -> Self;
     fn consume(&mut self, c: u8) -> Result<(), LexerError>;
+    /// Emit the token still being lexed at the end of the input.
+    fn finish(&mut self) -> Result<(), LexerError> {
+        self.consume(b' ')
+    }
+    /// Trivia of a chunk that had no token to keep it, read before [`LexerInterface::take`].
+    fn orphaned_trivia(&self) -> String {
+        String::new()
+    }
     fn take(self) -> (State, Vec<Token>, Vec<Data>);
 }
 
@@ -91,7 +107,7 @@ impl<'a, Lexer> ParallelLexer<'a, Lexer>
 where
     Lexer: LexerInterface,
 {
-    pub fn new(table: LexingTable, scope: &'a Scope<'a, '_>, threads: usize) -> Self {
+    pub fn new(table: &CompiledLexTable, scope: &'a Scope<'a, '_>, threads: usize) -> Self {
         let new_queue: Arc<SegQueue<WorkUnit>> = Arc::new(SegQueue::new());
         let (send, recv) = crossbeam_channel::bounded(threads);
         let outputs: HashMap<String, Batch> = HashMap::new();
@@ -114,7 +130,7 @@ where
                         if let Some(task) = task {
                             let mut lexers: Vec<(Lexer, usize, bool)> = Vec::new();
                             for state in &start_states {
-                                lexers.push((Lexer::new(grammar.clone(), *state), *state, true));
+                                lexers.push((Lexer::new(&grammar, *state), *state, true));
                             }
 
                             for c in task.1 {
@@ -127,13 +143,12 @@ where
                                 }
                             }
 
-                            // Send whitespace to make sure any tokens at then end of the chunk
-                            // so that any remaining tokens in the buffer actually get created.
-                            // This is okay because we split up the input string on word boundaries so
-                            // adding whitespace should make no difference.
+                            // Make sure any remaining tokens in the buffer at the end of the chunk
+                            // actually get created. This is okay because we split up the input
+                            // string on word boundaries.
                             for (lexer, _, is_successful) in &mut lexers {
                                 if *is_successful {
-                                    if let Err(_) = lexer.consume(' ' as u8) {
+                                    if let Err(_) = lexer.finish() {
                                         *is_successful = false;
                                     }
                                 }
@@ -141,6 +156,7 @@ where
 
                             let mut map: HashMap<usize, LexerPartialOutput> = HashMap::new();
                             for (lexer, start_state, is_successful) in lexers {
+                                let trivia = lexer.orphaned_trivia();
                                 let (finish_state, tokens, data) = lexer.take();
                                 map.insert(
                                     start_state,
@@ -149,6 +165,7 @@ where
                                         finish_state,
                                         list: tokens,
                                         data,
+                                        trivia,
                                     },
                                 );
                             }
@@ -199,7 +216,7 @@ where
             key.clone(),
             Batch {
                 output: Arc::new(SkipMap::new()),
-                size: 0,
+                orders: BTreeSet::new(),
             },
         );
         key
@@ -207,7 +224,7 @@ where
 
     pub fn add_to_batch(&mut self, id: &String, input: &'a [u8], order: usize) {
         let batch = self.outputs.get_mut(id).unwrap();
-        batch.size += 1;
+        batch.orders.insert(order);
         self.new_queue.push(WorkUnit(order, input, (*batch).output.clone()));
         for (_, unparker) in &mut self.handles {
             unparker.unpark();
@@ -223,49 +240,64 @@ where
     }
 
     pub fn collect_batch(&mut self, id: String) -> LinkedList<(Vec<usize>, Vec<Data>)> {
-        let batch: Batch = self.outputs.remove(id.as_str()).unwrap();
-
-        // Spin until threads have finished lexing.
-        while batch.size != batch.output.len() {}
-
-        // Append first item in list to output
         let mut result: LinkedList<(Vec<Token>, Vec<Data>)> = LinkedList::new();
+        self.drain_batch(id, |chunk| result.push_back(chunk));
+        result
+    }
 
-        // For some unknown (probably data-race) reason, if there is only one thread,
-        // it will intermittently fail to pop the top of the skiplist even though its
-        // .len() function shows that its not empty. Keeping pop'in till were not nothing.
-        let mut first = batch.output.pop_front();
-        while first.is_none() {
-            first = batch.output.pop_front();
-        }
+    /// Send the chunks of a batch to `sender` in order, each as soon as it and every chunk
+    /// before it have been lexed, so that a parser can start before the whole batch is done.
+    /// The channel is closed once the last chunk is sent.
+    pub fn stream_batch(&mut self, id: String, sender: crossbeam_channel::Sender<(Vec<Token>, Vec<Data>)>) {
+        self.drain_batch(id, |chunk| {
+            if sender.send(chunk).is_err() {
+                warn!("Receiver of a streamed batch hung up.");
+            }
+        });
+    }
 
-        let first = first.unwrap();
-        let mut first = first.value().write().unwrap().lists.take().unwrap();
-        let start_state_output = first.remove(&self.initial_state).unwrap();
-        result.push_back((start_state_output.list, start_state_output.data));
+    /// Hand over the output of each chunk in order, picking the list lexed from the state the
+    /// previous chunk finished in. Chunks without tokens are not handed over, their trivia goes
+    /// to the last token before them, so each chunk is held back until the next one is lexed.
+    fn drain_batch<F: FnMut((Vec<Token>, Vec<Data>))>(&mut self, id: String, mut f: F) {
+        let batch: Batch = self.outputs.remove(id.as_str()).unwrap();
 
         let mut previous_finish_state = self.initial_state;
-        for x in batch.output.iter() {
-            let mut val = x.value().write().unwrap();
-            let mut found_match = false;
-            for (start_state, partial_output) in val.lists.take().unwrap() {
-                trace!("Checking {:?} -> {:?} : ", previous_finish_state, start_state);
-                if previous_finish_state == start_state {
-                    trace!("yes");
-
-                    found_match = true;
+        let mut held: Option<(Vec<Token>, Vec<Data>)> = None;
+        // Trivia of token-less chunks before the first token.
+        let mut carried = String::new();
+        for order in &batch.orders {
+            // Spin until a thread has finished lexing this chunk.
+            let entry = loop {
+                if let Some(entry) = batch.output.get(order) {
+                    break entry;
+                }
+                thread::yield_now();
+            };
+            let mut lists = entry.value().write().unwrap().lists.take().unwrap();
+            trace!("Looking for chunk {} lexed from {:?}", order, previous_finish_state);
+            match lists.remove(&previous_finish_state) {
+                Some(mut partial_output) => {
                     previous_finish_state = partial_output.finish_state;
-                    result.push_back((partial_output.list, partial_output.data));
-                    break;
-                } else {
-                    trace!("no");
+                    if partial_output.data.is_empty() {
+                        match held.as_mut().and_then(|(_, data)| data.last_mut()) {
+                            Some(last) => last.trailing.push_str(&partial_output.trivia),
+                            None => carried.push_str(&partial_output.trivia),
+                        }
+                        continue;
+                    }
+                    partial_output.data[0].leading.insert_str(0, &carried);
+                    carried.clear();
+                    if let Some(chunk) = held.replace((partial_output.list, partial_output.data)) {
+                        f(chunk);
+                    }
                 }
+                None => panic!("ERROR: finished on {:?}", previous_finish_state),
             }
-            if !found_match {
-                panic!("ERROR: finished on {:?}", previous_finish_state);
-            }
         }
-        return result;
+        if let Some(chunk) = held {
+            f(chunk);
+        }
     }
 
     pub fn kill(mut self) {
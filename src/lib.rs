@@ -12,11 +12,23 @@ use std::thread;
 use std::time::{Duration, Instant};
 extern crate console_error_panic_hook;
 
+pub mod analysis;
+pub mod ast;
+pub mod cst;
 pub mod fern;
+pub mod fmt;
 pub mod grammar;
+pub mod ir;
 pub mod lexer;
+pub mod lower;
+pub mod lsp;
 pub mod parser;
 pub mod parsetree;
+pub mod pretty;
+pub mod query;
+pub mod serialize;
+pub mod trace;
+pub mod types;
 
 use grammar::lg;
 use log::{debug, info};
@@ -59,7 +71,6 @@ pub fn split_file_into_chunks<'a>(mmap: &'a memmap::Mmap, step: usize) -> Result
 #[cfg(not(target_arch = "wasm32"))]
 mod json;
 
-#[cfg(target_arch = "wasm32")]
 const COMP_TIME_GRAMMAR: &'static str = include_str!("../data/grammar/fern.g");
 const COMP_TIME_LEXICAL_GRAMMAR: &'static str = include_str!("../data/grammar/fern.lg");
 const COMP_TIME_KEYWORD_LEXICAL_GRAMMAR: &'static str = include_str!("../data/grammar/keywords.lg");
@@ -171,12 +182,13 @@ pub fn compile_fern(input: &str) -> String {
 
     let name_token = table.terminal_map.iter().position(|x| x == "NAME").unwrap();
     table.add_table(name_token, keywords);
+    let table = grammar::compiled::CompiledLexTable::new(table);
 
     let mut raw = grammar::opg::RawGrammar::new(COMP_TIME_GRAMMAR, table.terminal_map.clone()).unwrap();
     raw.delete_repeated_rhs().unwrap();
-    let grammar = grammar::opg::OpGrammar::new(raw).unwrap();
+    let grammar = grammar::compiled::CompiledGrammar::new(grammar::opg::OpGrammar::new(raw).unwrap());
 
-    let mut lexer: fern::FernLexer = fern::FernLexer::new(table.clone(), 0);
+    let mut lexer: fern::FernLexer = fern::FernLexer::new(&table, 0);
     for c in input.chars() {
         lexer.consume(c as u8).unwrap();
     }
@@ -184,9 +196,13 @@ pub fn compile_fern(input: &str) -> String {
 
     let tree: parsetree::ParseTree = {
         let mut trees = Vec::new();
-        let mut parser = parser::Parser::new(grammar.clone());
-        parser.parse(tokens.clone(), data);
-        parser.parse(vec![grammar.delim], Vec::new());
+        let mut parser = parser::Parser::new(&grammar);
+        let _ = parser.parse(tokens.clone(), data);
+        let _ = parser.parse(vec![grammar.delim], Vec::new());
+        let errors: Vec<String> = parser.errors().iter().map(|e| e.to_string()).collect();
+        if !errors.is_empty() {
+            return object! { tokens: "", ptree: "", ast: "", analysis: errors }.to_string();
+        }
         trees.push(parser.collect_parse_tree().unwrap());
 
         trees.reverse();
@@ -198,6 +214,7 @@ pub fn compile_fern(input: &str) -> String {
     };
 
     tree.print();
+    let ptree_json = tree.to_json();
     let mut result = BufWriter::new(Vec::new());
     tree.dot(&mut result).unwrap();
     let bytes = result.into_inner().unwrap();
@@ -205,6 +222,7 @@ pub fn compile_fern(input: &str) -> String {
 
     let ast: fern::FernAst = tree.into();
     ast.print();
+    let ast_json = ast.to_json();
     let analysis_output = ast.analysis();
 
     let mut result = BufWriter::new(Vec::new());
@@ -221,6 +239,8 @@ pub fn compile_fern(input: &str) -> String {
         tokens: tokens_string,
         ptree: tree_string,
         ast: ast_string,
+        ptree_json: ptree_json,
+        ast_json: ast_json,
         analysis: analysis_output
     };
     info!("{}", output);
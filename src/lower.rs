@@ -0,0 +1,444 @@
+use crate::ast::AstBuilder;
+use crate::fern::{AstNodeKind, FernAst, OPERATORS};
+use crate::grammar::annotation::annotate;
+use crate::grammar::opg::OpGrammar;
+use crate::parsetree::ParseTree;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+/// A construct the grammar accepts but the AST has no place for, such as assigning to a number,
+/// or one the parser recovered from. It is left out of the AST, along with the statement it is
+/// in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LowerError {
+    /// Tokens of the construct, in tokens passed to the parser.
+    pub span: Range<usize>,
+    message: String,
+}
+
+impl Error for LowerError {}
+
+impl LowerError {
+    fn new(span: Range<usize>, what: &str) -> Self {
+        LowerError {
+            message: format!("{} at token {}.", what, span.start),
+            span,
+        }
+    }
+}
+
+impl Display for LowerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lower Error: {}", self.message)
+    }
+}
+
+/// The statement being lowered was abandoned, and a [`LowerError`] says why.
+struct Skipped;
+
+type Lowered = Result<(), Skipped>;
+
+/// Lower a parse tree of Fern source to an AST rooted at a `Module`, as the annotations in
+/// fern.g describe it. `grammar` is the one the tree was parsed with.
+///
+/// On top of what [`annotate`] builds, member chains such as `a.b.c`, which the parser nests
+/// as `a.(b.c)`, are turned around into `(a.b).c`. Statements that cannot be lowered are left
+/// out and reported, and so are the syntax errors the parser recovered from, though the whole
+/// statements around them are kept.
+pub fn lower(grammar: &OpGrammar, tree: &ParseTree) -> (FernAst, Vec<LowerError>) {
+    let (annotated, spans) = annotate(grammar, tree);
+    let mut lowering = Lowering {
+        nodes: Vec::with_capacity(annotated.nodes.len()),
+        out: AstBuilder::new(),
+        errors: Vec::new(),
+    };
+    // Nodes still waiting for children, which come right after them in pre-order.
+    let mut open: Vec<usize> = Vec::new();
+    for (id, (node, span)) in annotated.nodes.iter().zip(spans).enumerate() {
+        if let Some(&parent) = open.last() {
+            lowering.nodes[parent].children.push(id);
+        }
+        lowering.nodes.push(Annotated {
+            name: annotated.token_map.get(&node.token).cloned().unwrap_or_default(),
+            raw: node.data.as_ref().map(|d| d.raw.clone()),
+            span,
+            children: Vec::with_capacity(node.child_count),
+        });
+        open.push(id);
+        while open
+            .last()
+            .is_some_and(|n| lowering.nodes[*n].children.len() == annotated.nodes[*n].child_count)
+        {
+            open.pop();
+        }
+    }
+
+    let mut chained = vec![false; lowering.nodes.len()];
+    for id in 0..lowering.nodes.len() {
+        if lowering.kind(id) == "Member" && !chained[id] {
+            for m in lowering.reassociate(id) {
+                chained[m] = true;
+            }
+        }
+    }
+
+    if lowering.nodes.is_empty() {
+        lowering.out.leaf(AstNodeKind::Module);
+    } else {
+        lowering.block(0);
+    }
+    let mut ast = lowering.out.finish();
+    ast.token_map = tree.token_map.clone();
+    (ast, lowering.errors)
+}
+
+/// The kind of a node fern.g names `name`. Operators are named after their [`OperatorKind`],
+/// and leaves have the text of their token.
+fn annotated_kind(name: &str, raw: Option<&str>) -> Option<AstNodeKind> {
+    if let Some(op) = OPERATORS.iter().find(|op| format!("{:?}", op) == name) {
+        return Some(AstNodeKind::Operator(*op));
+    }
+    Some(match (name, raw) {
+        ("Number", Some(raw)) => AstNodeKind::Number(raw.to_string()),
+        ("String", Some(raw)) => AstNodeKind::String(raw.to_string()),
+        ("Name", Some(raw)) => AstNodeKind::Name(raw.to_string()),
+        ("Bool", Some(raw)) => AstNodeKind::Bool(raw == "true"),
+        ("Nil", _) => AstNodeKind::Nil,
+        ("Member", _) => AstNodeKind::Member,
+        ("Field", _) => AstNodeKind::Field,
+        ("ExprList", _) => AstNodeKind::ExprList,
+        ("FieldList", _) => AstNodeKind::FieldList,
+        ("Assign", _) => AstNodeKind::Assign,
+        ("Let", _) => AstNodeKind::Let,
+        ("LetAssign", _) => AstNodeKind::LetAssign,
+        ("Return", _) => AstNodeKind::Return,
+        ("Module", _) => AstNodeKind::Module,
+        ("StatList", _) => AstNodeKind::StatList,
+        ("FunctionCall", _) => AstNodeKind::FunctionCall,
+        ("Function", _) => AstNodeKind::Function,
+        ("If", _) => AstNodeKind::If,
+        ("ElseIf", _) => AstNodeKind::ElseIf,
+        ("Else", _) => AstNodeKind::Else,
+        ("For", _) => AstNodeKind::For,
+        ("While", _) => AstNodeKind::While,
+        ("Struct", _) => AstNodeKind::Struct,
+        _ => return None,
+    })
+}
+
+/// A node of the annotated AST, with its children by index.
+struct Annotated {
+    name: String,
+    raw: Option<String>,
+    span: Option<Range<usize>>,
+    children: Vec<usize>,
+}
+
+/// Kinds that can be statements of a block.
+const STATEMENTS: &[&str] = &[
+    "StatList",
+    "Assign",
+    "FunctionCall",
+    "Return",
+    "While",
+    "If",
+    "Struct",
+    "Function",
+    "For",
+    "Let",
+    "LetAssign",
+];
+
+struct Lowering {
+    nodes: Vec<Annotated>,
+    out: AstBuilder,
+    errors: Vec<LowerError>,
+}
+
+impl Lowering {
+    fn kind(&self, id: usize) -> &str {
+        &self.nodes[id].name
+    }
+
+    fn children(&self, id: usize) -> Vec<usize> {
+        self.nodes[id].children.clone()
+    }
+
+    fn unsupported(&mut self, id: usize, what: &str) -> Skipped {
+        let span = self.nodes[id].span.clone().unwrap_or(0..0);
+        self.errors.push(LowerError::new(span, what));
+        Skipped
+    }
+
+    /// Nest the member chain under `top` from the left, whichever way the parser nested it, and
+    /// return its `Member` nodes. `top` stays the outermost one.
+    fn reassociate(&mut self, top: usize) -> Vec<usize> {
+        let mut members = Vec::new();
+        let mut operands = Vec::new();
+        let mut stack = vec![top];
+        while let Some(id) = stack.pop() {
+            match self.nodes[id].children.as_slice() {
+                &[lhs, rhs] if self.kind(id) == "Member" => {
+                    members.push(id);
+                    stack.push(rhs);
+                    stack.push(lhs);
+                }
+                _ => operands.push(id),
+            }
+        }
+        members.rotate_left(1);
+        let mut lhs = operands[0];
+        for (&m, &rhs) in members.iter().zip(&operands[1..]) {
+            if let (Some(l), Some(r), true) = (&self.nodes[lhs].span, &self.nodes[rhs].span, m != top) {
+                self.nodes[m].span = Some(l.start..r.end);
+            }
+            self.nodes[m].children = vec![lhs, rhs];
+            lhs = m;
+        }
+        members
+    }
+
+    /// Start the node `id` in the AST.
+    fn open(&mut self, id: usize) -> Lowered {
+        let node = &self.nodes[id];
+        let Some(kind) = annotated_kind(&node.name, node.raw.as_deref()) else {
+            return Err(self.unsupported(id, &format!("Unknown node kind {}", self.kind(id))));
+        };
+        self.out.open(kind);
+        if let Some(span) = self.nodes[id].span.clone() {
+            self.out.span(span);
+        }
+        Ok(())
+    }
+
+    /// A `Module` or `StatList`, with the statements in it that can be lowered.
+    fn block(&mut self, id: usize) {
+        match self.kind(id) {
+            "Module" | "StatList" => {
+                let _ = self.open(id);
+            }
+            _ => self.out.open(AstNodeKind::Module),
+        }
+        for s in self.children(id) {
+            self.item(s);
+        }
+        self.out.close();
+    }
+
+    /// An item of a block. An `Error` node that starts with a token the annotations leave out,
+    /// such as the `fn` of a function, is a construct the parser gave up on, and is reported and
+    /// left out whole. Any other holds the statements around a syntax error, which are kept, and
+    /// the syntax error is reported once.
+    fn item(&mut self, id: usize) {
+        match self.kind(id) {
+            "Error" => {
+                let c = self.children(id);
+                let start = |span: &Option<Range<usize>>| span.as_ref().map(|s| s.start);
+                let first = c.first().and_then(|&f| start(&self.nodes[f].span));
+                if first.is_none() || first != start(&self.nodes[id].span) {
+                    self.unsupported(id, "Syntax error");
+                    return;
+                }
+                let mut reported = false;
+                for c in c {
+                    match self.kind(c) {
+                        "Error" => {
+                            self.item(c);
+                            reported = true;
+                        }
+                        k if STATEMENTS.contains(&k) => self.item(c),
+                        _ if !reported => {
+                            self.unsupported(c, "Syntax error");
+                            reported = true;
+                        }
+                        _ => (),
+                    }
+                }
+                if !reported {
+                    self.unsupported(id, "Syntax error");
+                }
+            }
+            k if STATEMENTS.contains(&k) => {
+                let checkpoint = self.out.checkpoint();
+                if self.statement(id).is_err() {
+                    self.out.rewind(checkpoint);
+                }
+            }
+            _ => {
+                self.unsupported(id, "Expected a statement");
+            }
+        }
+    }
+
+    fn statement(&mut self, id: usize) -> Lowered {
+        let c = self.children(id);
+        match self.kind(id) {
+            "StatList" => {
+                self.block(id);
+                return Ok(());
+            }
+            "Assign" => {
+                if !matches!(self.kind(c[0]), "Name" | "Member") {
+                    return Err(self.unsupported(c[0], "Only names and fields can be assigned to"));
+                }
+                self.open(id)?;
+                self.expr(c[0])?;
+                self.expr(c[1])?;
+            }
+            "FunctionCall" => return self.call(id),
+            "Return" => {
+                self.open(id)?;
+                if let Some(&value) = c.first() {
+                    self.values(value)?;
+                }
+            }
+            "While" => {
+                self.open(id)?;
+                self.expr(c[0])?;
+                self.block(c[1]);
+            }
+            // `if` and `elseif`, which continue the same way.
+            "If" | "ElseIf" => {
+                self.open(id)?;
+                self.expr(c[0])?;
+                self.block(c[1]);
+                if let Some(&branch) = c.get(2) {
+                    self.statement(branch)?;
+                }
+            }
+            "Else" => {
+                self.open(id)?;
+                self.block(c[0]);
+            }
+            "Struct" => {
+                self.open(id)?;
+                self.name(c[0])?;
+                self.fields(c[1], false)?;
+            }
+            "Function" => {
+                self.open(id)?;
+                self.name(c[0])?;
+                self.fields(c[1], true)?;
+                if let [_, _, ty, _] = c.as_slice() {
+                    self.name(*ty)?;
+                }
+                self.block(c[c.len() - 1]);
+            }
+            // Several names or values become an `ExprList`.
+            "For" => {
+                self.open(id)?;
+                if self.kind(c[0]) == "ExprList" {
+                    self.open(c[0])?;
+                    for n in self.children(c[0]) {
+                        self.name(n)?;
+                    }
+                    self.out.close();
+                } else {
+                    self.name(c[0])?;
+                }
+                self.values(c[1])?;
+                self.block(c[2]);
+            }
+            // `let name`, with a type in a `Field` as in `let name: type`, and a value after `=`.
+            "Let" | "LetAssign" => {
+                self.open(id)?;
+                if self.kind(c[0]) == "Field" {
+                    self.field(c[0], false)?;
+                } else {
+                    self.name(c[0])?;
+                }
+                if let Some(&value) = c.get(1) {
+                    self.expr(value)?;
+                }
+            }
+            "Error" => return Err(self.unsupported(id, "Syntax error")),
+            _ => return Err(self.unsupported(id, "Expected a statement")),
+        }
+        self.out.close();
+        Ok(())
+    }
+
+    /// A `FieldList` of `name: type` fields. Parameters may leave out the type.
+    fn fields(&mut self, id: usize, untyped: bool) -> Lowered {
+        self.open(id)?;
+        for f in self.children(id) {
+            self.field(f, untyped)?;
+        }
+        self.out.close();
+        Ok(())
+    }
+
+    fn field(&mut self, id: usize, untyped: bool) -> Lowered {
+        match (self.kind(id), self.nodes[id].children.len()) {
+            ("Field", 2) => (),
+            ("Field", 1) if untyped => (),
+            ("Error", _) => return Err(self.unsupported(id, "Syntax error")),
+            _ => return Err(self.unsupported(id, "Expected a field with a type")),
+        }
+        self.open(id)?;
+        for n in self.children(id) {
+            self.name(n)?;
+        }
+        self.out.close();
+        Ok(())
+    }
+
+    fn call(&mut self, id: usize) -> Lowered {
+        let c = self.children(id);
+        self.open(id)?;
+        self.expr(c[0])?;
+        self.open(c[1])?;
+        for a in self.children(c[1]) {
+            self.expr(a)?;
+        }
+        self.out.close();
+        self.out.close();
+        Ok(())
+    }
+
+    /// One expression, or an `ExprList` if there are several.
+    fn values(&mut self, id: usize) -> Lowered {
+        if self.kind(id) != "ExprList" {
+            return self.expr(id);
+        }
+        self.open(id)?;
+        for v in self.children(id) {
+            self.expr(v)?;
+        }
+        self.out.close();
+        Ok(())
+    }
+
+    fn name(&mut self, id: usize) -> Lowered {
+        match self.kind(id) {
+            "Name" => self.expr(id),
+            "Error" => Err(self.unsupported(id, "Syntax error")),
+            _ => Err(self.unsupported(id, "Expected a name")),
+        }
+    }
+
+    fn expr(&mut self, id: usize) -> Lowered {
+        let c = self.children(id);
+        match self.kind(id) {
+            "FunctionCall" => return self.call(id),
+            "Member" => {
+                self.open(id)?;
+                self.expr(c[0])?;
+                self.name(c[1])?;
+            }
+            "Name" | "Number" | "String" | "Bool" | "Nil" => self.open(id)?,
+            k if OPERATORS.iter().any(|op| format!("{:?}", op) == k) => {
+                self.open(id)?;
+                for operand in c {
+                    self.expr(operand)?;
+                }
+            }
+            "ExprList" => return Err(self.unsupported(id, "Expected one expression, found a list")),
+            "Error" => return Err(self.unsupported(id, "Syntax error")),
+            _ => return Err(self.unsupported(id, "Expected an expression")),
+        }
+        self.out.close();
+        Ok(())
+    }
+}
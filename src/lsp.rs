@@ -0,0 +1,699 @@
+use crate::analysis::Resolution;
+use crate::ast::{walk, AstView, ForStmt, FunctionDecl, LetStmt, NameExpr, Node, StructDecl, Visitor};
+use crate::fern::{self, FernAst};
+use crate::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use crate::lower;
+use crate::parser::Parser;
+use crossbeam_channel::Receiver;
+use json_parse::JsonValue;
+use log::{info, trace, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+#[derive(Debug)]
+pub struct LspError {
+    message: String,
+}
+
+impl Error for LspError {}
+
+impl LspError {
+    pub fn from(s: String) -> LspError {
+        LspError { message: s }
+    }
+}
+
+impl Display for LspError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LSP Error: {}", self.message)
+    }
+}
+
+const METHOD_NOT_FOUND: i32 = -32601;
+const SERVER_NOT_INITIALIZED: i32 = -32002;
+const REQUEST_CANCELLED: i32 = -32800;
+const CONTENT_MODIFIED: i32 = -32801;
+
+/// Semantic token types, in the order of the legend sent to the client.
+pub const TOKEN_TYPES: &[&str] = &[
+    "keyword",
+    "variable",
+    "function",
+    "struct",
+    "parameter",
+    "property",
+    "string",
+    "number",
+    "operator",
+    "comment",
+];
+pub const TOKEN_MODIFIERS: &[&str] = &["declaration"];
+
+const KEYWORDS: &[&str] = &[
+    "LET", "IF", "ELSE", "ELSEIF", "WHILE", "FOR", "IN", "FUNCTION", "STRUCT", "RETURN", "TRUE", "FALSE", "NIL", "BREAK",
+];
+const OPERATORS: &[&str] = &[
+    "EQ", "PLUS", "MINUS", "UMINUS", "ASTERISK", "DIVIDE", "PERCENT", "CARET", "DOT2", "LT", "GT", "LTEQ", "GTEQ", "EQDOUBLE", "NEQ", "AND", "OR", "NOT",
+    "BANG", "SHARP",
+];
+
+/// Read one message framed with a `Content-Length` header. `None` at the end of the input.
+pub fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<JsonValue>, LspError> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|e| LspError::from(e.to_string()))? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|e| LspError::from(format!("Bad Content-Length: {}", e)))?,
+            );
+        }
+    }
+    let length = length.ok_or_else(|| LspError::from(String::from("Message without a Content-Length header")))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| LspError::from(e.to_string()))?;
+    let body = String::from_utf8(body).map_err(|e| LspError::from(e.to_string()))?;
+    json_parse::parse(&body).map(Some).map_err(|e| LspError::from(e.to_string()))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &JsonValue) -> io::Result<()> {
+    let body = message.dump();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+fn response(id: &JsonValue, result: JsonValue) -> JsonValue {
+    object! { jsonrpc: "2.0", id: id.clone(), result: result }
+}
+
+fn error_response(id: &JsonValue, code: i32, message: &str) -> JsonValue {
+    object! { jsonrpc: "2.0", id: id.clone(), error: { code: code, message: message } }
+}
+
+/// Byte offsets of line starts, to convert to and from LSP positions, which count UTF-16 code
+/// units.
+struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        Self { starts }
+    }
+
+    fn position(&self, text: &str, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|s| *s <= offset) - 1;
+        let character = text[self.starts[line]..offset].encode_utf16().count();
+        (line, character)
+    }
+
+    fn offset(&self, text: &str, line: usize, character: usize) -> usize {
+        let Some(start) = self.starts.get(line).copied() else {
+            return text.len();
+        };
+        let mut units = 0;
+        for (i, c) in text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        text.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Struct,
+    Variable,
+    Parameter,
+    Field,
+}
+
+impl SymbolKind {
+    fn token_type(&self) -> usize {
+        match self {
+            SymbolKind::Variable => 1,
+            SymbolKind::Function => 2,
+            SymbolKind::Struct => 3,
+            SymbolKind::Parameter => 4,
+            SymbolKind::Field => 5,
+        }
+    }
+}
+
+/// A name declared in a document.
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    /// Token of the name where it is declared.
+    name_token: usize,
+    /// Tokens of the whole declaration.
+    span: Range<usize>,
+    signature: String,
+    /// The function or struct declared around this one.
+    parent: Option<usize>,
+}
+
+struct Diagnostic {
+    range: Range<usize>,
+    message: String,
+}
+
+/// A lexed token and the bytes of the document it was lexed from.
+struct DocumentToken {
+    kind: String,
+    bytes: Range<usize>,
+    inserted: bool,
+}
+
+/// An open document and everything computed from its text.
+pub struct Document {
+    text: String,
+    version: i64,
+    lines: LineIndex,
+    tokens: Vec<DocumentToken>,
+    comments: Vec<Range<usize>>,
+    symbols: Vec<Symbol>,
+    /// Name tokens and the token of the name each refers to, see [`Document::resolve`].
+    references: BTreeMap<usize, usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Tokens under `node`: its own span, or the tokens of its children for nodes without one.
+fn tokens(node: Node) -> Option<Range<usize>> {
+    node.span().or_else(|| {
+        let spans: Vec<Range<usize>> = node.descendants().filter_map(|n| n.span()).collect();
+        Some(spans.iter().map(|s| s.start).min()?..spans.iter().map(|s| s.end).max()?)
+    })
+}
+
+/// Name tokens of `ast` and the token of the name each refers to. Declarations refer to
+/// themselves.
+fn references(ast: &FernAst, resolution: &Resolution) -> BTreeMap<usize, usize> {
+    let token = |id| ast.node(id).span().map(|s| s.start);
+    let declarations = resolution.declarations().map(|(id, _)| (id, id));
+    declarations
+        .chain(resolution.uses())
+        .filter_map(|(name, declaration)| Some((token(name)?, token(declaration)?)))
+        .collect()
+}
+
+/// Collects the declarations of a lowered document.
+struct Symbols<'d> {
+    document: &'d Document,
+    symbols: Vec<Symbol>,
+    /// The function or struct the walk is in.
+    parent: Option<usize>,
+}
+
+impl Symbols<'_> {
+    fn declare(&mut self, name: Option<NameExpr>, node: Node, kind: SymbolKind, signature: String) -> Option<usize> {
+        let name_token = name?.node().span()?.start;
+        self.symbols.push(Symbol {
+            name: self.document.token_text(name_token).to_string(),
+            kind,
+            name_token,
+            span: tokens(node)?,
+            signature,
+            parent: self.parent,
+        });
+        Some(self.symbols.len() - 1)
+    }
+
+    /// Source of a function or struct up to its opening brace.
+    fn header(&self, node: Node) -> String {
+        let Some(span) = tokens(node) else {
+            return String::new();
+        };
+        let end = (span.start..span.end).find(|t| self.document.tokens[*t].kind == "LBRACE").unwrap_or(span.end);
+        self.document.span_text(&(span.start..end)).trim_end().to_string()
+    }
+
+    fn text(&self, node: Node) -> String {
+        tokens(node).map_or_else(String::new, |span| self.document.span_text(&span).to_string())
+    }
+}
+
+impl<'a> Visitor<'a> for Symbols<'_> {
+    fn visit_function(&mut self, function: FunctionDecl<'a>) {
+        let signature = self.header(function.node());
+        let Some(index) = self.declare(function.name(), function.node(), SymbolKind::Function, signature) else {
+            return;
+        };
+        let outer = self.parent.replace(index);
+        for p in function.params() {
+            let signature = self.text(p.node());
+            self.declare(p.name(), p.node(), SymbolKind::Parameter, signature);
+        }
+        if let Some(body) = function.body() {
+            self.visit_node(body);
+        }
+        self.parent = outer;
+    }
+
+    fn visit_struct(&mut self, declaration: StructDecl<'a>) {
+        let signature = self.header(declaration.node());
+        let Some(index) = self.declare(declaration.name(), declaration.node(), SymbolKind::Struct, signature) else {
+            return;
+        };
+        let outer = self.parent.replace(index);
+        for f in declaration.fields() {
+            let signature = self.text(f.node());
+            self.declare(f.name(), f.node(), SymbolKind::Field, signature);
+        }
+        self.parent = outer;
+    }
+
+    fn visit_let(&mut self, statement: LetStmt<'a>) {
+        let signature = self.text(statement.node());
+        self.declare(statement.name(), statement.node(), SymbolKind::Variable, signature);
+    }
+
+    fn visit_for(&mut self, statement: ForStmt<'a>) {
+        let signature = self.header(statement.node());
+        for name in statement.bindings() {
+            self.declare(Some(name), statement.node(), SymbolKind::Variable, signature.clone());
+        }
+        walk(self, statement.node());
+    }
+}
+
+impl Document {
+    pub fn new(table: &CompiledLexTable, grammar: &CompiledGrammar, text: String, version: i64) -> Self {
+        let mut document = Document {
+            lines: LineIndex::new(&text),
+            text,
+            version,
+            tokens: Vec::new(),
+            comments: Vec::new(),
+            symbols: Vec::new(),
+            references: BTreeMap::new(),
+            diagnostics: Vec::new(),
+        };
+        let (tokens, data, _) = match fern::lex(table, document.text.as_bytes()) {
+            Ok(lexed) => lexed,
+            Err(e) => {
+                document.diagnostics.push(Diagnostic {
+                    range: 0..0,
+                    message: e.to_string(),
+                });
+                return document;
+            }
+        };
+
+        let mut offset = 0;
+        for (token, d) in tokens.iter().zip(&data) {
+            document.trivia(offset, &d.leading);
+            offset += d.leading.len();
+            let start = offset;
+            if !d.inserted {
+                offset += d.raw.len();
+            }
+            document.tokens.push(DocumentToken {
+                kind: table.terminal_map[*token].clone(),
+                bytes: start..offset,
+                inserted: d.inserted,
+            });
+            document.trivia(offset, &d.trailing);
+            offset += d.trailing.len();
+        }
+
+        let mut parser = Parser::new(grammar);
+        let _ = parser.parse(tokens, data);
+        let _ = parser.parse(vec![grammar.delim], Vec::new());
+        for e in parser.errors() {
+            let range = match (document.tokens.get(e.span.start), e.span.is_empty()) {
+                (Some(t), false) => t.bytes.clone(),
+                _ => document.text.len()..document.text.len(),
+            };
+            document.diagnostics.push(Diagnostic { range, message: e.to_string() });
+        }
+        let clean = parser.errors().is_empty();
+        let tree = match parser.collect_parse_tree() {
+            Ok(tree) => tree.into_tree(),
+            Err(e) => {
+                warn!("No parse tree for the document: {}", e);
+                return document;
+            }
+        };
+        let (ast, errors) = lower::lower(grammar, &tree);
+        let resolution = ast.resolve();
+        document.symbols = document.collect_symbols(&ast);
+        document.references = references(&ast, &resolution);
+
+        if clean {
+            for e in errors {
+                let range = document.tokens.get(e.span.start).map_or(0..0, |t| t.bytes.clone());
+                document.diagnostics.push(Diagnostic { range, message: e.to_string() });
+            }
+            let typing = ast.check(&resolution);
+            let names = resolution.errors().iter().map(|e| (&e.span, e.to_string()));
+            for (span, message) in names.chain(typing.errors().iter().map(|e| (&e.span, e.to_string()))) {
+                let range = span.as_ref().and_then(|s| document.tokens.get(s.start)).map_or(0..0, |t| t.bytes.clone());
+                document.diagnostics.push(Diagnostic { range, message });
+            }
+        }
+        document
+    }
+
+    fn trivia(&mut self, offset: usize, trivia: &str) {
+        let mut start = 0;
+        while let Some(i) = trivia[start..].find("//") {
+            let begin = start + i;
+            let end = trivia[begin..].find('\n').map_or(trivia.len(), |e| begin + e);
+            self.comments.push(offset + begin..offset + end);
+            start = end;
+        }
+    }
+
+    fn token_text(&self, token: usize) -> &str {
+        &self.text[self.tokens[token].bytes.clone()]
+    }
+
+    /// Source of the tokens in `span`, from the start of the first to the end of the last.
+    fn span_text(&self, span: &Range<usize>) -> &str {
+        &self.text[self.tokens[span.start].bytes.start..self.tokens[span.end - 1].bytes.end]
+    }
+
+    fn collect_symbols(&self, ast: &FernAst) -> Vec<Symbol> {
+        let mut symbols = Symbols {
+            document: self,
+            symbols: Vec::new(),
+            parent: None,
+        };
+        ast.visit(&mut symbols);
+        symbols.symbols
+    }
+
+    /// The token under a cursor, preferring the one that ends at it when none starts there.
+    fn token_at(&self, offset: usize) -> Option<usize> {
+        let starting = self.tokens.iter().position(|t| !t.inserted && t.bytes.contains(&offset));
+        starting.or_else(|| self.tokens.iter().position(|t| !t.inserted && t.bytes.end == offset && !t.bytes.is_empty()))
+    }
+
+    /// The declaration a name token refers to, as [`Resolution`] has it, or the one it makes.
+    /// Fields and types are not resolved, see [`crate::analysis`].
+    fn resolve(&self, token: usize) -> Option<&Symbol> {
+        let declaration = self.references.get(&token).unwrap_or(&token);
+        self.symbols.iter().find(|s| s.name_token == *declaration)
+    }
+
+    fn range(&self, bytes: &Range<usize>) -> JsonValue {
+        let (start_line, start_character) = self.lines.position(&self.text, bytes.start);
+        let (end_line, end_character) = self.lines.position(&self.text, bytes.end);
+        object! {
+            start: { line: start_line, character: start_character },
+            end: { line: end_line, character: end_character },
+        }
+    }
+
+    fn token_range(&self, tokens: &Range<usize>) -> JsonValue {
+        self.range(&(self.tokens[tokens.start].bytes.start..self.tokens[tokens.end - 1].bytes.end))
+    }
+
+    fn offset(&self, position: &JsonValue) -> usize {
+        let line = position["line"].as_usize().unwrap_or(0);
+        let character = position["character"].as_usize().unwrap_or(0);
+        self.lines.offset(&self.text, line, character)
+    }
+
+    pub fn diagnostics(&self) -> JsonValue {
+        let mut diagnostics = JsonValue::new_array();
+        for d in &self.diagnostics {
+            diagnostics
+                .push(object! { range: self.range(&d.range), severity: 1, source: "fern", message: d.message.clone() })
+                .unwrap();
+        }
+        diagnostics
+    }
+
+    /// Tokens in the relative encoding of `textDocument/semanticTokens`.
+    pub fn semantic_tokens(&self) -> Vec<usize> {
+        let mut tokens: Vec<(Range<usize>, usize, usize)> = self.comments.iter().map(|c| (c.clone(), 9, 0)).collect();
+        for (i, t) in self.tokens.iter().enumerate() {
+            if t.inserted {
+                continue;
+            }
+            let kind = t.kind.as_str();
+            let (token_type, modifiers) = if kind == "NAME" {
+                match self.resolve(i) {
+                    Some(s) => (s.kind.token_type(), usize::from(s.name_token == i)),
+                    None => (1, 0),
+                }
+            } else if KEYWORDS.contains(&kind) {
+                (0, 0)
+            } else if OPERATORS.contains(&kind) {
+                (8, 0)
+            } else if kind == "STRING" {
+                (6, 0)
+            } else if kind == "NUMBER" {
+                (7, 0)
+            } else {
+                continue;
+            };
+            tokens.push((t.bytes.clone(), token_type, modifiers));
+        }
+        tokens.sort_by_key(|(bytes, _, _)| bytes.start);
+
+        let mut data = Vec::with_capacity(tokens.len() * 5);
+        let (mut previous_line, mut previous_start) = (0, 0);
+        for (bytes, token_type, modifiers) in tokens {
+            let (line, start) = self.lines.position(&self.text, bytes.start);
+            let length = self.text[bytes].encode_utf16().count();
+            let delta_start = if line == previous_line { start - previous_start } else { start };
+            data.extend([line - previous_line, delta_start, length, token_type, modifiers]);
+            previous_line = line;
+            previous_start = start;
+        }
+        data
+    }
+
+    fn document_symbol(&self, index: usize) -> JsonValue {
+        let s = &self.symbols[index];
+        let kind = match s.kind {
+            SymbolKind::Function => 12,
+            SymbolKind::Struct => 23,
+            SymbolKind::Field => 8,
+            SymbolKind::Parameter | SymbolKind::Variable => 13,
+        };
+        let mut children = JsonValue::new_array();
+        for (i, c) in self.symbols.iter().enumerate() {
+            if c.parent == Some(index) && matches!(c.kind, SymbolKind::Function | SymbolKind::Struct | SymbolKind::Field) {
+                children.push(self.document_symbol(i)).unwrap();
+            }
+        }
+        object! {
+            name: s.name.clone(),
+            detail: s.signature.clone(),
+            kind: kind,
+            range: self.token_range(&s.span),
+            selectionRange: self.token_range(&(s.name_token..s.name_token + 1)),
+            children: children,
+        }
+    }
+
+    /// Functions and structs, with the ones declared inside them and struct fields as children.
+    pub fn document_symbols(&self) -> JsonValue {
+        let mut symbols = JsonValue::new_array();
+        for (i, s) in self.symbols.iter().enumerate() {
+            if s.parent.is_none() && matches!(s.kind, SymbolKind::Function | SymbolKind::Struct) {
+                symbols.push(self.document_symbol(i)).unwrap();
+            }
+        }
+        symbols
+    }
+}
+
+/// Answers LSP messages about Fern documents. Documents are synced in full on every change.
+pub struct LanguageServer<'a> {
+    table: &'a CompiledLexTable,
+    grammar: &'a CompiledGrammar,
+    documents: HashMap<String, Document>,
+    initialized: bool,
+    shutdown: bool,
+}
+
+impl<'a> LanguageServer<'a> {
+    pub fn new(table: &'a CompiledLexTable, grammar: &'a CompiledGrammar) -> Self {
+        Self {
+            table,
+            grammar,
+            documents: HashMap::new(),
+            initialized: false,
+            shutdown: false,
+        }
+    }
+
+    /// Whether a `shutdown` request came before `exit`.
+    pub fn shut_down(&self) -> bool {
+        self.shutdown
+    }
+
+    pub fn document(&self, uri: &str) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> JsonValue {
+        let (version, diagnostics) = match self.documents.get(uri) {
+            Some(d) => (JsonValue::from(d.version), d.diagnostics()),
+            None => (JsonValue::Null, JsonValue::new_array()),
+        };
+        object! {
+            jsonrpc: "2.0",
+            method: "textDocument/publishDiagnostics",
+            params: { uri: uri, version: version, diagnostics: diagnostics },
+        }
+    }
+
+    fn capabilities() -> JsonValue {
+        object! {
+            textDocumentSync: 1,
+            semanticTokensProvider: {
+                legend: { tokenTypes: TOKEN_TYPES, tokenModifiers: TOKEN_MODIFIERS },
+                full: true,
+            },
+            documentSymbolProvider: true,
+            definitionProvider: true,
+            hoverProvider: true,
+        }
+    }
+
+    /// Handle one message and return the messages to send back.
+    pub fn handle(&mut self, message: &JsonValue) -> Vec<JsonValue> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let id = &message["id"];
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        trace!("LSP {} {}", method, id);
+
+        if !self.initialized && !id.is_null() && method != "initialize" {
+            return vec![error_response(id, SERVER_NOT_INITIALIZED, "Server is not initialized")];
+        }
+        match method {
+            "initialize" => {
+                self.initialized = true;
+                info!("Initialized by {}", params["clientInfo"]["name"]);
+                vec![response(
+                    id,
+                    object! {
+                        capabilities: Self::capabilities(),
+                        serverInfo: { name: "fern-lsp", version: env!("CARGO_PKG_VERSION") },
+                    },
+                )]
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                vec![response(id, JsonValue::Null)]
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let text = document["text"].as_str().unwrap_or_default().to_string();
+                let version = document["version"].as_i64().unwrap_or(0);
+                self.documents.insert(uri.clone(), Document::new(self.table, self.grammar, text, version));
+                vec![self.publish_diagnostics(&uri)]
+            }
+            "textDocument/didChange" => {
+                let changes = &params["contentChanges"];
+                let Some(text) = changes[changes.len().saturating_sub(1)]["text"].as_str() else {
+                    return Vec::new();
+                };
+                let version = params["textDocument"]["version"].as_i64().unwrap_or(0);
+                self.documents
+                    .insert(uri.clone(), Document::new(self.table, self.grammar, text.to_string(), version));
+                vec![self.publish_diagnostics(&uri)]
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![self.publish_diagnostics(&uri)]
+            }
+            "textDocument/semanticTokens/full" => {
+                let result = match self.documents.get(&uri) {
+                    Some(d) => object! { data: d.semantic_tokens() },
+                    None => JsonValue::Null,
+                };
+                vec![response(id, result)]
+            }
+            "textDocument/documentSymbol" => {
+                let result = self.documents.get(&uri).map_or(JsonValue::Null, |d| d.document_symbols());
+                vec![response(id, result)]
+            }
+            "textDocument/definition" => {
+                let result = self.documents.get(&uri).and_then(|d| {
+                    let symbol = d.resolve(d.token_at(d.offset(&params["position"]))?)?;
+                    Some(object! { uri: uri.clone(), range: d.token_range(&(symbol.name_token..symbol.name_token + 1)) })
+                });
+                vec![response(id, result.unwrap_or(JsonValue::Null))]
+            }
+            "textDocument/hover" => {
+                let result = self.documents.get(&uri).and_then(|d| {
+                    let token = d.token_at(d.offset(&params["position"]))?;
+                    let symbol = d.resolve(token)?;
+                    Some(object! {
+                        contents: { kind: "markdown", value: format!("```fern\n{}\n```", symbol.signature) },
+                        range: d.token_range(&(token..token + 1)),
+                    })
+                });
+                vec![response(id, result.unwrap_or(JsonValue::Null))]
+            }
+            _ if !id.is_null() => vec![error_response(id, METHOD_NOT_FOUND, &format!("Unknown method {}", method))],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Handle messages until `exit` or until `incoming` disconnects. Messages that arrive while
+    /// others are handled are taken together, so a request cancelled by `$/cancelRequest`, or
+    /// about a document that is changed after it, is answered with an error instead of a result,
+    /// and only the last of several changes to a document is analysed.
+    pub fn serve<F: FnMut(JsonValue)>(&mut self, incoming: &Receiver<JsonValue>, mut outgoing: F) {
+        while let Ok(first) = incoming.recv() {
+            let mut queue = vec![first];
+            queue.extend(incoming.try_iter());
+            let cancelled: HashSet<String> = queue
+                .iter()
+                .filter(|m| m["method"] == "$/cancelRequest")
+                .map(|m| m["params"]["id"].dump())
+                .collect();
+            let changes = |m: &JsonValue| matches!(m["method"].as_str(), Some("textDocument/didChange" | "textDocument/didClose"));
+
+            for (i, message) in queue.iter().enumerate() {
+                let method = message["method"].as_str().unwrap_or_default();
+                let id = &message["id"];
+                let uri = &message["params"]["textDocument"]["uri"];
+                let changed_later = !uri.is_null() && queue[i + 1..].iter().any(|m| changes(m) && m["params"]["textDocument"]["uri"] == *uri);
+                if method == "$/cancelRequest" {
+                    continue;
+                } else if !id.is_null() && cancelled.contains(&id.dump()) {
+                    outgoing(error_response(id, REQUEST_CANCELLED, "Request cancelled"));
+                } else if !id.is_null() && changed_later {
+                    outgoing(error_response(id, CONTENT_MODIFIED, "Document changed"));
+                } else if method == "textDocument/didChange" && changed_later {
+                    trace!("Skipping superseded change to {}", uri);
+                } else {
+                    for response in self.handle(message) {
+                        outgoing(response);
+                    }
+                }
+                if method == "exit" {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,18 @@
-use crate::grammar::opg::{Associativity, OpGrammar, Rule, Token};
+use crate::grammar::compiled::CompiledGrammar;
+use crate::grammar::opg::{Associativity, Rule, Token};
 use crate::lexer::Data;
-use crate::parsetree::{Id, ParseTree};
-use log::{debug, error, info, trace, warn};
+use crate::parsetree::{Id, ParseTree, ParseTreeBuilder};
+use crate::trace::{ParseEvent, ParseTrace, StackEntry};
+use log::{debug, error, log_enabled, trace, warn, Level};
 use std::any::Any;
 use std::collections::{BTreeMap, BTreeSet, HashMap, LinkedList, VecDeque};
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::hint::unreachable_unchecked;
 use std::io::ErrorKind::AlreadyExists;
 use std::io::Read;
 use std::marker::PhantomData;
-use std::ops::Add;
+use std::ops::{Add, Range};
 use std::panic::{resume_unwind, set_hook};
 use std::slice::Iter;
 use std::sync::mpsc::channel;
@@ -54,6 +57,50 @@ impl TokenGrammarTuple {
     }
 }
 
+/// A syntax error. The parser recovers from it and keeps going, so one run can report several.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Index of the offending token among all tokens passed to [`Parser::parse`], not a byte
+    /// offset, like the spans of parse tree and AST nodes. The [`Data`] of the token has its
+    /// text. Empty at the end of the input.
+    pub span: Range<usize>,
+    pub found: Token,
+    /// Terminals the operator precedence table allows in place of `found`.
+    pub expected: Vec<Token>,
+    message: String,
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+    fn new(span: Range<usize>, found: Token, expected: Vec<Token>, g: &CompiledGrammar) -> Self {
+        let name = |t: &Token| match g.token_raw.get(t) {
+            Some(_) if *t == g.delim => String::from("end of input"),
+            Some(name) => name.clone(),
+            None => format!("unknown token {}", t),
+        };
+        let mut names: Vec<String> = expected.iter().map(name).collect();
+        names.sort();
+        let message = match names.len() {
+            0 => format!("Unexpected {} at token {}.", name(&found), span.start),
+            1 => format!("Unexpected {} at token {}, expected {}.", name(&found), span.start, names[0]),
+            _ => format!("Unexpected {} at token {}, expected one of {}.", name(&found), span.start, names.join(", ")),
+        };
+        Self {
+            span,
+            found,
+            expected,
+            message,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Parse Error: {}", self.message)
+    }
+}
+
 pub struct PartialParseTree {
     parser: Parser,
 }
@@ -61,70 +108,104 @@ pub struct PartialParseTree {
 impl PartialParseTree {
     pub fn merge(&mut self, tree: PartialParseTree) {}
     pub fn into_tree(self) -> ParseTree {
-        self.parser.tree
+        self.parser.tree.build()
+    }
+    pub fn errors(&self) -> &[ParseError] {
+        &self.parser.errors
     }
 }
 
 pub struct Parser {
-    tree: ParseTree,
+    tree: ParseTreeBuilder,
     stack: Vec<TokenGrammarTuple>,
-    pub g: OpGrammar,
+    pub g: CompiledGrammar,
     open_nodes: BTreeMap<u64, Node>,
     should_reconsume: bool,
     highest_id: u64,
     iteration: u64,
-    terminals_set: bittyset::BitSet<Token>,
-    non_terminals_set: bittyset::BitSet<Token>,
-    pub time_spent_rule_searching: Duration,
+    /// Total time spent matching handles against rules, only measured when set to `Some`.
+    pub time_spent_rule_searching: Option<Duration>,
+    /// Every shift, reduction and error, only recorded when set to `Some`.
+    pub trace: Option<ParseTrace>,
+    errors: Vec<ParseError>,
+    /// Index of the next input token.
+    position: usize,
+    /// Input skipped since the last error, while looking for a sync token.
+    recovering: Option<Vec<Id>>,
+    /// Errors are not reported again until a token is shifted, so one mistake is one error.
+    quiet: bool,
+}
+
+/// Parse every chunk with a parser of its own, in the order they arrive. The chunks can be a
+/// collected batch or the receiving end of [`ParallelLexer::stream_batch`], which yield the same
+/// trees and errors.
+///
+/// [`ParallelLexer::stream_batch`]: crate::lexer::ParallelLexer::stream_batch
+pub fn parse_chunks<I>(grammar: &CompiledGrammar, chunks: I) -> (Vec<PartialParseTree>, Vec<ParseError>)
+where
+    I: IntoIterator<Item = (Vec<Token>, Vec<Data>)>,
+{
+    let mut trees = Vec::new();
+    let mut errors = Vec::new();
+    for (tokens, data) in chunks {
+        let mut parser = Parser::new(grammar);
+        let _ = parser.parse(tokens, data);
+        let _ = parser.parse(vec![grammar.delim], Vec::new());
+        errors.extend_from_slice(parser.errors());
+        trees.push(parser.collect_parse_tree().unwrap());
+    }
+    (trees, errors)
 }
 
 impl Parser {
-    pub fn new(grammar: OpGrammar) -> Self {
-        let mut terminals_set = bittyset::BitSet::<Token>::new();
-        grammar.terminals.iter().for_each(|x| {
-            terminals_set.insert(*x as usize);
-        });
-
-        let mut non_terminals_set = bittyset::BitSet::<Token>::new();
-        grammar.non_terminals.iter().for_each(|x| {
-            non_terminals_set.insert(*x as usize);
-        });
-
-        let map = grammar
-            .token_raw
-            .clone()
-            .into_iter()
-            .map(|(k, v)| (k, v.rsplit_once('_').unwrap_or((v.as_str(), v.as_str())).1.to_string()))
-            .collect();
+    pub fn new(grammar: &CompiledGrammar) -> Self {
         let parser = Self {
             stack: Vec::new(),
-            tree: ParseTree::new(map),
-            g: grammar,
+            tree: ParseTreeBuilder::new(grammar.short_names().clone()),
+            g: grammar.clone(),
             should_reconsume: false,
             open_nodes: BTreeMap::new(),
             highest_id: 0,
             iteration: 0,
-            terminals_set,
-            non_terminals_set,
-            time_spent_rule_searching: Duration::new(0, 0),
+            time_spent_rule_searching: None,
+            trace: None,
+            errors: Vec::new(),
+            position: 0,
+            recovering: None,
+            quiet: false,
         };
 
         return parser;
     }
 
-    pub fn parse(&mut self, tokens: Vec<Token>, data: Vec<Data>) {
+    /// Parse the next part of the input, ending it with [`OpGrammar::delim`]. Returns the syntax
+    /// errors found in this part, all of which the parser recovered from.
+    pub fn parse(&mut self, tokens: Vec<Token>, data: Vec<Data>) -> Result<(), Vec<ParseError>> {
+        let before = self.errors.len();
         let mut iter = data.into_iter();
         for (i, t) in tokens.iter().enumerate() {
             if let Some(data) = iter.next() {
                 if data.token_index == i {
-                    self.consume_token(*t, Some(data)).expect("Parser raised an exception.");
+                    self.consume_token(*t, Some(data));
                 } else {
-                    self.consume_token(*t, None).expect("Parser raised an exception.");
+                    self.consume_token(*t, None);
                 }
             } else {
-                self.consume_token(*t, None).expect("Parser raised an exception.");
+                self.consume_token(*t, None);
+            }
+            if *t != self.g.delim {
+                self.position += 1;
             }
         }
+        if self.errors.len() > before {
+            return Err(self.errors[before..].to_vec());
+        }
+        Ok(())
+    }
+
+    /// Every syntax error found so far.
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
     }
 
     pub fn gen_id(&mut self) -> u64 {
@@ -134,40 +215,70 @@ impl Parser {
 
     pub fn push(&mut self, mut tuple: TokenGrammarTuple) {
         let id = self.tree.push(tuple.clone());
+        let event = ParseEvent::Shift {
+            token: tuple.token,
+            relation: tuple.associativity,
+            position: self.position,
+        };
         tuple.tree_id = Some(id);
         self.stack.push(tuple);
+        self.quiet = false;
+        self.record(event);
+    }
+
+    fn record(&mut self, event: ParseEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            let stack = self.stack.iter().map(|x| StackEntry {
+                token: x.token,
+                relation: x.associativity,
+            });
+            trace.record(event, stack);
+        }
     }
 
-    fn consume_token(&mut self, token: Token, data: Option<Data>) -> Result<(), Box<dyn Error>> {
+    fn consume_token(&mut self, token: Token, data: Option<Data>) {
+        if let Some(skipped) = self.recovering.as_mut() {
+            if token != self.g.delim && !self.g.sync.contains(&token) {
+                let t = TokenGrammarTuple::new(token, Associativity::None, self.highest_id + 1, data);
+                self.highest_id += 1;
+                skipped.push(self.tree.push(t));
+                return;
+            }
+            self.resynchronise(token);
+        }
+
+        if token != self.g.delim && !self.g.is_terminal(token) && !self.g.is_non_terminal(token) {
+            self.syntax_error(token, false);
+            self.recovering = Some(Vec::new());
+            return self.consume_token(token, data);
+        }
+
         if self.stack.is_empty() {
             let t = TokenGrammarTuple::new(token, Associativity::Left, self.gen_id(), data);
             self.push(t);
-            return Ok(());
+            return;
         }
 
         loop {
             self.iteration += 1;
             self.should_reconsume = false;
 
-            let mut output = String::new();
-            for (key, node) in &self.open_nodes {
-                output.push_str(format!("({:?} {:?}) ", key, self.g.token_raw.get(&node.symbol).unwrap()).as_str());
+            if log_enabled!(Level::Debug) {
+                let mut output = String::new();
+                for (key, node) in &self.open_nodes {
+                    output.push_str(format!("({:?} {:?}) ", key, self.g.token_raw.get(&node.symbol).unwrap()).as_str());
+                }
+                debug!("{} Open nodes: {}", self.iteration, output);
             }
-            debug!("{} Open nodes: {}", self.iteration, output);
             self.print_stack();
 
-            let mut y: Option<TokenGrammarTuple> = None;
-            for element in &self.stack {
-                if self.terminals_set.contains(element.token as usize) {
-                    y = Some(element.clone());
-                }
-            }
+            let y: Option<TokenGrammarTuple> = self.stack.iter().rev().find(|x| self.g.is_terminal(x.token)).cloned();
 
             let y = if self.g.delim != token {
                 if let None = y {
                     let t = TokenGrammarTuple::new(token, Associativity::Left, self.gen_id(), data);
                     self.push(t);
-                    return Ok(());
+                    return;
                 }
                 y.unwrap()
             } else {
@@ -176,7 +287,8 @@ impl Parser {
 
             if let Some(t) = self.stack.get(0) {
                 if t.token == self.g.axiom && y.token == self.g.delim {
-                    return Ok(());
+                    self.record(ParseEvent::Axiom);
+                    return;
                 }
             }
 
@@ -187,11 +299,9 @@ impl Parser {
             };
 
             if precedence == Associativity::None {
-                panic!(
-                    "No precedence between y = {} and token = {}, which is probably a user grammar error",
-                    self.g.token_raw.get(&y.token).unwrap(),
-                    self.g.token_raw.get(&token).unwrap()
-                )
+                self.syntax_error(token, false);
+                self.recovering = Some(Vec::new());
+                return self.consume_token(token, data);
             }
 
             trace!("{} Applying {:?} {:?}", self.iteration, self.g.token_raw.get(&token).unwrap(), precedence);
@@ -200,77 +310,82 @@ impl Parser {
                 let t = TokenGrammarTuple::new(token, Associativity::Left, self.gen_id(), data);
                 self.push(t);
                 debug!("{} Append", self.iteration);
-                return Ok(());
+                return;
             }
 
             if precedence == Associativity::Equal {
                 let t = TokenGrammarTuple::new(token, Associativity::Equal, self.gen_id(), data);
                 self.push(t);
                 debug!("{} Append", self.iteration);
-                return Ok(());
+                return;
             }
 
-            if self.non_terminals_set.contains(token as usize) {
+            if self.g.is_non_terminal(token) {
                 let t = TokenGrammarTuple::new(token, Associativity::Undefined, self.gen_id(), data);
                 self.push(t);
                 debug!("{}, Append", self.iteration);
-                return Ok(());
+                return;
             }
 
             if precedence == Associativity::Right {
-                let mut i: i32 = -1;
-                for (j, x) in self.stack.iter().enumerate() {
-                    if x.associativity == Associativity::Left {
-                        i = j as i32;
-                    }
-                }
+                let i = self.stack.iter().rposition(|x| x.associativity == Associativity::Left).map_or(-1, |j| j as i32);
 
                 if i < 0 && token != self.g.delim {
                     let t = TokenGrammarTuple::new(token, Associativity::Right, self.gen_id(), data);
                     self.push(t);
                     debug!("{}, Append", self.iteration);
-                    return Ok(());
+                    return;
                 } else if i - 1 >= 0 {
                     let xi_minus_one = self.stack.get((i - 1) as usize).unwrap();
 
-                    if self.terminals_set.contains(xi_minus_one.token as usize) {
-                        self.process_terminal(i);
-                    } else if self.non_terminals_set.contains(xi_minus_one.token as usize) {
-                        self.process_non_terminal(i);
+                    if self.g.is_terminal(xi_minus_one.token) {
+                        self.process_terminal(i, token);
+                    } else if self.g.is_non_terminal(xi_minus_one.token) {
+                        self.process_non_terminal(i, token);
                     } else {
-                        panic!("Should be able to reduce but cannot. Probably a parser bug.");
+                        // Nothing else should be on the stack. Should it happen anyway, the
+                        // handle and what is below it become an error node, as when a handle
+                        // has no rule.
+                        self.syntax_error(token, true);
+                        let children = self.pop_from((i - 1) as usize);
+                        self.push_error(children);
+                        self.should_reconsume = true;
                     }
                 } else {
-                    self.process_terminal(0);
+                    self.process_terminal(0, token);
                 }
             }
             if !self.should_reconsume {
                 break;
             }
         }
-        Ok(())
     }
 
-    fn process_terminal(&mut self, i: i32) {
-        self.reduce_stack(i, 0);
+    fn process_terminal(&mut self, i: i32, lookahead: Token) {
+        self.reduce_stack(i, 0, lookahead);
     }
 
-    fn process_non_terminal(&mut self, i: i32) {
-        self.reduce_stack(i, -1);
+    fn process_non_terminal(&mut self, i: i32, lookahead: Token) {
+        self.reduce_stack(i, -1, lookahead);
     }
 
-    fn reduce_stack(&mut self, i: i32, offset: i32) {
+    fn reduce_stack(&mut self, i: i32, offset: i32, lookahead: Token) {
         let apply_rewrites: HashMap<Token, Token> = HashMap::new();
         // let longest: i32 = 0;
 
-        // let now = Instant::now();
-        // TODO: Make this into a slice without collecting into vec, probably implement custom iter.
-        let iter: Vec<&Token> = (&self.stack[(i + offset) as usize..]).iter().map(|x| -> &Token { &x.token }).collect();
-        let rule: Option<&Rule> = self.g.new_reduction_tree.match_rule(&iter[..], &self.g.token_raw);
-
-        // let time = now.elapsed();
-        // debug!("Time spend searching: {:?}", &time);
-        // self.time_spent_rule_searching = self.time_spent_rule_searching.add(time);
+        let now = self.time_spent_rule_searching.map(|_| Instant::now());
+        let handle = &self.stack[(i + offset) as usize..];
+        let rule: Option<&Rule> = if handle.iter().any(|x| x.token == self.g.error) {
+            let tokens: Vec<Token> = handle.iter().map(|x| x.token).collect();
+            self.g
+                .new_reduction_tree
+                .match_rule_with_errors(&tokens, self.g.error, |t| self.g.is_non_terminal(t))
+        } else {
+            self.g.new_reduction_tree.match_rule(handle.iter().map(|x| x.token))
+        };
+        if let (Some(now), Some(total)) = (now, self.time_spent_rule_searching.as_mut()) {
+            *total += now.elapsed();
+        }
 
         if let Some(rule) = rule {
             if !apply_rewrites.is_empty() {
@@ -287,37 +402,118 @@ impl Parser {
             }
 
             // Take stuff off stack that will become new parents children.
-            let mut children = Vec::new();
-            for _ in 0..rule.right.len() {
-                let current = self.stack.remove((i + offset) as usize);
-                if self.open_nodes.contains_key(&current.id) {
-                    self.open_nodes.remove(&current.id).unwrap();
-                }
+            let start = (i + offset) as usize;
+            let end = start + rule.right.len();
+            let mut children = Vec::with_capacity(rule.right.len());
+            for current in &self.stack[start..end] {
+                self.open_nodes.remove(&current.id);
                 children.push(current.tree_id.unwrap());
             }
 
+            let event = ParseEvent::Reduce {
+                left: rule.left,
+                right: rule.right.clone(),
+            };
             let p_id = self.tree.reduce(rule.left, &children);
             let parent = Node::new(rule.left, None);
 
             let mut left = TokenGrammarTuple::new(rule.left, Associativity::Undefined, self.gen_id(), None);
             left.tree_id = Some(p_id);
-            for n in &mut self.stack {
-                if n.tree_id.unwrap() >= p_id {
-                    *n.tree_id.as_mut().unwrap() += 1;
-                }
-            }
 
             self.open_nodes.insert(left.id, parent);
-            self.stack.insert((i + offset) as usize, left);
+            self.stack.splice(start..end, [left]);
+            self.record(event);
             debug!("{} Reduce", self.iteration);
             self.should_reconsume = true;
-        } else if self.stack.len() > 0 && self.g.axiom == self.stack.get(0).unwrap().token {
+        } else if self.stack.len() == 1 && self.g.axiom == self.stack[0].token {
             debug!("{} Reached axiom and finished parsing.", self.iteration);
+            self.record(ParseEvent::Axiom);
         } else {
-            warn!("{} Should probably reduce but didn't. Could be a bug / error.", self.iteration);
+            // No rule matches the handle. It becomes an error node, and the parser tries again
+            // with the handle before it, until there are no terminals left to reduce.
+            self.syntax_error(lookahead, true);
+            let start = (i + offset) as usize;
+            if self.stack[start..].iter().any(|x| self.g.is_terminal(x.token)) {
+                let children = self.pop_from(start);
+                self.push_error(children);
+                self.should_reconsume = true;
+            }
         }
     }
 
+    /// Record an error for `found` unless the parser is still recovering from the previous one.
+    /// The expected terminals are those the table allows after the topmost terminal, or only
+    /// those that would be shifted if `shift_only` is set, as when a handle failed to reduce.
+    fn syntax_error(&mut self, found: Token, shift_only: bool) {
+        if self.quiet {
+            return;
+        }
+        self.quiet = true;
+        let expected = match self.stack.iter().rev().find(|x| self.g.is_terminal(x.token)) {
+            Some(y) => self
+                .g
+                .terminals
+                .iter()
+                .filter(|t| **t != self.g.delim)
+                .filter(|t| match self.g.get_precedence(y.token, **t) {
+                    Associativity::Left | Associativity::Equal => true,
+                    Associativity::Right => !shift_only,
+                    _ => false,
+                })
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+        let span = if found == self.g.delim {
+            self.position..self.position
+        } else {
+            self.position..self.position + 1
+        };
+        let error = ParseError::new(span, found, expected, &self.g);
+        debug!("{} {}", self.iteration, error);
+        self.errors.push(error.clone());
+        self.record(ParseEvent::Error(error));
+    }
+
+    /// Ends panic mode at sync token `s`. The nonterminals above the topmost terminal and every
+    /// terminal `s` has no relation with are popped, and together with the skipped input become
+    /// an error node that takes the place of the phrase they failed to form.
+    fn resynchronise(&mut self, s: Token) {
+        let skipped = self.recovering.take().unwrap();
+        let non_terminals_above = |stack: &[TokenGrammarTuple], mut start: usize| {
+            while start > 0 && !self.g.is_terminal(stack[start - 1].token) {
+                start -= 1;
+            }
+            start
+        };
+        let mut start = non_terminals_above(&self.stack, self.stack.len());
+        while start > 0 && s != self.g.delim && self.g.get_precedence(self.stack[start - 1].token, s) == Associativity::None {
+            start = non_terminals_above(&self.stack, start - 1);
+        }
+        let mut children = self.pop_from(start);
+        children.extend(skipped);
+        debug!("{} Resynchronised on {}", self.iteration, self.g.token_raw.get(&s).unwrap());
+        self.push_error(children);
+    }
+
+    fn pop_from(&mut self, start: usize) -> Vec<Id> {
+        let mut children = Vec::with_capacity(self.stack.len() - start);
+        for t in self.stack.drain(start..) {
+            self.open_nodes.remove(&t.id);
+            children.push(t.tree_id.unwrap());
+        }
+        children
+    }
+
+    fn push_error(&mut self, children: Vec<Id>) {
+        let id = self.tree.reduce(self.g.error, &children);
+        let mut error = TokenGrammarTuple::new(self.g.error, Associativity::Undefined, self.gen_id(), None);
+        error.tree_id = Some(id);
+        self.open_nodes.insert(error.id, Node::new(self.g.error, None));
+        self.stack.push(error);
+        self.record(ParseEvent::Recover);
+    }
+
     fn expand(n: &mut Node, p: &Parser) {
         trace!("Expanding: {}", p.g.token_raw.get(&n.symbol).unwrap());
         let term_list = p.g.new_non_terminal_reverse.get(&n.symbol);
@@ -328,13 +524,16 @@ impl Parser {
         };
         n.symbol = *term_list.last().unwrap();
         for (_i, next) in n.children.iter_mut().enumerate() {
-            if p.non_terminals_set.contains(next.symbol as usize) {
+            if p.g.is_non_terminal(next.symbol) {
                 Self::expand(next, p);
             }
         }
     }
 
     pub fn print_stack(&self) {
+        if !log_enabled!(Level::Trace) {
+            return;
+        }
         let mut output = String::new();
         for i in &self.stack {
             let x = match i.associativity {
@@ -1,6 +1,7 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::fs::File;
+use std::sync::Arc;
 use std::{
     borrow::Cow,
     io::{self, Write},
@@ -34,33 +35,132 @@ impl Node {
     }
 }
 
-/// An append only tree of Tokens. Root is at id 0.
-pub struct ParseTree {
-    pub nodes: Vec<Node>,
-    pub token_map: BTreeMap<Token, String>,
+/// Builds a [`ParseTree`] while parsing. Shifted tokens and reductions are appended, so every
+/// node comes after its children, and the children of a reduction are stored as a range of
+/// `links`. Ids never change once handed out, and [`ParseTreeBuilder::build`] lays the nodes
+/// out in pre-order in linear time.
+pub struct ParseTreeBuilder {
+    nodes: Vec<BuilderNode>,
+    links: Vec<Id>,
+    token_map: Arc<BTreeMap<Token, String>>,
 }
 
-impl ParseTree {
-    pub fn new(token_map: BTreeMap<Token, String>) -> Self {
-        Self { nodes: Vec::new(), token_map }
+struct BuilderNode {
+    token: Token,
+    data: Option<Data>,
+    first_link: usize,
+    child_count: usize,
+    has_parent: bool,
+}
+
+impl ParseTreeBuilder {
+    pub fn new(token_map: Arc<BTreeMap<Token, String>>) -> Self {
+        Self {
+            nodes: Vec::new(),
+            links: Vec::new(),
+            token_map,
+        }
     }
 
     pub fn push(&mut self, tuple: TokenGrammarTuple) -> Id {
-        self.nodes.push(Node::new(tuple.token, tuple.data));
-        return self.nodes.len() - 1;
+        self.nodes.push(BuilderNode {
+            token: tuple.token,
+            data: tuple.data,
+            first_link: 0,
+            child_count: 0,
+            has_parent: false,
+        });
+        self.nodes.len() - 1
     }
 
     pub fn reduce(&mut self, parent: Token, children: &[Id]) -> Id {
-        let m = children.iter().min().unwrap();
-        let mut p = Node::new(parent, None);
-        p.child_count = children.len();
+        for c in children {
+            self.nodes[*c].has_parent = true;
+        }
+        self.nodes.push(BuilderNode {
+            token: parent,
+            data: None,
+            first_link: self.links.len(),
+            child_count: children.len(),
+            has_parent: false,
+        });
+        self.links.extend_from_slice(children);
+        self.nodes.len() - 1
+    }
+
+    /// Nodes without a parent become roots, ordered by their leftmost token, which is only more
+    /// than one when parsing stopped before reaching the axiom.
+    pub fn build(mut self) -> ParseTree {
+        let mut leftmost: Vec<Id> = (0..self.nodes.len()).collect();
+        for (i, n) in self.nodes.iter().enumerate() {
+            if n.child_count > 0 {
+                leftmost[i] = leftmost[self.links[n.first_link]];
+            }
+        }
+        let mut roots: Vec<Id> = (0..self.nodes.len()).filter(|i| !self.nodes[*i].has_parent).collect();
+        roots.sort_by_key(|r| leftmost[*r]);
+
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut stack: Vec<Id> = roots.into_iter().rev().collect();
+        while let Some(i) = stack.pop() {
+            let n = &mut self.nodes[i];
+            nodes.push(Node {
+                token: n.token,
+                child_count: n.child_count,
+                data: n.data.take(),
+            });
+            stack.extend(self.links[n.first_link..n.first_link + n.child_count].iter().rev());
+        }
+        ParseTree {
+            nodes,
+            token_map: self.token_map,
+            trivia: String::new(),
+        }
+    }
+}
+
+/// An append only tree of Tokens. Root is at id 0.
+pub struct ParseTree {
+    pub nodes: Vec<Node>,
+    pub token_map: Arc<BTreeMap<Token, String>>,
+    /// Whitespace and comments of an input without tokens, which have no node to keep them.
+    /// The parser leaves it empty, see [`crate::fern::lex`].
+    pub trivia: String,
+}
 
-        self.nodes.insert(*m, p);
-        *m
+impl ParseTree {
+    pub fn new(token_map: Arc<BTreeMap<Token, String>>) -> Self {
+        Self {
+            nodes: Vec::new(),
+            token_map,
+            trivia: String::new(),
+        }
+    }
+
+    /// One past the last node of the subtree rooted at `id`.
+    pub fn subtree_end(&self, id: Id) -> Id {
+        let mut pending = 1;
+        let mut end = id;
+        while pending > 0 {
+            pending = pending - 1 + self.nodes[end].child_count;
+            end += 1;
+        }
+        end
     }
 
     fn pre_order_traverse<F: FnMut(&Vec<(Option<usize>, usize)>, usize)>(&self, mut f: F) {
-        let mut stack = Vec::from(&[(None, self.nodes.first().unwrap().child_count)]);
+        // A tree that stopped short of the axiom has several roots, stored one after the other.
+        let mut roots = 0;
+        let mut pending = 0;
+        for n in &self.nodes {
+            if pending == 0 {
+                roots += 1;
+            } else {
+                pending -= 1;
+            }
+            pending += n.child_count;
+        }
+        let mut stack = Vec::from(&[(None, roots)]);
 
         for (i, n) in self.nodes.iter().enumerate() {
             let last = stack.last_mut().unwrap();
@@ -141,8 +241,27 @@ const MAP: &[&str] = &["A", "B", "C", "D"];
 
 #[test]
 fn tree_traverse() {
-    let token_map: Vec<String> = MAP.iter().map(|s| s.to_string()).collect();
-    let mut tree = ParseTree::new(token_map);
+    use crate::grammar::opg::Associativity;
+
+    let token_map: BTreeMap<usize, String> = MAP.iter().enumerate().map(|(i, s)| (i, s.to_string())).collect();
+    let mut builder = ParseTreeBuilder::new(Arc::new(token_map));
+    let leaf = |builder: &mut ParseTreeBuilder, t: Token| builder.push(TokenGrammarTuple::new(t, Associativity::Left, 0, None));
+
+    // A(B(C D) C) followed by a D that was never reduced.
+    let c = leaf(&mut builder, 2);
+    let d = leaf(&mut builder, 3);
+    let b = builder.reduce(1, &[c, d]);
+    let c = leaf(&mut builder, 2);
+    builder.reduce(0, &[b, c]);
+    leaf(&mut builder, 3);
+
+    let tree = builder.build();
+    let shape: Vec<(&str, usize)> = tree
+        .nodes
+        .iter()
+        .map(|n| (tree.token_map.get(&n.token).unwrap().as_str(), n.child_count))
+        .collect();
+    assert_eq!(shape, [("A", 2), ("B", 2), ("C", 0), ("D", 0), ("C", 0), ("D", 0)]);
 }
 
 #[test]
@@ -0,0 +1,228 @@
+//! Printing a [`FernAst`] back to Fern source.
+//!
+//! Unlike [`crate::fmt`], which lays out the tokens of existing source and keeps its comments,
+//! this works from the AST alone, so it can print trees that were built or rewritten rather
+//! than parsed. Parsing the output gives back an equal AST. Blocks are indented with tabs, and
+//! expressions get only the parentheses [`OperatorKind::precedence`] calls for.
+
+use crate::ast::Node;
+use crate::fern::{AstNodeKind, FernAst, OperatorKind};
+use std::fmt::{Debug, Formatter};
+
+/// Binds tighter than any operator, see [`OperatorKind::precedence`].
+const ATOM: u8 = 9;
+
+impl FernAst {
+    /// The AST as Fern source. A root that is an expression is printed on its own, without a
+    /// trailing newline.
+    pub fn to_source(&self) -> String {
+        match self.root() {
+            Some(root) if matches!(root.kind(), AstNodeKind::Module | AstNodeKind::StatList) => statements(root.children(), 0),
+            Some(root) if is_statement(root) => statements(std::iter::once(root), 0),
+            Some(root) => expr(root),
+            None => String::new(),
+        }
+    }
+}
+
+/// ASTs are equal when they have the same shape and kinds. Spans are where nodes came from
+/// rather than what they are, so they are left out.
+impl PartialEq for FernAst {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes.len() == other.nodes.len()
+            && self
+                .nodes
+                .iter()
+                .zip(&other.nodes)
+                .all(|(a, b)| a.kind == b.kind && a.child_count == b.child_count)
+    }
+}
+
+impl Debug for FernAst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_sexp())
+    }
+}
+
+fn is_statement(node: Node) -> bool {
+    !matches!(
+        node.kind(),
+        AstNodeKind::Operator(_)
+            | AstNodeKind::Number(_)
+            | AstNodeKind::String(_)
+            | AstNodeKind::Name(_)
+            | AstNodeKind::Bool(_)
+            | AstNodeKind::Nil
+            | AstNodeKind::Member
+            | AstNodeKind::ExprList
+    )
+}
+
+fn indent(depth: usize) -> String {
+    "\t".repeat(depth)
+}
+
+/// One statement per line. Statements end in `;`, except after a `}` where the lexer inserts
+/// the `;` itself, which it does before `let`, `return`, `fn`, names and `}`.
+fn statements<'a, I: Iterator<Item = Node<'a>>>(nodes: I, depth: usize) -> String {
+    let printed: Vec<String> = nodes.map(|n| statement(n, depth)).collect();
+    let mut out = String::new();
+    for (i, s) in printed.iter().enumerate() {
+        out.push_str(&indent(depth));
+        out.push_str(s);
+        let inserted = match printed.get(i + 1) {
+            Some(next) => {
+                let keyword = ["if ", "while ", "for ", "struct "].iter().any(|k| next.starts_with(k));
+                next.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && !keyword
+            }
+            None => true,
+        };
+        if !s.ends_with('}') || !inserted {
+            out.push(';');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// `{`, the statements of a `StatList` one level deeper, and `}`.
+fn block(node: Option<Node>, depth: usize) -> String {
+    match node {
+        Some(list) if list.children().next().is_some() => format!("{{\n{}{}}}", statements(list.children(), depth + 1), indent(depth)),
+        _ => String::from("{}"),
+    }
+}
+
+fn statement(node: Node, depth: usize) -> String {
+    let child = |n| node.child(n);
+    let expr_at = |n| child(n).map(expr).unwrap_or_default();
+    match node.kind() {
+        AstNodeKind::Let => format!("let {}", child(0).map(field).unwrap_or_default()),
+        AstNodeKind::LetAssign => format!("let {} = {}", child(0).map(field).unwrap_or_default(), expr_at(1)),
+        AstNodeKind::Assign => format!("{} = {}", expr_at(0), expr_at(1)),
+        AstNodeKind::Return => match child(0) {
+            Some(value) => format!("return {}", expr(value)),
+            None => String::from("return"),
+        },
+        AstNodeKind::Function => match child(3) {
+            Some(body) => format!("fn {}[{}] -> {} {}", expr_at(0), fields(child(1), ", "), expr_at(2), block(Some(body), depth)),
+            None => format!("fn {}[{}] {}", expr_at(0), fields(child(1), ", "), block(child(2), depth)),
+        },
+        AstNodeKind::Struct => match child(1).filter(|f| f.children().next().is_some()) {
+            Some(list) => {
+                let inner = indent(depth + 1);
+                format!(
+                    "struct {} {{\n{}{}\n{}}}",
+                    expr_at(0),
+                    inner,
+                    fields(Some(list), &format!(",\n{}", inner)),
+                    indent(depth)
+                )
+            }
+            None => format!("struct {} {{}}", expr_at(0)),
+        },
+        AstNodeKind::If | AstNodeKind::ElseIf => {
+            let keyword = if *node.kind() == AstNodeKind::If { "if" } else { "elif" };
+            let mut out = format!("{} {} {}", keyword, expr_at(0), block(child(1), depth));
+            if let Some(branch) = child(2) {
+                out.push(' ');
+                out.push_str(&statement(branch, depth));
+            }
+            out
+        }
+        AstNodeKind::Else => format!("else {}", block(child(0), depth)),
+        AstNodeKind::While => format!("while {} {}", expr_at(0), block(child(1), depth)),
+        AstNodeKind::For => format!("for {} in {} {}", expr_at(0), expr_at(1), block(child(2), depth)),
+        AstNodeKind::StatList => block(Some(node), depth),
+        _ => expr(node),
+    }
+}
+
+/// `name` and `name: type` fields.
+fn fields(list: Option<Node>, separator: &str) -> String {
+    let fields: Vec<String> = list.iter().flat_map(|l| l.children()).map(field).collect();
+    fields.join(separator)
+}
+
+/// `name: type` for a `Field`, anything else as an expression.
+fn field(node: Node) -> String {
+    if *node.kind() != AstNodeKind::Field {
+        return expr(node);
+    }
+    match (node.child(0), node.child(1)) {
+        (Some(name), Some(ty)) => format!("{}: {}", expr(name), expr(ty)),
+        (Some(name), None) => expr(name),
+        _ => String::new(),
+    }
+}
+
+fn precedence(node: Node) -> u8 {
+    match node.kind() {
+        AstNodeKind::Operator(op) => op.precedence(),
+        _ => ATOM,
+    }
+}
+
+/// `node`, in parentheses if it binds less tightly than `min`.
+fn operand(node: Option<Node>, min: u8) -> String {
+    match node {
+        Some(n) if precedence(n) < min => format!("({})", expr(n)),
+        Some(n) => expr(n),
+        None => String::new(),
+    }
+}
+
+fn expr(node: Node) -> String {
+    match node.kind() {
+        AstNodeKind::Name(s) | AstNodeKind::Number(s) | AstNodeKind::String(s) => s.clone(),
+        AstNodeKind::Bool(b) => b.to_string(),
+        AstNodeKind::Nil => String::from("nil"),
+        AstNodeKind::Member => format!("{}.{}", operand(node.child(0), ATOM), operand(node.child(1), ATOM)),
+        AstNodeKind::FunctionCall => format!("{}({})", operand(node.child(0), ATOM), node.child(1).map(expr).unwrap_or_default()),
+        AstNodeKind::ExprList => node.children().map(expr).collect::<Vec<String>>().join(", "),
+        AstNodeKind::Operator(op) if op.is_unary() => {
+            let value = operand(node.child(0), op.precedence());
+            match op {
+                OperatorKind::Not => format!("not {}", value),
+                OperatorKind::Length => format!("#{}", value),
+                // The lexer only reads `-` as negation in front of a name or `(`.
+                _ if value.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '(') => format!("-{}", value),
+                _ => format!("-({})", value),
+            }
+        }
+        AstNodeKind::Operator(op) => {
+            let p = op.precedence();
+            let (left, right) = match op {
+                // The base of `^` is a name, literal or parenthesised expression.
+                OperatorKind::Power => (ATOM, p),
+                _ if op.is_right_associative() => (p + 1, p),
+                _ => (p, p + 1),
+            };
+            format!("{} {} {}", operand(node.child(0), left), symbol(*op), operand(node.child(1), right))
+        }
+        _ => statement(node, 0),
+    }
+}
+
+fn symbol(op: OperatorKind) -> &'static str {
+    match op {
+        OperatorKind::Add => "+",
+        OperatorKind::Multiply => "*",
+        OperatorKind::Divide => "/",
+        OperatorKind::Modulo => "%",
+        OperatorKind::Subtract => "-",
+        OperatorKind::Equal => "==",
+        OperatorKind::NotEqual => "!=",
+        OperatorKind::Or => "||",
+        OperatorKind::And => "&&",
+        OperatorKind::GreaterThan => ">",
+        OperatorKind::GreaterThanOrEqual => ">=",
+        OperatorKind::LessThan => "<",
+        OperatorKind::LessThanOrEqual => "<=",
+        OperatorKind::Concat => "..",
+        OperatorKind::Power => "^",
+        OperatorKind::Not => "not",
+        OperatorKind::Negate => "-",
+        OperatorKind::Length => "#",
+    }
+}
@@ -0,0 +1,347 @@
+use crate::parsetree::ParseTree;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+#[derive(Debug)]
+pub struct QueryError {
+    message: String,
+}
+
+impl Error for QueryError {}
+
+impl QueryError {
+    pub fn from(s: String) -> QueryError {
+        QueryError { message: s }
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Query Error: {}", self.message)
+    }
+}
+
+/// A tree stored as an array of nodes in pre-order, where each node is followed by its
+/// children. Node ids are positions in that order.
+pub trait Queryable {
+    fn node_count(&self) -> usize;
+    fn child_count(&self, id: usize) -> usize;
+    /// Name selectors match against, such as `Function` or `baseExp`.
+    fn kind(&self, id: usize) -> String;
+    fn attribute(&self, id: usize, name: &str) -> Option<String>;
+    /// Range of input tokens the node covers, when known.
+    fn span(&self, _id: usize) -> Option<Range<usize>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub id: usize,
+    pub span: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// One node test, like `Name[value="x"]:nth-child(2)`. No kind matches any node.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Compound {
+    kind: Option<String>,
+    /// Attribute name and the value it must have, or `None` if it only has to be present.
+    attributes: Vec<(String, Option<String>)>,
+    /// 1-based position among the siblings.
+    nth_child: Option<usize>,
+}
+
+/// A parsed selector. Supports kinds, `*`, attribute predicates `[name]` and `[name="value"]`,
+/// `:nth-child(n)`, the descendant (whitespace) and child (`>`) combinators, and lists of
+/// selectors separated by commas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    /// Each alternative is a compound followed by the combinators leading to the next one,
+    /// from the leftmost compound to the subject of the selector.
+    alternatives: Vec<Vec<(Compound, Option<Combinator>)>>,
+}
+
+struct SelectorParser<'a> {
+    input: &'a str,
+    chars: Vec<char>,
+    i: usize,
+}
+
+impl<'a> SelectorParser<'a> {
+    fn error(&self, what: &str) -> QueryError {
+        QueryError::from(format!("{} at column {} of '{}'", what, self.i + 1, self.input))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).copied()
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.i;
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.i += 1;
+        }
+        self.i > start
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), QueryError> {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected '{}'", c)))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, QueryError> {
+        let start = self.i;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            self.i += 1;
+        }
+        if start == self.i {
+            return Err(self.error("Expected a name"));
+        }
+        Ok(self.chars[start..self.i].iter().collect())
+    }
+
+    fn string(&mut self) -> Result<String, QueryError> {
+        let quote = match self.peek() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => return self.identifier(),
+        };
+        self.i += 1;
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("Unterminated string")),
+                Some('\\') => {
+                    self.i += 1;
+                    value.extend(self.peek());
+                }
+                Some(c) if c == quote => break,
+                Some(c) => value.push(c),
+            }
+            self.i += 1;
+        }
+        self.i += 1;
+        Ok(value)
+    }
+
+    fn compound(&mut self) -> Result<Compound, QueryError> {
+        let mut compound = Compound::default();
+        match self.peek() {
+            Some('*') => self.i += 1,
+            Some('[') | Some(':') => (),
+            _ => compound.kind = Some(self.identifier()?),
+        }
+        loop {
+            match self.peek() {
+                Some('[') => {
+                    self.i += 1;
+                    self.skip_whitespace();
+                    let name = self.identifier()?;
+                    self.skip_whitespace();
+                    let value = if self.peek() == Some('=') {
+                        self.i += 1;
+                        self.skip_whitespace();
+                        let value = self.string()?;
+                        self.skip_whitespace();
+                        Some(value)
+                    } else {
+                        None
+                    };
+                    self.expect(']')?;
+                    compound.attributes.push((name, value));
+                }
+                Some(':') => {
+                    self.i += 1;
+                    let pseudo = self.identifier()?;
+                    if pseudo != "nth-child" {
+                        return Err(self.error(&format!("Unknown pseudo-class :{}", pseudo)));
+                    }
+                    self.expect('(')?;
+                    self.skip_whitespace();
+                    let n = self.identifier()?;
+                    compound.nth_child = match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(self.error(&format!(":nth-child takes a positive number, not {}", n))),
+                    };
+                    self.skip_whitespace();
+                    self.expect(')')?;
+                }
+                _ => return Ok(compound),
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<Selector, QueryError> {
+        let mut alternatives = Vec::new();
+        let mut current: Vec<(Compound, Option<Combinator>)> = Vec::new();
+        self.skip_whitespace();
+        loop {
+            let compound = self.compound()?;
+            let spaced = self.skip_whitespace();
+            match self.peek() {
+                None => {
+                    current.push((compound, None));
+                    alternatives.push(current);
+                    return Ok(Selector { alternatives });
+                }
+                Some(',') => {
+                    self.i += 1;
+                    self.skip_whitespace();
+                    current.push((compound, None));
+                    alternatives.push(std::mem::take(&mut current));
+                }
+                Some('>') => {
+                    self.i += 1;
+                    self.skip_whitespace();
+                    current.push((compound, Some(Combinator::Child)));
+                }
+                Some(_) if spaced => current.push((compound, Some(Combinator::Descendant))),
+                Some(c) => return Err(self.error(&format!("Unexpected '{}'", c))),
+            }
+        }
+    }
+}
+
+/// Parent and 1-based position among siblings of every node.
+fn structure<T: Queryable + ?Sized>(tree: &T) -> Vec<(Option<usize>, usize)> {
+    let mut result = Vec::with_capacity(tree.node_count());
+    // Open parents with the number of children seen so far and still expected.
+    let mut stack: Vec<(usize, usize, usize)> = Vec::new();
+    let mut roots = 0;
+    for id in 0..tree.node_count() {
+        match stack.last_mut() {
+            Some((parent, seen, left)) => {
+                *seen += 1;
+                *left -= 1;
+                result.push((Some(*parent), *seen));
+            }
+            None => {
+                roots += 1;
+                result.push((None, roots));
+            }
+        }
+        while stack.last().is_some_and(|(_, _, left)| *left == 0) {
+            stack.pop();
+        }
+        if tree.child_count(id) > 0 {
+            stack.push((id, 0, tree.child_count(id)));
+        }
+    }
+    result
+}
+
+impl Selector {
+    pub fn parse(s: &str) -> Result<Selector, QueryError> {
+        let mut parser = SelectorParser {
+            input: s,
+            chars: s.chars().collect(),
+            i: 0,
+        };
+        parser.selector()
+    }
+
+    fn matches_compound<T: Queryable + ?Sized>(tree: &T, compound: &Compound, id: usize, position: usize) -> bool {
+        if let Some(kind) = &compound.kind {
+            if tree.kind(id) != *kind {
+                return false;
+            }
+        }
+        if compound.nth_child.is_some_and(|n| n != position) {
+            return false;
+        }
+        compound.attributes.iter().all(|(name, value)| match (tree.attribute(id, name), value) {
+            (Some(actual), Some(value)) => actual == *value,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+    }
+
+    /// Whether `id` matches the compounds of `alternative` up to and including `last`.
+    fn matches_from<T: Queryable + ?Sized>(
+        tree: &T,
+        structure: &[(Option<usize>, usize)],
+        alternative: &[(Compound, Option<Combinator>)],
+        last: usize,
+        id: usize,
+    ) -> bool {
+        if !Self::matches_compound(tree, &alternative[last].0, id, structure[id].1) {
+            return false;
+        }
+        if last == 0 {
+            return true;
+        }
+        let mut ancestor = structure[id].0;
+        match alternative[last - 1].1 {
+            Some(Combinator::Child) => ancestor.is_some_and(|p| Self::matches_from(tree, structure, alternative, last - 1, p)),
+            _ => {
+                while let Some(p) = ancestor {
+                    if Self::matches_from(tree, structure, alternative, last - 1, p) {
+                        return true;
+                    }
+                    ancestor = structure[p].0;
+                }
+                false
+            }
+        }
+    }
+
+    /// Every node the selector matches, in pre-order.
+    pub fn select<T: Queryable + ?Sized>(&self, tree: &T) -> Vec<Match> {
+        let structure = structure(tree);
+        (0..tree.node_count())
+            .filter(|id| self.alternatives.iter().any(|a| Self::matches_from(tree, &structure, a, a.len() - 1, *id)))
+            .map(|id| Match { id, span: tree.span(id) })
+            .collect()
+    }
+}
+
+/// Parse `selector` and run it over `tree`.
+pub fn select<T: Queryable + ?Sized>(tree: &T, selector: &str) -> Result<Vec<Match>, QueryError> {
+    Ok(Selector::parse(selector)?.select(tree))
+}
+
+impl Queryable for ParseTree {
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn child_count(&self, id: usize) -> usize {
+        self.nodes[id].child_count
+    }
+
+    fn kind(&self, id: usize) -> String {
+        self.token_map.get(&self.nodes[id].token).cloned().unwrap_or_default()
+    }
+
+    /// Tokens have a `value`, the text they were lexed from.
+    fn attribute(&self, id: usize, name: &str) -> Option<String> {
+        match name {
+            "value" => self.nodes[id].data.as_ref().map(|d| d.raw.clone()),
+            _ => None,
+        }
+    }
+
+    /// Tokens covered by the leaves under the node.
+    fn span(&self, id: usize) -> Option<Range<usize>> {
+        let mut span: Option<Range<usize>> = None;
+        for n in &self.nodes[id..self.subtree_end(id)] {
+            if let Some(d) = &n.data {
+                span = Some(match span {
+                    Some(s) => s.start.min(d.token_index)..s.end.max(d.token_index + 1),
+                    None => d.token_index..d.token_index + 1,
+                });
+            }
+        }
+        span
+    }
+}
@@ -0,0 +1,289 @@
+//! JSON and S-expression encodings of [`ParseTree`] and [`FernAst`], for tools that would
+//! rather not parse dot.
+//!
+//! Both JSON encodings are an object with the [`SCHEMA_VERSION`] they were written with and
+//! the kind of tree, followed by nested nodes. Spans are `[start, end]` ranges of input tokens,
+//! or null where they are not known.
+//!
+//! ```text
+//! { "version": 1, "tree": "parse", "roots": [ { "kind": "stat", "span": [0, 3], "children": [
+//!     { "kind": "NAME", "span": [0, 1], "children": [],
+//!       "token": { "raw": "x", "index": 0, "leading": "", "trailing": " ", "inserted": false } },
+//!     ... ] } ] }
+//! { "version": 1, "tree": "ast", "root": { "kind": "Module", "span": null, "children": [
+//!     { "kind": "Name", "value": "x", "span": [0, 1], "children": [] }, ... ] } }
+//! ```
+//!
+//! A parse tree that stopped short of the axiom has several roots. An AST has one, or null if
+//! it is empty. AST nodes have a `value` where [`AstNodeKind::value`] has one.
+//!
+//! The S-expressions are meant for reading and for comparing in tests. Strings are quoted
+//! with Rust's escapes, and spans are written as `@start..end`.
+//!
+//! ```text
+//! (parse 1 (stat (baseExp (NAME 0 "x")) (EQ 1 "=") ...))
+//! (ast 1 (Module (Assign @0..3 (Name "x" @0..1) (Number "1" @2..3))))
+//! ```
+//!
+//! The version only changes when a reader of the previous one could misread the output.
+//! [`FernAst::from_json`] only reads the current version.
+
+use crate::ast::AstBuilder;
+use crate::fern::{AstNodeKind, FernAst, OPERATORS};
+use crate::parsetree::ParseTree;
+use json_parse::JsonValue;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Write};
+use std::ops::Range;
+
+/// Version of the encodings written by this module.
+pub const SCHEMA_VERSION: usize = 1;
+
+#[derive(Debug)]
+pub struct SerializeError {
+    message: String,
+}
+
+impl Error for SerializeError {}
+
+impl SerializeError {
+    pub fn from(s: String) -> SerializeError {
+        SerializeError { message: s }
+    }
+}
+
+impl Display for SerializeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Serialize Error: {}", self.message)
+    }
+}
+
+fn span_json(span: &Option<Range<usize>>) -> JsonValue {
+    match span {
+        Some(s) => vec![s.start, s.end].into(),
+        None => JsonValue::Null,
+    }
+}
+
+fn union(a: Option<Range<usize>>, b: &Option<Range<usize>>) -> Option<Range<usize>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.start.min(b.start)..a.end.max(b.end)),
+        (a, b) => a.or(b.clone()),
+    }
+}
+
+/// Write nodes given in pre-order with their number of children as nested lists, each after a
+/// space.
+fn write_sexp<I: Iterator<Item = (usize, String)>>(out: &mut String, nodes: I) {
+    let mut pending: Vec<usize> = Vec::new();
+    for (child_count, label) in nodes {
+        write!(out, " ({}", label).unwrap();
+        pending.push(child_count);
+        while pending.last() == Some(&0) {
+            pending.pop();
+            out.push(')');
+            if let Some(parent) = pending.last_mut() {
+                *parent -= 1;
+            }
+        }
+    }
+}
+
+impl ParseTree {
+    /// The tree as JSON, see the [module documentation](crate::serialize).
+    pub fn to_json(&self) -> JsonValue {
+        // Nodes come after all of their descendants in reverse pre-order, and the first child is
+        // on top of the stack when its parent comes up.
+        let mut stack: Vec<(JsonValue, Option<Range<usize>>)> = Vec::new();
+        for n in self.nodes.iter().rev() {
+            let children = stack.split_off(stack.len() - n.child_count.min(stack.len()));
+            let mut span = n.data.as_ref().map(|d| d.token_index..d.token_index + 1);
+            let mut list = JsonValue::new_array();
+            for (child, child_span) in children.into_iter().rev() {
+                span = union(span, &child_span);
+                list.push(child).unwrap();
+            }
+            let mut node = object! {
+                kind: self.token_map.get(&n.token).cloned().unwrap_or_default(),
+                span: span_json(&span),
+                children: list
+            };
+            if let Some(d) = &n.data {
+                node["token"] = object! {
+                    raw: d.raw.clone(),
+                    index: d.token_index,
+                    leading: d.leading.clone(),
+                    trailing: d.trailing.clone(),
+                    inserted: d.inserted
+                };
+            }
+            stack.push((node, span));
+        }
+        let mut roots = JsonValue::new_array();
+        for (root, _) in stack.into_iter().rev() {
+            roots.push(root).unwrap();
+        }
+        object! { version: SCHEMA_VERSION, tree: "parse", roots: roots }
+    }
+
+    /// The tree as an S-expression. Tokens are written with their index and text, but without
+    /// trivia.
+    pub fn to_sexp(&self) -> String {
+        let mut out = format!("(parse {}", SCHEMA_VERSION);
+        let nodes = self.nodes.iter().map(|n| {
+            let kind = self.token_map.get(&n.token).cloned().unwrap_or_default();
+            let label = match &n.data {
+                Some(d) => format!("{} {} {:?}", kind, d.token_index, d.raw),
+                None => kind,
+            };
+            (n.child_count, label)
+        });
+        write_sexp(&mut out, nodes);
+        out.push(')');
+        out
+    }
+}
+
+impl FernAst {
+    /// The AST as JSON, see the [module documentation](crate::serialize).
+    pub fn to_json(&self) -> JsonValue {
+        // Stored in reverse pre-order, so children are built before their parents.
+        let mut stack: Vec<JsonValue> = Vec::new();
+        for n in &self.nodes {
+            let children = stack.split_off(stack.len() - n.child_count.min(stack.len()));
+            let mut list = JsonValue::new_array();
+            for child in children.into_iter().rev() {
+                list.push(child).unwrap();
+            }
+            let mut node = object! { kind: n.kind.name() };
+            if let Some(value) = n.kind.value() {
+                node["value"] = value.into();
+            }
+            node["span"] = span_json(&n.span);
+            node["children"] = list;
+            stack.push(node);
+        }
+        object! { version: SCHEMA_VERSION, tree: "ast", root: stack.pop().unwrap_or(JsonValue::Null) }
+    }
+
+    pub fn to_sexp(&self) -> String {
+        let mut out = format!("(ast {}", SCHEMA_VERSION);
+        let nodes = self.nodes.iter().rev().map(|n| {
+            let mut label = n.kind.name().to_string();
+            if let Some(value) = n.kind.value() {
+                write!(label, " {:?}", value).unwrap();
+            }
+            if let Some(span) = &n.span {
+                write!(label, " @{}..{}", span.start, span.end).unwrap();
+            }
+            (n.child_count, label)
+        });
+        write_sexp(&mut out, nodes);
+        out.push(')');
+        out
+    }
+
+    /// Read an AST written by [`FernAst::to_json`].
+    pub fn from_json(text: &str) -> Result<FernAst, SerializeError> {
+        let json = json_parse::parse(text).map_err(|e| SerializeError::from(format!("Invalid JSON: {}", e)))?;
+        match json["version"].as_usize() {
+            Some(SCHEMA_VERSION) => (),
+            Some(v) => return Err(SerializeError::from(format!("Unsupported schema version {}, expected {}.", v, SCHEMA_VERSION))),
+            None => return Err(SerializeError::from("Missing schema version.".to_string())),
+        }
+        if json["tree"].as_str() != Some("ast") {
+            return Err(SerializeError::from(format!("Expected an AST, found {}.", json["tree"])));
+        }
+
+        let mut out = AstBuilder::new();
+        let root = &json["root"];
+        if root.is_null() {
+            return Ok(out.finish());
+        }
+        // Nodes and how many of their children have been read, so deep trees need no recursion.
+        let mut stack = vec![(root, 0)];
+        out.open(Self::kind_from_json(root)?);
+        Self::span_from_json(root, &mut out)?;
+        while let Some((node, next)) = stack.last_mut() {
+            let node: &JsonValue = node;
+            if !node["children"].is_array() {
+                return Err(SerializeError::from(format!("Expected a list of children in {}.", node["kind"])));
+            }
+            match node["children"].members().nth(*next) {
+                Some(child) => {
+                    *next += 1;
+                    out.open(Self::kind_from_json(child)?);
+                    Self::span_from_json(child, &mut out)?;
+                    stack.push((child, 0));
+                }
+                None => {
+                    out.close();
+                    stack.pop();
+                }
+            }
+        }
+        Ok(out.finish())
+    }
+
+    fn kind_from_json(node: &JsonValue) -> Result<AstNodeKind, SerializeError> {
+        let name = node["kind"]
+            .as_str()
+            .ok_or_else(|| SerializeError::from(format!("Expected a node kind, found {}.", node["kind"])))?;
+        let value = || {
+            node["value"]
+                .as_str()
+                .map(|v| v.to_string())
+                .ok_or_else(|| SerializeError::from(format!("{} has no value.", name)))
+        };
+        let kind = match name {
+            "Operator" => {
+                let value = value()?;
+                let op = OPERATORS.iter().find(|op| format!("{:?}", op) == value);
+                AstNodeKind::Operator(*op.ok_or_else(|| SerializeError::from(format!("Unknown operator {}.", value)))?)
+            }
+            "Number" => AstNodeKind::Number(value()?),
+            "String" => AstNodeKind::String(value()?),
+            "Name" => AstNodeKind::Name(value()?),
+            "Bool" => match value()?.as_str() {
+                "true" => AstNodeKind::Bool(true),
+                "false" => AstNodeKind::Bool(false),
+                v => return Err(SerializeError::from(format!("Expected true or false, found {}.", v))),
+            },
+            "Nil" => AstNodeKind::Nil,
+            "Member" => AstNodeKind::Member,
+            "Field" => AstNodeKind::Field,
+            "ExprList" => AstNodeKind::ExprList,
+            "FieldList" => AstNodeKind::FieldList,
+            "Assign" => AstNodeKind::Assign,
+            "Let" => AstNodeKind::Let,
+            "LetAssign" => AstNodeKind::LetAssign,
+            "Return" => AstNodeKind::Return,
+            "Module" => AstNodeKind::Module,
+            "StatList" => AstNodeKind::StatList,
+            "FunctionCall" => AstNodeKind::FunctionCall,
+            "Function" => AstNodeKind::Function,
+            "If" => AstNodeKind::If,
+            "ElseIf" => AstNodeKind::ElseIf,
+            "Else" => AstNodeKind::Else,
+            "For" => AstNodeKind::For,
+            "While" => AstNodeKind::While,
+            "Struct" => AstNodeKind::Struct,
+            _ => return Err(SerializeError::from(format!("Unknown node kind {}.", name))),
+        };
+        Ok(kind)
+    }
+
+    fn span_from_json(node: &JsonValue, out: &mut AstBuilder) -> Result<(), SerializeError> {
+        let span = &node["span"];
+        if span.is_null() {
+            return Ok(());
+        }
+        match (span[0].as_usize(), span[1].as_usize(), span.len()) {
+            (Some(start), Some(end), 2) if start <= end => {
+                out.span(start..end);
+                Ok(())
+            }
+            _ => Err(SerializeError::from(format!("Invalid span {} in {}.", span, node["kind"]))),
+        }
+    }
+}
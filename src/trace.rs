@@ -0,0 +1,214 @@
+use crate::grammar::compiled::CompiledGrammar;
+use crate::grammar::opg::{Associativity, Token};
+use crate::parser::ParseError;
+use json_parse::JsonValue;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// A stack entry as it was when an event happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackEntry {
+    pub token: Token,
+    /// Relation the token was shifted with, `Undefined` for nonterminals.
+    pub relation: Associativity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseEvent {
+    /// `token`, the input at `position`, was pushed with its relation to the topmost terminal.
+    Shift {
+        token: Token,
+        relation: Associativity,
+        position: usize,
+    },
+    /// The handle on top of the stack matched `left : right` and was replaced by `left`.
+    Reduce {
+        left: Token,
+        right: Vec<Token>,
+    },
+    /// Only the axiom is left on the stack.
+    Axiom,
+    Error(ParseError),
+    /// Input the parser could not use was replaced by an error node.
+    Recover,
+}
+
+/// An event and how it changed the stack: `popped` entries were taken off the top, then `pushed`
+/// was put on, bottom first. [`ParseTrace::stacks`] has the whole stack after every step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub event: ParseEvent,
+    pub popped: usize,
+    pub pushed: Vec<StackEntry>,
+}
+
+/// Every step a [`Parser`] took, recorded when its `trace` is set to `Some`.
+///
+/// [`Parser`]: crate::parser::Parser
+#[derive(Debug, Clone)]
+pub struct ParseTrace {
+    pub steps: Vec<Step>,
+    names: Arc<BTreeMap<Token, String>>,
+    /// The stack after the last step.
+    stack: Vec<StackEntry>,
+}
+
+fn relation_symbol(relation: Associativity) -> &'static str {
+    match relation {
+        Associativity::Left => "⋖",
+        Associativity::Equal => "≐",
+        Associativity::Right => "⋗",
+        _ => "",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl ParseTrace {
+    pub fn new(grammar: &CompiledGrammar) -> Self {
+        Self {
+            steps: Vec::new(),
+            names: grammar.short_names().clone(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Add a step, keeping only the entries of `stack` that changed since the last one.
+    pub(crate) fn record(&mut self, event: ParseEvent, stack: impl Iterator<Item = StackEntry>) {
+        let mut kept = 0;
+        let mut pushed = Vec::new();
+        for entry in stack {
+            if pushed.is_empty() && self.stack.get(kept) == Some(&entry) {
+                kept += 1;
+            } else {
+                pushed.push(entry);
+            }
+        }
+        let popped = self.stack.len() - kept;
+        self.stack.truncate(kept);
+        self.stack.extend(pushed.iter().cloned());
+        self.steps.push(Step { event, popped, pushed });
+    }
+
+    /// The stack after every step, bottom first.
+    pub fn stacks(&self) -> impl Iterator<Item = Vec<StackEntry>> + '_ {
+        self.steps.iter().scan(Vec::new(), |stack: &mut Vec<StackEntry>, step| {
+            stack.truncate(stack.len() - step.popped);
+            stack.extend(step.pushed.iter().cloned());
+            Some(stack.clone())
+        })
+    }
+
+    fn name(&self, t: Token) -> String {
+        self.names.get(&t).cloned().unwrap_or_else(|| t.to_string())
+    }
+
+    fn event_name(event: &ParseEvent) -> &'static str {
+        match event {
+            ParseEvent::Shift { .. } => "shift",
+            ParseEvent::Reduce { .. } => "reduce",
+            ParseEvent::Axiom => "axiom",
+            ParseEvent::Error(_) => "error",
+            ParseEvent::Recover => "recover",
+        }
+    }
+
+    /// One line description of an event, without the stack.
+    pub fn describe(&self, event: &ParseEvent) -> String {
+        match event {
+            ParseEvent::Shift { token, relation, position } => {
+                format!("{} {} at token {}", relation_symbol(*relation), self.name(*token), position)
+            }
+            ParseEvent::Reduce { left, right } => {
+                let right: Vec<String> = right.iter().map(|t| self.name(*t)).collect();
+                format!("{} : {}", self.name(*left), right.join(" "))
+            }
+            ParseEvent::Axiom => String::from("Reached axiom"),
+            ParseEvent::Error(e) => e.to_string(),
+            ParseEvent::Recover => String::from("Replaced unusable input with an error node"),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut steps = JsonValue::new_array();
+        for (step, entries) in self.steps.iter().zip(self.stacks()) {
+            let mut stack = JsonValue::new_array();
+            for entry in &entries {
+                stack
+                    .push(object! {
+                        token: self.name(entry.token),
+                        relation: format!("{:?}", entry.relation)
+                    })
+                    .unwrap();
+            }
+            let mut event = object! { event: Self::event_name(&step.event) };
+            match &step.event {
+                ParseEvent::Shift { token, relation, position } => {
+                    event["token"] = self.name(*token).into();
+                    event["relation"] = format!("{:?}", relation).into();
+                    event["position"] = (*position).into();
+                }
+                ParseEvent::Reduce { left, right } => {
+                    event["left"] = self.name(*left).into();
+                    event["right"] = right.iter().map(|t| self.name(*t)).collect::<Vec<String>>().into();
+                }
+                ParseEvent::Error(e) => {
+                    event["message"] = e.to_string().into();
+                    event["span"] = vec![e.span.start, e.span.end].into();
+                    event["found"] = self.name(e.found).into();
+                    event["expected"] = e.expected.iter().map(|t| self.name(*t)).collect::<Vec<String>>().into();
+                }
+                ParseEvent::Axiom | ParseEvent::Recover => (),
+            }
+            event["stack"] = stack;
+            steps.push(event).unwrap();
+        }
+        object! { steps: steps }
+    }
+
+    /// Render the trace as a standalone HTML page with one row per step. Relations are shown
+    /// in front of the tokens that were shifted with them.
+    pub fn html<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html><head><meta charset=\"utf-8\"><title>Parse trace</title><style>")?;
+        writeln!(out, "body {{ font-family: monospace; }} table {{ border-collapse: collapse; }}")?;
+        writeln!(out, "td, th {{ border: 1px solid #ccc; padding: 2px 6px; text-align: left; }}")?;
+        writeln!(out, ".shift {{ background: #eef6ff; }} .reduce {{ background: #efffee; }}")?;
+        writeln!(
+            out,
+            ".error {{ background: #ffeeee; }} .recover {{ background: #fff6e0; }} .axiom {{ font-weight: bold; }}"
+        )?;
+        writeln!(out, ".relation {{ color: #888; }} .top {{ font-weight: bold; }}")?;
+        writeln!(out, "</style></head><body>")?;
+        writeln!(out, "<table><tr><th>#</th><th>Event</th><th></th><th>Stack</th></tr>")?;
+        for (i, (step, entries)) in self.steps.iter().zip(self.stacks()).enumerate() {
+            let class = Self::event_name(&step.event);
+            let mut stack = String::new();
+            for (j, entry) in entries.iter().enumerate() {
+                if j + 1 == entries.len() {
+                    stack.push_str("<span class=\"top\">");
+                } else {
+                    stack.push_str("<span>");
+                }
+                stack.push_str(&format!(
+                    "<span class=\"relation\">{}</span>{}</span> ",
+                    relation_symbol(entry.relation),
+                    escape(&self.name(entry.token))
+                ));
+            }
+            writeln!(
+                out,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                class,
+                i,
+                class,
+                escape(&self.describe(&step.event)),
+                stack.trim_end()
+            )?;
+        }
+        writeln!(out, "</table></body></html>")
+    }
+}
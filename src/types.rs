@@ -0,0 +1,631 @@
+//! Type checking and inference. Every expression and declaration gets a type, and operators,
+//! calls, assignments, returns and conditions are checked against them.
+//!
+//! Types come from annotations on parameters, lets and return types, which name one of the
+//! built in types or a struct. Anything without one starts out as a type variable, which is
+//! bound by unifying it with the types it meets across the module: the value of its `let`,
+//! later assignments, arguments of calls and returned values. Integer literals are variables
+//! only integer types can be bound to, and are `i64` if nothing binds them. A function that
+//! never returns a value returns `nil`. A declaration whose type is still a variable at the
+//! end cannot be inferred, which is reported once for each variable.
+//!
+//! [`Type::Unknown`] is the type of names that did not resolve and of other mistakes that are
+//! already reported, and goes with anything so they are not reported again.
+
+use crate::analysis::Resolution;
+use crate::ast::{
+    AssignStmt, AstView, BinaryExpr, CallExpr, ElseIfStmt, Expr, ForStmt, FunctionDecl, IfStmt, LetStmt, MemberExpr, NameExpr, Node, NodeId, ReturnStmt,
+    StructDecl, UnaryExpr, Visitor, WhileStmt,
+};
+use crate::fern::{FernAst, OperatorKind};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+#[derive(Debug)]
+pub struct TypeError {
+    /// The expression, statement or name the error is about.
+    pub node: NodeId,
+    /// Input tokens of the node, if it came from source.
+    pub span: Option<Range<usize>>,
+    message: String,
+}
+
+impl Error for TypeError {}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Type Error: {}", self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    Bool,
+    Str,
+    Nil,
+    /// One of the integer types, not yet known which. Only in diagnostics.
+    Integer,
+    Struct(String),
+    /// Parameter types and the return type.
+    Function(Vec<Type>, Box<Type>),
+    /// A type being inferred. None are left in a [`Typing`].
+    Var(usize),
+    Unknown,
+}
+
+impl Type {
+    /// The built in type called `name`, such as `u32`.
+    pub fn builtin(name: &str) -> Option<Type> {
+        Some(match name {
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "bool" => Type::Bool,
+            "str" => Type::Str,
+            _ => return None,
+        })
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::I8 | Type::I16 | Type::I32 | Type::I64 | Type::U8 | Type::U16 | Type::U32 | Type::U64 | Type::Integer
+        )
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::U8 => write!(f, "u8"),
+            Type::U16 => write!(f, "u16"),
+            Type::U32 => write!(f, "u32"),
+            Type::U64 => write!(f, "u64"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "str"),
+            Type::Nil => write!(f, "nil"),
+            Type::Integer => write!(f, "integer"),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "fn[{}]", params.join(", "))?;
+                match **ret {
+                    Type::Nil => Ok(()),
+                    ref ret => write!(f, " -> {}", ret),
+                }
+            }
+            Type::Var(_) => write!(f, "_"),
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Types of the expressions and declarations of an AST, by node id. Declarations are the
+/// `Name` nodes [`Resolution`] identifies them by.
+#[derive(Debug, Default)]
+pub struct Typing {
+    types: BTreeMap<NodeId, Type>,
+    errors: Vec<TypeError>,
+}
+
+impl Typing {
+    pub fn type_of(&self, id: NodeId) -> Option<&Type> {
+        self.types.get(&id)
+    }
+
+    pub fn types(&self) -> impl Iterator<Item = (NodeId, &Type)> {
+        self.types.iter().map(|(id, t)| (*id, t))
+    }
+
+    pub fn errors(&self) -> &[TypeError] {
+        &self.errors
+    }
+}
+
+impl FernAst {
+    /// Infer and check the types of the AST, with names resolved by `resolution`. See the
+    /// [module documentation](crate::types).
+    pub fn check(&self, resolution: &Resolution) -> Typing {
+        let mut checker = Checker {
+            resolution,
+            structs: BTreeMap::new(),
+            returns: None,
+            returned: false,
+            vars: Vec::new(),
+            trail: Vec::new(),
+            inferred: Vec::new(),
+            typing: Typing::default(),
+        };
+        if let Some(root) = self.root() {
+            checker.declarations(root);
+            checker.visit_node(root);
+        }
+        checker.finish()
+    }
+}
+
+/// What a type variable stands for so far.
+#[derive(Clone, Debug)]
+enum Binding {
+    Free { integer: bool },
+    Bound(Type),
+}
+
+struct Checker<'r> {
+    resolution: &'r Resolution,
+    /// Fields of each struct, by name.
+    structs: BTreeMap<String, Vec<(String, Type)>>,
+    /// Return type of the function being checked.
+    returns: Option<Type>,
+    /// Whether the function being checked has returned a value so far.
+    returned: bool,
+    vars: Vec<Binding>,
+    /// Variables changed by the unification in progress and what they were before, to undo
+    /// it if it fails.
+    trail: Vec<(usize, Binding)>,
+    /// Declarations without annotations, the variable for their type and what to call them
+    /// if it cannot be inferred.
+    inferred: Vec<(Node<'r>, Type, String)>,
+    typing: Typing,
+}
+
+impl<'r> Checker<'r> {
+    fn error(&mut self, node: Node, message: String) {
+        self.typing.errors.push(TypeError {
+            node: node.id(),
+            span: node.span(),
+            message,
+        });
+    }
+
+    /// Types of structs, functions and parameters, which can be used before they are declared.
+    fn declarations(&mut self, root: Node<'r>) {
+        let structs: Vec<StructDecl> = root.descendants().filter_map(Node::cast).collect();
+        for s in &structs {
+            if let Some(name) = s.name() {
+                self.structs.entry(name.name().to_string()).or_default();
+            }
+        }
+        for s in &structs {
+            let fields = s
+                .fields()
+                .iter()
+                .filter_map(|f| Some((f.name()?.name().to_string(), f.ty().map_or(Type::Unknown, |t| self.annotation(t)))))
+                .collect();
+            if let Some(name) = s.name() {
+                self.structs.insert(name.name().to_string(), fields);
+            }
+        }
+
+        for function in root.descendants().filter_map(FunctionDecl::cast) {
+            let mut params = Vec::new();
+            for param in function.params() {
+                let name = param.name();
+                let ty = self.declared(param.ty(), name.map(|n| (n, format!("the type of {}", n.name()))));
+                if let Some(name) = name {
+                    self.typing.types.insert(name.node().id(), ty.clone());
+                }
+                params.push(ty);
+            }
+            let name = function.name();
+            let ret = self.declared(function.return_type(), name.map(|n| (n, format!("the return type of {}", n.name()))));
+            if let Some(name) = name {
+                self.typing.types.insert(name.node().id(), Type::Function(params, Box::new(ret)));
+            }
+        }
+    }
+
+    /// The type an annotation names.
+    fn annotation(&mut self, ty: NameExpr) -> Type {
+        if let Some(builtin) = Type::builtin(ty.name()) {
+            return builtin;
+        }
+        if self.structs.contains_key(ty.name()) {
+            return Type::Struct(ty.name().to_string());
+        }
+        self.error(ty.node(), format!("Unknown type {}.", ty.name()));
+        Type::Unknown
+    }
+
+    /// The type of a declaration, which is inferred if it has no annotation. `what` is the name
+    /// it is declared with, and how to describe it if it cannot be.
+    fn declared(&mut self, ty: Option<NameExpr>, what: Option<(NameExpr<'r>, String)>) -> Type {
+        if let Some(ty) = ty {
+            return self.annotation(ty);
+        }
+        let ty = self.fresh(false);
+        if let Some((name, what)) = what {
+            self.inferred.push((name.node(), ty.clone(), what));
+        }
+        ty
+    }
+
+    fn fresh(&mut self, integer: bool) -> Type {
+        self.vars.push(Binding::Free { integer });
+        Type::Var(self.vars.len() - 1)
+    }
+
+    fn set(&mut self, var: usize, binding: Binding) {
+        let old = std::mem::replace(&mut self.vars[var], binding);
+        self.trail.push((var, old));
+    }
+
+    /// `ty`, or what it is bound to if it is a variable, down to a type that is not a bound
+    /// variable.
+    fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty {
+            match &self.vars[v] {
+                Binding::Bound(bound) => ty = bound.clone(),
+                Binding::Free { .. } => break,
+            }
+        }
+        ty
+    }
+
+    /// `ty` with every bound variable replaced by what it is bound to, and free integer
+    /// variables by [`Type::Integer`].
+    fn show(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Var(v) if matches!(self.vars[v], Binding::Free { integer: true }) => Type::Integer,
+            Type::Function(params, ret) => Type::Function(params.iter().map(|p| self.show(p)).collect(), Box::new(self.show(&ret))),
+            ty => ty,
+        }
+    }
+
+    fn occurs(&self, var: usize, ty: &Type) -> bool {
+        match self.shallow(ty) {
+            Type::Var(v) => v == var,
+            Type::Function(params, ret) => params.iter().any(|p| self.occurs(var, p)) || self.occurs(var, &ret),
+            _ => false,
+        }
+    }
+
+    /// Make `a` and `b` the same type by binding variables in them. Nothing is bound if they
+    /// cannot be.
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let mark = self.trail.len();
+        let unified = self.unify_inner(a, b);
+        if !unified {
+            while self.trail.len() > mark {
+                let (var, old) = self.trail.pop().unwrap();
+                self.vars[var] = old;
+            }
+        }
+        self.trail.truncate(mark);
+        unified
+    }
+
+    fn unify_inner(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.shallow(a), self.shallow(b)) {
+            // Whatever made it unknown is already reported.
+            (Type::Var(x), Type::Unknown) | (Type::Unknown, Type::Var(x)) => {
+                self.set(x, Binding::Bound(Type::Unknown));
+                true
+            }
+            (Type::Unknown, _) | (_, Type::Unknown) => true,
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(x), Type::Var(y)) => {
+                if matches!(self.vars[x], Binding::Free { integer: true }) {
+                    self.set(y, Binding::Free { integer: true });
+                }
+                self.set(x, Binding::Bound(Type::Var(y)));
+                true
+            }
+            (Type::Var(x), ty) | (ty, Type::Var(x)) => {
+                let integer = matches!(self.vars[x], Binding::Free { integer: true });
+                if integer && !ty.is_integer() || self.occurs(x, &ty) {
+                    return false;
+                }
+                self.set(x, Binding::Bound(ty));
+                true
+            }
+            (Type::Function(p1, r1), Type::Function(p2, r2)) => {
+                p1.len() == p2.len() && p1.iter().zip(&p2).all(|(a, b)| self.unify_inner(a, b)) && self.unify_inner(&r1, &r2)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Whether `ty` is or can only be one of the integer types.
+    fn integer(&mut self, ty: &Type) -> bool {
+        match self.shallow(ty) {
+            Type::Var(v) => {
+                self.vars[v] = Binding::Free { integer: true };
+                true
+            }
+            ty => ty.is_integer() || ty == Type::Unknown,
+        }
+    }
+
+    /// Default integer variables to `i64`, report declarations left with variables in their
+    /// types, and replace the variables in the table with what they stand for.
+    fn finish(mut self) -> Typing {
+        for binding in &mut self.vars {
+            if let Binding::Free { integer: true } = binding {
+                *binding = Binding::Bound(Type::I64);
+            }
+        }
+
+        let mut reported = BTreeSet::new();
+        self.inferred.sort_by_key(|(node, _, _)| node.id());
+        for (node, ty, what) in std::mem::take(&mut self.inferred) {
+            let free = self.free(&ty);
+            if !free.is_empty() && free.is_disjoint(&reported) {
+                self.error(node, format!("Cannot infer {}.", what));
+            }
+            reported.extend(free);
+        }
+
+        let types = std::mem::take(&mut self.typing.types);
+        self.typing.types = types.into_iter().map(|(id, ty)| (id, self.resolve(&ty))).collect();
+        self.typing
+    }
+
+    /// Free variables in `ty`.
+    fn free(&self, ty: &Type) -> BTreeSet<usize> {
+        match self.shallow(ty) {
+            Type::Var(v) => BTreeSet::from([v]),
+            Type::Function(params, ret) => params.iter().chain([&*ret]).flat_map(|t| self.free(t)).collect(),
+            _ => BTreeSet::new(),
+        }
+    }
+
+    /// `ty` with no variables left, where free ones are Unknown.
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Var(_) => Type::Unknown,
+            Type::Function(params, ret) => Type::Function(params.iter().map(|p| self.resolve(p)).collect(), Box::new(self.resolve(&ret))),
+            ty => ty,
+        }
+    }
+
+    /// Check that `value` can be given to something of type `expected`.
+    fn expect(&mut self, value: Expr, expected: &Type, what: &str) {
+        let found = self.expr(value);
+        if !self.unify(&found, expected) {
+            let message = format!("Expected {} {}, found {}.", what, self.show(expected), self.show(&found));
+            self.error(value.node(), message);
+        }
+    }
+
+    fn condition(&mut self, condition: Option<Expr>) {
+        if let Some(condition) = condition {
+            self.expect(condition, &Type::Bool, "a condition of type");
+        }
+    }
+
+    fn body(&mut self, body: Option<Node<'r>>) {
+        if let Some(body) = body {
+            self.visit_node(body);
+        }
+    }
+
+    fn expr(&mut self, expr: Expr) -> Type {
+        let ty = match expr {
+            Expr::Binary(e) => self.binary(e),
+            Expr::Unary(e) => self.unary(e),
+            Expr::Member(e) => self.member(e),
+            Expr::Call(e) => self.call(e),
+            Expr::Name(e) => self
+                .resolution
+                .resolve(e.node().id())
+                .and_then(|declaration| self.typing.types.get(&declaration).cloned())
+                .unwrap_or(Type::Unknown),
+            Expr::Number(_) => self.fresh(true),
+            Expr::String(_) => Type::Str,
+            Expr::Bool(_) => Type::Bool,
+            Expr::Nil(_) => Type::Nil,
+        };
+        self.typing.types.insert(expr.node().id(), ty.clone());
+        ty
+    }
+
+    fn operand(&mut self, operand: Option<Expr>) -> Type {
+        operand.map_or(Type::Unknown, |e| self.expr(e))
+    }
+
+    fn binary(&mut self, expr: BinaryExpr) -> Type {
+        let lhs = self.operand(expr.lhs());
+        let rhs = self.operand(expr.rhs());
+        let (ok, what) = match expr.op() {
+            OperatorKind::Equal | OperatorKind::NotEqual => (self.unify(&lhs, &rhs), "operands of the same type"),
+            OperatorKind::And | OperatorKind::Or => {
+                let left = self.unify(&lhs, &Type::Bool);
+                (self.unify(&rhs, &Type::Bool) && left, "bool operands")
+            }
+            OperatorKind::Concat => {
+                let left = self.unify(&lhs, &Type::Str);
+                (self.unify(&rhs, &Type::Str) && left, "str operands")
+            }
+            _ => (self.unify(&lhs, &rhs) && self.integer(&lhs), "integer operands of the same type"),
+        };
+        if !ok {
+            let message = format!("Operator {:?} expects {}, found {} and {}.", expr.op(), what, self.show(&lhs), self.show(&rhs));
+            self.error(expr.node(), message);
+        }
+        match expr.op() {
+            OperatorKind::Concat => Type::Str,
+            OperatorKind::Add | OperatorKind::Subtract | OperatorKind::Multiply | OperatorKind::Divide | OperatorKind::Modulo | OperatorKind::Power => match ok
+            {
+                true => lhs,
+                false => Type::Unknown,
+            },
+            _ => Type::Bool,
+        }
+    }
+
+    fn unary(&mut self, expr: UnaryExpr) -> Type {
+        let operand = self.operand(expr.operand());
+        let (ok, what, result) = match expr.op() {
+            OperatorKind::Not => (self.unify(&operand, &Type::Bool), "a bool operand", Type::Bool),
+            OperatorKind::Length => (self.unify(&operand, &Type::Str), "a str operand", Type::U64),
+            _ => (self.integer(&operand), "an integer operand", operand.clone()),
+        };
+        if !ok {
+            let message = format!("Operator {:?} expects {}, found {}.", expr.op(), what, self.show(&operand));
+            self.error(expr.node(), message);
+            return Type::Unknown;
+        }
+        result
+    }
+
+    fn member(&mut self, expr: MemberExpr) -> Type {
+        let object = self.operand(expr.object());
+        let Some(field) = expr.field() else {
+            return Type::Unknown;
+        };
+        match self.show(&object) {
+            // Which struct it is has to be known before its fields can be.
+            Type::Unknown | Type::Var(_) => Type::Unknown,
+            Type::Struct(name) => {
+                let fields = self.structs.get(&name).into_iter().flatten();
+                if let Some((_, ty)) = fields.clone().find(|(f, _)| f == field.name()) {
+                    return ty.clone();
+                }
+                self.error(field.node(), format!("Struct {} has no field {}.", name, field.name()));
+                Type::Unknown
+            }
+            object => {
+                self.error(expr.node(), format!("{} has no fields.", object));
+                Type::Unknown
+            }
+        }
+    }
+
+    fn call(&mut self, expr: CallExpr) -> Type {
+        let callee = self.operand(expr.callee());
+        let arguments = expr.arguments();
+        match self.shallow(&callee) {
+            Type::Function(params, ret) => {
+                if params.len() != arguments.len() {
+                    let message = format!("Expected {} arguments, found {}.", params.len(), arguments.len());
+                    self.error(expr.node(), message);
+                }
+                for (i, argument) in arguments.into_iter().enumerate() {
+                    match params.get(i) {
+                        Some(param) => self.expect(argument, param, "an argument of type"),
+                        None => {
+                            self.expr(argument);
+                        }
+                    }
+                }
+                *ret
+            }
+            callee => {
+                let arguments: Vec<Type> = arguments.into_iter().map(|a| self.expr(a)).collect();
+                let ret = self.fresh(false);
+                let function = Type::Function(arguments, Box::new(ret.clone()));
+                if !self.unify(&callee, &function) {
+                    self.error(expr.node(), format!("{} is not a function.", self.show(&callee)));
+                    return Type::Unknown;
+                }
+                ret
+            }
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for Checker<'a> {
+    fn visit_function(&mut self, function: FunctionDecl<'a>) {
+        let ret = match function.name().and_then(|n| self.typing.types.get(&n.node().id())) {
+            Some(Type::Function(_, ret)) => (**ret).clone(),
+            _ => Type::Unknown,
+        };
+        let outer = (self.returns.replace(ret.clone()), std::mem::take(&mut self.returned));
+        self.body(function.body());
+        if !self.returned && matches!(self.shallow(&ret), Type::Var(_)) {
+            self.unify(&ret, &Type::Nil);
+        }
+        (self.returns, self.returned) = outer;
+    }
+
+    fn visit_struct(&mut self, _declaration: StructDecl<'a>) {}
+
+    fn visit_let(&mut self, statement: LetStmt<'a>) {
+        let ty = match (statement.ty(), statement.value()) {
+            (Some(ty), Some(value)) => {
+                let declared = self.annotation(ty);
+                self.expect(value, &declared, "a value of type");
+                declared
+            }
+            (None, Some(value)) => self.expr(value),
+            (ty, None) => self.declared(ty, statement.name().map(|n| (n, format!("the type of {}", n.name())))),
+        };
+        if let Some(name) = statement.name() {
+            self.typing.types.insert(name.node().id(), ty);
+        }
+    }
+
+    fn visit_assign(&mut self, statement: AssignStmt<'a>) {
+        let target = self.operand(statement.target());
+        if let Some(value) = statement.value() {
+            self.expect(value, &target, "a value of type");
+        }
+    }
+
+    fn visit_return(&mut self, statement: ReturnStmt<'a>) {
+        let expected = self.returns.clone().unwrap_or(Type::Unknown);
+        match statement.value() {
+            Some(value) => {
+                self.returned = true;
+                self.expect(value, &expected, "a return value of type");
+            }
+            None if !self.unify(&expected, &Type::Nil) => {
+                let message = format!("Expected a return value of type {}.", self.show(&expected));
+                self.error(statement.node(), message);
+            }
+            None => (),
+        }
+    }
+
+    fn visit_if(&mut self, statement: IfStmt<'a>) {
+        self.condition(statement.condition());
+        self.body(statement.body());
+        self.body(statement.else_branch());
+    }
+
+    fn visit_else_if(&mut self, statement: ElseIfStmt<'a>) {
+        self.condition(statement.condition());
+        self.body(statement.body());
+        self.body(statement.else_branch());
+    }
+
+    fn visit_while(&mut self, statement: WhileStmt<'a>) {
+        self.condition(statement.condition());
+        self.body(statement.body());
+    }
+
+    fn visit_for(&mut self, statement: ForStmt<'a>) {
+        for iterable in statement.iterables() {
+            self.expr(iterable);
+        }
+        for binding in statement.bindings() {
+            self.typing.types.insert(binding.node().id(), Type::Unknown);
+        }
+        self.body(statement.body());
+    }
+
+    fn visit_call(&mut self, expr: CallExpr<'a>) {
+        self.expr(Expr::Call(expr));
+    }
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::ast;
+use libfern::analysis::{DeclarationKind, ScopeKind};
+use libfern::ast::{Node, NodeId};
+use libfern::fern::{AstNodeKind, FernAst};
+
+/// The id of the `n`th `Name` node called `name`, in pre-order.
+fn name(ast: &FernAst, name: &str, n: usize) -> NodeId {
+    let mut names = ast
+        .root()
+        .unwrap()
+        .descendants()
+        .filter(|node: &Node| *node.kind() == AstNodeKind::Name(name.to_string()));
+    names.nth(n).unwrap().id()
+}
+
+#[test]
+fn uses_resolve_to_their_declarations() {
+    let ast = ast("fn shadow[x: int] {
+	let y = x;
+	if y {
+		let y = 1;
+		let x = y;
+	}
+	return helper(x);
+}
+
+fn helper[n: int] {
+	return n;
+}
+");
+    let resolution = ast.resolve();
+    assert!(resolution.errors().is_empty());
+
+    let param = name(&ast, "x", 0);
+    assert_eq!(resolution.declaration(param).unwrap().kind, DeclarationKind::Parameter);
+    assert_eq!(resolution.resolve(name(&ast, "x", 1)), Some(param));
+    assert_eq!(resolution.resolve(name(&ast, "y", 1)), Some(name(&ast, "y", 0)));
+    // The inner `let y` shadows the outer one, and the inner `x` goes out of scope.
+    assert_eq!(resolution.resolve(name(&ast, "y", 3)), Some(name(&ast, "y", 2)));
+    assert_eq!(resolution.resolve(name(&ast, "x", 3)), Some(param));
+    // Functions can be called before they are declared.
+    let helper = name(&ast, "helper", 1);
+    assert_eq!(resolution.declaration(helper).unwrap().kind, DeclarationKind::Function);
+    assert_eq!(resolution.resolve(name(&ast, "helper", 0)), Some(helper));
+    assert_eq!(resolution.declaration(name(&ast, "x", 0)).unwrap().name, "x");
+    assert_eq!(resolution.resolve(param), None);
+}
+
+#[test]
+fn lets_stay_in_their_block() {
+    let ast = ast("fn main[argc: int] {
+	let x = 3 + 4 / 10 * 30;
+	if x {
+		let y = 3 * 3 / 100;
+	}
+	return y;
+}
+");
+    let resolution = ast.resolve();
+    let errors: Vec<String> = resolution.errors().iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, ["Resolve Error: Identifier y used but not declared."]);
+    let error = &resolution.errors()[0];
+    assert_eq!(error.node, name(&ast, "y", 1));
+    assert_eq!(error.span, Some(34..35));
+}
+
+#[test]
+fn declarations_are_checked_per_scope() {
+    let ast = ast("let x = 1;\nlet x = x;\nfn f[a: int, a: int] {\n\tlet x = a;\n}\n");
+    let resolution = ast.resolve();
+    let errors: Vec<String> = resolution.errors().iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        errors,
+        ["Resolve Error: Identifier x already exists.", "Resolve Error: Identifier a already exists."]
+    );
+    // The value of a let is resolved before its name is declared.
+    assert_eq!(resolution.resolve(name(&ast, "x", 2)), Some(name(&ast, "x", 0)));
+    assert_eq!(resolution.resolve(name(&ast, "a", 2)), Some(name(&ast, "a", 1)));
+}
+
+#[test]
+fn scopes_form_a_tree() {
+    let ast = ast("fn f[a: int] {\n\tif a {\n\t\tlet b = a;\n\t} else {\n\t\treturn a;\n\t}\n}\n");
+    let resolution = ast.resolve();
+    let scopes = resolution.scopes();
+    let kinds: Vec<ScopeKind> = scopes.iter().map(|s| s.kind).collect();
+    assert_eq!(kinds, [ScopeKind::Module, ScopeKind::Function, ScopeKind::Block, ScopeKind::Block]);
+    assert_eq!(scopes[0].parent, None);
+    assert_eq!(scopes[0].nodes, [1]);
+    assert_eq!(scopes[1].nodes, [2, 3]);
+    assert_eq!(scopes[2].parent, Some(1));
+    assert_eq!(scopes[0].node, 0);
+    assert_eq!(*ast.node(scopes[1].node).kind(), AstNodeKind::Function);
+    assert_eq!(scopes[0].tbl.keys().collect::<Vec<_>>(), ["f"]);
+    assert_eq!(scopes[1].tbl.keys().collect::<Vec<_>>(), ["a"]);
+    assert_eq!(scopes[2].tbl.get("b"), Some(&name(&ast, "b", 0)));
+    assert_eq!(resolution.declaration(name(&ast, "b", 0)).unwrap().scope, 2);
+}
@@ -0,0 +1,191 @@
+mod common;
+
+use common::ast;
+use libfern::ast::{fold_children, walk, AstBuilder, AstView, BinaryExpr, Expr, Fold, FunctionDecl, LetStmt, NameExpr, Node, Stmt, Visitor, VisitorMut};
+use libfern::fern::{AstNodeKind, FernAst, OperatorKind};
+
+const SOURCE: &str = "fn main[] {
+	let y;
+	if x || (y && z) {
+		let x = 0;
+	}
+	return x;
+}
+";
+
+fn name<'a>(expr: Option<Expr<'a>>) -> &'a str {
+    match expr {
+        Some(Expr::Name(n)) => n.name(),
+        e => panic!("Expected a name, found {:?}", e),
+    }
+}
+
+#[test]
+fn typed_views_expose_the_parts_of_nodes() {
+    let ast = ast(SOURCE);
+    let function = ast.root().unwrap().child(0).unwrap().cast::<FunctionDecl>().unwrap();
+    assert_eq!(function.name().unwrap().name(), "main");
+    assert!(function.params().is_empty());
+
+    let statements = function.statements();
+    assert_eq!(statements.len(), 3);
+    let Stmt::Let(declaration) = statements[0] else {
+        panic!("{:?}", statements[0])
+    };
+    assert_eq!(declaration.name().unwrap().name(), "y");
+    assert!(declaration.value().is_none());
+
+    let Stmt::If(branch) = statements[1] else { panic!("{:?}", statements[1]) };
+    let Some(Expr::Binary(or)) = branch.condition() else { panic!() };
+    assert_eq!(or.op(), OperatorKind::Or);
+    assert_eq!(name(or.lhs()), "x");
+    let Some(Expr::Binary(and)) = or.rhs() else { panic!() };
+    assert_eq!((and.op(), name(and.lhs()), name(and.rhs())), (OperatorKind::And, "y", "z"));
+    assert!(branch.else_branch().is_none());
+    let Stmt::Let(assignment) = branch.statements()[0] else { panic!() };
+    assert!(matches!(assignment.value(), Some(Expr::Number(n)) if n.value() == "0"));
+
+    let Stmt::Return(ret) = statements[2] else { panic!("{:?}", statements[2]) };
+    assert_eq!(name(ret.value()), "x");
+
+    assert!(ast.root().unwrap().cast::<LetStmt>().is_none());
+    let sizes: Vec<usize> = function.node().children().map(|c| c.size()).collect();
+    assert_eq!(sizes, [1, 1, ast.len() - 4]);
+}
+
+#[derive(Default)]
+struct Names {
+    used: Vec<String>,
+    declared: Vec<String>,
+}
+
+impl<'a> Visitor<'a> for Names {
+    fn visit_let(&mut self, statement: LetStmt<'a>) {
+        self.declared.push(statement.name().unwrap().name().to_string());
+        if let Some(value) = statement.value() {
+            self.visit_node(value.node());
+        }
+    }
+
+    fn visit_name(&mut self, name: NameExpr<'a>) {
+        self.used.push(name.name().to_string());
+    }
+}
+
+#[test]
+fn visitors_only_implement_the_nodes_they_need() {
+    let ast = ast(SOURCE);
+    let mut names = Names::default();
+    ast.visit(&mut names);
+    assert_eq!(names.declared, ["y", "x"]);
+    assert_eq!(names.used, ["main", "x", "y", "z", "x"]);
+
+    struct Operators(Vec<OperatorKind>);
+    impl<'a> Visitor<'a> for Operators {
+        fn visit_binary(&mut self, expr: BinaryExpr<'a>) {
+            self.0.push(expr.op());
+            walk(self, expr.node());
+        }
+    }
+    let mut operators = Operators(Vec::new());
+    ast.visit(&mut operators);
+    assert_eq!(operators.0, [OperatorKind::Or, OperatorKind::And]);
+}
+
+#[test]
+fn mutable_visitors_change_nodes_in_place() {
+    struct Rename;
+    impl VisitorMut for Rename {
+        fn visit_name_mut(&mut self, name: &mut String) {
+            if name == "x" {
+                *name = "renamed".to_string();
+            }
+        }
+
+        fn visit_operator_mut(&mut self, op: &mut OperatorKind) {
+            *op = OperatorKind::And;
+        }
+    }
+
+    let mut ast = ast(SOURCE);
+    let len = ast.len();
+    ast.visit_mut(&mut Rename);
+    assert_eq!(ast.len(), len);
+    let kinds: Vec<AstNodeKind> = ast.root().unwrap().descendants().map(|n| n.kind().clone()).collect();
+    assert_eq!(kinds.iter().filter(|k| **k == AstNodeKind::Name("renamed".to_string())).count(), 3);
+    assert!(!kinds.contains(&AstNodeKind::Name("x".to_string())));
+    assert!(!kinds.contains(&AstNodeKind::Operator(OperatorKind::Or)));
+}
+
+#[test]
+fn folds_rewrite_the_shape_of_the_tree() {
+    // Drop declarations without a value, and replace `a || b` with `a`.
+    struct Simplify;
+    impl Fold for Simplify {
+        fn fold_let(&mut self, statement: LetStmt<'_>, out: &mut AstBuilder) {
+            if statement.value().is_some() {
+                fold_children(self, statement.node(), out);
+            }
+        }
+
+        fn fold_binary(&mut self, expr: BinaryExpr<'_>, out: &mut AstBuilder) {
+            match (expr.op(), expr.lhs()) {
+                (OperatorKind::Or, Some(lhs)) => self.fold_node(lhs.node(), out),
+                _ => fold_children(self, expr.node(), out),
+            }
+        }
+    }
+
+    let ast = ast(SOURCE);
+    let folded = ast.fold(&mut Simplify);
+    let kinds = |ast: &FernAst| -> Vec<String> { ast.root().unwrap().descendants().map(|n| format!("{:?}", n.kind())).collect() };
+    assert_eq!(
+        kinds(&folded),
+        [
+            "Module",
+            "Function",
+            "Name(\"main\")",
+            "FieldList",
+            "StatList",
+            "If",
+            "Name(\"x\")",
+            "StatList",
+            "LetAssign",
+            "Name(\"x\")",
+            "Number(\"0\")",
+            "Return",
+            "Name(\"x\")",
+        ]
+    );
+    let function: FunctionDecl = folded.root().unwrap().child(0).unwrap().cast().unwrap();
+    let Stmt::If(branch) = function.statements()[0] else { panic!() };
+    assert_eq!(branch.statements().len(), 1);
+    // The original is left alone.
+    assert_eq!(ast.len(), 19);
+}
+
+#[test]
+fn builders_make_asts_from_scratch() {
+    let mut out = AstBuilder::new();
+    out.open(AstNodeKind::LetAssign);
+    out.leaf(AstNodeKind::Name("a".to_string()));
+    out.open(AstNodeKind::Operator(OperatorKind::Add));
+    out.leaf(AstNodeKind::Number("1".to_string()));
+    out.leaf(AstNodeKind::Number("2".to_string()));
+    out.close();
+    out.close();
+    out.leaf(AstNodeKind::Return);
+    let ast = out.finish();
+
+    let root: Node = ast.root().unwrap();
+    assert_eq!(*root.kind(), AstNodeKind::StatList);
+    assert_eq!(root.children().count(), 2);
+    let Stmt::Let(statement) = Stmt::cast(root.child(0).unwrap()).unwrap() else {
+        panic!()
+    };
+    let Some(Expr::Binary(sum)) = statement.value() else { panic!() };
+    assert_eq!(sum.op(), OperatorKind::Add);
+    assert!(matches!(sum.rhs(), Some(Expr::Number(n)) if n.value() == "2"));
+    assert_eq!(*root.child(1).unwrap().kind(), AstNodeKind::Return);
+    assert!(root.child(2).is_none());
+}
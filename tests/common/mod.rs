@@ -1,184 +1,167 @@
 #![allow(dead_code)]
-// extern crate core;
 
-// pub mod lexing;
-// pub use lexing::test_lex;
-
-// use std::error::Error;
-// use std::sync::{Mutex, Once};
-// use memmap::Mmap;
-
-// use core::grammar::OpGrammar;
-// use core::grammar::RawGrammar;
-// use core::grammar::Token;
-// use core::parser::ParseTree;
-// use crate::common::LexerDataParserState::{AwaitingToken, InToken};
-
-// use crate::common::Parser::{LexerData, NoParser};
-// use crate::common::State::{AwaitingWord, InCode, InData, InWord};
-
-// static INIT: Once = Once::new();
-// pub static FERN_GRAMMAR: Mutex<Option<OpGrammar>> = Mutex::new(None);
-
-// pub fn get_grammar(g: &Mutex<Option<OpGrammar>>) -> OpGrammar {
-//     INIT.call_once(|| {
-//         let mut option = g.lock().unwrap();
-//         let mut raw = RawGrammar::from("data/grammar/fern.g").unwrap();
-//         raw.delete_repeated_rhs().unwrap();
-//         let grammar = OpGrammar::new(raw).unwrap();
-//         let _ = option.insert(grammar);
-//     });
-//     let g = g.lock().unwrap().clone().unwrap();
-//     return g;
-// }
-
-// pub struct TestFile<'a> {
-//     pub code: &'a [u8],
-//     pub lexer_data: Option<Vec<Token>>,
-//     pub parser_data: Option<ParseTree>,
-// }
-
-// enum State {
-//     AwaitingWord,
-//     InWord,
-//     InData,
-//     InCode,
-// }
-
-// enum LexerDataParserState {
-//     InToken,
-//     AwaitingToken,
-// }
-
-// struct LexerDataParser<'a> {
-//     tokens: Vec<Token>,
-//     buf: String,
-//     state: LexerDataParserState,
-//     g: &'a OpGrammar,
-// }
-
-// impl<'a> LexerDataParser<'a> {
-//     pub fn new(g: &'a OpGrammar) -> Self {
-//         Self {
-//             tokens: Vec::new(),
-//             buf: String::new(),
-//             state: AwaitingToken,
-//             g
-//         }
-//     }
-
-//     pub fn parse(&mut self, c: char) -> Result<(), Box<dyn Error>> {
-//         match self.state {
-//             InToken => {
-//                 match c {
-//                     'a'..='z' | 'A'..='Z' | '_' => self.buf.push(c),
-//                     ' ' | '\n' | '\t' => self.build_token(),
-//                     _ => (),
-//                 }
-//             }
-//             AwaitingToken => {
-//                 match c {
-//                     'a'..='z' | 'A'..='Z' | '_' => {
-//                         self.state = InToken;
-//                         self.buf.push(c)
-//                     },
-//                     _ => (),
-//                 }
-//             }
-//         }
-//         Ok(())
-//     }
-
-//     pub fn build(mut self) -> Vec<Token> {
-//         if !self.buf.is_empty() {
-//             self.build_token();
-//         }
-//         self.tokens
-//     }
-
-//     fn build_token(&mut self) {
-//         if !self.buf.is_empty() {
-//             let t = self.g.token_reverse.get(self.buf.as_str());
-//             if let Some(t) = t {
-//                 self.tokens.push(t.0);
-//             } else {
-//                panic!("Expected data parse error: Lexeme doesn't exist in grammar = {}", self.buf.as_str())
-//             }
-//             self.buf.clear();
-//         }
-//     }
-// }
-
-// enum Parser<'a> {
-//     NoParser,
-//     LexerData(LexerDataParser<'a>),
-// }
-
-// pub fn read_test_file<'a>(mmap: &'a Mmap, g: &OpGrammar) -> Result<TestFile<'a>, Box<dyn Error>> {
-//     let mut buf = String::new();
-//     let mut state = AwaitingWord;
-//     let mut parser: Parser = NoParser;
-
-//     let mut result = TestFile {
-//         code: &mmap[..],
-//         lexer_data: None,
-//         parser_data: None,
-//     };
-
-//     for (i, c) in mmap[..].iter().enumerate() {
-//         let c = *c as char;
-//         match state {
-//             AwaitingWord => {
-//                 if c == '`' {
-//                     state = InWord;
-//                 }
-//             }
-//             InWord => {
-//                 match c {
-//                     'a'..='z' | 'A'..='Z' => buf.push(c),
-//                     '`' => (),
-//                     _ => {
-//                         state = InData;
-//                         match buf.as_str() {
-//                             "lexer" => {
-//                                 parser = LexerData(LexerDataParser::new(g));
-//                                 buf.clear();
-//                             }
-//                             "end" => {
-//                                 parser = NoParser;
-//                                 state = InCode;
-//                                 buf.clear();
-//                             }
-//                             _ => panic!("Unknown type of test data."),
-//                         }
-//                     }
-//                 }
-//             }
-//             InData => {
-//                 if c == '`' {
-//                     state = InWord;
-//                     match parser {
-//                         NoParser => {
-//                             panic!("Parsed with no parser, probably a bug.");
-//                         }
-//                         LexerData(lexer_data_parser) => {
-//                             result.lexer_data = Some(lexer_data_parser.build());
-//                         }
-//                     }
-//                     parser = NoParser;
-//                 } else {
-//                     match &mut parser {
-//                         NoParser => panic!("Parsing with no parser, probably a bug."),
-//                         LexerData(lexer_data_parser) => {lexer_data_parser.parse(c)?}
-//                     }
-//                 }
-//             }
-//             InCode => {
-//                 result.code = &mmap[i..];
-//                 break;
-//             }
-//         }
-//     }
-
-//     Ok(result)
-// }
+//! Helpers shared by the integration tests: parsing Fern source, and reading and writing
+//! `.testfile`s, which hold Fern source and what each stage of the compiler
+//! is expected to make of it.
+//!
+//! Expectations come first, each in a section that starts with a line holding the name of the
+//! stage in backticks, such as `` `lexer ``. The closing backtick is optional. A `` `end ``
+//! line ends the last section, and the source follows it.
+//!
+//! ```text
+//! `lexer
+//!
+//! LET NAME EQ NUMBER SEMI
+//!
+//! `end
+//!
+//! let x = 0;
+//! ```
+
+use libfern::fern::{self, FernAst};
+use libfern::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use libfern::parser::{ParseError, Parser};
+use libfern::parsetree::ParseTree;
+use std::error::Error;
+use std::fmt::Write;
+
+thread_local! {
+    // Building the grammar takes longer than the tests that use it.
+    static GRAMMAR: (CompiledLexTable, CompiledGrammar) = {
+        let table = fern::lexing_table().unwrap();
+        let grammar = fern::grammar(&table).unwrap();
+        (table, grammar)
+    };
+}
+
+/// Call `f` with the fern lexing table and grammar, which are built once per thread.
+pub fn with_grammar<T>(f: impl FnOnce(&CompiledLexTable, &CompiledGrammar) -> T) -> T {
+    GRAMMAR.with(|(table, grammar)| f(table, grammar))
+}
+
+/// The parse tree of `source` as the parser recovers it, along with the syntax errors it
+/// recovered from. The tree keeps the trivia of a source without tokens.
+pub fn parse_with_errors(source: &str) -> (ParseTree, Vec<ParseError>) {
+    with_grammar(|table, grammar| {
+        let (tokens, data, trivia) = fern::lex(table, source.as_bytes()).unwrap();
+        let mut parser = Parser::new(grammar);
+        let mut errors = parser.parse(tokens, data).err().unwrap_or_default();
+        errors.extend(parser.parse(vec![grammar.delim], Vec::new()).err().unwrap_or_default());
+        let mut tree = parser.collect_parse_tree().unwrap().into_tree();
+        tree.trivia = trivia;
+        (tree, errors)
+    })
+}
+
+/// The parse tree of `source`, which must not have syntax errors.
+pub fn parse(source: &str) -> ParseTree {
+    let (tree, errors) = parse_with_errors(source);
+    assert!(errors.is_empty(), "{:?} in {}", errors, source);
+    tree
+}
+
+/// The AST of `source`, which must not have syntax errors.
+pub fn ast(source: &str) -> FernAst {
+    parse(source).into()
+}
+
+/// Sections in the order they are written.
+pub const SECTIONS: [&str; 5] = ["lexer", "parser", "ast", "diagnostics", "ir"];
+
+pub struct TestFile {
+    pub code: String,
+    /// Names and contents of the sections in the file, in the order they appear.
+    pub sections: Vec<(String, String)>,
+}
+
+impl TestFile {
+    pub fn section(&self, name: &str) -> Option<&str> {
+        self.sections.iter().find(|(n, _)| n == name).map(|(_, s)| s.as_str())
+    }
+
+    /// The file with every section in [`SECTIONS`] order.
+    pub fn write(&self) -> String {
+        let mut out = String::new();
+        for name in SECTIONS {
+            if let Some(data) = self.section(name) {
+                write!(out, "`{}\n\n", name).unwrap();
+                if !data.is_empty() {
+                    write!(out, "{}\n\n", data).unwrap();
+                }
+            }
+        }
+        write!(out, "`end\n\n{}", self.code).unwrap();
+        out
+    }
+}
+
+pub fn read_test_file(text: &str) -> Result<TestFile, Box<dyn Error>> {
+    let mut sections: Vec<(String, String)> = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    let mut lines = text.split_inclusive('\n');
+    for line in lines.by_ref() {
+        let header = line.trim_end();
+        if let Some(name) = header.strip_prefix('`') {
+            let name = name.strip_suffix('`').unwrap_or(name);
+            if let Some((name, data)) = current.take() {
+                sections.push((name, data.concat().trim_matches('\n').to_string()));
+            }
+            match name {
+                "end" => break,
+                _ if SECTIONS.contains(&name) => {
+                    if sections.iter().any(|(n, _)| n == name) {
+                        return Err(format!("Section {} appears twice.", name).into());
+                    }
+                    current = Some((name.to_string(), Vec::new()));
+                }
+                _ => return Err(format!("Unknown type of test data: {}.", name).into()),
+            }
+        } else if let Some((_, data)) = &mut current {
+            data.push(line);
+        } else if !header.is_empty() {
+            return Err(format!("Expected a section, found {:?}.", header).into());
+        }
+    }
+    if current.is_some() {
+        return Err("Missing `end before the source.".into());
+    }
+    let code: String = lines.collect();
+    Ok(TestFile {
+        code: code.trim_start_matches('\n').to_string(),
+        sections,
+    })
+}
+
+/// Lines of `expected` and `actual`, prefixed with `-` where they are only in `expected`, `+`
+/// where they are only in `actual`, and a space where they are in both.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // Length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            writeln!(out, "  {}", a[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            writeln!(out, "+ {}", b[j]).unwrap();
+            j += 1;
+        } else {
+            writeln!(out, "- {}", a[i]).unwrap();
+            i += 1;
+        }
+    }
+    out
+}
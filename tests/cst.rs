@@ -0,0 +1,92 @@
+mod common;
+
+use common::{parse, parse_with_errors, with_grammar};
+use libfern::cst::Cst;
+use libfern::fern::{self, FernLexer};
+use libfern::lexer::ParallelLexer;
+use libfern::parser::parse_chunks;
+use libfern::query::select;
+use std::thread;
+
+const SOURCE: &str = "
+// struct Test {
+//   x: int,
+// }
+
+fn main[] {
+	let y;   // unused
+	if x || (y && z) {
+		let s = \"hello world\";
+	}
+  let x = 0;
+
+
+	return x;
+}   \n\t";
+
+#[test]
+fn cst_prints_the_exact_input() {
+    let tree = parse(SOURCE);
+    assert_eq!(Cst::new(&tree).to_string(), SOURCE);
+
+    // The semicolon inserted after the if block is in the tree but not in the text.
+    let (tokens, data, trivia) = with_grammar(|table, _| fern::lex(table, SOURCE.as_bytes()).unwrap());
+    assert!(data.iter().any(|d| d.inserted));
+    assert_eq!(tokens.len(), data.len());
+    // Trivia after the last token trails it rather than being left over.
+    assert_eq!(trivia, "");
+
+    let cst = Cst::new(&tree);
+    let string = select(&tree, "STRING").unwrap();
+    assert_eq!(cst.text(string[0].id), "\"hello world\"");
+    let keyword = select(&tree, "stat > LET").unwrap()[0].id;
+    assert_eq!(cst.leading(keyword), "\t");
+    assert_eq!(cst.trailing(keyword), " ");
+    // A comment on the rest of the line belongs to the token before it.
+    let semi = select(&tree, "SEMI").unwrap().into_iter().find(|m| m.span == Some(7..8)).unwrap();
+    assert_eq!(cst.trailing(semi.id), "   // unused\n");
+    let function = select(&tree, "stat:nth-child(1)").unwrap()[0].id;
+    assert!(cst.text(function).starts_with("fn main[] {"));
+    assert!(cst.text(function).ends_with("return x;\n}"));
+    assert!(cst.full_text(function).starts_with("\n// struct Test {"));
+}
+
+#[test]
+fn cst_keeps_input_around_syntax_errors() {
+    for source in ["fn main[] { let = ; let x = 1; }", "let x = ;\nlet y = 2 2;\n", "let ) x;"] {
+        let (tree, errors) = parse_with_errors(source);
+        assert!(!errors.is_empty());
+        assert_eq!(Cst::new(&tree).to_string(), source);
+    }
+}
+
+#[test]
+fn cst_keeps_input_without_tokens() {
+    for source in ["", "\n\t ", "// only a comment", "// one\n\n// two\n"] {
+        let (tokens, _, trivia) = with_grammar(|table, _| fern::lex(table, source.as_bytes()).unwrap());
+        assert!(tokens.is_empty());
+        assert_eq!(trivia, source);
+        assert_eq!(Cst::new(&parse(source)).to_string(), source);
+    }
+}
+
+#[test]
+fn cst_of_chunks_prints_the_input() {
+    let source = SOURCE.repeat(3);
+    let chunks: Vec<&[u8]> = source.split_inclusive('\n').map(|s| s.as_bytes()).collect();
+
+    let (trees, _) = with_grammar(|table, grammar| {
+        thread::scope(|s| {
+            let mut lexer: ParallelLexer<FernLexer> = ParallelLexer::new(table, s, 2);
+            let batch = lexer.new_batch();
+            for (i, chunk) in chunks.iter().enumerate() {
+                lexer.add_to_batch(&batch, chunk, i);
+            }
+            let tokens = lexer.collect_batch(batch);
+            lexer.kill();
+            parse_chunks(grammar, tokens)
+        })
+    });
+    let printed: String = trees.into_iter().map(|t| Cst::new(&t.into_tree()).to_string()).collect();
+    assert_eq!(printed, source);
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::with_grammar;
+use libfern::fern;
+use libfern::fmt::{format_source, FormatError, FormatOptions};
+
+const MESSY: &str = "// header comment
+
+
+fn   main[a:int,b:int]{let y=a+b*2;   // trailing note
+	if x||(y&&z){let s=\"hi\";
+	}
+
+
+	// about the rest
+	let z = f(x,y);x=-z;
+	return x
+};
+struct Point{x:int,y:int};
+fn more[] { let a = 1 };
+let b = 2;";
+
+const FORMATTED: &str = "// header comment
+
+fn main[a: int, b: int] {
+	let y = a + b * 2; // trailing note
+	if x || (y && z) {
+		let s = \"hi\";
+	}
+
+	// about the rest
+	let z = f(x, y);
+	x = -z;
+	return x
+};
+struct Point {
+	x: int,
+	y: int
+}
+fn more[] {
+	let a = 1
+}
+let b = 2;
+";
+
+fn format_with(source: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    with_grammar(|table, grammar| format_source(table, grammar, source.as_bytes(), options))
+}
+
+fn format(source: &str) -> String {
+    format_with(source, &FormatOptions::default()).unwrap()
+}
+
+#[test]
+fn formatter_normalises_layout_and_keeps_comments() {
+    assert_eq!(format(MESSY), FORMATTED);
+}
+
+#[test]
+fn formatting_is_idempotent() {
+    let test = std::fs::read_to_string("data/test.fern").unwrap();
+    for source in [MESSY, FORMATTED, test.as_str(), "let x = 1;\n\n\n\nlet y = 2;   \n\n"] {
+        let once = format(source);
+        assert_eq!(format(&once), once, "{}", source);
+    }
+}
+
+#[test]
+fn inserted_semicolons_are_not_duplicated() {
+    let source = "fn f[] {\n\tlet x = 1;\n}\nfn g[] {}\nlet y = 2;\n";
+    assert_eq!(format(source), source);
+
+    // A written semicolon the lexer would insert anyway is dropped, one it would not is kept.
+    let explicit = "fn f[] {}; // done\nlet y = 2;\nstruct S {x: int};\nlet z = 3;\nif y {};\nstruct T {y: int}";
+    let formatted = format(explicit);
+    assert_eq!(
+        formatted,
+        "fn f[] {} // done\nlet y = 2;\nstruct S {\n\tx: int\n}\nlet z = 3;\nif y {};\nstruct T {\n\ty: int\n}\n"
+    );
+    let kinds = |source: &str| with_grammar(|table, _| fern::lex(table, source.as_bytes()).unwrap().0);
+    assert_eq!(kinds(&formatted), kinds(explicit));
+}
+
+#[test]
+fn long_lines_are_broken_after_operators_and_commas() {
+    let options = FormatOptions {
+        width: 40,
+        ..Default::default()
+    };
+    let source = "fn main[] {\nif first && second || third && fourth {\nlet x = f(alpha, beta, gamma, del);\n}\n}";
+    let formatted = format_with(source, &options).unwrap();
+    assert_eq!(
+        formatted,
+        "fn main[] {\n\tif first && second ||\n\t\tthird && fourth {\n\t\tlet x =\n\t\t\tf(alpha, beta, gamma, del);\n\t}\n}\n"
+    );
+    let again = format_with(&formatted, &options).unwrap();
+    assert_eq!(again, formatted);
+}
+
+#[test]
+fn comments_without_code_are_kept() {
+    assert_eq!(format("// just a note\n\n\n// and another   "), "// just a note\n\n// and another\n");
+    assert_eq!(format("fn f[] {}\n\n// the end"), "fn f[] {}\n\n// the end\n");
+    assert_eq!(format(""), "");
+}
+
+#[test]
+fn syntax_errors_are_not_formatted() {
+    let error = format_with("let = ;", &FormatOptions::default()).unwrap_err();
+    assert!(error.to_string().starts_with("Format Error: Parse Error: Unexpected"), "{}", error);
+}
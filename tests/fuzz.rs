@@ -0,0 +1,182 @@
+use libfern::fern::{FernAst, FernLexer};
+use libfern::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use libfern::grammar::generate::{SentenceGenerator, SentenceRenderer};
+use libfern::grammar::lg::{LexicalGrammar, StateGraph};
+use libfern::grammar::opg::{OpGrammar, RawGrammar, Token};
+use libfern::lexer::{Data, LexerInterface};
+use libfern::parser::{ParseError, Parser};
+use libfern::parsetree::ParseTree;
+use std::collections::BTreeMap;
+
+const EXPR_GRAMMAR: &str = "%nonterminal S
+%nonterminal E
+%nonterminal T
+%nonterminal F
+
+%axiom S
+
+%terminal NUMBER
+%terminal PLUS
+%terminal ASTERISK
+%terminal LPAREN
+%terminal RPAREN
+
+%%
+
+S : E
+\t;
+
+E : E PLUS T
+\t| T
+\t;
+
+T : T ASTERISK F
+\t| F
+\t;
+
+F : LPAREN E RPAREN
+\t| NUMBER
+\t;
+";
+
+const EXPR_LEXICAL_GRAMMAR: &str = "NUMBER = \"[0-9][0-9]*\"
+PLUS = \"\\+\"
+ASTERISK = \"\\*\"
+LPAREN = \"\\(\"
+RPAREN = \"\\)\"
+";
+
+fn expr_grammar() -> OpGrammar {
+    let terminals = ["NUMBER", "PLUS", "ASTERISK", "LPAREN", "RPAREN"].iter().map(|s| s.to_string()).collect();
+    let mut raw = RawGrammar::new(EXPR_GRAMMAR, terminals).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    OpGrammar::new(raw).unwrap()
+}
+
+/// The parse tree of `tokens`, or the first parse error.
+fn parse(g: &OpGrammar, tokens: Vec<Token>, data: Vec<Data>) -> Result<ParseTree, String> {
+    let mut parser = Parser::new(&CompiledGrammar::new(g.clone()));
+    let first = |errors: Vec<ParseError>| errors[0].to_string();
+    parser.parse(tokens, data).map_err(first)?;
+    parser.parse(vec![g.delim], Vec::new()).map_err(first)?;
+    Ok(parser.collect_parse_tree().map_err(|e| e.to_string())?.into_tree())
+}
+
+fn leaves(tree: &ParseTree) -> Vec<Token> {
+    tree.nodes.iter().filter(|n| n.child_count == 0).map(|n| n.token).collect()
+}
+
+#[test]
+fn generated_sentences_parse_to_their_tokens() {
+    let g = expr_grammar();
+    for seed in 0..100 {
+        let sentence = SentenceGenerator::new(&g, seed, 8).sentence().unwrap();
+        assert_eq!(leaves(&parse(&g, sentence.clone(), Vec::new()).unwrap()), sentence, "seed {}", seed);
+    }
+}
+
+#[test]
+fn generator_respects_depth_and_exclusions() {
+    let g = expr_grammar();
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+
+    // The shortest derivation is S -> E -> T -> F -> NUMBER, whatever the depth asked for.
+    assert_eq!(SentenceGenerator::new(&g, 7, 0).sentence().unwrap(), vec![t("NUMBER")]);
+
+    let excluded = [t("PLUS"), t("LPAREN")];
+    for seed in 0..20 {
+        let sentence = SentenceGenerator::excluding(&g, seed, 8, &excluded).sentence().unwrap();
+        assert!(sentence.iter().all(|x| *x == t("NUMBER") || *x == t("ASTERISK")));
+    }
+    assert!(SentenceGenerator::excluding(&g, 0, 8, &[t("NUMBER")]).sentence().is_err());
+}
+
+#[test]
+fn renderer_uses_lexical_examples() {
+    let g = expr_grammar();
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    let mut renderer = SentenceRenderer::new(vec![LexicalGrammar::from(EXPR_LEXICAL_GRAMMAR)], 3);
+    let text = renderer
+        .render(&[t("LPAREN"), t("NUMBER"), t("PLUS"), t("NUMBER"), t("RPAREN")], &g.token_raw)
+        .unwrap();
+    let parts: Vec<&str> = text.split(' ').collect();
+    assert_eq!(parts.len(), 5);
+    assert_eq!((parts[0], parts[2], parts[4]), ("(", "+", ")"));
+    assert!(parts[1].chars().all(|c| c.is_ascii_digit()) && !parts[1].is_empty());
+
+    renderer.fix("NUMBER", "1", true);
+    assert_eq!(renderer.render(&[t("NUMBER"), t("ASTERISK"), t("NUMBER")], &g.token_raw).unwrap(), "1* 1");
+}
+
+#[test]
+fn renderer_avoids_keywords() {
+    let names = LexicalGrammar::from("NAME = \"[ab]\"\n");
+    let keywords = LexicalGrammar::from("IF = \"a\"\n");
+    let token_raw = BTreeMap::from([(0, "NAME".to_string()), (1, "IF".to_string())]);
+    let mut renderer = SentenceRenderer::new(vec![names, keywords], 0);
+    assert_eq!(renderer.render(&[0, 0, 0, 0, 1], &token_raw).unwrap(), "b b b b a");
+}
+
+fn lexical_grammar(path: &str) -> LexicalGrammar {
+    LexicalGrammar::from(&std::fs::read_to_string(path).unwrap())
+}
+
+fn fern_table() -> CompiledLexTable {
+    let mut table = StateGraph::from(lexical_grammar("data/grammar/fern.lg")).convert_to_dfa().build_table();
+    table.terminal_map.push("UMINUS".to_string());
+    let keywords = StateGraph::from(lexical_grammar("data/grammar/keywords.lg")).convert_to_dfa().build_table();
+    let name_token = table.terminal_map.iter().position(|x| x == "NAME").unwrap();
+    table.add_table(name_token, keywords);
+    CompiledLexTable::new(table)
+}
+
+#[test]
+fn fern_programs_survive_lexing_parsing_and_ast() {
+    let table = fern_table();
+    let mut raw = RawGrammar::from("data/grammar/fern.g", table.terminal_map.clone()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let g = OpGrammar::new(raw).unwrap();
+
+    let grammars = vec![lexical_grammar("data/grammar/fern.lg"), lexical_grammar("data/grammar/keywords.lg")];
+    let lexable: Vec<String> = grammars.iter().flat_map(|lg| lg.token_names()).collect();
+    // UMINUS is only lexed from a `-` right before a name or `(`, which a generated sentence
+    // does not guarantee. Member access chains such as `a . b ( c ) ( d )` are in fern.g but
+    // are not operator precedence parseable yet.
+    let excluded: Vec<Token> = g
+        .terminals
+        .iter()
+        .filter(|t| !lexable.contains(&g.token_raw[t]) || g.token_raw[t] == "DOT")
+        .copied()
+        .collect();
+    let mut renderer = SentenceRenderer::new(grammars, 0);
+
+    let mut failures = Vec::new();
+    for seed in 0..200 {
+        let sentence = SentenceGenerator::excluding(&g, seed, 6, &excluded).sentence().unwrap();
+        let text = renderer.render(&sentence, &g.token_raw).unwrap();
+        let result = std::panic::catch_unwind(|| {
+            let mut lexer = FernLexer::new(&table, 0);
+            for c in text.chars().chain(['\n']) {
+                lexer.consume(c as u8).unwrap();
+            }
+            let (_, tokens, data) = lexer.take();
+            // Semicolons the lexer inserts after a closing brace are not in the sentence.
+            let written: Vec<Token> = tokens.iter().zip(&data).filter(|(_, d)| !d.inserted).map(|(t, _)| *t).collect();
+            if written != sentence {
+                return Err("lexed to different tokens".to_string());
+            }
+            let tree = parse(&g, tokens.clone(), data)?;
+            if leaves(&tree) != tokens {
+                return Err("parse tree lost tokens".to_string());
+            }
+            let _: FernAst = tree.into();
+            Ok(())
+        });
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => failures.push(format!("seed {}: {}: {}", seed, e, text)),
+            Err(_) => failures.push(format!("seed {}: panicked: {}", seed, text)),
+        }
+    }
+    assert!(failures.is_empty(), "{} failures:\n{}", failures.len(), failures.join("\n"));
+}
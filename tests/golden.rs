@@ -0,0 +1,186 @@
+//! Runs every `tests/data/*.testfile` through the compiler and compares the output of each stage
+//! with the sections of the file, see [`common`].
+//!
+//! `cargo test --test golden -- --bless` writes the actual output back to the files instead,
+//! with a section for every stage. Other arguments only run files whose name contains them.
+
+mod common;
+
+use common::{diff, read_test_file, TestFile, SECTIONS};
+use libfern::fern::{self, FernAst};
+use libfern::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use libfern::grammar::opg::Token;
+use libfern::ir::Block;
+use libfern::lexer::Data;
+use libfern::lower::lower;
+use libfern::parser::Parser;
+use libfern::parsetree::ParseTree;
+use std::error::Error;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// What each stage made of some source, in [`SECTIONS`] order.
+fn run(table: &CompiledLexTable, grammar: &CompiledGrammar, code: &str) -> Vec<String> {
+    let mut diagnostics = Vec::new();
+    let (tokens, data, _) = match fern::lex(table, code.as_bytes()) {
+        Ok(lexed) => lexed,
+        Err(e) => {
+            diagnostics.push(e.to_string());
+            (Vec::new(), Vec::new(), String::new())
+        }
+    };
+    let lexed = render_tokens(table, &tokens, &data);
+
+    let mut parser = Parser::new(grammar);
+    if let Err(errors) = parser.parse(tokens, data) {
+        diagnostics.extend(errors.iter().map(|e| e.to_string()));
+    }
+    if let Err(errors) = parser.parse(vec![grammar.delim], Vec::new()) {
+        diagnostics.extend(errors.iter().map(|e| e.to_string()));
+    }
+    let tree = match parser.collect_parse_tree() {
+        Ok(tree) => tree.into_tree(),
+        Err(e) => {
+            diagnostics.push(e.to_string());
+            return vec![lexed, String::new(), String::new(), diagnostics.join("\n"), String::new()];
+        }
+    };
+
+    let (ast, errors) = lower(grammar, &tree);
+    diagnostics.extend(errors.iter().map(|e| e.to_string()));
+    diagnostics.extend(ast.analysis());
+    let typing = ast.check(&ast.resolve());
+    let ir = match Block::from(&ast, &typing) {
+        Ok(block) => block.to_string().trim_end().to_string(),
+        Err(e) => {
+            diagnostics.push(e.to_string());
+            String::new()
+        }
+    };
+
+    vec![lexed, render_tree(&tree), render_ast(&ast), diagnostics.join("\n"), ir]
+}
+
+/// Token names, on the line of the source they were lexed from. Tokens the lexer inserted go
+/// on the line of the token before them.
+fn render_tokens(table: &CompiledLexTable, tokens: &[Token], data: &[Data]) -> String {
+    let mut out = String::new();
+    let mut line_ended = false;
+    for (t, d) in tokens.iter().zip(data) {
+        if !out.is_empty() {
+            out.push(if line_ended && !d.inserted { '\n' } else { ' ' });
+        }
+        out.push_str(&table.terminal_map[*t]);
+        line_ended = (line_ended && d.inserted) || d.trailing.contains('\n');
+    }
+    out
+}
+
+/// One node per line, indented under its parent. Tokens are followed by their text.
+fn render_tree(tree: &ParseTree) -> String {
+    let mut out = String::new();
+    let mut pending: Vec<usize> = Vec::new();
+    for n in &tree.nodes {
+        let kind = tree.token_map.get(&n.token).cloned().unwrap_or_default();
+        write!(out, "{}{}", "  ".repeat(pending.len()), kind).unwrap();
+        if let Some(d) = &n.data {
+            write!(out, " {:?}", d.raw).unwrap();
+        }
+        out.push('\n');
+        if let Some(parent) = pending.last_mut() {
+            *parent -= 1;
+        }
+        pending.push(n.child_count);
+        while pending.last() == Some(&0) {
+            pending.pop();
+        }
+    }
+    out.trim_end().to_string()
+}
+
+/// One node per line, indented under its parent, with the value of names, literals and
+/// operators.
+fn render_ast(ast: &FernAst) -> String {
+    let mut out = String::new();
+    let mut pending = Vec::from_iter(ast.root().map(|root| (root, 0)));
+    while let Some((node, depth)) = pending.pop() {
+        write!(out, "{}{}", "  ".repeat(depth), node.kind().name()).unwrap();
+        if let Some(value) = node.kind().value() {
+            write!(out, " {}", value).unwrap();
+        }
+        out.push('\n');
+        let children: Vec<_> = node.children().collect();
+        pending.extend(children.into_iter().rev().map(|c| (c, depth + 1)));
+    }
+    out.trim_end().to_string()
+}
+
+fn test_files(filters: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir("tests/data")? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if name.ends_with(".testfile") && (filters.is_empty() || filters.iter().any(|f| name.contains(f.as_str()))) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let bless = args.iter().any(|a| a == "--bless") || std::env::var("BLESS").is_ok_and(|v| v == "1");
+    let filters: Vec<String> = args.into_iter().filter(|a| !a.starts_with('-')).collect();
+
+    let table = fern::lexing_table().unwrap();
+    let grammar = fern::grammar(&table).unwrap();
+    let paths = test_files(&filters).unwrap();
+
+    let mut failed = 0;
+    for path in &paths {
+        let text = std::fs::read_to_string(path).unwrap();
+        let file = match read_test_file(&text) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("test {} ... FAILED\n{}\n", path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let actual = run(&table, &grammar, &file.code);
+
+        if bless {
+            let sections = SECTIONS.iter().map(|s| s.to_string()).zip(actual).collect();
+            let blessed = TestFile { code: file.code, sections };
+            std::fs::write(path, blessed.write()).unwrap();
+            println!("test {} ... blessed", path.display());
+            continue;
+        }
+
+        let mut report = String::new();
+        for (name, actual) in SECTIONS.iter().zip(&actual) {
+            match file.section(name) {
+                Some(expected) if expected != actual.trim_matches('\n') => {
+                    write!(report, "`{} differs:\n{}", name, diff(expected, actual)).unwrap();
+                }
+                _ => (),
+            }
+        }
+        if report.is_empty() {
+            println!("test {} ... ok", path.display());
+        } else {
+            println!("test {} ... FAILED\n{}", path.display(), report);
+            failed += 1;
+        }
+    }
+
+    println!("\ngolden: {} passed; {} failed", paths.len() - failed, failed);
+    if failed > 0 {
+        println!("Run `cargo test --features build-binary --test golden -- --bless` to accept the new output.");
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
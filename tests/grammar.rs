@@ -39,3 +39,949 @@ extern crate core;
 //     // TODO: Actually test this here.
 //     let _ = read_grammar_file(buf.as_str()).unwrap();
 // }
+
+use libfern::fern;
+use libfern::grammar::analysis::GrammarAnalysis;
+use libfern::grammar::annotation::{build_ast, Annotation};
+use libfern::grammar::compiled::{CompiledGrammar, CompiledLexTable};
+use libfern::grammar::lg::LexicalGrammar;
+use libfern::grammar::opg::{Associativity, OpGrammar, RawGrammar, ReductionTree, Rule, Token};
+use libfern::grammar::optable::OpTable;
+use libfern::lexer::Data;
+use libfern::parser::Parser;
+use libfern::parsetree::ParseTree;
+use libfern::trace::{ParseEvent, ParseTrace};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+const FLAT_EXPR_GRAMMAR: &str = "%nonterminal S
+%nonterminal E
+
+%axiom S
+
+%terminal NUMBER
+%terminal PLUS
+%terminal MINUS
+%terminal ASTERISK
+%terminal CARET
+
+%left PLUS MINUS
+%left ASTERISK
+%right CARET
+
+%%
+
+S : E
+\t;
+
+E : E PLUS E
+\t| E MINUS E
+\t| E ASTERISK E
+\t| E CARET E
+\t| NUMBER
+\t;
+";
+
+fn flat_terminals() -> Vec<String> {
+    ["NUMBER", "PLUS", "MINUS", "ASTERISK", "CARET"].iter().map(|s| s.to_string()).collect()
+}
+
+fn flat_grammar() -> OpGrammar {
+    let mut raw = RawGrammar::new(FLAT_EXPR_GRAMMAR, flat_terminals()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    OpGrammar::new(raw).unwrap()
+}
+
+#[test]
+fn precedence_declarations_resolve_conflicts() {
+    let g = flat_grammar();
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+
+    assert_eq!(g.get_precedence(t("PLUS"), t("ASTERISK")), Associativity::Left);
+    assert_eq!(g.get_precedence(t("ASTERISK"), t("PLUS")), Associativity::Right);
+    assert_eq!(g.get_precedence(t("PLUS"), t("MINUS")), Associativity::Right);
+    assert_eq!(g.get_precedence(t("PLUS"), t("PLUS")), Associativity::Right);
+    assert_eq!(g.get_precedence(t("CARET"), t("CARET")), Associativity::Left);
+}
+
+#[test]
+fn flat_expression_grammar_parses() {
+    let g = CompiledGrammar::new(flat_grammar());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+
+    // 1 + 2 * 3 ^ 4 ^ 5
+    let tokens = vec![
+        t("NUMBER"),
+        t("PLUS"),
+        t("NUMBER"),
+        t("ASTERISK"),
+        t("NUMBER"),
+        t("CARET"),
+        t("NUMBER"),
+        t("CARET"),
+        t("NUMBER"),
+    ];
+    let mut parser = Parser::new(&g);
+    parser.parse(tokens, Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    let tree = parser.collect_parse_tree().unwrap().into_tree();
+
+    let shape: Vec<(String, usize)> = tree
+        .nodes
+        .iter()
+        .map(|n| (tree.token_map.get(&n.token).unwrap().clone(), n.child_count))
+        .collect();
+    let expected: Vec<(String, usize)> = [
+        ("NewAxiom", 1),
+        ("E", 3),
+        ("E", 1),
+        ("NUMBER", 0),
+        ("PLUS", 0),
+        ("E", 3),
+        ("E", 1),
+        ("NUMBER", 0),
+        ("ASTERISK", 0),
+        ("E", 3),
+        ("E", 1),
+        ("NUMBER", 0),
+        ("CARET", 0),
+        ("E", 3),
+        ("E", 1),
+        ("NUMBER", 0),
+        ("CARET", 0),
+        ("E", 1),
+        ("NUMBER", 0),
+    ]
+    .iter()
+    .map(|(s, c)| (s.to_string(), *c))
+    .collect();
+    assert_eq!(shape, expected);
+}
+
+#[test]
+fn reduction_tree_matches_handles() {
+    let rule = |left: Token, right: &[Token]| {
+        let mut r = Rule::from(left);
+        r.right = right.to_vec();
+        r
+    };
+    let mut tree = ReductionTree::new();
+    tree.add_rule(&rule(10, &[1]));
+    tree.add_rule(&rule(11, &[1, 2, 3]));
+    tree.add_rule(&rule(12, &[1, 2, 4]));
+    tree.add_rule(&rule(13, &[1, 2, 3]));
+
+    let left = |rhs: &[Token]| tree.match_rule(rhs.iter().copied()).map(|r| r.left);
+    assert_eq!(left(&[1]), Some(10));
+    assert_eq!(left(&[1, 2, 3]), Some(11));
+    assert_eq!(left(&[1, 2, 4]), Some(12));
+    // No rule ends at 1 2, and a handle is matched up to the deepest node it reaches.
+    assert_eq!(left(&[1, 2]), None);
+    assert_eq!(left(&[1, 5]), Some(10));
+    assert_eq!(left(&[2]), None);
+    assert_eq!(left(&[]), None);
+}
+
+#[test]
+fn parser_measures_rule_searching_on_request() {
+    let g = CompiledGrammar::new(flat_grammar());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    let tokens = vec![t("NUMBER"), t("PLUS"), t("NUMBER")];
+
+    let mut parser = Parser::new(&g);
+    parser.parse(tokens.clone(), Vec::new()).unwrap();
+    assert_eq!(parser.time_spent_rule_searching, None);
+
+    let mut parser = Parser::new(&g);
+    parser.time_spent_rule_searching = Some(Duration::ZERO);
+    parser.parse(tokens, Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    assert!(parser.time_spent_rule_searching.unwrap() > Duration::ZERO);
+}
+
+#[test]
+fn compiled_grammar_is_shared_between_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CompiledGrammar>();
+    assert_send_sync::<CompiledLexTable>();
+
+    let g = CompiledGrammar::new(flat_grammar());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    let tokens = vec![t("NUMBER"), t("ASTERISK"), t("NUMBER"), t("MINUS"), t("NUMBER")];
+    let shape = |g: &CompiledGrammar| -> Vec<(Token, usize)> {
+        let mut parser = Parser::new(g);
+        parser.parse(tokens.clone(), Vec::new()).unwrap();
+        parser.parse(vec![g.delim], Vec::new()).unwrap();
+        let tree = parser.collect_parse_tree().unwrap().into_tree();
+        tree.nodes.iter().map(|n| (n.token, n.child_count)).collect()
+    };
+
+    let expected = shape(&g);
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..4).map(|_| s.spawn(|| shape(&g))).collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), expected);
+        }
+    });
+    // Parsers keep a handle on the grammar rather than a copy of it.
+    assert!(Arc::ptr_eq(Parser::new(&g).g.short_names(), g.short_names()));
+}
+
+#[test]
+fn nonassoc_has_no_relation() {
+    let grammar = FLAT_EXPR_GRAMMAR.replace("%left PLUS MINUS", "%nonassoc PLUS MINUS");
+    let mut raw = RawGrammar::new(grammar.as_str(), flat_terminals()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let g = OpGrammar::new(raw).unwrap();
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    assert_eq!(g.get_precedence(t("PLUS"), t("MINUS")), Associativity::None);
+}
+
+#[test]
+fn prec_annotation_applies_to_its_rule() {
+    let grammar = FLAT_EXPR_GRAMMAR
+        .replace("%terminal CARET\n", "%terminal CARET\n%terminal UMINUS\n")
+        .replace("%right CARET\n", "%right CARET\n%right UMINUS\n");
+    let mut terminals = flat_terminals();
+    terminals.push("UMINUS".to_string());
+    let build = |grammar: &str| {
+        let mut raw = RawGrammar::new(grammar, terminals.clone()).unwrap();
+        raw.delete_repeated_rhs().unwrap();
+        OpGrammar::new(raw).unwrap()
+    };
+
+    // Only unary minus, which binds tighter than multiplication.
+    let g = build(&grammar.replace("\t| E MINUS E\n", "\t| MINUS E %prec UMINUS\n"));
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    assert_eq!(g.get_precedence(t("MINUS"), t("ASTERISK")), Associativity::Right);
+
+    // The op table cannot tell unary from binary minus when they share the terminal.
+    let shared = grammar.replace("\t| NUMBER\n", "\t| MINUS E %prec UMINUS\n\t| NUMBER\n");
+    let error = RawGrammar::new(&shared, terminals.clone()).err().unwrap().to_string();
+    assert!(error.contains("%prec UMINUS cannot apply to E : MINUS E, because E : E MINUS E also ends in MINUS."));
+}
+
+#[test]
+fn prec_annotation_must_name_terminal() {
+    let grammar = FLAT_EXPR_GRAMMAR.replace("\t| NUMBER\n", "\t| MINUS E %prec E\n\t| NUMBER\n");
+    assert!(RawGrammar::new(grammar.as_str(), flat_terminals()).is_err());
+}
+
+const EBNF_GRAMMAR: &str = "%nonterminal S
+%nonterminal E
+%nonterminal L
+
+%axiom S
+
+%terminal NUMBER
+%terminal PLUS
+%terminal LPAREN
+%terminal RPAREN
+%terminal COMMA
+
+%%
+
+S : E
+\t;
+
+E : NUMBER (PLUS NUMBER)*
+\t| LPAREN L? RPAREN
+\t;
+
+L : (E COMMA)+ E
+\t;
+";
+
+fn ebnf_terminals() -> Vec<String> {
+    ["NUMBER", "PLUS", "LPAREN", "RPAREN", "COMMA"].iter().map(|s| s.to_string()).collect()
+}
+
+fn rule_strings(g: &RawGrammar) -> BTreeSet<String> {
+    g.rules.iter().map(|r| rule_string(g, r)).collect()
+}
+
+fn rule_string(g: &RawGrammar, r: &Rule) -> String {
+    format!(
+        "{} : {}",
+        g.token_raw[&r.left],
+        OpGrammar::token_list_to_string(&r.right, &g.token_raw).join(" ")
+    )
+}
+
+#[test]
+fn ebnf_expands_to_operator_rules() {
+    let g = RawGrammar::new(EBNF_GRAMMAR, ebnf_terminals()).unwrap();
+    let expected: BTreeSet<String> = [
+        "S : E",
+        "E : ERepA",
+        "ERepA : NUMBER",
+        "ERepA : ERepA PLUS NUMBER",
+        "E : LPAREN RPAREN",
+        "E : LPAREN L RPAREN",
+        "L : E COMMA LRepA",
+        "LRepA : E",
+        "LRepA : E COMMA LRepA",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    assert_eq!(rule_strings(&g), expected);
+
+    let generated = g.rules.iter().find(|r| g.token_raw[&r.left] == "LRepA").unwrap();
+    let origin = g.rule_origin(generated).unwrap();
+    assert_eq!(origin.line, 22);
+    assert_eq!(origin.source, "L : (E COMMA)+ E");
+
+    let mut raw = g;
+    raw.delete_repeated_rhs().unwrap();
+    let g = CompiledGrammar::new(OpGrammar::new(raw).unwrap());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    let tokens = vec![t("NUMBER"), t("PLUS"), t("NUMBER"), t("PLUS"), t("NUMBER")];
+    let mut parser = Parser::new(&g);
+    parser.parse(tokens, Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    let tree = parser.collect_parse_tree().unwrap().into_tree();
+    assert_eq!(tree.nodes.iter().filter(|n| n.child_count == 0).count(), 5);
+}
+
+#[test]
+fn ebnf_rejects_empty_expansion() {
+    let grammar = EBNF_GRAMMAR.replace("L : (E COMMA)+ E", "L : E?");
+    let err = RawGrammar::new(grammar.as_str(), ebnf_terminals()).err().unwrap();
+    assert!(err.to_string().contains("Line 22"));
+}
+
+const ADJACENT_GRAMMAR: &str = "%nonterminal S
+%nonterminal Stats
+%nonterminal Stat
+%nonterminal Call
+%nonterminal Args
+%nonterminal Var
+
+%axiom S
+
+%terminal NAME
+%terminal STRING
+%terminal LPAREN
+%terminal RPAREN
+%terminal SEMI
+
+%%
+
+S : Stats
+\t;
+
+Stats : Stat
+\t| Stats SEMI Stat
+\t;
+
+Stat : Call
+\t;
+
+Call : Var Args
+\t;
+
+Args : LPAREN Var RPAREN
+\t| STRING
+\t;
+
+Var : NAME
+\t;
+";
+
+fn adjacent_terminals() -> Vec<String> {
+    ["NAME", "STRING", "LPAREN", "RPAREN", "SEMI"].iter().map(|s| s.to_string()).collect()
+}
+
+fn declared_terminals(path: &str) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter_map(|l| l.strip_prefix("%terminal "))
+        .map(|s| s.trim().to_string())
+        .collect()
+}
+
+#[test]
+fn operator_form_expands_adjacent_non_terminals() {
+    let mut raw = RawGrammar::new(ADJACENT_GRAMMAR, adjacent_terminals()).unwrap();
+    let report = raw.to_operator_form().unwrap();
+    let expected: BTreeSet<String> = [
+        "S : Stats",
+        "Stats : Stat",
+        "Stats : Stats SEMI Stat",
+        "Stat : Call",
+        "Call : Var LPAREN Var RPAREN",
+        "Call : Var STRING",
+        "Var : NAME",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    assert_eq!(rule_strings(&raw), expected);
+    assert_eq!(report.rewrites.len(), 1);
+    assert_eq!(raw.token_raw[&report.rewrites[0].expanded], "Args");
+    assert_eq!(report.removed_non_terminals, vec![raw.token_reverse["Args"].0]);
+    assert!(report.describe(&raw).contains("removed unreachable nonterminal Args"));
+
+    raw.delete_repeated_rhs().unwrap();
+    let g = CompiledGrammar::new(OpGrammar::new(raw).unwrap());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    // f(x); g "s"
+    let tokens = vec![t("NAME"), t("LPAREN"), t("NAME"), t("RPAREN"), t("SEMI"), t("NAME"), t("STRING")];
+    let mut parser = Parser::new(&g);
+    parser.parse(tokens, Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    let tree = parser.collect_parse_tree().unwrap().into_tree();
+    assert_eq!(tree.nodes.iter().filter(|n| n.child_count == 0).count(), 7);
+}
+
+#[test]
+fn operator_form_required_by_op_grammar() {
+    let mut raw = RawGrammar::new(ADJACENT_GRAMMAR, adjacent_terminals()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let err = OpGrammar::new(raw).err().unwrap();
+    assert!(err.to_string().contains("adjacent non-terminals"));
+}
+
+#[test]
+fn operator_form_rejects_opposite_recursion() {
+    let grammar = ADJACENT_GRAMMAR
+        .replace("Args : LPAREN Var RPAREN", "Args : Args LPAREN Var RPAREN")
+        .replace("Var : NAME\n", "Var : NAME\n\t| NAME SEMI Var\n");
+    let mut raw = RawGrammar::new(grammar.as_str(), adjacent_terminals()).unwrap();
+    let err = raw.to_operator_form().err().unwrap();
+    assert!(err.to_string().contains("Var is right recursive and Args is left recursive"));
+}
+
+#[test]
+fn lua_like_grammars_load() {
+    for path in ["data/grammar/lua.g", "data/grammar/eslang.g"] {
+        let mut raw = RawGrammar::from(path, declared_terminals(path)).unwrap();
+        raw.to_operator_form().unwrap();
+        raw.delete_repeated_rhs().unwrap();
+        OpGrammar::new(raw).unwrap();
+    }
+
+    // Terminal names ending in digits must survive the rule parser intact.
+    let raw = RawGrammar::from("data/grammar/lua.g", declared_terminals("data/grammar/lua.g")).unwrap();
+    assert!(rule_strings(&raw).contains("concatExp : additiveExp DOT2 concatExp"));
+    assert!(rule_strings(&raw).contains("label : COLON2 NAME COLON2"));
+}
+
+#[test]
+fn lua_grammar_builds_ast() {
+    let path = "data/grammar/lua.g";
+    let mut raw = RawGrammar::from(path, declared_terminals(path)).unwrap();
+    raw.to_operator_form().unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let g = CompiledGrammar::new(OpGrammar::new(raw).unwrap());
+    let ast = |source: &[(&str, &str)]| {
+        let tokens = source.iter().map(|(name, _)| g.token_reverse[*name].0).collect();
+        let data = source
+            .iter()
+            .enumerate()
+            .map(|(token_index, (_, text))| Data {
+                raw: text.to_string(),
+                token_index,
+                ..Default::default()
+            })
+            .collect();
+        let mut parser = Parser::new(&g);
+        parser.parse(tokens, data).unwrap();
+        parser.parse(vec![g.delim], Vec::new()).unwrap();
+        sexp(&build_ast(&g, &parser.collect_parse_tree().unwrap().into_tree()))
+    };
+    let local = [
+        ("LOCAL", "local"),
+        ("NAME", "x"),
+        ("XEQ", "="),
+        ("NAME", "f"),
+        ("LPAREN", "("),
+        ("NUMBER", "1"),
+        ("COMMA", ","),
+        ("NAME", "y"),
+        ("PLUS", "+"),
+        ("NUMBER", "2"),
+        ("RPAREN", ")"),
+    ];
+    assert_eq!(
+        ast(&local),
+        "(Module (LocalAssign (NameList Name:x) (ExprList (FunctionCall Name:f (ExprList Number:1 (Add Name:y Number:2))))))"
+    );
+    let ret = [
+        ("WHILE", "while"),
+        ("TRUE", "true"),
+        ("DO", "do"),
+        ("BREAK", "break"),
+        ("END", "end"),
+        ("RETURN", "return"),
+    ];
+    assert_eq!(ast(&ret), "(Module (While Bool:true (StatList (Break))) (Return))");
+}
+
+const ANALYSIS_GRAMMAR: &str = "%nonterminal S
+%nonterminal E
+%nonterminal A
+%nonterminal B
+%nonterminal Loop
+%nonterminal Orphan
+
+%axiom S
+
+%terminal NUMBER
+%terminal PLUS
+%terminal LPAREN
+%terminal RPAREN
+
+%%
+
+S : E
+\t| A
+\t;
+
+E : E PLUS NUMBER
+\t| LPAREN E RPAREN
+\t| NUMBER
+\t;
+
+A : B
+\t;
+
+B : A
+\t| Loop PLUS NUMBER
+\t;
+
+Loop : LPAREN Loop
+\t;
+
+Orphan : NUMBER
+\t;
+";
+
+#[test]
+fn analysis_reports_grammar_problems() {
+    let lexical_sync: Vec<String> = ["NUMBER", "PLUS", "LPAREN", "RPAREN", "COMMA"].iter().map(|s| s.to_string()).collect();
+    let raw = RawGrammar::new(ANALYSIS_GRAMMAR, lexical_sync.clone()).unwrap();
+    let analysis = GrammarAnalysis::new(&raw, &lexical_sync);
+    let set = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<BTreeSet<String>>();
+
+    assert_eq!(analysis.axiom, "S");
+    assert_eq!(analysis.unreachable, set(&["Orphan"]));
+    assert_eq!(analysis.unproductive, set(&["A", "B", "Loop"]));
+    assert_eq!(analysis.unused_terminals, set(&["COMMA"]));
+    assert_eq!(analysis.renaming_cycles, vec![vec!["A".to_string(), "B".to_string()]]);
+    assert_eq!(analysis.left_terminals["E"], set(&["LPAREN", "NUMBER", "PLUS"]));
+    assert_eq!(analysis.right_terminals["E"], set(&["NUMBER", "RPAREN"]));
+    assert_eq!(analysis.left_terminals["S"], set(&["LPAREN", "NUMBER", "PLUS"]));
+    assert!(!analysis.is_clean());
+
+    let json = analysis.to_json();
+    assert_eq!(json["unreachable"][0], "Orphan");
+    assert_eq!(json["renaming_cycles"][0][1], "B");
+    assert_eq!(json["left_terminals"]["E"].len(), 3);
+    assert!(analysis.to_string().contains("LT(E) = {LPAREN NUMBER PLUS}"));
+}
+
+#[test]
+fn analysis_finds_unused_keywords() {
+    let mut lexical_sync = Vec::new();
+    for path in ["data/grammar/fern.lg", "data/grammar/keywords.lg"] {
+        lexical_sync.extend(LexicalGrammar::from(&std::fs::read_to_string(path).unwrap()).token_names());
+    }
+    let raw = RawGrammar::from("data/grammar/fern.g", lexical_sync.clone()).unwrap();
+    let analysis = GrammarAnalysis::new(&raw, &lexical_sync);
+    assert!(analysis.unused_terminals.contains("MOV"));
+    assert!(analysis.unused_terminals.contains("LEA"));
+    assert!(!analysis.unused_terminals.contains("LET"));
+    assert_eq!(analysis.axiom, "chunk");
+    assert!(analysis.left_terminals["retStat"].contains("RETURN"));
+}
+
+#[test]
+fn op_table_compresses_into_precedence_functions() {
+    let g = flat_grammar();
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    assert!(g.op_table().functions().is_some());
+
+    assert_eq!(g.get_precedence(t("PLUS"), t("ASTERISK")), Associativity::Left);
+    assert_eq!(g.get_precedence(t("ASTERISK"), t("PLUS")), Associativity::Right);
+    assert_eq!(g.get_precedence(t("CARET"), t("CARET")), Associativity::Left);
+    assert_eq!(g.get_precedence(t("NUMBER"), t("NUMBER")), Associativity::None);
+    assert_eq!(g.get_precedence(t("PLUS"), g.delim), Associativity::Right);
+    assert_eq!(g.get_precedence(g.delim, g.delim), Associativity::Equal);
+    assert_eq!(g.get_precedence(t("PLUS"), g.axiom), Associativity::None);
+}
+
+fn relations(cells: &[(Token, Token, Associativity)]) -> HashMap<Token, HashMap<Token, Associativity>> {
+    let mut relations: HashMap<Token, HashMap<Token, Associativity>> = HashMap::new();
+    for (left, right, relation) in cells {
+        relations.entry(*left).or_default().insert(*right, *relation);
+    }
+    relations
+}
+
+#[test]
+fn op_table_keeps_matrix_without_precedence_functions() {
+    // f(a) < g(a) < f(b) < g(b) < f(a) has no solution.
+    let cells = [
+        (3, 3, Associativity::Left),
+        (3, 7, Associativity::Right),
+        (7, 3, Associativity::Right),
+        (7, 7, Associativity::Left),
+        (9, 9, Associativity::Equal),
+    ];
+    let table = OpTable::new(&[3, 7, 9], 9, &relations(&cells));
+    assert!(table.functions().is_none());
+    for (left, right, relation) in cells {
+        assert_eq!(table.get(left, right), relation);
+    }
+    assert_eq!(table.get(3, 9), Associativity::None);
+    assert_eq!(table.get(4, 3), Associativity::None);
+
+    let cells = [
+        (3, 7, Associativity::Left),
+        (7, 3, Associativity::Right),
+        (3, 3, Associativity::Right),
+        (9, 9, Associativity::Equal),
+    ];
+    let table = OpTable::new(&[3, 7, 9], 9, &relations(&cells));
+    let functions = table.functions().unwrap();
+    assert!(functions.f[0] < functions.g[1] && functions.f[1] > functions.g[0]);
+    for (left, right, relation) in cells {
+        assert_eq!(table.get(left, right), relation);
+    }
+    assert_eq!(table.get(7, 7), Associativity::None);
+}
+
+#[test]
+fn op_table_compresses_equal_relations() {
+    // E : LPAREN E RPAREN | NUMBER, with LPAREN 3, NUMBER 5 and RPAREN 7.
+    let cells = [
+        (3, 3, Associativity::Left),
+        (3, 5, Associativity::Left),
+        (3, 7, Associativity::Equal),
+        (5, 7, Associativity::Right),
+        (7, 7, Associativity::Right),
+        (9, 9, Associativity::Equal),
+    ];
+    let table = OpTable::new(&[3, 5, 7, 9], 9, &relations(&cells));
+    let functions = table.functions().unwrap();
+    assert_eq!(functions.f[0], functions.g[2]);
+    for (left, right, relation) in cells {
+        assert_eq!(table.get(left, right), relation);
+    }
+    assert_eq!(table.get(7, 3), Associativity::None);
+
+    let mut raw = RawGrammar::new(EBNF_GRAMMAR, ebnf_terminals()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    assert!(OpGrammar::new(raw).unwrap().op_table().functions().is_some());
+}
+
+#[test]
+fn parse_error_reports_found_and_expected_terminals() {
+    let g = CompiledGrammar::new(flat_grammar());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+
+    // 1 2 + 3
+    let tokens = vec![t("NUMBER"), t("NUMBER"), t("PLUS"), t("NUMBER")];
+    let mut parser = Parser::new(&g);
+    let errors = parser.parse(tokens, Vec::new()).unwrap_err();
+    assert!(parser.parse(vec![g.delim], Vec::new()).is_ok());
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].span, 1..2);
+    assert_eq!(errors[0].found, t("NUMBER"));
+    let expected: BTreeSet<Token> = errors[0].expected.iter().copied().collect();
+    assert_eq!(expected, BTreeSet::from([t("PLUS"), t("MINUS"), t("ASTERISK"), t("CARET")]));
+    assert_eq!(
+        errors[0].to_string(),
+        "Parse Error: Unexpected NUMBER at token 1, expected one of ASTERISK, CARET, MINUS, PLUS."
+    );
+    parser.collect_parse_tree().unwrap().into_tree().print();
+}
+
+#[test]
+fn parser_recovers_on_sync_tokens() {
+    let grammar = ADJACENT_GRAMMAR.replace("%%", "%sync SEMI\n\n%%");
+    let mut raw = RawGrammar::new(grammar.as_str(), adjacent_terminals()).unwrap();
+    raw.to_operator_form().unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let g = CompiledGrammar::new(OpGrammar::new(raw).unwrap());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+    assert_eq!(g.sync, vec![t("SEMI")]);
+
+    // f( ; g ) ; h "s"
+    let tokens = vec![t("NAME"), t("LPAREN"), t("SEMI"), t("NAME"), t("RPAREN"), t("SEMI"), t("NAME"), t("STRING")];
+    let mut parser = Parser::new(&g);
+    let errors = parser.parse(tokens, Vec::new()).unwrap_err();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    let spans: Vec<_> = errors.iter().map(|e| e.span.clone()).collect();
+    assert_eq!(spans, [2..3, 4..5]);
+    assert_eq!(parser.errors(), errors.as_slice());
+
+    let tree = parser.collect_parse_tree().unwrap().into_tree();
+    assert_eq!(tree.nodes.iter().filter(|n| n.token == g.error).count(), 2);
+    assert_eq!(tree.nodes.iter().filter(|n| n.child_count == 0).count(), 8);
+    assert_eq!(tree.token_map[&tree.nodes[0].token], "NewAxiom");
+}
+
+#[test]
+fn sync_tokens_must_be_terminals() {
+    let grammar = ADJACENT_GRAMMAR.replace("%%", "%sync SEMI Stat\n\n%%");
+    let err = RawGrammar::new(grammar.as_str(), adjacent_terminals()).err().unwrap();
+    assert!(err.to_string().contains("Only declared terminals can be sync tokens : Stat"));
+}
+
+#[test]
+fn parser_traces_events_with_stack_snapshots() {
+    let g = CompiledGrammar::new(flat_grammar());
+    let t = |name: &str| g.token_reverse.get(name).unwrap().0;
+
+    // 1 + 2
+    let mut parser = Parser::new(&g);
+    parser.trace = Some(ParseTrace::new(&g));
+    parser.parse(vec![t("NUMBER"), t("PLUS"), t("NUMBER")], Vec::new()).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    let trace = parser.trace.take().unwrap();
+
+    let events: Vec<String> = trace.steps.iter().map(|s| trace.describe(&s.event)).collect();
+    assert_eq!(
+        events,
+        [
+            "⋖ NUMBER at token 0",
+            "E : NUMBER",
+            "⋖ PLUS at token 1",
+            "⋖ NUMBER at token 2",
+            "E : NUMBER",
+            "E : E PLUS E",
+            "NewAxiom : E",
+            "Reached axiom"
+        ]
+    );
+    assert_eq!(
+        trace.steps[0].event,
+        ParseEvent::Shift {
+            token: t("NUMBER"),
+            relation: Associativity::Left,
+            position: 0
+        }
+    );
+    assert_eq!(trace.steps.last().unwrap().event, ParseEvent::Axiom);
+    assert_eq!(trace.stacks().last().unwrap().len(), 1);
+    let json = trace.to_json();
+    assert_eq!(json["steps"][0]["event"], "shift");
+    assert_eq!(json["steps"][0]["stack"][0]["token"], "NUMBER");
+
+    // 1 2
+    let mut parser = Parser::new(&g);
+    parser.trace = Some(ParseTrace::new(&g));
+    let _ = parser.parse(vec![t("NUMBER"), t("NUMBER")], Vec::new());
+    let trace = parser.trace.take().unwrap();
+    let error = trace.steps.iter().position(|s| matches!(s.event, ParseEvent::Error(_))).unwrap();
+    assert_eq!(trace.stacks().nth(error).unwrap().len(), 1);
+    assert_eq!((trace.steps[error].popped, trace.steps[error].pushed.len()), (0, 0));
+    assert_eq!(trace.to_json()["steps"][1]["span"][0], 1);
+
+    let mut html = Vec::new();
+    trace.html(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("<tr class=\"error\">"));
+}
+
+const ANNOTATED_GRAMMAR: &str = "%nonterminal S
+%nonterminal E
+%nonterminal L
+
+%axiom S
+
+%terminal NUMBER
+%terminal PLUS
+%terminal MINUS
+%terminal LPAREN
+%terminal RPAREN
+%terminal COMMA
+
+%left PLUS MINUS
+
+%%
+
+S : E
+\t;
+
+E : E PLUS E %ast Add
+\t| E MINUS.keep E %ast Binary
+\t| LPAREN L RPAREN %ast Tuple
+\t| LPAREN E RPAREN
+\t| NUMBER.Number
+\t;
+
+L : E COMMA E %ast List
+\t| L.flatten COMMA E %ast List
+\t;
+";
+
+fn annotated_terminals() -> Vec<String> {
+    ["NUMBER", "PLUS", "MINUS", "LPAREN", "RPAREN", "COMMA"].iter().map(|s| s.to_string()).collect()
+}
+
+/// The AST as an s-expression, with the text of leaves.
+fn sexp(ast: &ParseTree) -> String {
+    fn node(ast: &ParseTree, i: &mut usize, out: &mut String) {
+        let n = &ast.nodes[*i];
+        *i += 1;
+        let kind = &ast.token_map[&n.token];
+        match &n.data {
+            Some(data) if n.child_count == 0 => out.push_str(format!("{}:{}", kind, data.raw).as_str()),
+            _ => {
+                out.push('(');
+                out.push_str(kind);
+                for _ in 0..n.child_count {
+                    out.push(' ');
+                    node(ast, i, out);
+                }
+                out.push(')');
+            }
+        }
+    }
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < ast.nodes.len() {
+        let mut s = String::new();
+        node(ast, &mut i, &mut s);
+        out.push(s);
+    }
+    out.join(" ")
+}
+
+fn annotated_ast(source: &[&str]) -> ParseTree {
+    annotated_ast_of(ANNOTATED_GRAMMAR, source)
+}
+
+fn annotated_ast_of(grammar: &str, source: &[&str]) -> ParseTree {
+    let mut raw = RawGrammar::new(grammar, annotated_terminals()).unwrap();
+    raw.delete_repeated_rhs().unwrap();
+    let g = CompiledGrammar::new(OpGrammar::new(raw).unwrap());
+    let names = [("+", "PLUS"), ("-", "MINUS"), ("(", "LPAREN"), (")", "RPAREN"), (",", "COMMA")];
+    let tokens = source
+        .iter()
+        .map(|s| g.token_reverse[names.iter().find(|(c, _)| c == s).map_or("NUMBER", |(_, name)| name)].0)
+        .collect();
+    let data = source
+        .iter()
+        .enumerate()
+        .map(|(token_index, s)| Data {
+            raw: s.to_string(),
+            token_index,
+            ..Default::default()
+        })
+        .collect();
+    let mut parser = Parser::new(&g);
+    parser.parse(tokens, data).unwrap();
+    parser.parse(vec![g.delim], Vec::new()).unwrap();
+    build_ast(&g, &parser.collect_parse_tree().unwrap().into_tree())
+}
+
+#[test]
+fn annotations_build_asts() {
+    assert_eq!(
+        sexp(&annotated_ast(&["1", "+", "2", "-", "3"])),
+        "(Binary (Add Number:1 Number:2) MINUS:- Number:3)"
+    );
+    assert_eq!(sexp(&annotated_ast(&["(", "1", "+", "2", ")"])), "(Add Number:1 Number:2)");
+    assert_eq!(
+        sexp(&annotated_ast(&["(", "1", ",", "2", ",", "(", "3", ",", "4", ")", ")"])),
+        "(Tuple (List Number:1 Number:2 (Tuple (List Number:3 Number:4))))"
+    );
+
+    let ast = annotated_ast(&["1", "+", "2"]);
+    assert_eq!(ast.nodes[2].data.as_ref().unwrap().token_index, 2);
+}
+
+#[test]
+fn annotations_wrap_nonterminals() {
+    let ast = |replacements: &[(&str, &str)], source: &[&str]| {
+        let grammar = replacements.iter().fold(ANNOTATED_GRAMMAR.to_string(), |g, (from, to)| g.replace(from, to));
+        sexp(&annotated_ast_of(&grammar, source))
+    };
+    let tuple = ("LPAREN L RPAREN %ast Tuple", "LPAREN L.List? RPAREN %ast Tuple");
+    assert_eq!(ast(&[tuple], &["(", "1", ",", "2", ")"]), "(Tuple (List Number:1 Number:2))");
+    // A left out `L.List?` is an empty `List`.
+    assert_eq!(ast(&[tuple], &["(", ")"]), "(Tuple (List))");
+
+    let item = ("LPAREN E RPAREN\n", "LPAREN E.Item.List RPAREN %ast Tuple\n");
+    assert_eq!(ast(&[item], &["(", "1", "+", "2", ")"]), "(Tuple (List (Item (Add Number:1 Number:2))))");
+
+    let sign = ("E PLUS E %ast Add", "E PLUS .Plus E %ast Add");
+    assert_eq!(ast(&[sign], &["1", "+", "2"]), "(Add Number:1 (Plus) Number:2)");
+
+    // The axiom's annotation applies to the root. Only nodes made with `%ast` are taken apart.
+    let root = ("S : E", "S : E.List");
+    let open = ("LPAREN L RPAREN %ast Tuple", "LPAREN L RPAREN");
+    let closed = ("LPAREN L RPAREN %ast Tuple", "LPAREN L.List RPAREN");
+    assert_eq!(ast(&[root, open], &["(", "1", ",", "2", ")"]), "(List Number:1 Number:2)");
+    assert_eq!(ast(&[root, closed], &["(", "1", ",", "2", ")"]), "(List (List Number:1 Number:2))");
+    assert_eq!(ast(&[root], &["1", "+", "2"]), "(List (Add Number:1 Number:2))");
+}
+
+#[test]
+fn annotations_are_checked() {
+    let g = RawGrammar::new(ANNOTATED_GRAMMAR, annotated_terminals()).unwrap();
+    let e = g.rules.iter().find(|r| r.ast.as_deref() == Some("Binary")).unwrap();
+    assert_eq!(e.annotations, [None, Some(Annotation::Keep), None]);
+    let list = g
+        .rules
+        .iter()
+        .find(|r| r.right.len() == 3 && r.ast.as_deref() == Some("List") && r.left == r.right[0])
+        .unwrap();
+    assert_eq!(list.annotations[0], Some(Annotation::Flatten));
+
+    let error = |from: &str, to: &str| {
+        let grammar = ANNOTATED_GRAMMAR.replace(from, to);
+        RawGrammar::new(grammar.as_str(), annotated_terminals()).err().unwrap().to_string()
+    };
+    assert!(error("L.flatten COMMA", "L.keep COMMA").contains("Only terminals can be kept as leaves : L.keep"));
+    assert!(error("S : E", "S : E %ast Root").contains("%ast Root has no effect on S : E"));
+    assert!(error("MINUS.keep", "MINUS.keep.elide").contains("Expected one annotation on MINUS"));
+    assert!(error("MINUS.keep", "MINUS.2").contains("Invalid annotation on MINUS"));
+    assert!(error("%ast Add", "%ast Add %ast Plus").contains("more than one %ast"));
+    assert!(error("E PLUS E %ast Add", "E PLUS .elide E %ast Add").contains("Expected a node kind, found .elide"));
+    assert!(error("PLUS E %ast Add", "PLUS E %ast Add\n\t| .Empty %ast Add").contains("nothing but empty nodes"));
+}
+
+#[test]
+fn ebnf_rules_keep_annotations() {
+    let grammar = ANNOTATED_GRAMMAR.replace("L : E COMMA E %ast List\n\t| L.flatten COMMA E %ast List", "L : E (COMMA E.elide)+ %ast List");
+    let g = RawGrammar::new(grammar.as_str(), annotated_terminals()).unwrap();
+    let list: Vec<String> = g
+        .rules
+        .iter()
+        .filter(|r| r.ast.as_deref() == Some("List"))
+        .map(|r| rule_string(&g, r))
+        .collect();
+    assert_eq!(list, ["L : LRepA COMMA E"]);
+    let elided: BTreeSet<String> = g
+        .rules
+        .iter()
+        .filter(|r| r.annotations.last() == Some(&Some(Annotation::Elide)))
+        .map(|r| rule_string(&g, r))
+        .collect();
+    assert_eq!(elided, BTreeSet::from(["L : LRepA COMMA E".to_string(), "LRepA : LRepA COMMA E".to_string()]));
+}
+
+#[test]
+fn fern_grammar_builds_ast() {
+    let table = fern::lexing_table().unwrap();
+    let grammar = fern::grammar(&table).unwrap();
+    let source = "let x = 1 + f(2, 3) * -y;\nif x == 1 {\n\treturn x.y;\n}\n";
+    let (tokens, data, _) = fern::lex(&table, source.as_bytes()).unwrap();
+    let mut parser = Parser::new(&grammar);
+    parser.parse(tokens, data).unwrap();
+    parser.parse(vec![grammar.delim], Vec::new()).unwrap();
+    let ast = build_ast(&grammar, &parser.collect_parse_tree().unwrap().into_tree());
+    assert_eq!(
+        sexp(&ast),
+        "(Module (LetAssign Name:x (Add Number:1 (Multiply (FunctionCall Name:f (ExprList Number:2 Number:3)) (Negate Name:y)))) \
+         (If (Equal Name:x Number:1) (StatList (Return (Member Name:x Name:y)))))"
+    );
+}
@@ -1,20 +1,71 @@
 pub extern crate core;
-// use std::collections::LinkedList;
-// use std::error::Error;
-// use std::fs::File;
-// use std::iter::zip;
-// use std::thread;
-// use memmap::MmapOptions;
-// use tungstenite::accept;
-// use core::lexer;
-// use crate::common::test_lex;
-// use crate::core::fern::{FernLexer, FernLexerState};
-// use crate::core::grammar::Token;
-// use crate::core::lexer::ParallelLexer;
-// use crate::core::lua::LuaLexer;
 
 mod common;
 
+use common::with_grammar;
+use libfern::fern::{self, FernLexer};
+use libfern::grammar::opg::Token;
+use libfern::lexer::{Data, ParallelLexer};
+use libfern::parser::parse_chunks;
+use std::thread;
+
+type Shape = Vec<(Token, usize, Option<Data>)>;
+
+#[test]
+fn pipelined_parse_matches_batch() {
+    let mut source = String::new();
+    for i in 0..40 {
+        source.push_str(&format!("let x{} = {} + {};\n", i, i, i * 2));
+        if i % 13 == 0 {
+            source.push_str("let = ;\n");
+        }
+    }
+    let chunks: Vec<&[u8]> = source.split_inclusive('\n').map(|s| s.as_bytes()).collect();
+
+    let run = |pipelined: bool| {
+        with_grammar(|table, g| {
+            let (trees, errors) = thread::scope(|s| {
+                let mut lexer: ParallelLexer<FernLexer> = ParallelLexer::new(table, s, 4);
+                let batch = lexer.new_batch();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    lexer.add_to_batch(&batch, chunk, i);
+                }
+                let result = if pipelined {
+                    let (send, recv) = crossbeam_channel::unbounded();
+                    let parser = s.spawn(|| parse_chunks(g, recv));
+                    lexer.stream_batch(batch, send);
+                    parser.join().unwrap()
+                } else {
+                    parse_chunks(g, lexer.collect_batch(batch))
+                };
+                lexer.kill();
+                result
+            });
+            let shapes: Vec<Shape> = trees
+                .into_iter()
+                .map(|t| t.into_tree().nodes.into_iter().map(|n| (n.token, n.child_count, n.data)).collect())
+                .collect();
+            (shapes, errors)
+        })
+    };
+
+    let (batch_trees, batch_errors) = run(false);
+    let (pipelined_trees, pipelined_errors) = run(true);
+    assert_eq!(batch_trees.len(), chunks.len());
+    assert!(!batch_errors.is_empty());
+    assert_eq!(pipelined_trees, batch_trees);
+    assert_eq!(pipelined_errors, batch_errors);
+}
+
+#[test]
+fn keywords_are_whole_words() {
+    with_grammar(|table, _| {
+        let (tokens, _, _) = fern::lex(table, b"for int in letter").unwrap();
+        let terminals: Vec<&str> = tokens.iter().map(|t| table.terminal_map[*t].as_str()).collect();
+        assert_eq!(terminals, ["FOR", "NAME", "IN", "NAME"]);
+    });
+}
+
 // #[test]
 // fn let_stmt_lex_test() {
 //     test_lex("tests/data/let_stmt.testfile").unwrap();
@@ -0,0 +1,340 @@
+mod common;
+
+use common::{parse, parse_with_errors, with_grammar};
+use libfern::lower::lower;
+use libfern::parsetree::ParseTree;
+
+fn lowered(tree: &ParseTree) -> String {
+    let (ast, errors) = with_grammar(|_, grammar| lower(grammar, tree));
+    assert!(errors.is_empty(), "{:?}", errors);
+    ast.to_sexp()
+}
+
+fn check(source: &str, expected: &str) {
+    assert_eq!(lowered(&parse(source)), expected, "{}", source);
+}
+
+/// The value of `let x = <expr>;`, where the statement ends with the value.
+fn check_expr(expr: &str, expected: &str) {
+    let (_, span) = expected.split_once("..").unwrap();
+    let end: usize = span.chars().take_while(char::is_ascii_digit).collect::<String>().parse().unwrap();
+    check(
+        &format!("let x = {};", expr),
+        &format!(r#"(ast 1 (Module @0..{} (LetAssign @0..{} (Name "x" @1..2) {})))"#, end + 1, end, expected),
+    );
+}
+
+/// Lowering errors of `source`, which may also have syntax errors the parser recovered from.
+fn errors(source: &str) -> Vec<(String, std::ops::Range<usize>)> {
+    let (ast, errors) = with_grammar(|_, grammar| lower(grammar, &parse_with_errors(source).0));
+    assert_eq!(ast.root().unwrap().children().count(), 0, "{}", source);
+    errors.iter().map(|e| (e.to_string(), e.span.clone())).collect()
+}
+
+#[test]
+fn chunk_and_stat_list() {
+    check("", "(ast 1 (Module))");
+    check(";", "(ast 1 (Module @0..1))");
+    check("let x", r#"(ast 1 (Module @0..2 (Let @0..2 (Name "x" @1..2))))"#);
+    check("let x;", r#"(ast 1 (Module @0..3 (Let @0..2 (Name "x" @1..2))))"#);
+    check(
+        "let x; let y;",
+        r#"(ast 1 (Module @0..6 (Let @0..2 (Name "x" @1..2)) (Let @3..5 (Name "y" @4..5))))"#,
+    );
+    check(
+        "let x;; let y",
+        r#"(ast 1 (Module @0..6 (Let @0..2 (Name "x" @1..2)) (Let @4..6 (Name "y" @5..6))))"#,
+    );
+    // Deep lists are flattened without recursion.
+    let long = "f();".repeat(500);
+    assert_eq!(lowered(&parse(&long)).matches("(FunctionCall").count(), 500);
+}
+
+#[test]
+fn stat_assignment() {
+    check("x = 2;", r#"(ast 1 (Module @0..4 (Assign @0..3 (Name "x" @0..1) (Number "2" @2..3))))"#);
+    check(
+        "a.b = 3;",
+        r#"(ast 1 (Module @0..6 (Assign @0..5 (Member @0..3 (Name "a" @0..1) (Name "b" @2..3)) (Number "3" @4..5))))"#,
+    );
+    check(
+        "a.b.c = d.e;",
+        r#"(ast 1 (Module @0..10 (Assign @0..9 (Member @0..5 (Member @0..3 (Name "a" @0..1) (Name "b" @2..3)) (Name "c" @4..5)) (Member @6..9 (Name "d" @6..7) (Name "e" @8..9)))))"#,
+    );
+    check(
+        "f(x).y = 1;",
+        r#"(ast 1 (Module @0..9 (Assign @0..8 (Member @0..6 (FunctionCall @0..4 (Name "f" @0..1) (ExprList @2..3 (Name "x" @2..3))) (Name "y" @5..6)) (Number "1" @7..8))))"#,
+    );
+}
+
+#[test]
+fn stat_block() {
+    check("{ let x; }", r#"(ast 1 (Module @0..5 (StatList @1..4 (Let @1..3 (Name "x" @2..3)))))"#);
+    check("{ }", "(ast 1 (Module @0..2 (StatList)))");
+}
+
+#[test]
+fn stat_while() {
+    check(
+        "while x { f(); }",
+        r#"(ast 1 (Module @0..8 (While @0..8 (Name "x" @1..2) (StatList @3..7 (FunctionCall @3..6 (Name "f" @3..4) (ExprList))))))"#,
+    );
+    check("while x {}", r#"(ast 1 (Module @0..4 (While @0..4 (Name "x" @1..2) (StatList))))"#);
+}
+
+#[test]
+fn stat_if() {
+    check(
+        "if x { f(); }",
+        r#"(ast 1 (Module @0..8 (If @0..8 (Name "x" @1..2) (StatList @3..7 (FunctionCall @3..6 (Name "f" @3..4) (ExprList))))))"#,
+    );
+    check("if x {}", r#"(ast 1 (Module @0..4 (If @0..4 (Name "x" @1..2) (StatList))))"#);
+}
+
+#[test]
+fn stat_struct() {
+    check(
+        "struct P { x: int, y: int }",
+        r#"(ast 1 (Module @0..11 (Struct @0..11 (Name "P" @1..2) (FieldList @3..10 (Field @3..6 (Name "x" @3..4) (Name "int" @5..6)) (Field @7..10 (Name "y" @7..8) (Name "int" @9..10))))))"#,
+    );
+    check("struct P {}", r#"(ast 1 (Module @0..4 (Struct @0..4 (Name "P" @1..2) (FieldList))))"#);
+}
+
+#[test]
+fn stat_function() {
+    check(
+        "fn f[] {}",
+        r#"(ast 1 (Module @0..6 (Function @0..6 (Name "f" @1..2) (FieldList) (StatList))))"#,
+    );
+    check(
+        "fn f[a] { return a; }",
+        r#"(ast 1 (Module @0..10 (Function @0..10 (Name "f" @1..2) (FieldList @3..4 (Field @3..4 (Name "a" @3..4))) (StatList @6..9 (Return @6..8 (Name "a" @7..8))))))"#,
+    );
+    check(
+        "fn f[a: int, b: int] {}",
+        r#"(ast 1 (Module @0..13 (Function @0..13 (Name "f" @1..2) (FieldList @3..10 (Field @3..6 (Name "a" @3..4) (Name "int" @5..6)) (Field @7..10 (Name "b" @7..8) (Name "int" @9..10))) (StatList))))"#,
+    );
+    check(
+        "fn f[a: u8] -> u8 { return a; }",
+        r#"(ast 1 (Module @0..14 (Function @0..14 (Name "f" @1..2) (FieldList @3..6 (Field @3..6 (Name "a" @3..4) (Name "u8" @5..6))) (Name "u8" @8..9) (StatList @10..13 (Return @10..12 (Name "a" @11..12))))))"#,
+    );
+}
+
+#[test]
+fn stat_for() {
+    check(
+        "for x in xs {}",
+        r#"(ast 1 (Module @0..6 (For @0..6 (Name "x" @1..2) (Name "xs" @3..4) (StatList))))"#,
+    );
+    check(
+        "for k, v in a, b { k = v; }",
+        r#"(ast 1 (Module @0..14 (For @0..14 (ExprList @1..4 (Name "k" @1..2) (Name "v" @3..4)) (ExprList @5..8 (Name "a" @5..6) (Name "b" @7..8)) (StatList @9..13 (Assign @9..12 (Name "k" @9..10) (Name "v" @11..12))))))"#,
+    );
+}
+
+#[test]
+fn stat_let() {
+    check("let x;", r#"(ast 1 (Module @0..3 (Let @0..2 (Name "x" @1..2))))"#);
+    check("let x = 1;", r#"(ast 1 (Module @0..5 (LetAssign @0..4 (Name "x" @1..2) (Number "1" @3..4))))"#);
+    check(
+        "let x: u8;",
+        r#"(ast 1 (Module @0..5 (Let @0..4 (Field @1..4 (Name "x" @1..2) (Name "u8" @3..4)))))"#,
+    );
+    check(
+        "let x: u8 = 1;",
+        r#"(ast 1 (Module @0..7 (LetAssign @0..6 (Field @1..4 (Name "x" @1..2) (Name "u8" @3..4)) (Number "1" @5..6))))"#,
+    );
+}
+
+#[test]
+fn function_call() {
+    check("f();", r#"(ast 1 (Module @0..4 (FunctionCall @0..3 (Name "f" @0..1) (ExprList))))"#);
+    check(
+        "f(1);",
+        r#"(ast 1 (Module @0..5 (FunctionCall @0..4 (Name "f" @0..1) (ExprList @2..3 (Number "1" @2..3)))))"#,
+    );
+    check(
+        "f(1, 2, 3);",
+        r#"(ast 1 (Module @0..9 (FunctionCall @0..8 (Name "f" @0..1) (ExprList @2..7 (Number "1" @2..3) (Number "2" @4..5) (Number "3" @6..7)))))"#,
+    );
+    check(
+        "f(g(x));",
+        r#"(ast 1 (Module @0..8 (FunctionCall @0..7 (Name "f" @0..1) (ExprList @2..6 (FunctionCall @2..6 (Name "g" @2..3) (ExprList @4..5 (Name "x" @4..5)))))))"#,
+    );
+}
+
+#[test]
+fn ret_stat() {
+    check("return;", "(ast 1 (Module @0..2 (Return @0..1)))");
+    check("return x;", r#"(ast 1 (Module @0..3 (Return @0..2 (Name "x" @1..2))))"#);
+    check("return x", r#"(ast 1 (Module @0..2 (Return @0..2 (Name "x" @1..2))))"#);
+    check(
+        "return 1, 2;",
+        r#"(ast 1 (Module @0..5 (Return @0..4 (ExprList @1..4 (Number "1" @1..2) (Number "2" @3..4)))))"#,
+    );
+}
+
+#[test]
+fn else_if_block() {
+    check(
+        "if x { a(); } elif y { b(); } else { c(); }",
+        r#"(ast 1 (Module @0..23 (If @0..23 (Name "x" @1..2) (StatList @3..7 (FunctionCall @3..6 (Name "a" @3..4) (ExprList))) (ElseIf @8..23 (Name "y" @9..10) (StatList @11..15 (FunctionCall @11..14 (Name "b" @11..12) (ExprList))) (Else @16..23 (StatList @18..22 (FunctionCall @18..21 (Name "c" @18..19) (ExprList))))))))"#,
+    );
+    check(
+        "if x {} elif y {}",
+        r#"(ast 1 (Module @0..8 (If @0..8 (Name "x" @1..2) (StatList) (ElseIf @4..8 (Name "y" @5..6) (StatList)))))"#,
+    );
+    check(
+        "if x {} else {}",
+        r#"(ast 1 (Module @0..7 (If @0..7 (Name "x" @1..2) (StatList) (Else @4..7 (StatList)))))"#,
+    );
+}
+
+#[test]
+fn expr_list() {
+    check(
+        "return a, b, c;",
+        r#"(ast 1 (Module @0..7 (Return @0..6 (ExprList @1..6 (Name "a" @1..2) (Name "b" @3..4) (Name "c" @5..6)))))"#,
+    );
+    check(
+        "f(a + 1, b);",
+        r#"(ast 1 (Module @0..9 (FunctionCall @0..8 (Name "f" @0..1) (ExprList @2..7 (Operator "Add" @2..5 (Name "a" @2..3) (Number "1" @4..5)) (Name "b" @6..7)))))"#,
+    );
+}
+
+#[test]
+fn logical_or_and_exp() {
+    check_expr("a || b", r#"(Operator "Or" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a && b", r#"(Operator "And" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr(
+        "a || b && c",
+        r#"(Operator "Or" @3..8 (Name "a" @3..4) (Operator "And" @5..8 (Name "b" @5..6) (Name "c" @7..8)))"#,
+    );
+}
+
+#[test]
+fn relational_exp() {
+    check_expr("a < b", r#"(Operator "LessThan" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a > b", r#"(Operator "GreaterThan" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a <= b", r#"(Operator "LessThanOrEqual" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a >= b", r#"(Operator "GreaterThanOrEqual" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a != b", r#"(Operator "NotEqual" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr("a == b", r#"(Operator "Equal" @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+}
+
+#[test]
+fn concat_exp() {
+    check_expr(
+        "a .. b .. c",
+        r#"(Operator "Concat" @3..8 (Name "a" @3..4) (Operator "Concat" @5..8 (Name "b" @5..6) (Name "c" @7..8)))"#,
+    );
+}
+
+#[test]
+fn additive_exp() {
+    check_expr(
+        "a + b - c",
+        r#"(Operator "Subtract" @3..8 (Operator "Add" @3..6 (Name "a" @3..4) (Name "b" @5..6)) (Name "c" @7..8))"#,
+    );
+}
+
+#[test]
+fn multiplicative_exp() {
+    check_expr(
+        "a * b / c % d",
+        r#"(Operator "Modulo" @3..10 (Operator "Divide" @3..8 (Operator "Multiply" @3..6 (Name "a" @3..4) (Name "b" @5..6)) (Name "c" @7..8)) (Name "d" @9..10))"#,
+    );
+}
+
+#[test]
+fn unary_exp() {
+    check_expr("not a", r#"(Operator "Not" @3..5 (Name "a" @4..5))"#);
+    check_expr("#a", r#"(Operator "Length" @3..5 (Name "a" @4..5))"#);
+    check_expr("-a", r#"(Operator "Negate" @3..5 (Name "a" @4..5))"#);
+}
+
+#[test]
+fn caret_exp() {
+    check_expr(
+        "a ^ b ^ c",
+        r#"(Operator "Power" @3..8 (Name "a" @3..4) (Operator "Power" @5..8 (Name "b" @5..6) (Name "c" @7..8)))"#,
+    );
+}
+
+#[test]
+fn base_exp() {
+    check_expr("nil", "(Nil @3..4)");
+    check_expr("false", r#"(Bool "false" @3..4)"#);
+    check_expr("true", r#"(Bool "true" @3..4)"#);
+    check_expr("12", r#"(Number "12" @3..4)"#);
+    check_expr("\"s\"", r#"(String "\"s\"" @3..4)"#);
+    check_expr("a", r#"(Name "a" @3..4)"#);
+}
+
+#[test]
+fn prefix_exp_and_var() {
+    check_expr(
+        "(a + b) * c",
+        r#"(Operator "Multiply" @3..10 (Operator "Add" @4..7 (Name "a" @4..5) (Name "b" @6..7)) (Name "c" @9..10))"#,
+    );
+    check_expr("a.b", r#"(Member @3..6 (Name "a" @3..4) (Name "b" @5..6))"#);
+    check_expr(
+        "f(x).y",
+        r#"(Member @3..9 (FunctionCall @3..7 (Name "f" @3..4) (ExprList @5..6 (Name "x" @5..6))) (Name "y" @8..9))"#,
+    );
+}
+
+#[test]
+fn field_list() {
+    check(
+        "struct P { x: int, }",
+        r#"(ast 1 (Module @0..8 (Struct @0..8 (Name "P" @1..2) (FieldList @3..7 (Field @3..6 (Name "x" @3..4) (Name "int" @5..6))))))"#,
+    );
+    check(
+        "struct P { x: int, y: int, z: int }",
+        r#"(ast 1 (Module @0..15 (Struct @0..15 (Name "P" @1..2) (FieldList @3..14 (Field @3..6 (Name "x" @3..4) (Name "int" @5..6)) (Field @7..10 (Name "y" @7..8) (Name "int" @9..10)) (Field @11..14 (Name "z" @11..12) (Name "int" @13..14))))))"#,
+    );
+}
+
+#[test]
+fn unsupported_constructs_are_reported() {
+    assert_eq!(errors("x;"), [("Lower Error: Syntax error at token 0.".to_string(), 0..1)]);
+    assert_eq!(errors("x.y;"), [("Lower Error: Syntax error at token 0.".to_string(), 0..3)]);
+    assert_eq!(
+        errors("1 = 2;"),
+        [("Lower Error: Only names and fields can be assigned to at token 0.".to_string(), 0..1)]
+    );
+    assert_eq!(
+        errors("f(x) = 1;"),
+        [("Lower Error: Only names and fields can be assigned to at token 0.".to_string(), 0..4)]
+    );
+    assert_eq!(errors("let 1;"), [("Lower Error: Expected a name at token 1.".to_string(), 1..2)]);
+    assert_eq!(errors("fn f[1] {}"), [("Lower Error: Expected a name at token 3.".to_string(), 3..4)]);
+    assert_eq!(errors("let x = a.1;"), [("Lower Error: Expected a name at token 5.".to_string(), 5..6)]);
+}
+
+#[test]
+fn failed_statements_leave_the_rest() {
+    let (ast, errors) = with_grammar(|_, grammar| lower(grammar, &parse_with_errors("let a = 1; let 2; fn f[] { x; let b; }").0));
+    assert_eq!(
+        ast.to_sexp(),
+        r#"(ast 1 (Module @0..19 (LetAssign @0..4 (Name "a" @1..2) (Number "1" @3..4)) (Function @8..19 (Name "f" @9..10) (FieldList) (StatList @13..18 (Let @15..17 (Name "b" @16..17))))))"#
+    );
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, ["Lower Error: Expected a name at token 6.", "Lower Error: Syntax error at token 13."]);
+
+    // The body of a function the parser gave up on goes with it.
+    let (ast, errors) = with_grammar(|_, grammar| lower(grammar, &parse_with_errors("fn f[a] { let b;").0));
+    assert_eq!(ast.to_sexp(), "(ast 1 (Module @0..9))");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].span, 0..9);
+
+    // A syntax error is reported once, however much of the statement the parser gave up on.
+    let (ast, errors) = with_grammar(|_, grammar| lower(grammar, &parse_with_errors("let x = a + ; let y = 1;").0));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].span, 0..5);
+    assert_eq!(
+        ast.to_sexp(),
+        r#"(ast 1 (Module @0..11 (LetAssign @6..10 (Name "y" @7..8) (Number "1" @9..10))))"#
+    );
+}
@@ -0,0 +1,261 @@
+use json::{object, JsonValue};
+use libfern::fern;
+use libfern::lsp::{read_message, write_message, LanguageServer, TOKEN_TYPES};
+use std::io::Cursor;
+
+const URI: &str = "file:///test.fern";
+const SOURCE: &str = "// a point
+struct Point {
+	x: i64,
+	y: i64
+};
+fn add[a: i64, b: i64] {
+	let sum = a + b;
+	return sum;
+}
+let total = add(1, 2);
+";
+
+/// Sends a script of messages to a server running in this thread and keeps what it answers.
+struct Client {
+    messages: Vec<JsonValue>,
+    next_id: usize,
+}
+
+impl Client {
+    fn new() -> Self {
+        let mut client = Client {
+            messages: Vec::new(),
+            next_id: 0,
+        };
+        client.request("initialize", object! { capabilities: {}, clientInfo: { name: "test" } });
+        client.notify("initialized", object! {});
+        client
+    }
+
+    fn request(&mut self, method: &str, params: JsonValue) -> usize {
+        self.next_id += 1;
+        self.messages.push(object! { jsonrpc: "2.0", id: self.next_id, method: method, params: params });
+        self.next_id
+    }
+
+    fn notify(&mut self, method: &str, params: JsonValue) {
+        self.messages.push(object! { jsonrpc: "2.0", method: method, params: params });
+    }
+
+    fn open(&mut self, text: &str) {
+        self.notify(
+            "textDocument/didOpen",
+            object! { textDocument: { uri: URI, languageId: "fern", version: 1, text: text } },
+        );
+    }
+
+    fn change(&mut self, version: usize, text: &str) {
+        self.notify(
+            "textDocument/didChange",
+            object! { textDocument: { uri: URI, version: version }, contentChanges: [{ text: text }] },
+        );
+    }
+
+    fn at(&mut self, method: &str, line: usize, character: usize) -> usize {
+        self.request(method, object! { textDocument: { uri: URI }, position: { line: line, character: character } })
+    }
+
+    /// Run the script through a server and return everything it sent back.
+    fn run(mut self) -> Vec<JsonValue> {
+        self.request("shutdown", JsonValue::Null);
+        self.notify("exit", JsonValue::Null);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for m in self.messages {
+            sender.send(m).unwrap();
+        }
+        let table = fern::lexing_table().unwrap();
+        let grammar = fern::grammar(&table).unwrap();
+        let mut server = LanguageServer::new(&table, &grammar);
+        let mut responses = Vec::new();
+        server.serve(&receiver, |m| responses.push(m));
+        assert!(server.shut_down());
+        responses
+    }
+}
+
+fn response(responses: &[JsonValue], id: usize) -> &JsonValue {
+    responses.iter().find(|r| r["id"] == id).unwrap_or_else(|| panic!("No response to {}", id))
+}
+
+fn diagnostics(responses: &[JsonValue]) -> Vec<&JsonValue> {
+    responses.iter().filter(|r| r["method"] == "textDocument/publishDiagnostics").collect()
+}
+
+fn range(start: (usize, usize), end: (usize, usize)) -> JsonValue {
+    object! {
+        start: { line: start.0, character: start.1 },
+        end: { line: end.0, character: end.1 },
+    }
+}
+
+#[test]
+fn messages_are_framed_with_content_length() {
+    let message = object! { jsonrpc: "2.0", id: 1, method: "initialize", params: { text: "é" } };
+    let mut buffer = Vec::new();
+    write_message(&mut buffer, &message).unwrap();
+    write_message(&mut buffer, &message).unwrap();
+    assert!(buffer.starts_with(format!("Content-Length: {}\r\n\r\n", message.dump().len()).as_bytes()));
+
+    let mut reader = Cursor::new(buffer);
+    assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+    assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+    assert_eq!(read_message(&mut reader).unwrap(), None);
+    assert!(read_message(&mut Cursor::new(b"Content-Type: x\r\n\r\n{}".to_vec())).is_err());
+}
+
+#[test]
+fn server_publishes_diagnostics() {
+    let mut client = Client::new();
+    client.open(SOURCE);
+    client.change(2, "let x = ;\nlet y = 2;\n");
+    client.request(
+        "textDocument/hover",
+        object! { textDocument: { uri: URI }, position: { line: 0, character: 0 } },
+    );
+    let responses = client.run();
+
+    let initialize = response(&responses, 1);
+    assert_eq!(initialize["result"]["serverInfo"]["name"], "fern-lsp");
+    let legend = &initialize["result"]["capabilities"]["semanticTokensProvider"]["legend"]["tokenTypes"];
+    assert_eq!(legend.len(), TOKEN_TYPES.len());
+
+    let published = diagnostics(&responses);
+    assert_eq!(published.len(), 2);
+    assert_eq!(published[0]["params"]["diagnostics"].len(), 0);
+    let errors = &published[1]["params"]["diagnostics"];
+    assert_eq!(published[1]["params"]["version"], 2);
+    assert!(!errors.is_empty());
+    assert!(errors[0]["message"].as_str().unwrap().starts_with("Parse Error: Unexpected SEMI"));
+    assert_eq!(errors[0]["range"], range((0, 8), (0, 9)));
+}
+
+#[test]
+fn server_reports_analysis_issues() {
+    let mut client = Client::new();
+    client.open("let x = 1;\nlet x = 2;\n");
+    let responses = client.run();
+    let published = diagnostics(&responses);
+    let issues = &published[0]["params"]["diagnostics"];
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0]["message"], "Resolve Error: Identifier x already exists.");
+    assert_eq!(issues[0]["range"], range((1, 4), (1, 5)));
+}
+
+#[test]
+fn server_answers_symbol_requests() {
+    let mut client = Client::new();
+    client.open(SOURCE);
+    let tokens = client.request("textDocument/semanticTokens/full", object! { textDocument: { uri: URI } });
+    let symbols = client.request("textDocument/documentSymbol", object! { textDocument: { uri: URI } });
+    // `sum` in `return sum;`, `a` in `a + b` and `add` in the call.
+    let sum = client.at("textDocument/definition", 7, 9);
+    let parameter = client.at("textDocument/definition", 6, 12);
+    let function = client.at("textDocument/definition", 9, 14);
+    let hover = client.at("textDocument/hover", 9, 13);
+    let nothing = client.at("textDocument/hover", 6, 1);
+    let responses = client.run();
+
+    let data: Vec<usize> = response(&responses, tokens)["result"]["data"]
+        .members()
+        .map(|v| v.as_usize().unwrap())
+        .collect();
+    let decoded: Vec<(usize, usize, usize, &str, usize)> = data
+        .chunks(5)
+        .scan((0, 0), |(line, start), t| {
+            *start = if t[0] == 0 { *start + t[1] } else { t[1] };
+            *line += t[0];
+            Some((*line, *start, t[2], TOKEN_TYPES[t[3]], t[4]))
+        })
+        .collect();
+    assert_eq!(decoded[0], (0, 0, 10, "comment", 0));
+    assert_eq!(decoded[1], (1, 0, 6, "keyword", 0));
+    assert_eq!(decoded[2], (1, 7, 5, "struct", 1));
+    assert!(decoded.contains(&(5, 3, 3, "function", 1)));
+    assert!(decoded.contains(&(5, 7, 1, "parameter", 1)));
+    assert!(decoded.contains(&(6, 11, 1, "parameter", 0)));
+    assert!(decoded.contains(&(6, 13, 1, "operator", 0)));
+    assert!(decoded.contains(&(9, 12, 3, "function", 0)));
+    assert!(decoded.contains(&(9, 16, 1, "number", 0)));
+
+    let symbols = &response(&responses, symbols)["result"];
+    assert_eq!(symbols.len(), 2);
+    assert_eq!(symbols[0]["name"], "Point");
+    assert_eq!(symbols[0]["kind"], 23);
+    assert_eq!(symbols[0]["range"], range((1, 0), (4, 1)));
+    assert_eq!(symbols[0]["selectionRange"], range((1, 7), (1, 12)));
+    let fields: Vec<&str> = symbols[0]["children"].members().map(|c| c["name"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["x", "y"]);
+    assert_eq!(symbols[1]["name"], "add");
+    assert_eq!(symbols[1]["kind"], 12);
+    assert_eq!(symbols[1]["detail"], "fn add[a: i64, b: i64]");
+
+    let location = &response(&responses, sum)["result"];
+    assert_eq!(location["uri"], URI);
+    assert_eq!(location["range"], range((6, 5), (6, 8)));
+    assert_eq!(response(&responses, parameter)["result"]["range"], range((5, 7), (5, 8)));
+    assert_eq!(response(&responses, function)["result"]["range"], range((5, 3), (5, 6)));
+
+    let hover = &response(&responses, hover)["result"];
+    assert_eq!(hover["contents"]["value"], "```fern\nfn add[a: i64, b: i64]\n```");
+    assert_eq!(hover["range"], range((9, 12), (9, 15)));
+    assert!(response(&responses, nothing)["result"].is_null());
+}
+
+#[test]
+fn definitions_follow_name_resolution() {
+    let mut client = Client::new();
+    client.open("fn f[xs: i64] {\n\tlet x = 1;\n\tfor k, v in xs {\n\t\tlet x = v;\n\t\treturn x;\n\t}\n\treturn x;\n}\n");
+    // `v` in the loop, the `x` declared in the loop and the one declared before it.
+    let binding = client.at("textDocument/definition", 3, 10);
+    let inner = client.at("textDocument/definition", 4, 9);
+    let outer = client.at("textDocument/definition", 6, 8);
+    let responses = client.run();
+
+    assert_eq!(response(&responses, binding)["result"]["range"], range((2, 8), (2, 9)));
+    assert_eq!(response(&responses, inner)["result"]["range"], range((3, 6), (3, 7)));
+    assert_eq!(response(&responses, outer)["result"]["range"], range((1, 5), (1, 6)));
+}
+
+#[test]
+fn stale_requests_are_cancelled() {
+    let mut client = Client::new();
+    client.open(SOURCE);
+    let cancelled = client.request("textDocument/documentSymbol", object! { textDocument: { uri: URI } });
+    client.notify("$/cancelRequest", object! { id: cancelled });
+    let outdated = client.request("textDocument/documentSymbol", object! { textDocument: { uri: URI } });
+    client.change(2, "fn a[] {}\n");
+    client.change(3, "fn b[] {}\n");
+    let current = client.request("textDocument/documentSymbol", object! { textDocument: { uri: URI } });
+    let unknown = client.request("textDocument/unknown", object! {});
+    let responses = client.run();
+
+    assert_eq!(response(&responses, cancelled)["error"]["code"], -32800);
+    assert_eq!(response(&responses, outdated)["error"]["code"], -32801);
+    assert_eq!(response(&responses, current)["result"][0]["name"], "b");
+    assert_eq!(response(&responses, unknown)["error"]["code"], -32601);
+    // Only the open and the last of the two changes are analysed.
+    let versions: Vec<&JsonValue> = diagnostics(&responses).iter().map(|d| &d["params"]["version"]).collect();
+    assert_eq!(versions, [1, 3]);
+}
+
+#[test]
+fn requests_before_initialize_are_refused() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    sender
+        .send(object! { jsonrpc: "2.0", id: 1, method: "textDocument/hover", params: {} })
+        .unwrap();
+    sender.send(object! { jsonrpc: "2.0", method: "exit" }).unwrap();
+    let table = fern::lexing_table().unwrap();
+    let grammar = fern::grammar(&table).unwrap();
+    let mut server = LanguageServer::new(&table, &grammar);
+    let mut responses = Vec::new();
+    server.serve(&receiver, |m| responses.push(m));
+    assert_eq!(responses[0]["error"]["code"], -32002);
+    assert!(!server.shut_down());
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::ast;
+use libfern::ast::AstBuilder;
+use libfern::fern::{AstNodeKind, OperatorKind};
+
+/// Print, check against `expected`, and check the output parses back to the same AST.
+fn round_trip(source: &str, expected: &str) {
+    let parsed = ast(source);
+    let printed = parsed.to_source();
+    assert_eq!(printed, expected);
+    assert_eq!(ast(&printed), parsed, "{}", printed);
+}
+
+#[test]
+fn statements_print_as_source() {
+    round_trip(
+        "fn main[a: int, b: int] {\n\tlet y;\n\tif x || (y && z) {\n\t\tlet x = 0;\n\t}\n\treturn x;\n}\n",
+        "fn main[a: int, b: int] {\n\tlet y;\n\tif x || y && z {\n\t\tlet x = 0;\n\t}\n\treturn x;\n}\n",
+    );
+    round_trip(
+        "struct Point { x: int, y: int, };\nstruct Empty {};\nlet p = nil;",
+        "struct Point {\n\tx: int,\n\ty: int\n};\nstruct Empty {}\nlet p = nil;\n",
+    );
+    round_trip(
+        "if a { b = 1; } elif c { b = 2; } elif d {} else { b.c = f(1, 2); };\nwhile not done { step(); };\n{ let x; }",
+        "if a {\n\tb = 1;\n} elif c {\n\tb = 2;\n} elif d {} else {\n\tb.c = f(1, 2);\n};\nwhile not done {\n\tstep();\n};\n{\n\tlet x;\n}\n",
+    );
+    round_trip(
+        "fn f[] {}\nfn g[a] { return a, b; };\nreturn;",
+        "fn f[] {}\nfn g[a] {\n\treturn a, b;\n}\nreturn;\n",
+    );
+    round_trip("x = \"s\" .. true .. false;", "x = \"s\" .. true .. false;\n");
+    round_trip(
+        "fn add[a: u32, b: u32] -> u32 { let c: u32 = a + b; let d: bool; return c; }",
+        "fn add[a: u32, b: u32] -> u32 {\n\tlet c: u32 = a + b;\n\tlet d: bool;\n\treturn c;\n}\n",
+    );
+}
+
+#[test]
+fn expressions_get_minimal_parentheses() {
+    let expr = |source: &str| {
+        let parsed = ast(&format!("x = {};", source));
+        let printed = parsed.to_source();
+        assert_eq!(ast(&printed), parsed, "{}", printed);
+        printed.trim_start_matches("x = ").trim_end_matches(";\n").to_string()
+    };
+    assert_eq!(expr("(a + b) + c"), "a + b + c");
+    assert_eq!(expr("a + (b + c)"), "a + (b + c)");
+    assert_eq!(expr("(a * b) + (c * d)"), "a * b + c * d");
+    assert_eq!(expr("(a + b) * (c - d)"), "(a + b) * (c - d)");
+    assert_eq!(expr("a - (b - c)"), "a - (b - c)");
+    assert_eq!(expr("(a .. b) .. c"), "(a .. b) .. c");
+    assert_eq!(expr("a .. (b .. c)"), "a .. b .. c");
+    assert_eq!(expr("a ^ (b ^ c)"), "a ^ b ^ c");
+    assert_eq!(expr("(a ^ b) ^ c"), "(a ^ b) ^ c");
+    assert_eq!(expr("-(a ^ b)"), "-a ^ b");
+    assert_eq!(expr("(-a) ^ b"), "(-a) ^ b");
+    assert_eq!(expr("-(a + b)"), "-(a + b)");
+    assert_eq!(expr("not (a == b)"), "not (a == b)");
+    assert_eq!(expr("(not a) == b"), "not a == b");
+    assert_eq!(expr("#a.b + f(c).d"), "#a.b + f(c).d");
+    assert_eq!(expr("(a < b) == (c >= d)"), "a < b == (c >= d)");
+    assert_eq!(expr("a || (b || c) && d"), "a || (b || c) && d");
+    assert_eq!(expr("(a + b).c"), "(a + b).c");
+}
+
+#[test]
+fn built_asts_print_as_source() {
+    let mut out = AstBuilder::new();
+    out.open(AstNodeKind::LetAssign);
+    out.leaf(AstNodeKind::Name("a".to_string()));
+    out.open(AstNodeKind::Operator(OperatorKind::Multiply));
+    out.open(AstNodeKind::Operator(OperatorKind::Add));
+    out.leaf(AstNodeKind::Number("1".to_string()));
+    out.leaf(AstNodeKind::Number("2".to_string()));
+    out.close();
+    out.open(AstNodeKind::Operator(OperatorKind::Negate));
+    out.leaf(AstNodeKind::Number("3".to_string()));
+    out.close();
+    out.close();
+    out.close();
+    out.open(AstNodeKind::Return);
+    out.leaf(AstNodeKind::Name("a".to_string()));
+    out.close();
+    let ast = out.finish();
+    assert_eq!(ast.to_source(), "let a = (1 + 2) * -(3);\nreturn a;\n");
+
+    let mut out = AstBuilder::new();
+    out.open(AstNodeKind::Operator(OperatorKind::Power));
+    out.leaf(AstNodeKind::Name("a".to_string()));
+    out.open(AstNodeKind::Operator(OperatorKind::Length));
+    out.leaf(AstNodeKind::Name("b".to_string()));
+    out.close();
+    out.close();
+    assert_eq!(out.finish().to_source(), "a ^ (#b)");
+}
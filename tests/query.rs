@@ -0,0 +1,60 @@
+mod common;
+
+use common::parse;
+use libfern::fern::FernAst;
+use libfern::query::{select, Queryable, Selector};
+
+const SOURCE: &str = "fn main[] {
+	let y;
+	if x || (y && z) {
+		let x = 0;
+	}
+	return x;
+}
+";
+
+fn values<T: Queryable>(tree: &T, selector: &str) -> Vec<String> {
+    select(tree, selector)
+        .unwrap()
+        .iter()
+        .map(|m| tree.attribute(m.id, "value").unwrap_or_else(|| tree.kind(m.id)))
+        .collect()
+}
+
+#[test]
+fn selectors_match_ast_nodes() {
+    let ast: FernAst = parse(SOURCE).into();
+
+    assert_eq!(values(&ast, "Function > Name"), ["main"]);
+    assert_eq!(values(&ast, "Function Let > Name, Function LetAssign > Name"), ["y", "x"]);
+    assert_eq!(values(&ast, "Operator[value=\"And\"] > :nth-child(2)"), ["z"]);
+    assert_eq!(values(&ast, "If > Operator Name"), ["x", "y", "z"]);
+    assert_eq!(values(&ast, "LetAssign > *:nth-child(2)"), ["0"]);
+    assert_eq!(values(&ast, "Return > Name[value='x']"), ["x"]);
+    assert!(select(&ast, "Return > Name[value=y]").unwrap().is_empty());
+    assert_eq!(select(&ast, "Function").unwrap()[0].id, 1);
+}
+
+#[test]
+fn selectors_match_parse_tree_nodes_with_spans() {
+    let tree = parse(SOURCE);
+
+    let lets = select(&tree, "stat > LET").unwrap();
+    let spans: Vec<_> = lets.iter().map(|m| m.span.clone().unwrap()).collect();
+    assert_eq!(spans, [5..6, 17..18]);
+    let z = select(&tree, "NAME[value=\"z\"]").unwrap();
+    assert_eq!(z.len(), 1);
+    assert_eq!(z[0].span, Some(14..15));
+    let root = select(&tree, "NewAxiom").unwrap();
+    assert_eq!(root[0].id, 0);
+    // 27 tokens, and a semicolon the lexer inserts after a closing brace.
+    assert_eq!(root[0].span, Some(0..28));
+}
+
+#[test]
+fn selector_syntax_errors() {
+    for bad in ["Function >", "Name[value", "Name[value=\"x]", ":nth-child(0)", ":first-child", "A ! B"] {
+        assert!(Selector::parse(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(Selector::parse("A > B").unwrap(), Selector::parse("A>B").unwrap());
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::parse;
+use libfern::fern::FernAst;
+use libfern::serialize::SCHEMA_VERSION;
+
+const SOURCE: &str = "fn main[] {
+	let y;
+	if x || (y && z) {
+		let x = 0;
+	}
+	return x;
+}
+";
+
+#[test]
+fn asts_print_as_sexps_with_spans() {
+    let ast: FernAst = parse("x = a + 1;\nreturn \"s\";").into();
+    assert_eq!(
+        ast.to_sexp(),
+        "(ast 1 (Module @0..9 (Assign @0..5 (Name \"x\" @0..1) (Operator \"Add\" @2..5 (Name \"a\" @2..3) (Number \"1\" @4..5))) \
+         (Return @6..8 (String \"\\\"s\\\"\" @7..8))))"
+    );
+}
+
+#[test]
+fn asts_round_trip_through_json() {
+    let ast: FernAst = parse(SOURCE).into();
+    let json = ast.to_json();
+    assert_eq!(json["version"], SCHEMA_VERSION);
+    assert_eq!(json["tree"], "ast");
+    let function = &json["root"]["children"][0];
+    assert_eq!(function["kind"], "Function");
+    assert_eq!(function["children"][0]["value"], "main");
+    assert_eq!(function["children"][0]["span"], json::array![1, 2]);
+    assert_eq!(json["root"]["span"], json::array![0, 28]);
+
+    let read = FernAst::from_json(&json.dump()).unwrap();
+    assert_eq!(read.to_sexp(), ast.to_sexp());
+    assert_eq!(read.root().unwrap().child(0).unwrap().span(), Some(0..28));
+
+    let empty = FernAst::from_json("{\"version\": 1, \"tree\": \"ast\", \"root\": null}").unwrap();
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn reading_json_checks_the_schema() {
+    let error = |text: &str| FernAst::from_json(text).err().unwrap().to_string();
+    let node = |kind: &str| format!("{{\"version\": 1, \"tree\": \"ast\", \"root\": {}}}", kind);
+    assert_eq!(error("{"), "Serialize Error: Invalid JSON: Unexpected end of JSON");
+    assert!(error("{\"version\": 2, \"tree\": \"ast\"}").contains("Unsupported schema version 2, expected 1."));
+    assert!(error("{\"version\": 1, \"tree\": \"parse\"}").contains("Expected an AST, found parse."));
+    assert!(error(&node("{\"kind\": \"Loop\", \"children\": []}")).contains("Unknown node kind Loop."));
+    assert!(error(&node("{\"kind\": \"Name\", \"children\": []}")).contains("Name has no value."));
+    assert!(error(&node("{\"kind\": \"Operator\", \"value\": \"Xor\", \"children\": []}")).contains("Unknown operator Xor."));
+    assert!(error(&node("{\"kind\": \"Nil\", \"span\": [3, 1], \"children\": []}")).contains("Invalid span [3,1] in Nil."));
+    assert!(error(&node("{\"kind\": \"Nil\"}")).contains("Expected a list of children in Nil."));
+}
+
+#[test]
+fn parse_trees_keep_token_data() {
+    let tree = parse("let x = 1; // one\n");
+    let json = tree.to_json();
+    assert_eq!(json["version"], SCHEMA_VERSION);
+    assert_eq!(json["tree"], "parse");
+    assert_eq!(json["roots"].len(), 1);
+    let root = &json["roots"][0];
+    assert_eq!(root["kind"], "NewAxiom");
+    assert_eq!(root["span"], json::array![0, 5]);
+
+    let mut leaves = Vec::new();
+    let mut pending = vec![root];
+    while let Some(n) = pending.pop() {
+        if n["children"].is_empty() {
+            leaves.push(n);
+        }
+        pending.extend(n["children"].members().rev());
+    }
+    let x = leaves.iter().find(|l| l["kind"] == "NAME").unwrap();
+    assert_eq!(x["token"]["raw"], "x");
+    assert_eq!(x["token"]["index"], 1);
+    assert_eq!(x["span"], json::array![1, 2]);
+    let semi = leaves.iter().find(|l| l["kind"] == "SEMI").unwrap();
+    assert_eq!(semi["token"]["trailing"], " // one\n");
+    assert_eq!(semi["token"]["inserted"], false);
+
+    assert!(tree.to_sexp().starts_with("(parse 1 (NewAxiom "));
+    assert!(tree.to_sexp().contains("(LET 0 \"let\") (baseExp (NAME 1 \"x\"))"));
+}
+
+#[test]
+fn long_programs_serialise_without_recursion() {
+    let source = "x = 1;\n".repeat(2000);
+    let tree = parse(&source);
+    let ast: FernAst = parse(&source).into();
+    assert_eq!(tree.to_json()["roots"].len(), 1);
+    assert_eq!(tree.to_sexp().matches("(NUMBER").count(), 2000);
+    let read = FernAst::from_json(&ast.to_json().dump()).unwrap();
+    assert_eq!(read.len(), ast.len());
+}
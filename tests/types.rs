@@ -0,0 +1,151 @@
+mod common;
+
+use common::ast;
+use libfern::ast::{Node, NodeId};
+use libfern::fern::{AstNodeKind, FernAst};
+use libfern::types::Type;
+
+/// Type errors in `source`.
+fn errors(source: &str) -> Vec<String> {
+    let ast = ast(source);
+    let typing = ast.check(&ast.resolve());
+    typing.errors().iter().map(|e| e.to_string()).collect()
+}
+
+/// The id of the first node of kind `kind`, in pre-order.
+fn first(ast: &FernAst, kind: AstNodeKind) -> NodeId {
+    ast.root().unwrap().descendants().find(|node: &Node| *node.kind() == kind).unwrap().id()
+}
+
+#[test]
+fn builtin_types() {
+    let names = ["i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "bool", "str"];
+    for name in names {
+        assert_eq!(Type::builtin(name).unwrap().to_string(), name);
+    }
+    assert_eq!(Type::builtin("int"), None);
+    assert!(Type::U8.is_integer() && !Type::Bool.is_integer());
+    assert_eq!(Type::Function(vec![Type::I8, Type::Str], Box::new(Type::Nil)).to_string(), "fn[i8, str]");
+}
+
+#[test]
+fn expressions_are_typed() {
+    let ast = ast("fn add[a: u32, b: u32] -> u32 {\n\treturn a + b;\n}\nfn main[] {\n\tlet x = add(1, 2) > 3;\n\tlet s = \"a\" .. \"b\";\n\tlet n = #s;\n}\n");
+    let typing = ast.check(&ast.resolve());
+    assert!(typing.errors().is_empty());
+    let ty = |kind| typing.type_of(first(&ast, kind)).unwrap().to_string();
+    assert_eq!(ty(AstNodeKind::Name("add".to_string())), "fn[u32, u32] -> u32");
+    assert_eq!(ty(AstNodeKind::Name("a".to_string())), "u32");
+    assert_eq!(ty(AstNodeKind::FunctionCall), "u32");
+    assert_eq!(ty(AstNodeKind::Number("3".to_string())), "u32");
+    assert_eq!(ty(AstNodeKind::Name("x".to_string())), "bool");
+    assert_eq!(ty(AstNodeKind::Name("s".to_string())), "str");
+    assert_eq!(ty(AstNodeKind::Name("n".to_string())), "u64");
+}
+
+#[test]
+fn operators_check_their_operands() {
+    assert_eq!(
+        errors("let x = \"best type checker is no type checker\" / 3;"),
+        ["Type Error: Operator Divide expects integer operands of the same type, found str and integer."]
+    );
+    assert_eq!(
+        errors("let a: u8 = 1;\nlet b: i8 = 2;\nlet c = a + b;\nlet d = a < 2 || not a;"),
+        [
+            "Type Error: Operator Add expects integer operands of the same type, found u8 and i8.",
+            "Type Error: Operator Not expects a bool operand, found u8.",
+        ]
+    );
+    assert_eq!(
+        errors("let s = \"a\" .. 1;\nlet t = true == 1;"),
+        [
+            "Type Error: Operator Concat expects str operands, found str and integer.",
+            "Type Error: Operator Equal expects operands of the same type, found bool and integer.",
+        ]
+    );
+}
+
+#[test]
+fn statements_check_against_annotations() {
+    let source = "fn f[a: i64] -> bool {
+	let b: i64 = a;
+	let c: str = b;
+	if b {
+		return;
+	};
+	while a > b {
+		a = true;
+	}
+	return f(1, 2);
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            "Type Error: Expected a value of type str, found i64.",
+            "Type Error: Expected a condition of type bool, found i64.",
+            "Type Error: Expected a return value of type bool.",
+            "Type Error: Expected a value of type i64, found bool.",
+            "Type Error: Expected 1 arguments, found 2.",
+        ]
+    );
+}
+
+#[test]
+fn unannotated_names_are_not_errors() {
+    assert!(errors("fn f[a] {\n\tlet b;\n\treturn a + b * 2;\n}\n").is_empty());
+    assert_eq!(errors("let x: int = 1;"), ["Type Error: Unknown type int."]);
+}
+
+#[test]
+fn literals_default_to_i64() {
+    let ast = ast("fn main[] {\n\tlet x = 3 + 4 / 10 * 30;\n\tlet y: u8 = 1;\n}\n");
+    let typing = ast.check(&ast.resolve());
+    assert!(typing.errors().is_empty());
+    let ty = |kind| typing.type_of(first(&ast, kind)).unwrap().to_string();
+    assert_eq!(ty(AstNodeKind::Name("main".to_string())), "fn[]");
+    assert_eq!(ty(AstNodeKind::Name("x".to_string())), "i64");
+    assert_eq!(ty(AstNodeKind::Number("30".to_string())), "i64");
+    assert_eq!(ty(AstNodeKind::Number("1".to_string())), "u8");
+}
+
+#[test]
+fn types_are_inferred_across_statements() {
+    let ast = ast("fn id[a] {
+\treturn a;
+}
+fn main[] {
+\tlet x;
+\tx = 1;
+\tlet y: u16 = id(x);
+\tlet s = id;
+}
+");
+    let typing = ast.check(&ast.resolve());
+    assert!(typing.errors().is_empty());
+    let ty = |kind| typing.type_of(first(&ast, kind)).unwrap().to_string();
+    assert_eq!(ty(AstNodeKind::Name("id".to_string())), "fn[u16] -> u16");
+    assert_eq!(ty(AstNodeKind::Name("x".to_string())), "u16");
+    assert_eq!(ty(AstNodeKind::Name("s".to_string())), "fn[u16] -> u16");
+    assert_eq!(ty(AstNodeKind::Number("1".to_string())), "u16");
+
+    assert_eq!(
+        errors("fn f[a] {\n\ta = 1;\n\ta = true;\n}\n"),
+        ["Type Error: Expected a value of type integer, found bool."]
+    );
+}
+
+#[test]
+fn uninferred_types_are_errors() {
+    assert_eq!(
+        errors("fn f[a] {\n\tlet d;\n\treturn;\n}\nfn g[b] {\n\tlet c = b;\n\treturn c;\n}\n"),
+        [
+            "Type Error: Cannot infer the type of a.",
+            "Type Error: Cannot infer the type of d.",
+            // The type of `b` is the same one, so it is only reported once.
+            "Type Error: Cannot infer the return type of g.",
+        ]
+    );
+    // A call is enough to infer them.
+    assert!(errors("fn f[a] {\n\treturn a;\n}\nfn main[] {\n\tlet r = f(true);\n}\n").is_empty());
+}